@@ -0,0 +1,62 @@
+use std::collections::BTreeSet;
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+
+// Principals allowed to write when the topic enforces access control.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct AccessControl {
+    controllers: BTreeSet<Principal>,
+}
+
+impl AccessControl {
+    pub(crate) fn add(&mut self, controller: Principal) -> Result<(), FsError> {
+        if !self.controllers.insert(controller) {
+            return Err(FsError::InvalidArgument(format!("{} is already a controller", controller)));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, controller: &Principal) -> Result<(), FsError> {
+        if !self.controllers.remove(controller) {
+            return Err(FsError::InvalidArgument(format!("{} is not a controller", controller)));
+        }
+        Ok(())
+    }
+
+    // An empty set lets nobody through.
+    pub(crate) fn guard(&self, caller: &Principal) -> Result<(), FsError> {
+        if !self.controllers.contains(caller) {
+            return Err(FsError::Unauthorized(caller.to_string()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn controllers(&self) -> Vec<Principal> {
+        self.controllers.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use candid::Principal;
+
+    use crate::access_control::AccessControl;
+    use crate::error::FsError;
+
+    #[test]
+    fn it_guards_by_controller() {
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut access = AccessControl::default();
+        assert!(access.guard(&a).is_err());
+        access.add(a).unwrap();
+        assert!(access.add(a).is_err());
+        access.guard(&a).unwrap();
+        assert_eq!(access.guard(&b), Err(FsError::Unauthorized(b.to_string())));
+        access.remove(&a).unwrap();
+        assert!(access.remove(&a).is_err());
+        assert_eq!(access.controllers(), vec![]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::cell::RefCell;
+use std::ops::Range;
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+use crate::{BlockStorage, EventFilesystem, FnStorage, StableEncode};
+
+const CONFIG_RECORD: &str = "access_log.config";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccessRecord {
+    pub caller: Principal,
+    pub start: u64,
+    pub take: u64,
+    pub timestamp: u64,
+}
+
+impl StableEncode for AccessRecord {}
+
+// Which reads make it into the audit topic. Sampling is deterministic, every `sample_every`th read is
+// kept, and at most `max_per_window` reads are kept per window of `window_nanos` on the topic clock.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessLogConfig {
+    pub sample_every: u64,
+    pub max_per_window: u64,
+    pub window_nanos: u64,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig { sample_every: 1, max_per_window: u64::MAX, window_nanos: 60 * 1_000_000_000 }
+    }
+}
+
+#[derive(Default)]
+struct Sampler {
+    seen: u64,
+    window_start: u64,
+    in_window: u64,
+    dropped: u64,
+}
+
+// Records who read which range of a topic into a dedicated audit topic. Writes made in query calls are
+// discarded, so only reads served from update calls end up in the log. Expose `records` to controllers
+// only, the log tells who read what.
+pub struct AccessLog<S: BlockStorage = FnStorage> {
+    fs: EventFilesystem<S>,
+    config: AccessLogConfig,
+    sampler: RefCell<Sampler>,
+}
+
+impl<S: BlockStorage> AccessLog<S> {
+    // Keeps the config of an existing log unless `config` is given.
+    pub fn new(fs: EventFilesystem<S>, config: Option<AccessLogConfig>) -> Result<Self, FsError> {
+        let config = match config {
+            Some(config) => config,
+            None => fs.meta.get_value(CONFIG_RECORD)?.unwrap_or_default(),
+        };
+        if config.sample_every == 0 || config.window_nanos == 0 {
+            return Err(FsError::InvalidArgument(format!("Access log needs a non-zero sampling rate and window, got {:?}", config)));
+        }
+        fs.meta.put_value(CONFIG_RECORD, &config)?;
+        Ok(AccessLog { fs, config, sampler: RefCell::new(Sampler::default()) })
+    }
+
+    pub fn config(&self) -> AccessLogConfig {
+        self.config
+    }
+
+    // Appends a record of `caller` reading `range` if sampling and the rate limit let it through, and
+    // returns its height in the audit topic.
+    pub fn record(&self, caller: Principal, range: Range<u64>) -> Result<Option<u64>, FsError> {
+        let timestamp = (self.fs.clock)();
+        if !self.sampler.borrow_mut().admit(&self.config, timestamp) {
+            return Ok(None);
+        }
+        let record = AccessRecord { caller, start: range.start, take: range.end.saturating_sub(range.start), timestamp };
+        self.fs.write_topic_message(&record).map(Some)
+    }
+
+    // Sampled reads that were over the rate limit since the log was opened.
+    pub fn dropped(&self) -> u64 {
+        self.sampler.borrow().dropped
+    }
+
+    pub fn records(&self, start: u64, take: u64) -> Result<Vec<AccessRecord>, FsError> {
+        let take = take.min(self.fs.get_topic_height().saturating_sub(start));
+        self.fs.read_topic_messages(start, take)
+    }
+
+    pub fn filesystem(&self) -> &EventFilesystem<S> {
+        &self.fs
+    }
+}
+
+impl Sampler {
+    fn admit(&mut self, config: &AccessLogConfig, now: u64) -> bool {
+        let sampled = self.seen.is_multiple_of(config.sample_every);
+        self.seen += 1;
+        if !sampled {
+            return false;
+        }
+        if now.saturating_sub(self.window_start) >= config.window_nanos {
+            self.window_start = now - now % config.window_nanos;
+            self.in_window = 0;
+        }
+        if self.in_window >= config.max_per_window {
+            self.dropped += 1;
+            return false;
+        }
+        self.in_window += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use candid::Principal;
+
+    use crate::access_log::{AccessLog, AccessLogConfig, AccessRecord};
+    use crate::{EventFilesystemBuilder, VecStorage};
+
+    #[test]
+    fn it_samples_and_rate_limits_reads() {
+        let fs = EventFilesystemBuilder::with_storage(VecStorage::default(), || 5).get_or_create("audit".to_string()).unwrap();
+        let config = AccessLogConfig { sample_every: 2, max_per_window: 2, window_nanos: 10 };
+        let log = AccessLog::new(fs, Some(config)).unwrap();
+        let caller = Principal::from_slice(&[7]);
+
+        let heights = (0..6).map(|i| log.record(caller, i..i + 3).unwrap()).collect::<Vec<_>>();
+        assert_eq!(heights, vec![Some(0), None, Some(1), None, None, None]);
+        assert_eq!(log.dropped(), 1);
+        assert_eq!(log.records(1, 10).unwrap(), vec![AccessRecord { caller, start: 2, take: 3, timestamp: 5 }]);
+        assert!(AccessLog::new(log.fs, Some(AccessLogConfig { sample_every: 0, ..config })).is_err());
+    }
+}
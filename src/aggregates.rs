@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+// Folds a message, as JSON, into the accumulator of an aggregate.
+pub type FoldFn = fn(&mut Value, &Value);
+
+#[derive(Clone)]
+struct Aggregate {
+    fold: FoldFn,
+    accumulator: Value,
+}
+
+// Aggregates kept up to date on every append so they can be read without scanning the topic. Fold
+// functions can't be persisted and are registered again after an upgrade, their accumulators are
+// restored from the meta zone.
+#[derive(Clone, Default)]
+pub(crate) struct Aggregates {
+    aggregates: BTreeMap<String, Aggregate>,
+}
+
+impl Aggregates {
+    pub(crate) fn register(&mut self, name: &str, fold: FoldFn, accumulator: Value) {
+        self.aggregates.insert(name.to_string(), Aggregate { fold, accumulator });
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.aggregates.is_empty()
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Value> {
+        self.aggregates.get(name).map(|aggregate| &aggregate.accumulator)
+    }
+
+    // Names whose accumulator differs from the one in `before`.
+    pub(crate) fn changed_from(&self, before: &Aggregates) -> Vec<String> {
+        self.aggregates.iter()
+            .filter(|(name, aggregate)| before.get(name) != Some(&aggregate.accumulator))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Returns the updated accumulators by name.
+    pub(crate) fn apply(&mut self, message: &Value) -> Vec<(&str, &Value)> {
+        self.aggregates.iter_mut()
+            .map(|(name, aggregate)| {
+                (aggregate.fold)(&mut aggregate.accumulator, message);
+                (name.as_str(), &aggregate.accumulator)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use crate::aggregates::Aggregates;
+
+    fn sum(accumulator: &mut Value, message: &Value) {
+        *accumulator = json!(accumulator.as_u64().unwrap_or(0) + message["amount"].as_u64().unwrap_or(0));
+    }
+
+    #[test]
+    fn it_folds_messages_into_accumulators() {
+        let mut aggregates = Aggregates::default();
+        assert!(aggregates.is_empty());
+        aggregates.register("total", sum, json!(5));
+
+        let updated = aggregates.apply(&json!({ "amount": 3 }));
+        assert_eq!(updated, vec![("total", &json!(8))]);
+        let before = aggregates.clone();
+        aggregates.apply(&json!({ "kind": "other" }));
+        assert_eq!(aggregates.get("total"), Some(&json!(8)));
+        assert!(aggregates.changed_from(&before).is_empty());
+        aggregates.apply(&json!({ "amount": 1 }));
+        assert_eq!(aggregates.changed_from(&before), vec!["total".to_string()]);
+        assert_eq!(aggregates.get("missing"), None);
+    }
+}
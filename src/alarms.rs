@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// Alarm windows are measured with the filesystem clock, which on the IC is `ic_cdk::api::time` in nanoseconds.
+pub const ALARM_WINDOW_NANOS: u64 = 60 * 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AlarmKind {
+    MessagesPerMinute,
+    BytesPerMinute,
+    ErrorsPerMinute,
+    // Messages retention would drop but a slow consumer holds back, checked by apply_retention.
+    ConsumerLag,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct AlarmBreach {
+    pub kind: AlarmKind,
+    pub observed: u64,
+    pub threshold: u64,
+    pub timestamp: u64,
+}
+
+pub type AlarmCallback = fn(&AlarmBreach);
+
+struct Alarm {
+    threshold: u64,
+    callback: AlarmCallback,
+    fired_in_window: bool,
+}
+
+// Fixed one minute windows of write activity. Each alarm fires at most once per window.
+#[derive(Default)]
+pub(crate) struct Alarms {
+    alarms: BTreeMap<AlarmKind, Alarm>,
+    window_start: u64,
+    messages: u64,
+    bytes: u64,
+    errors: u64,
+    blocked: u64,
+}
+
+impl Alarms {
+    pub(crate) fn set(&mut self, kind: AlarmKind, threshold: u64, callback: AlarmCallback) {
+        self.alarms.insert(kind, Alarm { threshold, callback, fired_in_window: false });
+    }
+
+    pub(crate) fn clear(&mut self, kind: AlarmKind) {
+        self.alarms.remove(&kind);
+    }
+
+    pub(crate) fn record_write(&mut self, now: u64, bytes: u64) -> Vec<AlarmBreach> {
+        self.roll_window(now);
+        self.messages += 1;
+        self.bytes += bytes;
+        self.check(now)
+    }
+
+    pub(crate) fn record_error(&mut self, now: u64) -> Vec<AlarmBreach> {
+        self.roll_window(now);
+        self.errors += 1;
+        self.check(now)
+    }
+
+    pub(crate) fn record_blocked(&mut self, now: u64, blocked: u64) -> Vec<AlarmBreach> {
+        self.roll_window(now);
+        self.blocked = blocked;
+        self.check(now)
+    }
+
+    // Activity so far in the window `now` falls in, with the threshold of each alarm that is set.
+    pub(crate) fn usage(&self, now: u64) -> Vec<(AlarmKind, u64, u64)> {
+        let current = now < self.window_start + ALARM_WINDOW_NANOS;
+        self.alarms.iter().map(|(kind, alarm)| {
+            let observed = match kind {
+                AlarmKind::MessagesPerMinute => self.messages,
+                AlarmKind::BytesPerMinute => self.bytes,
+                AlarmKind::ErrorsPerMinute => self.errors,
+                AlarmKind::ConsumerLag => self.blocked,
+            };
+            (*kind, if current { observed } else { 0 }, alarm.threshold)
+        }).collect()
+    }
+
+    fn roll_window(&mut self, now: u64) {
+        if now >= self.window_start + ALARM_WINDOW_NANOS {
+            self.window_start = now - now % ALARM_WINDOW_NANOS;
+            self.messages = 0;
+            self.bytes = 0;
+            self.errors = 0;
+            self.blocked = 0;
+            for alarm in self.alarms.values_mut() {
+                alarm.fired_in_window = false;
+            }
+        }
+    }
+
+    fn check(&mut self, now: u64) -> Vec<AlarmBreach> {
+        let mut breaches = Vec::new();
+        for (kind, alarm) in self.alarms.iter_mut() {
+            let observed = match kind {
+                AlarmKind::MessagesPerMinute => self.messages,
+                AlarmKind::BytesPerMinute => self.bytes,
+                AlarmKind::ErrorsPerMinute => self.errors,
+                AlarmKind::ConsumerLag => self.blocked,
+            };
+            if observed > alarm.threshold && !alarm.fired_in_window {
+                alarm.fired_in_window = true;
+                let breach = AlarmBreach {
+                    kind: *kind,
+                    observed,
+                    threshold: alarm.threshold,
+                    timestamp: now,
+                };
+                debug!("Alarm breached {:?}", breach);
+                (alarm.callback)(&breach);
+                breaches.push(breach);
+            }
+        }
+        breaches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use crate::alarms::{ALARM_WINDOW_NANOS, AlarmBreach, AlarmKind, Alarms};
+
+    thread_local! {
+        static FIRED: RefCell<Vec<AlarmBreach>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn on_breach(breach: &AlarmBreach) {
+        FIRED.with(|f| f.borrow_mut().push(*breach));
+    }
+
+    #[test]
+    fn it_fires_once_per_window() {
+        let mut alarms = Alarms::default();
+        alarms.set(AlarmKind::MessagesPerMinute, 2, on_breach);
+        alarms.set(AlarmKind::BytesPerMinute, 1000, on_breach);
+
+        assert!(alarms.record_write(10, 100).is_empty());
+        assert!(alarms.record_write(20, 100).is_empty());
+        assert_eq!(alarms.record_write(30, 100)[0].kind, AlarmKind::MessagesPerMinute);
+        assert!(alarms.record_write(40, 100).is_empty());
+
+        let breaches = alarms.record_write(ALARM_WINDOW_NANOS + 1, 5000);
+        assert_eq!(breaches.len(), 1);
+        assert_eq!(breaches[0].kind, AlarmKind::BytesPerMinute);
+        assert_eq!(breaches[0].observed, 5000);
+
+        FIRED.with(|f| assert_eq!(f.borrow().len(), 2));
+    }
+
+    #[test]
+    fn it_counts_errors_and_clears_alarms() {
+        let mut alarms = Alarms::default();
+        alarms.set(AlarmKind::ErrorsPerMinute, 0, on_breach);
+        assert_eq!(alarms.record_error(5).len(), 1);
+
+        alarms.clear(AlarmKind::ErrorsPerMinute);
+        assert!(alarms.record_error(ALARM_WINDOW_NANOS * 2).is_empty());
+    }
+}
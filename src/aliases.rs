@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct AliasRun {
+    source_start: u64,
+    local_start: u64,
+    len: u64,
+}
+
+// Source heights of imported or merged messages mapped to their local heights. Imports mostly keep
+// consecutive messages consecutive, so each source is kept as runs sorted by source height.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AliasTable {
+    sources: BTreeMap<String, Vec<AliasRun>>,
+}
+
+impl AliasTable {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, source: &str, source_height: u64, local_height: u64) -> Result<(), FsError> {
+        if let Some(existing) = self.resolve(source, source_height) {
+            if existing == local_height {
+                return Ok(());
+            }
+            return Err(FsError::InvalidArgument(format!("{} height {} is already an alias of {}", source, source_height, existing)));
+        }
+        let runs = self.sources.entry(source.to_string()).or_default();
+        let position = runs.partition_point(|run| run.source_start < source_height);
+        match position.checked_sub(1).map(|previous| &mut runs[previous]) {
+            Some(run) if run.source_start + run.len == source_height && run.local_start + run.len == local_height => run.len += 1,
+            _ => runs.insert(position, AliasRun { source_start: source_height, local_start: local_height, len: 1 }),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn resolve(&self, source: &str, source_height: u64) -> Option<u64> {
+        let runs = self.sources.get(source)?;
+        let run = runs[..runs.partition_point(|run| run.source_start <= source_height)].last()?;
+        (source_height < run.source_start + run.len).then(|| run.local_start + source_height - run.source_start)
+    }
+
+    pub(crate) fn aliases_of(&self, local_height: u64) -> Vec<(String, u64)> {
+        self.sources.iter()
+            .flat_map(|(source, runs)| runs.iter()
+                .filter(move |run| (run.local_start..run.local_start + run.len).contains(&local_height))
+                .map(move |run| (source.clone(), run.source_start + local_height - run.local_start)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::aliases::AliasTable;
+
+    #[test]
+    fn it_maps_source_heights_in_runs() {
+        let mut table = AliasTable::default();
+        for (source_height, local_height) in [(10, 0), (11, 1), (12, 2), (20, 3), (5, 7)] {
+            table.insert("shard-a", source_height, local_height).unwrap();
+        }
+        table.insert("shard-b", 0, 4).unwrap();
+        table.insert("shard-a", 11, 1).unwrap();
+        assert!(table.insert("shard-a", 11, 2).is_err());
+
+        assert_eq!(table.sources["shard-a"].len(), 3);
+        assert_eq!(table.resolve("shard-a", 12), Some(2));
+        assert_eq!(table.resolve("shard-a", 13), None);
+        assert_eq!(table.resolve("shard-a", 5), Some(7));
+        assert_eq!(table.resolve("shard-a", 4), None);
+        assert_eq!(table.resolve("shard-c", 0), None);
+        assert_eq!(table.aliases_of(4), vec![("shard-b".to_string(), 0)]);
+        assert_eq!(table.aliases_of(1), vec![("shard-a".to_string(), 11)]);
+    }
+}
@@ -0,0 +1,331 @@
+use serde::Serialize;
+
+use crate::constants::U64_SIZE;
+use crate::error::FsError;
+use crate::read_write::{BlockRead, BlockWrite};
+use crate::storage::{FnStorage, Storage};
+
+pub const ARENA_MAGIC: u64 = 0x4152_454e_415f_4653;
+
+// magic | top | free list head
+pub(crate) const ARENA_HEADER_SIZE: u64 = U64_SIZE * 3;
+// capacity | next free chunk, or CHUNK_IN_USE while allocated
+const CHUNK_HEADER_SIZE: u64 = U64_SIZE * 2;
+const CHUNK_IN_USE: u64 = u64::MAX;
+const MIN_CHUNK_SIZE: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct ArenaStats {
+    pub region_size: u64,
+    pub allocated_bytes: u64,
+    pub allocated_chunks: u64,
+    pub free_list_bytes: u64,
+    pub free_chunks: u64,
+    pub unallocated_bytes: u64,
+}
+
+// A first-fit allocator over a fixed stable memory region. All state lives in the region itself,
+// so an arena can be re-opened after an upgrade with `Arena::open` on the same bounds.
+pub struct Arena {
+    start: u64,
+    end: u64,
+    storage: Storage,
+}
+
+fn align(size: u64) -> u64 {
+    size.max(MIN_CHUNK_SIZE).div_ceil(U64_SIZE) * U64_SIZE
+}
+
+impl Arena {
+    pub fn open(start: u64, end: u64, write_fn: BlockWrite, read_fn: BlockRead) -> Result<Arena, FsError> {
+        Arena::open_in(start, end, Storage::new(FnStorage::new(write_fn, read_fn)))
+    }
+
+    pub fn open_in(start: u64, end: u64, storage: Storage) -> Result<Arena, FsError> {
+        if end < start + ARENA_HEADER_SIZE {
+            return Err(FsError::InvalidArgument(format!("Arena region {}..{} is too small", start, end)));
+        }
+        let arena = Arena { start, end, storage };
+        if arena.read_u64(start) != ARENA_MAGIC {
+            debug!("Formatting arena at {}..{}", start, end);
+            arena.write_u64(start, ARENA_MAGIC);
+            arena.set_top(start + ARENA_HEADER_SIZE);
+            arena.set_free_head(0);
+        }
+        Ok(arena)
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    // Returns the offset of a payload area of at least `size` bytes.
+    pub fn allocate(&self, size: u64) -> Result<u64, FsError> {
+        let size = align(size);
+
+        let mut prev = 0;
+        let mut chunk = self.free_head();
+        while chunk != 0 {
+            let capacity = self.read_u64(chunk);
+            let next = self.read_u64(chunk + U64_SIZE);
+            if capacity >= size {
+                self.unlink(prev, next);
+                if capacity - size >= CHUNK_HEADER_SIZE + MIN_CHUNK_SIZE {
+                    let rest = chunk + CHUNK_HEADER_SIZE + size;
+                    self.write_u64(chunk, size);
+                    self.write_u64(rest, capacity - size - CHUNK_HEADER_SIZE);
+                    self.push_free(rest);
+                }
+                self.write_u64(chunk + U64_SIZE, CHUNK_IN_USE);
+                return Ok(chunk + CHUNK_HEADER_SIZE);
+            }
+            prev = chunk;
+            chunk = next;
+        }
+
+        let chunk = self.top();
+        if chunk + CHUNK_HEADER_SIZE + size > self.end {
+            return Err(FsError::OutOfSpace(format!("Arena requested {} bytes, {} available", size, self.end.saturating_sub(chunk + CHUNK_HEADER_SIZE))));
+        }
+        self.write_u64(chunk, size);
+        self.write_u64(chunk + U64_SIZE, CHUNK_IN_USE);
+        self.set_top(chunk + CHUNK_HEADER_SIZE + size);
+        Ok(chunk + CHUNK_HEADER_SIZE)
+    }
+
+    pub fn free(&self, ptr: u64) -> Result<(), FsError> {
+        let chunk = self.chunk_of(ptr)?;
+        let capacity = self.read_u64(chunk);
+        if chunk + CHUNK_HEADER_SIZE + capacity == self.top() {
+            self.set_top(chunk);
+        } else {
+            self.push_free(chunk);
+        }
+        Ok(())
+    }
+
+    // Grows or shrinks an allocation, moving (and copying) it only when it no longer fits.
+    pub fn realloc(&self, ptr: u64, size: u64) -> Result<u64, FsError> {
+        if self.resize_in_place(ptr, size)? {
+            return Ok(ptr);
+        }
+        let capacity = self.capacity(ptr)?;
+        let new_ptr = self.allocate(size)?;
+        let mut buf = vec![0u8; capacity as usize];
+        self.storage.read(ptr, &mut buf);
+        self.storage.write(new_ptr, &buf);
+        self.free(ptr)?;
+        Ok(new_ptr)
+    }
+
+    // Whether the allocation holds `size` bytes now, growing it if it is the last one.
+    pub fn resize_in_place(&self, ptr: u64, size: u64) -> Result<bool, FsError> {
+        let capacity = self.capacity(ptr)?;
+        if align(size) <= capacity {
+            return Ok(true);
+        }
+        let chunk = ptr - CHUNK_HEADER_SIZE;
+        if chunk + CHUNK_HEADER_SIZE + capacity == self.top() && ptr + align(size) <= self.end {
+            self.write_u64(chunk, align(size));
+            self.set_top(ptr + align(size));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub fn capacity(&self, ptr: u64) -> Result<u64, FsError> {
+        let chunk = self.chunk_of(ptr)?;
+        Ok(self.read_u64(chunk))
+    }
+
+    pub fn write(&self, ptr: u64, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let capacity = self.capacity(ptr)?;
+        if offset + data.len() as u64 > capacity {
+            return Err(FsError::InvalidArgument(format!("Write of {} bytes at {} overflows allocation of {} bytes", data.len(), offset, capacity)));
+        }
+        self.storage.write(ptr + offset, data);
+        Ok(())
+    }
+
+    pub fn read(&self, ptr: u64, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let capacity = self.capacity(ptr)?;
+        if offset + buf.len() as u64 > capacity {
+            return Err(FsError::InvalidArgument(format!("Read of {} bytes at {} overflows allocation of {} bytes", buf.len(), offset, capacity)));
+        }
+        self.storage.read(ptr + offset, buf);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        let mut stats = ArenaStats {
+            region_size: self.end - self.start,
+            unallocated_bytes: self.end - self.top(),
+            ..Default::default()
+        };
+        let mut chunk = self.start + ARENA_HEADER_SIZE;
+        while chunk < self.top() {
+            let capacity = self.read_u64(chunk);
+            if self.read_u64(chunk + U64_SIZE) == CHUNK_IN_USE {
+                stats.allocated_bytes += capacity;
+                stats.allocated_chunks += 1;
+            } else {
+                stats.free_list_bytes += capacity;
+                stats.free_chunks += 1;
+            }
+            chunk += CHUNK_HEADER_SIZE + capacity;
+        }
+        stats
+    }
+
+    fn chunk_of(&self, ptr: u64) -> Result<u64, FsError> {
+        if ptr < self.start + ARENA_HEADER_SIZE + CHUNK_HEADER_SIZE || ptr >= self.top() {
+            return Err(FsError::InvalidArgument(format!("Pointer {} is outside the arena", ptr)));
+        }
+        let chunk = ptr - CHUNK_HEADER_SIZE;
+        if self.read_u64(chunk + U64_SIZE) != CHUNK_IN_USE {
+            return Err(FsError::InvalidArgument(format!("Pointer {} is not an allocated chunk", ptr)));
+        }
+        Ok(chunk)
+    }
+
+    fn push_free(&self, chunk: u64) {
+        self.write_u64(chunk + U64_SIZE, self.free_head());
+        self.set_free_head(chunk);
+    }
+
+    fn unlink(&self, prev: u64, next: u64) {
+        if prev == 0 {
+            self.set_free_head(next);
+        } else {
+            self.write_u64(prev + U64_SIZE, next);
+        }
+    }
+
+    fn top(&self) -> u64 {
+        self.read_u64(self.start + U64_SIZE)
+    }
+
+    fn set_top(&self, top: u64) {
+        self.write_u64(self.start + U64_SIZE, top);
+    }
+
+    fn free_head(&self) -> u64 {
+        self.read_u64(self.start + U64_SIZE * 2)
+    }
+
+    fn set_free_head(&self, chunk: u64) {
+        self.write_u64(self.start + U64_SIZE * 2, chunk);
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.storage.read(offset, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.storage.write(offset, &value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use crate::arena::Arena;
+
+    const REGION_START: u64 = 64;
+    const REGION_END: u64 = 64 * 1024;
+
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; REGION_END as usize]);
+    }
+
+    fn write(offset: u64, data: &[u8]) {
+        MEMORY.with(|v| {
+            let mut v = v.borrow_mut();
+            v[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        });
+    }
+
+    fn read(offset: u64, data: &mut [u8]) {
+        MEMORY.with(|v| {
+            let v = v.borrow();
+            data.copy_from_slice(&v[offset as usize..offset as usize + data.len()]);
+        });
+    }
+
+    fn get_arena() -> Arena {
+        Arena::open(REGION_START, REGION_END, write, read).unwrap()
+    }
+
+    #[test]
+    fn it_allocates_and_reads_back() {
+        let arena = get_arena();
+        let a = arena.allocate(10).unwrap();
+        let b = arena.allocate(100).unwrap();
+        assert_ne!(a, b);
+
+        arena.write(a, 0, b"hello").unwrap();
+        arena.write(b, 50, b"world").unwrap();
+
+        let mut buf = [0u8; 5];
+        arena.read(a, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        arena.read(b, 50, &mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+
+        assert!(arena.write(a, 12, b"overflow").is_err());
+    }
+
+    #[test]
+    fn it_reuses_freed_chunks() {
+        let arena = get_arena();
+        let a = arena.allocate(256).unwrap();
+        let _b = arena.allocate(256).unwrap();
+        arena.free(a).unwrap();
+
+        let c = arena.allocate(64).unwrap();
+        assert_eq!(c, a);
+        let d = arena.allocate(64).unwrap();
+        assert!(d > c && d < a + 256);
+
+        assert!(arena.free(a + 1).is_err());
+        arena.free(c).unwrap();
+        assert!(arena.free(c).is_err());
+    }
+
+    #[test]
+    fn it_reallocs_and_keeps_data() {
+        let arena = get_arena();
+        let a = arena.allocate(16).unwrap();
+        arena.write(a, 0, b"sixteen bytes!!!").unwrap();
+        let _b = arena.allocate(16).unwrap();
+
+        let grown = arena.realloc(a, 1024).unwrap();
+        assert_ne!(grown, a);
+        assert!(arena.capacity(grown).unwrap() >= 1024);
+
+        let mut buf = [0u8; 16];
+        arena.read(grown, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"sixteen bytes!!!");
+
+        let stats = arena.stats();
+        assert_eq!(stats.allocated_chunks, 2);
+        assert_eq!(stats.free_chunks, 1);
+    }
+
+    #[test]
+    fn it_survives_reopen_and_reports_exhaustion() {
+        let arena = get_arena();
+        let a = arena.allocate(32).unwrap();
+
+        let arena = get_arena();
+        assert_eq!(arena.capacity(a).unwrap(), 32);
+        assert!(arena.allocate(REGION_END).is_err());
+    }
+}
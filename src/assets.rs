@@ -0,0 +1,194 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::{BlockStorage, EventFilesystem, FnStorage};
+use crate::codec::BincodeCodec;
+use crate::error::FsError;
+use crate::hash::{Hasher, Sha256Hasher};
+
+// Chunks stay below the 2 MiB ingress limit of create_chunk calls.
+pub const MAX_ASSET_CHUNK_SIZE: usize = 1_900_000;
+
+// A file for an asset canister batch: upload each chunk with create_chunk, then commit_batch with
+// CreateAsset { key, content_type } and SetAssetContent { key, content_encoding, chunk_ids, sha256 }.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct AssetFile {
+    pub key: String,
+    pub content_type: String,
+    pub content_encoding: String,
+    pub sha256: Vec<u8>,
+    pub chunks: Vec<Vec<u8>>,
+}
+
+impl AssetFile {
+    fn new(key: String, content_type: &str, content: Vec<u8>) -> Self {
+        AssetFile {
+            key,
+            content_type: content_type.to_string(),
+            content_encoding: "identity".to_string(),
+            sha256: Sha256Hasher.digest(&content),
+            chunks: content.chunks(MAX_ASSET_CHUNK_SIZE).map(<[u8]>::to_vec).collect(),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.len() as u64).sum()
+    }
+}
+
+// One line of a data file. Payloads are the serialized messages, base64 encoded, markers are empty.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetLine {
+    pub height: u64,
+    pub timestamp: u64,
+    pub payload: String,
+}
+
+// manifest.json, the last file of an export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetManifest {
+    pub topic: String,
+    pub binary_version: u32,
+    // The codec the payloads were serialized with when the export started.
+    pub codec: BincodeCodec,
+    pub first_message: u64,
+    pub height: u64,
+    pub exported_at: u64,
+    pub files: Vec<AssetManifestFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetManifestFile {
+    pub key: String,
+    // Heights first..end are in the file.
+    pub first: u64,
+    pub end: u64,
+    pub size: u64,
+    // Hex, as served in the asset canister's sha256 header.
+    pub sha256: String,
+}
+
+// The messages committed when the export was created as JSON lines files of about `file_size` bytes,
+// then the manifest. Messages committed later aren't exported, the export stops after the first error.
+pub struct AssetExport<'a, S: BlockStorage = FnStorage> {
+    fs: &'a EventFilesystem<S>,
+    prefix: String,
+    file_size: u64,
+    next: u64,
+    end: u64,
+    manifest: Option<AssetManifest>,
+}
+
+impl<'a, S: BlockStorage> AssetExport<'a, S> {
+    // `prefix` is the key the files go under, e.g. "/logs/orders".
+    pub(crate) fn new(fs: &'a EventFilesystem<S>, prefix: &str, file_size: u64) -> Result<Self, FsError> {
+        if !prefix.starts_with('/') {
+            return Err(FsError::InvalidArgument(format!("Asset keys start with /, not {}", prefix)));
+        }
+        if file_size == 0 {
+            return Err(FsError::InvalidArgument("Asset file size must be positive".to_string()));
+        }
+        let manifest = AssetManifest {
+            topic: fs.topic_header.event_stream_name.clone(),
+            binary_version: fs.topic_header.binary_version,
+            codec: fs.codec(),
+            first_message: fs.first_message_height(),
+            height: fs.get_topic_height(),
+            exported_at: (fs.clock)(),
+            files: Vec::new(),
+        };
+        Ok(AssetExport {
+            fs,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            file_size,
+            next: manifest.first_message,
+            end: manifest.height,
+            manifest: Some(manifest),
+        })
+    }
+
+    fn next_file(&mut self) -> Result<AssetFile, FsError> {
+        let first = self.next;
+        let mut content = Vec::new();
+        while self.next < self.end && (content.is_empty() || (content.len() as u64) < self.file_size) {
+            let idx = self.fs.reader.read_idx(self.next, &self.fs.storage)?;
+            let payload = self.fs.reader.read_payload(self.next, &self.fs.storage)?;
+            let line = AssetLine { height: self.next, timestamp: idx.timestamp, payload: STANDARD.encode(payload) };
+            serde_json::to_writer(&mut content, &line).map_err(|e| FsError::Serialize(e.to_string()))?;
+            content.push(b'\n');
+            self.next += 1;
+        }
+        let file = AssetFile::new(format!("{}/messages-{:020}-{:020}.jsonl", self.prefix, first, self.next), "application/jsonl", content);
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.files.push(AssetManifestFile { key: file.key.clone(), first, end: self.next, size: file.size(), sha256: hex(&file.sha256) });
+        }
+        Ok(file)
+    }
+}
+
+impl<S: BlockStorage> Iterator for AssetExport<'_, S> {
+    type Item = Result<AssetFile, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next < self.end {
+            let file = self.next_file();
+            if file.is_err() {
+                self.next = self.end;
+                self.manifest = None;
+            }
+            return Some(file);
+        }
+        let manifest = self.manifest.take()?;
+        Some(serde_json::to_vec_pretty(&manifest)
+            .map(|content| AssetFile::new(format!("{}/manifest.json", self.prefix), "application/json", content))
+            .map_err(|e| FsError::Serialize(e.to_string())))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    use crate::{EventFilesystemBuilder, VecStorage};
+    use crate::fixtures::small_layout;
+    use crate::assets::{AssetLine, AssetManifest, MAX_ASSET_CHUNK_SIZE};
+    use crate::hash::{Hasher, Sha256Hasher};
+
+    #[test]
+    fn it_exports_files_and_a_manifest() {
+        let layout = small_layout();
+        let fs = EventFilesystemBuilder::with_storage(VecStorage::default(), || 9).layout(layout).get_or_create("orders".to_string()).unwrap();
+        for i in 0..5u64 {
+            fs.write_topic_message(&i).unwrap();
+        }
+        fs.write_marker("sealed").unwrap();
+        assert!(fs.export_assets("logs", 100).is_err());
+
+        let files = fs.export_assets("/logs/orders/", 150).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(files.iter().map(|file| file.key.as_str()).collect::<Vec<_>>(), vec![
+            "/logs/orders/messages-00000000000000000000-00000000000000000003.jsonl",
+            "/logs/orders/messages-00000000000000000003-00000000000000000006.jsonl",
+            "/logs/orders/manifest.json",
+        ]);
+        let lines: Vec<AssetLine> = files[1].chunks.concat().split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines[0], AssetLine { height: 3, timestamp: 9, payload: STANDARD.encode(3u64.to_le_bytes()) });
+        assert!(lines[2].payload.is_empty());
+        assert_eq!(files[0].sha256, Sha256Hasher.digest(&files[0].chunks.concat()));
+
+        let manifest: AssetManifest = serde_json::from_slice(&files[2].chunks.concat()).unwrap();
+        assert_eq!((manifest.topic.as_str(), manifest.first_message, manifest.height, manifest.files.len()), ("orders", 0, 6, 2));
+        assert_eq!((manifest.files[1].first, manifest.files[1].end, manifest.files[1].size), (3, 6, files[1].size()));
+        assert_eq!(manifest.files[0].sha256.len(), 64);
+        assert!(files.iter().all(|file| file.chunks.iter().all(|chunk| chunk.len() <= MAX_ASSET_CHUNK_SIZE)));
+    }
+}
@@ -0,0 +1,60 @@
+// Tracks instructions spent by a long running operation so it can stop and hand back a resume point
+// instead of trapping. In a canister pass `ic_cdk::api::instruction_counter` as the counter.
+pub struct InstructionBudget {
+    counter: fn() -> u64,
+    start: u64,
+    limit: u64,
+}
+
+impl InstructionBudget {
+    pub fn new(counter: fn() -> u64, limit: u64) -> Self {
+        InstructionBudget {
+            counter,
+            start: counter(),
+            limit,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        InstructionBudget {
+            counter: || 0,
+            start: 0,
+            limit: u64::MAX,
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        (self.counter)().saturating_sub(self.start)
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.limit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use crate::budget::InstructionBudget;
+
+    thread_local! {
+        static COUNTER: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn tick() -> u64 {
+        COUNTER.with(|c| {
+            c.set(c.get() + 10);
+            c.get()
+        })
+    }
+
+    #[test]
+    fn it_exhausts_after_limit() {
+        let budget = InstructionBudget::new(tick, 25);
+        assert!(!budget.is_exhausted());
+        assert!(!budget.is_exhausted());
+        assert!(budget.is_exhausted());
+        assert!(!InstructionBudget::unlimited().is_exhausted());
+    }
+}
@@ -0,0 +1,139 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{EventFilesystem, is_magic_number_valid, read_topic_block};
+use crate::error::FsError;
+use crate::hash::HashAlgorithm;
+use crate::layout::LayoutConfig;
+use crate::read_write::{BlockGrow, BlockRead, BlockWrite};
+use crate::recovery::HeightPolicy;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{read_snapshot, SnapshotManifest};
+use crate::storage::{BlockStorage, FnStorage, Storage};
+
+// Options that only matter when a topic is created. Opening an existing topic takes them from its
+// header, an explicitly requested layout must match the recorded one.
+pub struct EventFilesystemBuilder<S: BlockStorage = FnStorage> {
+    storage: Rc<RefCell<S>>,
+    clock: fn() -> u64,
+    hash_algorithm: HashAlgorithm,
+    layout: Option<LayoutConfig>,
+    instruction_counter: Option<fn() -> u64>,
+    allow_name_mismatch: bool,
+    height_policy: HeightPolicy,
+}
+
+// Clones share the storage, like topics opened from them.
+impl<S: BlockStorage> Clone for EventFilesystemBuilder<S> {
+    fn clone(&self) -> Self {
+        EventFilesystemBuilder {
+            storage: self.storage.clone(),
+            clock: self.clock,
+            hash_algorithm: self.hash_algorithm,
+            layout: self.layout,
+            instruction_counter: self.instruction_counter,
+            allow_name_mismatch: self.allow_name_mismatch,
+            height_policy: self.height_policy,
+        }
+    }
+}
+
+impl EventFilesystemBuilder<FnStorage> {
+    pub fn new(write_fn: BlockWrite, read_fn: BlockRead, clock: fn() -> u64) -> Self {
+        EventFilesystemBuilder::with_storage(FnStorage::new(write_fn, read_fn), clock)
+    }
+
+    // See FnStorage::with_grow_fn, without it the memory must already be large enough.
+    pub fn grow_fn(self, grow_fn: BlockGrow) -> Self {
+        let storage = *self.storage.borrow();
+        *self.storage.borrow_mut() = storage.with_grow_fn(grow_fn);
+        self
+    }
+}
+
+impl<S: BlockStorage> EventFilesystemBuilder<S> {
+    pub fn with_storage(storage: S, clock: fn() -> u64) -> Self {
+        EventFilesystemBuilder {
+            storage: Rc::new(RefCell::new(storage)),
+            clock,
+            hash_algorithm: HashAlgorithm::default(),
+            layout: None,
+            instruction_counter: None,
+            allow_name_mismatch: false,
+            height_policy: HeightPolicy::default(),
+        }
+    }
+
+    pub fn hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    pub fn layout(mut self, layout: LayoutConfig) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    // Records instructions per read and write in histograms exposed through metrics_text. In a canister
+    // pass `ic_cdk::api::instruction_counter`.
+    pub fn instruction_counter(mut self, counter: fn() -> u64) -> Self {
+        self.instruction_counter = Some(counter);
+        self
+    }
+
+    // Lets get_or_create open a topic that was created under another name, e.g. after a rename.
+    pub fn allow_name_mismatch(mut self, allow: bool) -> Self {
+        self.allow_name_mismatch = allow;
+        self
+    }
+
+    // What opening does when the committed heights and the index disagree, failing by default. The
+    // report of a repair is kept, see EventFilesystem::height_repair.
+    pub fn height_policy(mut self, policy: HeightPolicy) -> Self {
+        self.height_policy = policy;
+        self
+    }
+
+    pub fn get_or_create(self, event_stream_name: String) -> Result<EventFilesystem<S>, FsError> {
+        let storage = Storage::shared(&self.storage);
+        if is_magic_number_valid(&storage) {
+            // Checked before open, which may migrate and write to the topic.
+            let found = read_topic_block(&storage)?.event_stream_name;
+            if !self.allow_name_mismatch && found != event_stream_name {
+                return Err(FsError::TopicNameMismatch { expected: event_stream_name, found });
+            }
+            return self.open();
+        }
+        let mut fs = EventFilesystem::create(self.storage, self.clock, event_stream_name, self.hash_algorithm, self.layout.unwrap_or_default())?;
+        fs.instruction_counter = self.instruction_counter;
+        Ok(fs)
+    }
+
+    // Restores a topic written by EventFilesystem::export_snapshot into storage that doesn't hold one
+    // yet, e.g. in a fresh canister, and opens it. A stream that is cut short or doesn't match its
+    // digest leaves no topic behind.
+    #[cfg(feature = "snapshot")]
+    pub fn import_snapshot(self, mut reader: impl std::io::Read) -> Result<(EventFilesystem<S>, SnapshotManifest), FsError> {
+        let storage = Storage::shared(&self.storage);
+        if is_magic_number_valid(&storage) {
+            return Err(FsError::InvalidState("The storage already holds a topic".to_string()));
+        }
+        let manifest = read_snapshot(&mut reader, &storage, |algorithm| algorithm.hasher())?;
+        Ok((self.open()?, manifest))
+    }
+
+    pub fn open(self) -> Result<EventFilesystem<S>, FsError> {
+        let mut fs = EventFilesystem::open(self.storage, self.clock, self.layout, self.height_policy, false)?;
+        fs.instruction_counter = self.instruction_counter;
+        Ok(fs)
+    }
+
+    // Opens the topic without writing to the storage, e.g. a FileStorage::open_read_only file. Canaries
+    // aren't installed, the open isn't counted or journaled, and topics that need a migration or a
+    // height repair are refused. Reads work as usual, writes reach the storage.
+    pub fn open_read_only(self) -> Result<EventFilesystem<S>, FsError> {
+        let mut fs = EventFilesystem::open(self.storage, self.clock, self.layout, self.height_policy, true)?;
+        fs.instruction_counter = self.instruction_counter;
+        Ok(fs)
+    }
+}
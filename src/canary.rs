@@ -0,0 +1,61 @@
+use crate::constants::*;
+use crate::error::FsError;
+use crate::layout::LayoutConfig;
+use crate::storage::Storage;
+
+// "RED_ZONE"
+pub(crate) const CANARY: u64 = 0x5245_445f_5a4f_4e45;
+
+// Writes ending this close to a canary re-check it straight away.
+const CHECK_DISTANCE: u64 = BLOCK_SIZE;
+
+// Known patterns in the last slot of each zone. A write that strays over a zone boundary clobbers
+// the canary, which is then reported as a typed error instead of silently corrupting the neighbour.
+pub(crate) fn canaries(layout: &LayoutConfig) -> [(u64, &'static str); 4] {
+    [
+        (TOPIC_BLOCK_CANARY_IDX, "topic header/free memory"),
+        (layout.meta_zone_idx() - CANARY_SIZE, "free memory/meta"),
+        (layout.idx_zone_idx() - CANARY_SIZE, "meta/index"),
+        (layout.idx_zone_end() - CANARY_SIZE, "index/data"),
+    ]
+}
+
+pub(crate) fn write_canaries(layout: &LayoutConfig, storage: &Storage) {
+    for (offset, _) in canaries(layout) {
+        storage.write(offset, &CANARY.to_le_bytes());
+    }
+}
+
+// Filesystems formatted before canaries existed have zeroed slots, those are filled in on open.
+pub(crate) fn install_missing_canaries(layout: &LayoutConfig, storage: &Storage) {
+    for (offset, boundary) in canaries(layout) {
+        if read_u64(offset, storage) == 0 {
+            debug!("Installing {} canary at {}", boundary, offset);
+            storage.write(offset, &CANARY.to_le_bytes());
+        }
+    }
+}
+
+pub(crate) fn check_canaries(layout: &LayoutConfig, storage: &Storage) -> Result<(), FsError> {
+    canaries(layout).iter().try_for_each(|(offset, boundary)| check_canary(*offset, boundary, storage))
+}
+
+// Checks the canaries a write to `start..end` could have reached.
+pub(crate) fn check_canaries_near(layout: &LayoutConfig, start: u64, end: u64, storage: &Storage) -> Result<(), FsError> {
+    canaries(layout).iter()
+        .filter(|(offset, _)| *offset + CANARY_SIZE > start && offset.saturating_sub(CHECK_DISTANCE) < end)
+        .try_for_each(|(offset, boundary)| check_canary(*offset, boundary, storage))
+}
+
+fn check_canary(offset: u64, boundary: &str, storage: &Storage) -> Result<(), FsError> {
+    if read_u64(offset, storage) != CANARY {
+        return Err(FsError::RedZoneOverwritten { offset, boundary: boundary.to_string() });
+    }
+    Ok(())
+}
+
+fn read_u64(offset: u64, storage: &Storage) -> u64 {
+    let mut bytes = [0u8; 8];
+    storage.read(offset, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
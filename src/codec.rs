@@ -0,0 +1,149 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use crate::error::FsError;
+
+// Messages larger than this are rejected on write, and index entries claiming more are treated as
+// corrupt on read instead of being allocated.
+pub const DEFAULT_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum IntEncoding {
+    Fixed,
+    Varint,
+}
+
+// Bincode settings applied to message payloads. Index entries and the topic header keep their
+// fixed layout regardless. The defaults match `bincode::serialize`, which older topics were written with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BincodeCodec {
+    pub int_encoding: IntEncoding,
+    pub size_limit: u64,
+    pub reject_trailing_bytes: bool,
+}
+
+impl Default for BincodeCodec {
+    fn default() -> Self {
+        BincodeCodec {
+            int_encoding: IntEncoding::Fixed,
+            size_limit: DEFAULT_SIZE_LIMIT,
+            reject_trailing_bytes: false,
+        }
+    }
+}
+
+macro_rules! with_options {
+    ($codec:expr, $options:ident => $body:expr) => {{
+        let base = bincode::DefaultOptions::new().with_limit($codec.size_limit);
+        match ($codec.int_encoding, $codec.reject_trailing_bytes) {
+            (IntEncoding::Fixed, false) => { let $options = base.with_fixint_encoding().allow_trailing_bytes(); $body }
+            (IntEncoding::Fixed, true) => { let $options = base.with_fixint_encoding().reject_trailing_bytes(); $body }
+            (IntEncoding::Varint, false) => { let $options = base.with_varint_encoding().allow_trailing_bytes(); $body }
+            (IntEncoding::Varint, true) => { let $options = base.with_varint_encoding().reject_trailing_bytes(); $body }
+        }
+    }};
+}
+
+impl BincodeCodec {
+    pub fn serialize_into<S: Serialize>(&self, buf: &mut Vec<u8>, value: &S) -> Result<(), FsError> {
+        with_options!(self, options => options.serialize_into(buf, value))
+            .map_err(|e| FsError::Serialize(e.to_string()))
+    }
+
+    pub fn serialize<S: Serialize>(&self, value: &S) -> Result<Vec<u8>, FsError> {
+        let mut buf = Vec::new();
+        self.serialize_into(&mut buf, value)?;
+        Ok(buf)
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FsError> {
+        with_options!(self, options => options.deserialize(bytes))
+            .map_err(|e| FsError::Deserialize(e.to_string()))
+    }
+
+    // Whether data written with `other` can be read back with `self`.
+    pub fn is_layout_compatible(&self, other: &BincodeCodec) -> bool {
+        self.int_encoding == other.int_encoding && self.reject_trailing_bytes == other.reject_trailing_bytes
+    }
+}
+
+// How message payloads and the stable store are encoded by the `_with` APIs. Payloads written with
+// one codec must be read back with the same one, the topic only records its bincode settings.
+pub trait Codec<T> {
+    fn encode_into(&self, buf: &mut Vec<u8>, value: &T) -> Result<(), FsError>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, FsError>;
+
+    fn encode(&self, value: &T) -> Result<Vec<u8>, FsError> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf, value)?;
+        Ok(buf)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for BincodeCodec {
+    fn encode_into(&self, buf: &mut Vec<u8>, value: &T) -> Result<(), FsError> {
+        self.serialize_into(buf, value)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, FsError> {
+        self.deserialize(bytes)
+    }
+}
+
+// Candid is self-describing, so payloads can be read by canisters that only share the Candid interface.
+// Candid messages can't be inspected by aggregates or a schema.
+#[cfg(feature = "candid")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CandidCodec;
+
+#[cfg(feature = "candid")]
+impl<T: candid::CandidType + DeserializeOwned> Codec<T> for CandidCodec {
+    fn encode_into(&self, buf: &mut Vec<u8>, value: &T) -> Result<(), FsError> {
+        let bytes = candid::encode_one(value).map_err(|e| FsError::Serialize(e.to_string()))?;
+        buf.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, FsError> {
+        candid::decode_one(bytes).map_err(|e| FsError::Deserialize(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::{BincodeCodec, IntEncoding};
+
+    #[test]
+    fn it_matches_bincode_defaults() {
+        let codec = BincodeCodec::default();
+        let value = ("hello".to_string(), 42u64, vec![1u32, 2, 3]);
+        let bytes = codec.serialize(&value).unwrap();
+        assert_eq!(bytes, bincode::serialize(&value).unwrap());
+        assert_eq!(codec.deserialize::<(String, u64, Vec<u32>)>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn it_uses_varint_encoding() {
+        let codec = BincodeCodec { int_encoding: IntEncoding::Varint, ..Default::default() };
+        let bytes = codec.serialize(&(1u64, 2u64)).unwrap();
+        assert_eq!(bytes.len(), 2);
+        assert_eq!(codec.deserialize::<(u64, u64)>(&bytes).unwrap(), (1, 2));
+    }
+
+    #[test]
+    fn it_enforces_limits_and_trailing_bytes() {
+        let codec = BincodeCodec { size_limit: 16, reject_trailing_bytes: true, ..Default::default() };
+        assert!(codec.serialize(&vec![0u8; 32]).is_err());
+
+        let mut bytes = codec.serialize(&7u32).unwrap();
+        bytes.push(0);
+        assert!(codec.deserialize::<u32>(&bytes).is_err());
+        assert_eq!(BincodeCodec::default().deserialize::<u32>(&bytes).unwrap(), 7);
+
+        // A corrupt length prefix claiming a huge string must fail, not allocate.
+        let corrupt = u64::MAX.to_le_bytes();
+        assert!(codec.deserialize::<String>(&corrupt).is_err());
+    }
+}
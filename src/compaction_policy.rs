@@ -0,0 +1,91 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::export::IndexExportEntry;
+use crate::retention::RetentionPolicy;
+use crate::segments::SegmentManifest;
+
+// What compact_with_policy sees of the topic when it plans a compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicStats {
+    pub height: u64,
+    pub first_message: u64,
+    pub data_bytes: u64,
+    pub segment_size: u64,
+    // Topic clock time of the call.
+    pub now: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SegmentAction {
+    Keep,
+    Drop,
+    // Keeps the messages CompactionPolicy::keep accepts.
+    Rewrite,
+}
+
+// Decides per segment what a compaction keeps, e.g. keep every 10th event of old segments or keep
+// error events forever. Heights no segment manifest describes are kept.
+pub trait CompactionPolicy {
+    fn plan(&self, stats: &TopicStats, segment: &SegmentManifest) -> SegmentAction;
+
+    // Gets each message of a rewritten segment with its serialized payload, empty for markers.
+    fn keep(&self, entry: &IndexExportEntry, payload: &[u8]) -> bool {
+        let _ = (entry, payload);
+        true
+    }
+}
+
+// The built-in rules, by segment: only segments that lie entirely beyond the policy are dropped.
+impl CompactionPolicy for RetentionPolicy {
+    fn plan(&self, stats: &TopicStats, segment: &SegmentManifest) -> SegmentAction {
+        let expired = match *self {
+            RetentionPolicy::MaxMessages(max) => segment.heights().end <= stats.height.saturating_sub(max),
+            RetentionPolicy::MaxAgeNanos(max) => segment.max_timestamp < stats.now.saturating_sub(max),
+        };
+        if expired { SegmentAction::Drop } else { SegmentAction::Keep }
+    }
+}
+
+// The planned actions by height range, ascending.
+pub(crate) struct CompactionPlan {
+    actions: Vec<(Range<u64>, SegmentAction)>,
+}
+
+impl CompactionPlan {
+    pub(crate) fn new(policy: &impl CompactionPolicy, stats: &TopicStats, segments: &[SegmentManifest]) -> Self {
+        CompactionPlan { actions: segments.iter().map(|segment| (segment.heights(), policy.plan(stats, segment))).collect() }
+    }
+
+    pub(crate) fn action(&self, height: u64) -> SegmentAction {
+        let i = self.actions.partition_point(|(heights, _)| heights.end <= height);
+        match self.actions.get(i) {
+            Some((heights, action)) if heights.contains(&height) => *action,
+            _ => SegmentAction::Keep,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compaction_policy::{CompactionPlan, CompactionPolicy, SegmentAction, TopicStats};
+    use crate::retention::RetentionPolicy;
+    use crate::segments::SegmentManifest;
+
+    fn segment(start: u64, max_timestamp: u64) -> SegmentManifest {
+        SegmentManifest { start, entries: 10, bytes: 0, min_timestamp: 0, max_timestamp, min_key_hash: None, max_key_hash: None }
+    }
+
+    #[test]
+    fn it_plans_the_built_in_rules_by_segment() {
+        let stats = TopicStats { height: 35, first_message: 0, data_bytes: 0, segment_size: 10, now: 100 };
+        let segments = [segment(10, 50), segment(20, 80), segment(30, 95)];
+        let by_count = CompactionPlan::new(&RetentionPolicy::MaxMessages(12), &stats, &segments);
+        assert_eq!((0..4).map(|i| by_count.action(i * 10 + 5)).collect::<Vec<_>>(),
+                   vec![SegmentAction::Keep, SegmentAction::Drop, SegmentAction::Keep, SegmentAction::Keep]);
+        let by_age = CompactionPlan::new(&RetentionPolicy::MaxAgeNanos(30), &stats, &segments);
+        assert_eq!((by_age.action(19), by_age.action(20), by_age.action(40)), (SegmentAction::Drop, SegmentAction::Keep, SegmentAction::Keep));
+        assert!(RetentionPolicy::MaxAgeNanos(30).keep(&crate::IndexExportEntry { height: 0, timestamp: 0, data_size: 0, flags: 0, key_hash: None }, &[]));
+    }
+}
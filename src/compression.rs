@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use crate::error::FsError;
+
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+// Compressed payloads start with the id of the method they were stored with and their uncompressed size.
+pub(crate) const COMPRESSION_HEADER_SIZE: usize = 9;
+// Payloads compressed with a dictionary follow it with the dictionary's id.
+pub(crate) const MAX_COMPRESSION_HEADER_SIZE: usize = COMPRESSION_HEADER_SIZE + 4;
+const STORED_ID: u8 = 0;
+#[cfg(feature = "zstd")]
+const ZSTD_ID: u8 = 1;
+#[cfg(feature = "zstd")]
+const ZSTD_DICTIONARY_ID: u8 = 2;
+
+// How payloads are stored. Messages written while compression is on carry a header naming the method,
+// a payload that doesn't get smaller is stored as is behind it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PayloadCompression {
+    #[default]
+    None,
+    Zstd { level: i32 },
+}
+
+impl PayloadCompression {
+    pub(crate) fn validate(&self) -> Result<(), FsError> {
+        match self {
+            PayloadCompression::None => Ok(()),
+            #[cfg(feature = "zstd")]
+            PayloadCompression::Zstd { .. } => Ok(()),
+            #[cfg(not(feature = "zstd"))]
+            PayloadCompression::Zstd { .. } => Err(FsError::Unsupported("zstd compression needs the zstd feature".to_string())),
+        }
+    }
+
+    // Appends the header and the stored payload to `out`. zstd uses `dictionary` when one is given.
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    pub(crate) fn compress_into(&self, payload: &[u8], out: &mut Vec<u8>, dictionary: Option<&CompressionDictionary>) -> Result<(), FsError> {
+        let compressed: Option<(u8, Option<u32>, Vec<u8>)> = match (self, dictionary) {
+            (PayloadCompression::None, _) => None,
+            #[cfg(feature = "zstd")]
+            (PayloadCompression::Zstd { level }, Some(dictionary)) => Some(dictionary.compress(payload, *level)?)
+                .filter(|compressed| compressed.len() + 4 < payload.len())
+                .map(|compressed| (ZSTD_DICTIONARY_ID, Some(dictionary.id), compressed)),
+            #[cfg(feature = "zstd")]
+            (PayloadCompression::Zstd { level }, None) => Some(zstd::bulk::compress(payload, *level).map_err(|e| FsError::Compression(e.to_string()))?)
+                .filter(|compressed| compressed.len() < payload.len())
+                .map(|compressed| (ZSTD_ID, None, compressed)),
+            #[cfg(not(feature = "zstd"))]
+            (PayloadCompression::Zstd { .. }, _) => return self.validate(),
+        };
+        let (id, dictionary_id, stored) = match &compressed {
+            Some((id, dictionary_id, compressed)) => (*id, *dictionary_id, compressed.as_slice()),
+            None => (STORED_ID, None, payload),
+        };
+        out.push(id);
+        out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        if let Some(dictionary_id) = dictionary_id {
+            out.extend_from_slice(&dictionary_id.to_le_bytes());
+        }
+        out.extend_from_slice(stored);
+        Ok(())
+    }
+}
+
+// Undoes compress_into, looking the dictionary a payload names up in `dictionaries`. Sizes above
+// `size_limit` are refused before anything is allocated for them.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(crate) fn decompress(stored: &[u8], size_limit: u64, dictionaries: &[CompressionDictionary]) -> Result<Vec<u8>, FsError> {
+    if stored.len() < COMPRESSION_HEADER_SIZE {
+        return Err(FsError::Compression(format!("{} bytes are too few for a compression header", stored.len())));
+    }
+    let size = u64::from_le_bytes(stored[1..COMPRESSION_HEADER_SIZE].try_into().unwrap());
+    if size > size_limit {
+        return Err(FsError::Compression(format!("Payload claims {} bytes, above the {} byte limit", size, size_limit)));
+    }
+    let data = &stored[COMPRESSION_HEADER_SIZE..];
+    match stored[0] {
+        STORED_ID if size != data.len() as u64 => Err(FsError::Compression(format!("Payload claims {} bytes, {} are stored", size, data.len()))),
+        STORED_ID => Ok(data.to_vec()),
+        #[cfg(feature = "zstd")]
+        ZSTD_ID => zstd::bulk::decompress(data, size as usize).map_err(|e| FsError::Compression(e.to_string())),
+        #[cfg(feature = "zstd")]
+        ZSTD_DICTIONARY_ID => {
+            if data.len() < 4 {
+                return Err(FsError::Compression(format!("{} bytes are too few for a dictionary id", data.len())));
+            }
+            let id = u32::from_le_bytes(data[..4].try_into().unwrap());
+            let dictionary = dictionaries.iter().find(|dictionary| dictionary.id == id)
+                .ok_or_else(|| FsError::Compression(format!("Payload was compressed with dictionary {}, which the topic doesn't hold", id)))?;
+            dictionary.decompress(&data[4..], size as usize)
+        }
+        id => Err(FsError::Unsupported(format!("Payload compressed with unknown method {}", id))),
+    }
+}
+
+// A zstd dictionary trained on representative payloads. Small, similar events compress poorly on
+// their own; sharing a dictionary recovers most of the redundancy between them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CompressionDictionary {
+    pub id: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl CompressionDictionary {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let digest = sha2::Sha256::digest(&bytes);
+        CompressionDictionary {
+            id: u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]),
+            bytes,
+        }
+    }
+
+    // Offline helper: samples are sorted first, so the same sample set always yields the same
+    // dictionary regardless of the order it was collected in.
+    #[cfg(feature = "zstd")]
+    pub fn train(samples: &[Vec<u8>], max_size: usize) -> Result<Self, FsError> {
+        let mut samples: Vec<&Vec<u8>> = samples.iter().collect();
+        samples.sort();
+        let bytes = zstd::dict::from_samples(&samples, max_size)
+            .map_err(|e| FsError::Compression(format!("Failed to train dictionary: {}", e)))?;
+        Ok(Self::from_bytes(bytes))
+    }
+
+    #[cfg(feature = "zstd")]
+    pub fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>, FsError> {
+        let mut compressor = zstd::bulk::Compressor::with_dictionary(level, &self.bytes)
+            .map_err(|e| FsError::Compression(format!("Failed to load dictionary: {}", e)))?;
+        compressor.compress(data).map_err(|e| FsError::Compression(e.to_string()))
+    }
+
+    #[cfg(feature = "zstd")]
+    pub fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, FsError> {
+        let mut decompressor = zstd::bulk::Decompressor::with_dictionary(&self.bytes)
+            .map_err(|e| FsError::Compression(format!("Failed to load dictionary: {}", e)))?;
+        decompressor.decompress(data, max_size).map_err(|e| FsError::Compression(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compression::{decompress, PayloadCompression};
+    use crate::error::FsError;
+    #[cfg(feature = "zstd")]
+    use crate::compression::{CompressionDictionary, DEFAULT_COMPRESSION_LEVEL};
+
+    #[test]
+    fn it_stores_payloads_behind_a_header() {
+        let mut stored = Vec::new();
+        PayloadCompression::None.compress_into(b"abc", &mut stored, None).unwrap();
+        assert_eq!(stored, [0, 3, 0, 0, 0, 0, 0, 0, 0, b'a', b'b', b'c']);
+        assert_eq!(decompress(&stored, 3, &[]).unwrap(), b"abc");
+        assert!(decompress(&stored, 2, &[]).is_err());
+        assert!(decompress(&stored[..8], 3, &[]).is_err());
+        assert!(matches!(decompress(&stored[..11], 3, &[]), Err(FsError::Compression(_))));
+        stored.push(b'd');
+        assert!(matches!(decompress(&stored, 4, &[]), Err(FsError::Compression(_))));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_compresses_repetitive_payloads() {
+        let payload = vec![7u8; 4096];
+        let mut stored = Vec::new();
+        PayloadCompression::Zstd { level: 3 }.compress_into(&payload, &mut stored, None).unwrap();
+        assert!(stored.len() < 100);
+        assert_eq!(decompress(&stored, 4096, &[]).unwrap(), payload);
+
+        let mut stored = Vec::new();
+        PayloadCompression::Zstd { level: 3 }.compress_into(b"x", &mut stored, None).unwrap();
+        assert_eq!(stored[0], 0);
+    }
+
+    #[cfg(feature = "zstd")]
+    fn samples() -> Vec<Vec<u8>> {
+        (0..500u32)
+            .map(|i| format!("{{\"kind\":\"transfer\",\"from\":\"account-{}\",\"to\":\"account-{}\",\"amount\":{}}}", i % 17, i % 23, i * 31).into_bytes())
+            .collect()
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_trains_deterministically() {
+        let mut reversed = samples();
+        reversed.reverse();
+
+        let a = CompressionDictionary::train(&samples(), 4096).unwrap();
+        let b = CompressionDictionary::train(&reversed, 4096).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_compresses_small_payloads_with_dictionary() {
+        let dictionary = CompressionDictionary::train(&samples(), 4096).unwrap();
+        let payload = b"{\"kind\":\"transfer\",\"from\":\"account-3\",\"to\":\"account-9\",\"amount\":1234}".to_vec();
+
+        let compressed = dictionary.compress(&payload, DEFAULT_COMPRESSION_LEVEL).unwrap();
+        let plain = zstd::bulk::compress(&payload, DEFAULT_COMPRESSION_LEVEL).unwrap();
+        assert!(compressed.len() < plain.len());
+        assert_eq!(dictionary.decompress(&compressed, payload.len()).unwrap(), payload);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_names_the_dictionary_in_the_header() {
+        let dictionary = CompressionDictionary::train(&samples(), 4096).unwrap();
+        let payload = b"{\"kind\":\"transfer\",\"from\":\"account-3\",\"to\":\"account-9\",\"amount\":1234}".to_vec();
+        let mut stored = Vec::new();
+        PayloadCompression::Zstd { level: 3 }.compress_into(&payload, &mut stored, Some(&dictionary)).unwrap();
+        assert_eq!(stored[0], 2);
+        assert_eq!(stored[9..13], dictionary.id.to_le_bytes());
+        assert_eq!(decompress(&stored, 1024, &[dictionary]).unwrap(), payload);
+        assert!(matches!(decompress(&stored, 1024, &[]), Err(crate::FsError::Compression(_))));
+    }
+}
@@ -1,7 +1,7 @@
 pub const WASM_PAGE_SIZE: u64 = 65536;
 
 pub const BLOCK_SIZE: u64 = 512;
-pub const IDX_BLOCK_SIZE: u64 = 40;
+pub const IDX_BLOCK_SIZE: u64 = 48;
 pub const U64_SIZE: u64 = 8;
 pub const TOPIC_BLOCK_MAX_SIZE: usize = 512;
 
@@ -15,8 +15,17 @@ pub const TOPIC_BLOCK_DATA_START_IDX: u64 = INDEX_HEIGHT_IDX + U64_SIZE;
 pub const FREE_MEMORY_BLOCK_SIZE_IDX: u64 = TOPIC_BLOCK_DATA_START_IDX + TOPIC_BLOCK_MAX_SIZE as u64;
 pub const FREE_MEMORY_BLOCK_START_IDX: u64 = FREE_MEMORY_BLOCK_SIZE_IDX + U64_SIZE;
 
+// Everything from here on describes the default layout. Zone sizes are configurable per topic
+// through LayoutConfig, code working on an open topic must go through its layout instead.
 pub const FREE_MEMORY_BLOCK_SIZE: u64 = 256 * 1024 * 1024;
 
 pub const IDX_ZONE_IDX : u64 = FREE_MEMORY_BLOCK_START_IDX + FREE_MEMORY_BLOCK_SIZE;
 pub const IDX_ZONE_END: u64 = IDX_ZONE_IDX + (8192 * WASM_PAGE_SIZE);
 
+
+// Tail of the free memory block reserved for the filesystem's own records, see meta.rs.
+pub const META_ZONE_SIZE: u64 = 16 * 1024 * 1024;
+
+// Canary slots taken from the end of the zone before each boundary, see canary.rs.
+pub const CANARY_SIZE: u64 = U64_SIZE;
+pub const TOPIC_BLOCK_CANARY_IDX: u64 = TOPIC_BLOCK_DATA_START_IDX + TOPIC_BLOCK_MAX_SIZE as u64 - CANARY_SIZE;
@@ -0,0 +1,125 @@
+use serde::de::DeserializeOwned;
+
+use crate::{BlockStorage, EventFilesystem, FnStorage};
+use crate::error::FsError;
+
+// Bookmarks are kept in one meta zone record, these bound its size.
+pub const MAX_BOOKMARKS: usize = 64;
+pub const MAX_BOOKMARK_NAME_SIZE: usize = 64;
+
+// A named read position kept in the meta zone, for canisters that poll the topic. Batches move the
+// position in memory only, a consumer that doesn't reach commit() reads them again after opening the
+// cursor the next time.
+pub struct ConsumerCursor<'a, S: BlockStorage = FnStorage> {
+    fs: &'a EventFilesystem<S>,
+    name: String,
+    committed: u64,
+    position: u64,
+}
+
+impl<'a, S: BlockStorage> ConsumerCursor<'a, S> {
+    pub(crate) fn new(fs: &'a EventFilesystem<S>, name: String, committed: u64) -> Self {
+        ConsumerCursor { fs, name, committed, position: committed }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The next message that will be read by whoever opens the cursor.
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    // The next message next_batch returns.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // Up to `max` messages after the position, fewer at the end of the topic. Truncated messages are
+    // skipped.
+    pub fn next_batch<T: DeserializeOwned>(&mut self, max: u64) -> Result<Vec<T>, FsError> {
+        let start = self.position.max(self.fs.first_message_height());
+        let take = max.min(self.fs.get_topic_height().saturating_sub(start));
+        if take == 0 {
+            return Ok(Vec::new());
+        }
+        let messages = self.fs.read_topic_messages(start, take)?;
+        self.position = start + take;
+        Ok(messages)
+    }
+
+    // Persists the position, messages before it aren't returned to this cursor again.
+    pub fn commit(&mut self) -> Result<(), FsError> {
+        self.fs.commit_cursor(&self.name, self.position)?;
+        self.committed = self.position;
+        Ok(())
+    }
+
+    // Drops the batches read since the last commit, they are returned again.
+    pub fn rewind(&mut self) {
+        self.position = self.committed;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EventFilesystemBuilder, FsError, InstructionBudget, MAX_BOOKMARK_NAME_SIZE, MAX_BOOKMARKS, VecStorage};
+    use crate::fixtures::small_layout;
+
+    #[test]
+    fn it_advances_only_on_commit() {
+        let mut fs = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("test".to_string()).unwrap();
+        for i in 0..5u64 {
+            fs.write_topic_message(&i).unwrap();
+        }
+        let mut cursor = fs.create_cursor("indexer").unwrap();
+        assert_eq!(cursor.next_batch::<u64>(2).unwrap(), vec![0, 1]);
+        assert_eq!(cursor.next_batch::<u64>(2).unwrap(), vec![2, 3]);
+        cursor.rewind();
+        assert_eq!(cursor.next_batch::<u64>(2).unwrap(), vec![0, 1]);
+        cursor.commit().unwrap();
+        assert_eq!(cursor.next_batch::<u64>(10).unwrap(), vec![2, 3, 4]);
+        assert!(cursor.next_batch::<u64>(10).unwrap().is_empty());
+        assert!(matches!(fs.create_cursor("indexer"), Err(FsError::InvalidArgument(_))));
+
+        let fs_reopened = EventFilesystemBuilder::with_storage(fs.storage().clone(), || 0).open().unwrap();
+        let mut cursor = fs_reopened.cursor("indexer").unwrap();
+        assert_eq!((cursor.committed(), cursor.next_batch::<u64>(1).unwrap()), (2, vec![2]));
+        assert_eq!(fs_reopened.cursors(), vec![("indexer".to_string(), 2)]);
+
+        fs.truncate_before(4, &InstructionBudget::unlimited()).unwrap();
+        let mut cursor = fs.cursor("indexer").unwrap();
+        assert_eq!(cursor.next_batch::<u64>(10).unwrap(), vec![4]);
+        fs.remove_cursor("indexer").unwrap();
+        assert!(cursor.commit().is_err());
+        assert!(fs.cursor("indexer").is_err());
+        assert!(fs.remove_cursor("indexer").is_err());
+    }
+
+    #[test]
+    fn it_keeps_bookmarks_without_registration() {
+        let layout = small_layout();
+        let fs = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("test".to_string()).unwrap();
+        for i in 0..3u64 {
+            fs.write_topic_message(&i).unwrap();
+        }
+        assert_eq!(fs.get_bookmark("projection"), None);
+        fs.set_bookmark("projection", 2).unwrap();
+        fs.set_bookmark("timer", 3).unwrap();
+        assert!(matches!(fs.set_bookmark("timer", 4), Err(FsError::InvalidArgument(_))));
+        assert!(fs.set_bookmark(&"n".repeat(MAX_BOOKMARK_NAME_SIZE + 1), 0).is_err());
+
+        let reopened = EventFilesystemBuilder::with_storage(fs.storage().clone(), || 0).open().unwrap();
+        assert_eq!(reopened.bookmarks(), vec![("projection".to_string(), 2), ("timer".to_string(), 3)]);
+        assert_eq!(reopened.remove_bookmark("timer").unwrap(), Some(3));
+        assert_eq!(reopened.remove_bookmark("timer").unwrap(), None);
+
+        for i in 2..MAX_BOOKMARKS {
+            fs.set_bookmark(&format!("b{}", i), 1).unwrap();
+        }
+        assert!(matches!(fs.set_bookmark("one_more", 1), Err(FsError::OutOfSpace(_))));
+        fs.set_bookmark("projection", 3).unwrap();
+        assert_eq!(fs.get_bookmark("projection"), Some(3));
+    }
+}
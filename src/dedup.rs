@@ -0,0 +1,101 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+use crate::hash::Hasher;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DuplicateMode {
+    // The write fails with FsError::Duplicate.
+    Reject,
+    // The write goes through and a DuplicateWritten admin event is recorded.
+    Flag,
+}
+
+// Payloads are compared by their digest with the topic's hash algorithm, against the last `window`
+// messages that carry one. Markers are never treated as duplicates.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeduplicationConfig {
+    pub window: u64,
+    pub mode: DuplicateMode,
+}
+
+pub(crate) struct Deduplication {
+    config: DeduplicationConfig,
+    hasher: Box<dyn Hasher>,
+    // Oldest first.
+    recent: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Deduplication {
+    pub(crate) fn new(config: DeduplicationConfig, hasher: Box<dyn Hasher>) -> Result<Self, FsError> {
+        if config.window == 0 {
+            return Err(FsError::InvalidArgument("Deduplication window must be positive".to_string()));
+        }
+        Ok(Deduplication { config, hasher, recent: VecDeque::new() })
+    }
+
+    pub(crate) fn config(&self) -> DeduplicationConfig {
+        self.config
+    }
+
+    // None for markers.
+    pub(crate) fn digest(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        (!payload.is_empty()).then(|| self.hasher.digest(payload))
+    }
+
+    // The digest to record once the payload is written.
+    pub(crate) fn check(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, FsError> {
+        let digest = self.digest(payload);
+        match (digest.as_deref().and_then(|digest| self.find(digest)), self.config.mode) {
+            (Some(height), DuplicateMode::Reject) => Err(FsError::Duplicate { height }),
+            _ => Ok(digest),
+        }
+    }
+
+    // Returns the height of an earlier message with the same digest.
+    pub(crate) fn record(&mut self, height: u64, digest: Vec<u8>) -> Option<u64> {
+        let duplicate_of = self.find(&digest);
+        self.recent.push_back((height, digest));
+        if self.recent.len() as u64 > self.config.window {
+            self.recent.pop_front();
+        }
+        duplicate_of
+    }
+
+    fn find(&self, digest: &[u8]) -> Option<u64> {
+        self.recent.iter().rev().find(|(_, recent)| recent.as_slice() == digest).map(|(height, _)| *height)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dedup::{Deduplication, DeduplicationConfig, DuplicateMode};
+    use crate::error::FsError;
+    use crate::hash::Sha256Hasher;
+
+    #[test]
+    fn it_finds_duplicates_within_the_window() {
+        let config = DeduplicationConfig { window: 2, mode: DuplicateMode::Reject };
+        let mut dedup = Deduplication::new(config, Box::new(Sha256Hasher)).unwrap();
+        assert_eq!(dedup.check(&[]), Ok(None));
+
+        let a = dedup.check(b"a").unwrap().unwrap();
+        assert_eq!(dedup.record(0, a), None);
+        assert_eq!(dedup.check(b"a"), Err(FsError::Duplicate { height: 0 }));
+
+        let b = dedup.check(b"b").unwrap().unwrap();
+        dedup.record(1, b);
+        let c = dedup.check(b"c").unwrap().unwrap();
+        dedup.record(2, c);
+        assert!(dedup.check(b"a").is_ok());
+
+        let mut flagging = Deduplication::new(DeduplicationConfig { mode: DuplicateMode::Flag, ..config }, Box::new(Sha256Hasher)).unwrap();
+        let b = flagging.check(b"b").unwrap().unwrap();
+        flagging.record(5, b.clone());
+        assert_eq!(flagging.check(b"b"), Ok(Some(b.clone())));
+        assert_eq!(flagging.record(6, b), Some(5));
+        assert!(Deduplication::new(DeduplicationConfig { window: 0, ..config }, Box::new(Sha256Hasher)).is_err());
+    }
+}
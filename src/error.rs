@@ -0,0 +1,82 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::recovery::HeightReport;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FsError {
+    Serialize(String),
+    Deserialize(String),
+    // An index entry (or another persisted length) doesn't describe valid data. Nothing was allocated for it.
+    CorruptIndex { height: u64, reason: String },
+    // The payload of the message at `height` doesn't match the checksum it was written with.
+    CorruptData { height: u64 },
+    MessageTooLarge { size: u64, limit: u64 },
+    // A canary at a zone boundary no longer holds its pattern, something wrote across the boundary.
+    RedZoneOverwritten { offset: u64, boundary: String },
+    OutOfSpace(String),
+    // Every slot of the index zone holds a message, grow_index_zone makes room for more.
+    IndexZoneFull { capacity: u64 },
+    InvalidArgument(String),
+    InvalidState(String),
+    Unsupported(String),
+    Compression(String),
+    // The payload matches the message at `height` within the deduplication window.
+    Duplicate { height: u64 },
+    // The message doesn't conform to the topic's schema, `path` points at the offending value.
+    SchemaViolation { path: String, reason: String },
+    // get_or_create found a topic under another name in the memory it was given.
+    TopicNameMismatch { expected: String, found: String },
+    // Another canister rejected a call or answered with an error.
+    Remote(String),
+    // The message at `height` was dropped by truncate_before, `first` is the lowest height left.
+    Truncated { height: u64, first: u64 },
+    // The caller, as principal text, isn't a controller of a topic that enforces access control.
+    Unauthorized(String),
+    // The storage couldn't grow to hold `needed` bytes, e.g. the canister reached its stable memory limit.
+    GrowFailed { needed: u64, reason: String },
+    // Reading or writing a snapshot stream failed.
+    Io(String),
+    // The storage panicked writing at `offset`. Nothing the write belonged to was committed.
+    WriteFailed { offset: u64, reason: String },
+    // open found the committed heights and the index entries disagreeing, see HeightPolicy.
+    HeightMismatch(HeightReport),
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Serialize(e) => write!(f, "Failed to serialize: {}", e),
+            FsError::Deserialize(e) => write!(f, "Failed to deserialize: {}", e),
+            FsError::CorruptIndex { height, reason } => write!(f, "Corrupt index entry {}: {}", height, reason),
+            FsError::CorruptData { height } => write!(f, "Payload of message {} doesn't match its checksum", height),
+            FsError::MessageTooLarge { size, limit } => write!(f, "Data is too large: {} bytes, limit is {}", size, limit),
+            FsError::RedZoneOverwritten { offset, boundary } => write!(f, "Red zone at {} ({}) was overwritten", offset, boundary),
+            FsError::OutOfSpace(e) => write!(f, "Out of space: {}", e),
+            FsError::IndexZoneFull { capacity } => write!(f, "Index zone is full at {} entries", capacity),
+            FsError::InvalidArgument(e) => write!(f, "Invalid argument: {}", e),
+            FsError::InvalidState(e) => write!(f, "Invalid state: {}", e),
+            FsError::Unsupported(e) => write!(f, "Unsupported: {}", e),
+            FsError::Compression(e) => write!(f, "Compression failed: {}", e),
+            FsError::Duplicate { height } => write!(f, "Duplicate of message {}", height),
+            FsError::SchemaViolation { path, reason } => write!(f, "Message violates the schema at {}: {}", path, reason),
+            FsError::TopicNameMismatch { expected, found } => write!(f, "Expected topic {} but the memory holds topic {}", expected, found),
+            FsError::Remote(e) => write!(f, "Remote call failed: {}", e),
+            FsError::Truncated { height, first } => write!(f, "Message {} was truncated, the topic starts at {}", height, first),
+            FsError::Unauthorized(caller) => write!(f, "{} is not a controller", caller),
+            FsError::GrowFailed { needed, reason } => write!(f, "Storage could not grow to {} bytes: {}", needed, reason),
+            FsError::Io(e) => write!(f, "I/O failed: {}", e),
+            FsError::WriteFailed { offset, reason } => write!(f, "Storage write at {} failed: {}", offset, reason),
+            FsError::HeightMismatch(report) => write!(f, "Topic height {} disagrees with the index ending at {}: {}", report.stored_height, report.index_height, report.reason),
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
+
+impl From<FsError> for String {
+    fn from(e: FsError) -> Self {
+        e.to_string()
+    }
+}
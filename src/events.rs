@@ -1,31 +1,80 @@
-use ic_cdk::export::Principal;
+use candid::Principal;
 use serde::{Deserialize, Serialize};
 
+use crate::alarms::AlarmBreach;
+use crate::error::FsError;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ControllerAdded {
-    pub(crate) controller: Principal,
+    pub controller: Principal,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ControllerRemoved {
-    pub(crate) controller: Principal,
+    pub controller: Principal,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SubscriberAdded {
-    pub(crate) subscriber: Principal,
-    pub(crate) offset: u64,
+    pub subscriber: Principal,
+    pub offset: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SubscriberRemoved {
-    pub(crate) subscriber: Principal,
+    pub subscriber: Principal,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SubscriberOffsetModified {
-    pub(crate) subscriber: Principal,
-    pub(crate) offset: u64,
+    pub subscriber: Principal,
+    pub offset: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TopicCreated {
+    pub event_stream_name: String,
+    pub binary_version: u32,
+    pub timestamp: u64,
+}
+
+// Emitted whenever an existing topic is opened, which in a canister means after an upgrade.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TopicOpened {
+    pub height: u64,
+    pub binary_version: u32,
+    pub timestamp: u64,
+}
+
+// Emitted by the open that migrated the topic to a newer format.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VersionMigrated {
+    pub from: u32,
+    pub to: u32,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CompactionCompleted {
+    // Heights before and after, messages were renumbered from 0.
+    pub height: u64,
+    pub kept: u64,
+    pub reclaimed_bytes: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IntegrityChecked {
+    pub error: Option<FsError>,
+    pub timestamp: u64,
+}
+
+// A payload identical to a recent message was written while deduplication flags instead of rejecting.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DuplicateWritten {
+    pub height: u64,
+    pub duplicate_of: u64,
+    pub timestamp: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -35,4 +84,11 @@ pub enum EventFilesystemEvent {
     SubscriberAdded(SubscriberAdded),
     SubscriberRemoved(SubscriberRemoved),
     SubscriberOffsetModified(SubscriberOffsetModified),
-}
\ No newline at end of file
+    AlarmTriggered(AlarmBreach),
+    TopicCreated(TopicCreated),
+    TopicOpened(TopicOpened),
+    VersionMigrated(VersionMigrated),
+    CompactionCompleted(CompactionCompleted),
+    IntegrityChecked(IntegrityChecked),
+    DuplicateWritten(DuplicateWritten),
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::Path;
+
+use crate::codec::Codec;
+use crate::error::FsError;
+
+// Checks that a new version of an event type still reads every serialized fixture of its earlier
+// versions, so breaking changes fail CI instead of the first upgrade that reads old messages.
+//
+//     let check = EvolutionCheck::new(BincodeCodec::default())
+//         .upcast(BincodeCodec::default(), |old: v1::Transfer| Transfer { amount: old.amount, memo: None });
+//     check.check(&load_fixtures("tests/fixtures/transfer")?).assert_compatible();
+pub struct EvolutionCheck<'a, T> {
+    decoders: Vec<Decoder<'a, T>>,
+}
+
+type Decoder<'a, T> = Box<dyn Fn(&[u8]) -> Result<T, FsError> + 'a>;
+
+// Outcome of EvolutionCheck::check, fixtures by name.
+#[derive(Debug)]
+pub struct EvolutionReport<T> {
+    pub decoded: Vec<(String, T)>,
+    // The error of the codec, the upcasters' errors only explain why they didn't apply.
+    pub failures: Vec<(String, FsError)>,
+}
+
+impl<T> EvolutionReport<T> {
+    pub fn is_compatible(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn assert_compatible(&self) {
+        let failures: Vec<String> = self.failures.iter().map(|(name, e)| format!("{}: {}", name, e)).collect();
+        assert!(failures.is_empty(), "fixtures no longer decode:\n{}", failures.join("\n"));
+    }
+}
+
+impl<'a, T> EvolutionCheck<'a, T> {
+    // `codec` is the one the topic reads messages with.
+    pub fn new(codec: impl Codec<T> + 'a) -> Self {
+        EvolutionCheck { decoders: vec![Box::new(move |bytes| codec.decode(bytes))] }
+    }
+
+    // Tried in order when the codec can't read a fixture, decodes an earlier version and converts it.
+    pub fn upcast<Old>(mut self, codec: impl Codec<Old> + 'a, convert: impl Fn(Old) -> T + 'a) -> Self {
+        self.decoders.push(Box::new(move |bytes| codec.decode(bytes).map(&convert)));
+        self
+    }
+
+    pub fn check<N: AsRef<str>, B: AsRef<[u8]>>(&self, fixtures: &[(N, B)]) -> EvolutionReport<T> {
+        let mut report = EvolutionReport { decoded: Vec::new(), failures: Vec::new() };
+        for (name, bytes) in fixtures {
+            let mut decoded = self.decoders.iter().map(|decode| decode(bytes.as_ref()));
+            let first = decoded.next().expect("the codec decoder");
+            let result = match first {
+                Ok(value) => Ok(value),
+                Err(e) => decoded.find(Result::is_ok).unwrap_or(Err(e)),
+            };
+            match result {
+                Ok(value) => report.decoded.push((name.as_ref().to_string(), value)),
+                Err(e) => report.failures.push((name.as_ref().to_string(), e)),
+            }
+        }
+        report
+    }
+}
+
+// Every file in `dir` as a fixture named after the file, sorted by name.
+pub fn load_fixtures(dir: impl AsRef<Path>) -> Result<Vec<(String, Vec<u8>)>, FsError> {
+    let unreadable = |e: std::io::Error| FsError::InvalidArgument(format!("Can't read fixtures in {}: {}", dir.as_ref().display(), e));
+    let mut fixtures = Vec::new();
+    for entry in fs::read_dir(dir.as_ref()).map_err(unreadable)? {
+        let path = entry.map_err(unreadable)?.path();
+        if path.is_file() {
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            fixtures.push((name, fs::read(&path).map_err(unreadable)?));
+        }
+    }
+    fixtures.sort();
+    Ok(fixtures)
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use crate::codec::{BincodeCodec, Codec};
+    use crate::evolution::{EvolutionCheck, load_fixtures};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct TransferV1 {
+        amount: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Transfer {
+        amount: u64,
+        memo: Option<String>,
+    }
+
+    #[test]
+    fn it_reads_old_fixtures_through_upcasters() {
+        let codec = BincodeCodec::default();
+        let fixtures = vec![
+            ("v1", codec.encode(&TransferV1 { amount: 5 }).unwrap()),
+            ("v2", codec.encode(&Transfer { amount: 7, memo: Some("rent".to_string()) }).unwrap()),
+        ];
+        let strict = EvolutionCheck::<Transfer>::new(codec).check(&fixtures);
+        assert!(!strict.is_compatible());
+        assert_eq!(strict.failures.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["v1"]);
+
+        let check = EvolutionCheck::new(codec).upcast(codec, |old: TransferV1| Transfer { amount: old.amount, memo: None });
+        let report = check.check(&fixtures);
+        report.assert_compatible();
+        assert_eq!(report.decoded[0], ("v1".to_string(), Transfer { amount: 5, memo: None }));
+
+        let dir = std::env::temp_dir().join(format!("ic_event_fs_fixtures_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, bytes) in &fixtures {
+            std::fs::write(dir.join(name), bytes).unwrap();
+        }
+        let loaded = load_fixtures(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["v1", "v2"]);
+        assert!(check.check(&loaded).is_compatible());
+        assert!(load_fixtures(&dir).is_err());
+    }
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+use crate::index_block::IndexBlock;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct IndexExportEntry {
+    pub height: u64,
+    pub timestamp: u64,
+    pub data_size: u64,
+    // The IndexBlock flags, e.g. IndexBlock::HAS_KEY.
+    pub flags: u32,
+    // The key index hash of a HAS_KEY entry, only filled in by export_index.
+    pub key_hash: Option<u64>,
+}
+
+impl From<IndexBlock> for IndexExportEntry {
+    fn from(idx: IndexBlock) -> Self {
+        IndexExportEntry {
+            height: idx.height,
+            timestamp: idx.timestamp,
+            data_size: idx.data_size,
+            flags: idx.flags,
+            key_hash: None,
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::FsError;
+
+// A predicate over decoded messages. Messages are inspected through their serde representation,
+// so any `Serialize` message type can be filtered by field path.
+//
+// Expression syntax, e.g. `kind == "transfer" && (amount != 0 || tags contains "refund")`:
+//   path      := `$` | ident ( `.` ident )*    numeric idents index into arrays
+//   literal   := "string" | number | true | false | null
+//   compare   := path ( `==` | `!=` | `contains` ) literal
+//   expr      := compare | `!` expr | `(` expr `)` | expr `&&` expr | expr `||` expr
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageFilter {
+    All,
+    Eq(Vec<String>, Value),
+    NotEq(Vec<String>, Value),
+    Contains(Vec<String>, Value),
+    Not(Box<MessageFilter>),
+    And(Box<MessageFilter>, Box<MessageFilter>),
+    Or(Box<MessageFilter>, Box<MessageFilter>),
+}
+
+impl MessageFilter {
+    pub fn parse(expression: &str) -> Result<MessageFilter, FsError> {
+        let tokens = tokenize(expression)?;
+        if tokens.is_empty() {
+            return Ok(MessageFilter::All);
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let filter = parser.parse_or()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(FsError::InvalidArgument(format!("Unexpected token {:?} in filter", parser.tokens[parser.pos])));
+        }
+        Ok(filter)
+    }
+
+    pub fn matches<T: Serialize>(&self, message: &T) -> Result<bool, FsError> {
+        let value = serde_json::to_value(message).map_err(|e| FsError::Serialize(format!("Failed to inspect message: {}", e)))?;
+        Ok(self.matches_value(&value))
+    }
+
+    pub fn matches_value(&self, value: &Value) -> bool {
+        match self {
+            MessageFilter::All => true,
+            MessageFilter::Eq(path, expected) => lookup(value, path).is_some_and(|v| values_equal(v, expected)),
+            MessageFilter::NotEq(path, expected) => !lookup(value, path).is_some_and(|v| values_equal(v, expected)),
+            MessageFilter::Contains(path, needle) => lookup(value, path).is_some_and(|v| contains(v, needle)),
+            MessageFilter::Not(inner) => !inner.matches_value(value),
+            MessageFilter::And(a, b) => a.matches_value(value) && b.matches_value(value),
+            MessageFilter::Or(a, b) => a.matches_value(value) || b.matches_value(value),
+        }
+    }
+}
+
+fn invalid(reason: String) -> FsError {
+    FsError::InvalidArgument(reason)
+}
+
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut current = value;
+    for segment in path {
+        current = match current {
+            Value::Object(map) => map.get(segment)?,
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn contains(haystack: &Value, needle: &Value) -> bool {
+    match (haystack, needle) {
+        (Value::String(s), Value::String(n)) => s.contains(n.as_str()),
+        (Value::Array(items), _) => items.iter().any(|item| values_equal(item, needle)),
+        (Value::Object(map), Value::String(key)) => map.contains_key(key),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Path(Vec<String>),
+    Literal(Value),
+    Eq,
+    NotEq,
+    Contains,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, FsError> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' => i += 1,
+            '(' => { tokens.push(Token::Open); i += 1; }
+            ')' => { tokens.push(Token::Close); i += 1; }
+            '=' if chars.get(i + 1) == Some(&'=') => { tokens.push(Token::Eq); i += 2; }
+            '!' if chars.get(i + 1) == Some(&'=') => { tokens.push(Token::NotEq); i += 2; }
+            '!' => { tokens.push(Token::Not); i += 1; }
+            '&' if chars.get(i + 1) == Some(&'&') => { tokens.push(Token::And); i += 2; }
+            '|' if chars.get(i + 1) == Some(&'|') => { tokens.push(Token::Or); i += 2; }
+            '"' => {
+                let mut s = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('"') => break,
+                        Some('\\') => {
+                            s.push(*chars.get(i + 1).ok_or_else(|| invalid("Unterminated string in filter".to_string()))?);
+                            i += 2;
+                        }
+                        Some(c) => { s.push(*c); i += 1; }
+                        None => return Err(invalid("Unterminated string in filter".to_string())),
+                    }
+                }
+                tokens.push(Token::Literal(Value::String(s)));
+                i += 1;
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let number = serde_json::from_str::<Value>(&text).map_err(|_| invalid(format!("Invalid number {} in filter", text)))?;
+                tokens.push(Token::Literal(number));
+            }
+            c if c == '$' || c == '_' || c.is_alphanumeric() => {
+                let start = i;
+                while i < chars.len() && (chars[i] == '_' || chars[i] == '.' || chars[i] == '$' || chars[i].is_alphanumeric()) {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(match word.as_str() {
+                    "contains" => Token::Contains,
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Path(parse_path(&word)?),
+                });
+            }
+            c => return Err(invalid(format!("Unexpected character '{}' in filter", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_path(word: &str) -> Result<Vec<String>, FsError> {
+    let word = word.strip_prefix('$').unwrap_or(word);
+    let word = word.strip_prefix('.').unwrap_or(word);
+    if word.is_empty() {
+        return Ok(Vec::new());
+    }
+    word.split('.')
+        .map(|segment| if segment.is_empty() || segment.contains('$') {
+            Err(invalid(format!("Invalid path {} in filter", word)))
+        } else {
+            Ok(segment.to_string())
+        })
+        .collect()
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn parse_or(&mut self) -> Result<MessageFilter, FsError> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            left = MessageFilter::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<MessageFilter, FsError> {
+        let mut left = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            left = MessageFilter::And(Box::new(left), Box::new(self.parse_unary()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<MessageFilter, FsError> {
+        match self.next() {
+            Some(Token::Not) => Ok(MessageFilter::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let inner = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    other => Err(invalid(format!("Expected ')' in filter, found {:?}", other))),
+                }
+            }
+            Some(Token::Path(path)) => {
+                let op = self.next();
+                let literal = match self.next() {
+                    Some(Token::Literal(value)) => value,
+                    other => return Err(invalid(format!("Expected literal in filter, found {:?}", other))),
+                };
+                match op {
+                    Some(Token::Eq) => Ok(MessageFilter::Eq(path, literal)),
+                    Some(Token::NotEq) => Ok(MessageFilter::NotEq(path, literal)),
+                    Some(Token::Contains) => Ok(MessageFilter::Contains(path, literal)),
+                    other => Err(invalid(format!("Expected comparison in filter, found {:?}", other))),
+                }
+            }
+            other => Err(invalid(format!("Unexpected token {:?} in filter", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Serialize;
+
+    use crate::filter::MessageFilter;
+
+    #[derive(Serialize)]
+    struct Transfer {
+        kind: String,
+        amount: u64,
+        tags: Vec<String>,
+        meta: Meta,
+    }
+
+    #[derive(Serialize)]
+    struct Meta {
+        memo: String,
+    }
+
+    fn transfer(kind: &str, amount: u64, tags: &[&str]) -> Transfer {
+        Transfer {
+            kind: kind.to_string(),
+            amount,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            meta: Meta { memo: format!("{} of {}", kind, amount) },
+        }
+    }
+
+    #[test]
+    fn it_matches_field_comparisons() {
+        let filter = MessageFilter::parse(r#"kind == "transfer" && amount != 0"#).unwrap();
+        assert!(filter.matches(&transfer("transfer", 5, &[])).unwrap());
+        assert!(!filter.matches(&transfer("transfer", 0, &[])).unwrap());
+        assert!(!filter.matches(&transfer("mint", 5, &[])).unwrap());
+    }
+
+    #[test]
+    fn it_matches_contains_nesting_and_precedence() {
+        let filter = MessageFilter::parse(r#"!(kind == "mint") && (tags contains "refund" || meta.memo contains "of 7")"#).unwrap();
+        assert!(filter.matches(&transfer("transfer", 1, &["refund"])).unwrap());
+        assert!(filter.matches(&transfer("transfer", 7, &[])).unwrap());
+        assert!(!filter.matches(&transfer("mint", 7, &["refund"])).unwrap());
+        assert!(!filter.matches(&transfer("transfer", 1, &["other"])).unwrap());
+
+        assert!(MessageFilter::parse("tags.0 == \"a\"").unwrap().matches(&transfer("t", 1, &["a"])).unwrap());
+        assert!(MessageFilter::parse("$ == 3").unwrap().matches(&3u32).unwrap());
+        assert!(MessageFilter::parse("").unwrap().matches(&3u32).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_malformed_expressions() {
+        assert!(MessageFilter::parse("kind ==").is_err());
+        assert!(MessageFilter::parse("kind == \"a").is_err());
+        assert!(MessageFilter::parse("(kind == 1").is_err());
+        assert!(MessageFilter::parse("kind == 1 )").is_err());
+        assert!(MessageFilter::parse("kind ~ 1").is_err());
+    }
+}
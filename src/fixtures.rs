@@ -0,0 +1,56 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use crate::layout::LayoutConfig;
+
+// A layout small enough that a test topic fits in a little over a megabyte of storage.
+pub(crate) fn small_layout() -> LayoutConfig {
+    LayoutConfig { free_memory_block_size: 1024 * 1024, meta_zone_size: 64 * 1024, index_zone_size: 4096, ..LayoutConfig::default() }
+}
+
+thread_local! {
+    static NOW: Cell<u64> = const { Cell::new(0) };
+}
+
+// A clock tests move with set_now. Every test runs on its own thread and starts at 0.
+pub(crate) fn now() -> u64 {
+    NOW.with(Cell::get)
+}
+
+pub(crate) fn set_now(now: u64) {
+    NOW.with(|cell| cell.set(now));
+}
+
+// Counts the allocations of the thread it runs on, so tests can check a path doesn't allocate without
+// seeing the tests running beside them.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Allocations `f` made on this thread, reallocations included.
+pub(crate) fn count_allocations(f: impl FnOnce()) -> u64 {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
@@ -0,0 +1,133 @@
+use crate::constants::IDX_BLOCK_SIZE;
+use crate::error::FsError;
+use crate::index_block::UNFLAGGED_IDX_BLOCK_SIZE;
+use crate::storage::Storage;
+use crate::topic_header_block::TopicHeaderBlock;
+use crate::write_topic_block;
+
+// Version of the stable memory format new topics are created with and older ones are migrated to.
+pub const BINARY_VERSION: u32 = 1_001_000;
+
+// Formats that are read and written as they are, without a migration. 1_000_000 wrote index entries
+// without flags, rewriting them would cost more than an upgrade may spend on a full index zone and
+// need room the zone may not have.
+const KEPT_FORMATS: &[u32] = &[1_000_000];
+
+// Upgrades a topic written in format `from` to format `to`, e.g. when index entries change layout.
+// Runs on the raw storage before the topic is opened. The header is bumped after each step, so a
+// step that traps is run again on the next open and has to cope with its own partial work.
+#[derive(Debug, Clone, Copy)]
+pub struct FormatMigration {
+    pub from: u32,
+    pub to: u32,
+    pub step: fn(storage: &Storage, header: &TopicHeaderBlock) -> Result<(), FsError>,
+}
+
+// Registered in version order. Formats older than the first `from` can't be opened.
+const MIGRATIONS: &[FormatMigration] = &[];
+
+// Refuses formats newer than this build and migrates older ones, then sizes index entries for the
+// format the topic ends up in. Returns whether anything ran.
+pub(crate) fn migrate(header: &mut TopicHeaderBlock, storage: &Storage) -> Result<bool, FsError> {
+    let migrated = migrate_to(header, storage, MIGRATIONS, KEPT_FORMATS, BINARY_VERSION)?;
+    header.layout.index_entry_size = index_entry_size(header.binary_version);
+    Ok(migrated)
+}
+
+// Bytes an index entry takes in topics of format `binary_version`.
+pub(crate) fn index_entry_size(binary_version: u32) -> u64 {
+    match binary_version < 1_001_000 {
+        true => UNFLAGGED_IDX_BLOCK_SIZE,
+        false => IDX_BLOCK_SIZE,
+    }
+}
+
+fn migrate_to(header: &mut TopicHeaderBlock, storage: &Storage, migrations: &[FormatMigration], kept: &[u32], target: u32) -> Result<bool, FsError> {
+    if header.binary_version > target {
+        return Err(FsError::Unsupported(format!("Topic format {} is newer than {}, open it with a newer build", header.binary_version, target)));
+    }
+    let migrated = header.binary_version < target && !kept.contains(&header.binary_version);
+    while header.binary_version < target && !kept.contains(&header.binary_version) {
+        let migration = migrations.iter().find(|migration| migration.from == header.binary_version)
+            .ok_or_else(|| FsError::Unsupported(format!("No migration from topic format {}", header.binary_version)))?;
+        (migration.step)(storage, header)?;
+        header.binary_version = migration.to;
+        write_topic_block(header, storage);
+    }
+    Ok(migrated)
+}
+
+// Like migrate for topics opened read-only, a format that would have to be migrated is refused.
+pub(crate) fn check_format(header: &mut TopicHeaderBlock) -> Result<(), FsError> {
+    if header.binary_version > BINARY_VERSION {
+        return Err(FsError::Unsupported(format!("Topic format {} is newer than {}, open it with a newer build", header.binary_version, BINARY_VERSION)));
+    }
+    if header.binary_version < BINARY_VERSION && !KEPT_FORMATS.contains(&header.binary_version) {
+        return Err(FsError::InvalidState(format!("Topic format {} has to be migrated, open it writable first", header.binary_version)));
+    }
+    header.layout.index_entry_size = index_entry_size(header.binary_version);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::error::FsError;
+    use crate::format::{BINARY_VERSION, FormatMigration, check_format, migrate_to};
+    use crate::layout::LayoutConfig;
+    use crate::read_topic_block;
+    use crate::storage::{Storage, VecStorage};
+    use crate::topic_header_block::TopicHeaderBlock;
+
+    fn header(binary_version: u32) -> TopicHeaderBlock {
+        TopicHeaderBlock {
+            event_stream_name: "format".to_string(),
+            first_message_ptr: 0,
+            binary_version,
+            hash_algorithm: 0,
+            layout: LayoutConfig::default(),
+            registers: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn it_runs_migrations_in_order() {
+        let storage = Storage::new(VecStorage::default());
+        let migrations = [
+            FormatMigration { from: 2, to: 3, step: |storage, header| {
+                storage.write(0, &[header.binary_version as u8]);
+                Ok(())
+            } },
+            FormatMigration { from: 1, to: 2, step: |_, _| Ok(()) },
+        ];
+        let mut topic = header(1);
+        assert!(migrate_to(&mut topic, &storage, &migrations, &[], 3).unwrap());
+        assert_eq!(topic.binary_version, 3);
+        assert_eq!(read_topic_block(&storage).unwrap(), topic);
+        let mut byte = [0u8];
+        storage.read(0, &mut byte);
+        assert_eq!(byte, [2]);
+        assert!(!migrate_to(&mut topic, &storage, &migrations, &[], 3).unwrap());
+
+        assert!(matches!(migrate_to(&mut header(4), &storage, &migrations, &[], 3), Err(FsError::Unsupported(_))));
+        assert!(matches!(migrate_to(&mut header(0), &storage, &migrations, &[], 3), Err(FsError::Unsupported(_))));
+        let failing = [FormatMigration { from: 1, to: 2, step: |_, _| Err(FsError::InvalidState("trapped".to_string())) }];
+        let mut topic = header(1);
+        assert!(migrate_to(&mut topic, &storage, &failing, &[], 2).is_err());
+        assert_eq!(topic.binary_version, 1);
+        // Kept formats stay as they are, migrations from them don't run.
+        assert!(!migrate_to(&mut topic, &storage, &failing, &[1], 2).unwrap());
+        assert_eq!(topic.binary_version, 1);
+    }
+
+    #[test]
+    fn it_checks_formats_without_migrating() {
+        let mut kept = header(1_000_000);
+        check_format(&mut kept).unwrap();
+        assert_eq!(kept.layout.index_entry_size, 40);
+        check_format(&mut header(BINARY_VERSION)).unwrap();
+        assert!(matches!(check_format(&mut header(BINARY_VERSION + 1)), Err(FsError::Unsupported(_))));
+        assert!(matches!(check_format(&mut header(999_000)), Err(FsError::InvalidState(_))));
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use crate::error::FsError;
+
+// Ids are persisted in the topic header, never renumber them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+    Crc32,
+}
+
+impl HashAlgorithm {
+    pub fn id(&self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => 1,
+            HashAlgorithm::Blake3 => 2,
+            HashAlgorithm::Crc32 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, FsError> {
+        match id {
+            1 => Ok(HashAlgorithm::Sha256),
+            2 => Ok(HashAlgorithm::Blake3),
+            3 => Ok(HashAlgorithm::Crc32),
+            _ => Err(FsError::Unsupported(format!("Unknown hash algorithm id: {}", id))),
+        }
+    }
+
+    pub fn hasher(&self) -> Result<Box<dyn Hasher>, FsError> {
+        match self {
+            HashAlgorithm::Sha256 => Ok(Box::new(Sha256Hasher)),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Ok(Box::new(Blake3Hasher)),
+            #[cfg(feature = "crc32")]
+            HashAlgorithm::Crc32 => Ok(Box::new(Crc32Hasher)),
+            #[allow(unreachable_patterns)]
+            other => Err(FsError::Unsupported(format!("Hash algorithm {:?} is not compiled in, enable its cargo feature", other))),
+        }
+    }
+}
+
+pub trait Hasher {
+    fn algorithm(&self) -> HashAlgorithm;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+}
+
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Sha256
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        sha2::Sha256::digest(data).to_vec()
+    }
+}
+
+#[cfg(feature = "blake3")]
+pub struct Blake3Hasher;
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Blake3
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+}
+
+#[cfg(feature = "crc32")]
+pub struct Crc32Hasher;
+
+#[cfg(feature = "crc32")]
+impl Hasher for Crc32Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Crc32
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        crc32fast::hash(data).to_le_bytes().to_vec()
+    }
+}
+
+// CRC32 of the concatenated parts, for the per-message checksums.
+pub(crate) fn crc32(parts: &[&[u8]]) -> Result<u32, FsError> {
+    #[cfg(feature = "crc32")]
+    {
+        let mut hasher = crc32fast::Hasher::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        Ok(hasher.finalize())
+    }
+    #[cfg(not(feature = "crc32"))]
+    {
+        let _ = parts;
+        Err(FsError::Unsupported("Message checksums need the crc32 feature".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hash::HashAlgorithm;
+
+    #[test]
+    fn it_round_trips_algorithm_ids() {
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3, HashAlgorithm::Crc32] {
+            assert_eq!(HashAlgorithm::from_id(algorithm.id()).unwrap(), algorithm);
+        }
+        assert!(HashAlgorithm::from_id(0).is_err());
+    }
+
+    #[test]
+    fn it_hashes_with_sha256_by_default() {
+        let hasher = HashAlgorithm::default().hasher().unwrap();
+        let digest = hasher.digest(b"abc");
+        assert_eq!(hasher.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(digest.len(), 32);
+        assert_eq!(digest[..4], [0xba, 0x78, 0x16, 0xbf]);
+    }
+
+    #[cfg(feature = "crc32")]
+    #[test]
+    fn it_hashes_with_crc32() {
+        let hasher = HashAlgorithm::Crc32.hasher().unwrap();
+        assert_eq!(hasher.digest(b"123456789"), 0xcbf43926u32.to_le_bytes().to_vec());
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::arena::ArenaStats;
+use crate::journal::JournalEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct ZoneUsage {
+    pub used: u64,
+    pub capacity: u64,
+}
+
+impl ZoneUsage {
+    // Share of the zone in use, from 0 to 1.
+    pub fn utilization(&self) -> f64 {
+        match self.capacity {
+            0 => 0.0,
+            capacity => self.used as f64 / capacity as f64,
+        }
+    }
+}
+
+// Journaled errors by operation, with the latest one.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct ErrorSummary {
+    pub total: u64,
+    pub by_operation: BTreeMap<String, u64>,
+    pub latest: Option<JournalEntry>,
+}
+
+impl ErrorSummary {
+    pub(crate) fn new(entries: impl Iterator<Item = JournalEntry>) -> Self {
+        let mut summary = ErrorSummary::default();
+        for entry in entries {
+            summary.total += 1;
+            *summary.by_operation.entry(entry.operation.clone()).or_default() += 1;
+            summary.latest = Some(entry);
+        }
+        summary
+    }
+}
+
+// Outcome of EventFilesystem::health, everything a monitoring endpoint needs in one call. Index usage
+// counts slots, the other zones count bytes. The data zone is unbounded, only its use is reported.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub stable_store: ZoneUsage,
+    pub meta: ZoneUsage,
+    // Share of the free meta bytes that sit in free-list chunks rather than the untouched tail of the
+    // zone, from 0 to 1. High values mean large records may no longer fit although enough is free.
+    pub meta_fragmentation: f64,
+    pub meta_free_chunks: u64,
+    pub index_slots: ZoneUsage,
+    pub data_bytes: u64,
+    // Index slots held by truncated messages, compact() reclaims them.
+    pub compaction_debt: u64,
+    // What a growth, compaction or truncation in progress still has to do.
+    pub pending_migration: Option<String>,
+    pub errors: ErrorSummary,
+}
+
+pub(crate) fn meta_fragmentation(stats: &ArenaStats) -> f64 {
+    match stats.free_list_bytes + stats.unallocated_bytes {
+        0 => 0.0,
+        free => stats.free_list_bytes as f64 / free as f64,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arena::ArenaStats;
+    use crate::health::{meta_fragmentation, ZoneUsage};
+
+    #[test]
+    fn it_measures_usage_and_fragmentation() {
+        assert_eq!(ZoneUsage { used: 25, capacity: 100 }.utilization(), 0.25);
+        assert_eq!(ZoneUsage::default().utilization(), 0.0);
+        let stats = ArenaStats { free_list_bytes: 300, unallocated_bytes: 100, ..ArenaStats::default() };
+        assert_eq!(meta_fragmentation(&stats), 0.75);
+        assert_eq!(meta_fragmentation(&ArenaStats::default()), 0.0);
+    }
+}
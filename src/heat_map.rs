@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeatMapSegment {
+    pub start: u64,
+    pub end: u64,
+    pub reads: u64,
+}
+
+// Read counts per fixed size height segment, only segments that were read are kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HeatMap {
+    segment_size: u64,
+    reads: BTreeMap<u64, u64>,
+}
+
+impl HeatMap {
+    pub(crate) fn new(segment_size: u64) -> Result<Self, FsError> {
+        if segment_size == 0 {
+            return Err(FsError::InvalidArgument("Heat map segment size must be positive".to_string()));
+        }
+        Ok(HeatMap { segment_size, reads: BTreeMap::new() })
+    }
+
+    pub(crate) fn segment_size(&self) -> u64 {
+        self.segment_size
+    }
+
+    pub(crate) fn record(&mut self, start: u64, count: u64) {
+        let mut height = start;
+        let end = start.saturating_add(count);
+        while height < end {
+            let segment = height / self.segment_size;
+            let segment_end = ((segment + 1) * self.segment_size).min(end);
+            *self.reads.entry(segment).or_default() += segment_end - height;
+            height = segment_end;
+        }
+    }
+
+    pub(crate) fn segments(&self) -> Vec<HeatMapSegment> {
+        self.reads.iter()
+            .map(|(segment, reads)| HeatMapSegment {
+                start: segment * self.segment_size,
+                end: (segment + 1) * self.segment_size,
+                reads: *reads,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::heat_map::{HeatMap, HeatMapSegment};
+
+    #[test]
+    fn it_counts_reads_per_segment() {
+        let mut heat = HeatMap::new(10).unwrap();
+        heat.record(3, 1);
+        heat.record(8, 15);
+        heat.record(40, 0);
+
+        assert_eq!(heat.segments(), vec![
+            HeatMapSegment { start: 0, end: 10, reads: 3 },
+            HeatMapSegment { start: 10, end: 20, reads: 10 },
+            HeatMapSegment { start: 20, end: 30, reads: 3 },
+        ]);
+        assert!(HeatMap::new(0).is_err());
+    }
+}
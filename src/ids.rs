@@ -0,0 +1,35 @@
+// Derives the id of a message from its ingestion timestamp and height. Ids only have to be unique
+// within the topic, the topic raises any id that isn't above the previous one to the one after it, so
+// ids always increase with height. Generators that mix in the canister id make them globally unique.
+pub type IdGenerator = fn(timestamp: u64, height: u64) -> u128;
+
+const TIME_BITS: u32 = 48;
+const SEQUENCE_BITS: u32 = 128 - TIME_BITS;
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+// ULID layout: milliseconds since the epoch in the top 48 bits, then the height where ULIDs have
+// random bits, so ids sort by time and stay unique within the topic.
+pub fn ulid_id(timestamp: u64, height: u64) -> u128 {
+    let millis = (timestamp / 1_000_000) as u128 & ((1 << TIME_BITS) - 1);
+    (millis << SEQUENCE_BITS) | height as u128
+}
+
+// The 26 character Crockford base32 text of an id, the way ULIDs are usually written.
+pub fn id_to_string(id: u128) -> String {
+    (0..26).rev().map(|i| CROCKFORD[((id >> (i * 5)) & 0x1f) as usize] as char).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ids::{id_to_string, ulid_id};
+
+    #[test]
+    fn it_orders_ids_by_time_then_height() {
+        assert!(ulid_id(2_000_000, 0) > ulid_id(1_999_999, 7));
+        assert!(ulid_id(1_000_000, 8) > ulid_id(1_000_000, 7));
+        assert_eq!(ulid_id(1_000_000, 5), (1 << 80) | 5);
+        assert_eq!(id_to_string(0), "00000000000000000000000000");
+        assert_eq!(id_to_string(u128::MAX), "7ZZZZZZZZZZZZZZZZZZZZZZZZZ");
+        assert_eq!(id_to_string(ulid_id(1_000_000, 31)), "0000000001000000000000000Z");
+    }
+}
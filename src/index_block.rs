@@ -1,30 +1,194 @@
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::ops::Range;
 
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+use crate::IDX_BLOCK_SIZE;
+
+// Size of entries written before the flags were stored, see IndexBlock::from_unflagged_bytes.
+pub const UNFLAGGED_IDX_BLOCK_SIZE: u64 = 40;
+
+// On-disk index entry, IDX_BLOCK_SIZE bytes of little endian numbers at fixed offsets:
+//   0..8    height
+//   8..16   data_size
+//   16..24  start_idx
+//   24..32  end_idx
+//   32..40  timestamp
+//   40..44  flags, see the flag constants
+//   44..48  reserved, zero
+// The layout is written by hand so it doesn't change with the serializer. Public so tools reading raw
+// stable memory through LayoutDescriptor can decode entries.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub struct IndexBlock {
-    pub(crate) height: u64,
-    pub(crate) data_size: u64,
-    pub(crate) start_idx: u64,
-    pub(crate) end_idx: u64,
-    pub(crate) timestamp: u64,
+    pub height: u64,
+    pub data_size: u64,
+    pub start_idx: u64,
+    pub end_idx: u64,
+    pub timestamp: u64,
+    pub flags: u32,
+}
+
+impl IndexBlock {
+    // Set on every entry that was written, a slot that never was reads as zeroes.
+    pub const COMMITTED: u32 = 1 << 0;
+    // The payload was dropped by truncate_before.
+    pub const ERASED: u32 = 1 << 1;
+    // The payload was compressed before it was stored.
+    pub const COMPRESSED: u32 = 1 << 2;
+    // Reserved for payloads kept in the entry instead of the data zone.
+    pub const INLINED: u32 = 1 << 3;
+    // The height is recorded in the key index.
+    pub const HAS_KEY: u32 = 1 << 4;
+    // A marker open put in place of an entry that was lost, see HeightPolicy::TrustHeight.
+    pub const ABORTED: u32 = 1 << 5;
+    // Bits 6..32 are reserved for future per-message features and written as zero.
+
+    pub fn to_bytes(&self) -> [u8; IDX_BLOCK_SIZE as usize] {
+        let mut bytes = [0u8; IDX_BLOCK_SIZE as usize];
+        for (slot, value) in bytes.chunks_exact_mut(8).zip([self.height, self.data_size, self.start_idx, self.end_idx, self.timestamp]) {
+            slot.copy_from_slice(&value.to_le_bytes());
+        }
+        bytes[40..44].copy_from_slice(&self.flags.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FsError> {
+        if bytes.len() != IDX_BLOCK_SIZE as usize {
+            return Err(FsError::Deserialize(format!("index entry of {} bytes, expected {}", bytes.len(), IDX_BLOCK_SIZE)));
+        }
+        Ok(IndexBlock {
+            flags: u32::from_le_bytes(bytes[40..44].try_into().unwrap()),
+            ..IndexBlock::from_unflagged_bytes(&bytes[..UNFLAGGED_IDX_BLOCK_SIZE as usize])?
+        })
+    }
+
+    // An entry as the topic's format stores it, see format::index_entry_size.
+    pub(crate) fn from_entry(bytes: &[u8]) -> Result<Self, FsError> {
+        match bytes.len() as u64 {
+            UNFLAGGED_IDX_BLOCK_SIZE => IndexBlock::from_unflagged_bytes(bytes),
+            _ => IndexBlock::from_bytes(bytes),
+        }
+    }
+
+    // Entries of UNFLAGGED_IDX_BLOCK_SIZE bytes end before the flags, format 1_000_000 topics keep writing
+    // them. They only get COMMITTED, what else the flags would say isn't recorded.
+    pub fn from_unflagged_bytes(bytes: &[u8]) -> Result<Self, FsError> {
+        if bytes.len() != UNFLAGGED_IDX_BLOCK_SIZE as usize {
+            return Err(FsError::Deserialize(format!("unflagged index entry of {} bytes, expected {}", bytes.len(), UNFLAGGED_IDX_BLOCK_SIZE)));
+        }
+        Ok(IndexBlock {
+            height: read_u64(bytes, 0),
+            data_size: read_u64(bytes, 8),
+            start_idx: read_u64(bytes, 16),
+            end_idx: read_u64(bytes, 24),
+            timestamp: read_u64(bytes, 32),
+            flags: IndexBlock::COMMITTED,
+        })
+    }
+
+    // Markers carry no payload (e.g. `()` heartbeats): they own no data blocks but keep their height.
+    pub(crate) fn is_marker(&self) -> bool {
+        self.data_size == 0
+    }
+
+    pub fn is_committed(&self) -> bool {
+        self.flags & IndexBlock::COMMITTED != 0
+    }
+
+    pub fn is_erased(&self) -> bool {
+        self.flags & IndexBlock::ERASED != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & IndexBlock::COMPRESSED != 0
+    }
+
+    pub fn is_inlined(&self) -> bool {
+        self.flags & IndexBlock::INLINED != 0
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.flags & IndexBlock::HAS_KEY != 0
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.flags & IndexBlock::ABORTED != 0
+    }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// What the index and envelope record about a message, next to its payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageMeta {
+    pub height: u64,
+    // Ingestion time from the topic clock.
+    pub timestamp: u64,
+    // None for markers and messages written while event times were disabled.
+    pub event_time: Option<u64>,
+    // None for markers and messages written while message ids were disabled.
+    pub id: Option<u128>,
+    // Bytes stored, including the envelope and after compression.
+    pub data_size: u64,
+    // Data blocks the payload occupies, empty for markers.
+    pub blocks: Range<u64>,
+}
+
+impl MessageMeta {
+    pub(crate) fn new(idx: &IndexBlock, event_time: Option<u64>, id: Option<u128>) -> Self {
+        MessageMeta {
+            height: idx.height,
+            timestamp: idx.timestamp,
+            event_time,
+            id,
+            data_size: idx.data_size,
+            blocks: idx.start_idx..idx.end_idx,
+        }
+    }
+
+    pub fn is_marker(&self) -> bool {
+        self.data_size == 0
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::error::FsError;
     use crate::index_block::IndexBlock;
+    use crate::IDX_BLOCK_SIZE;
 
-    #[test]
-    fn it_serializes_and_deserializes() {
-        let idx = IndexBlock {
+    fn idx() -> IndexBlock {
+        IndexBlock {
             height: 1,
             data_size: 100,
             start_idx: 200,
             end_idx: 300,
             timestamp: 123456789,
-        };
+            flags: IndexBlock::COMMITTED | IndexBlock::HAS_KEY,
+        }
+    }
 
-        let res = bincode::serialize(&idx).unwrap();
-        assert_eq!(res.len(), 40);
-        assert_eq!(idx, bincode::deserialize(&res).unwrap());
+    #[test]
+    fn it_serializes_and_deserializes() {
+        let idx = idx();
+        let res = idx.to_bytes();
+        assert_eq!(res.len() as u64, IDX_BLOCK_SIZE);
+        assert_eq!(&res[16..24], &200u64.to_le_bytes());
+        assert_eq!(&res[40..], &[17, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(idx, IndexBlock::from_bytes(&res).unwrap());
+        assert!(matches!(IndexBlock::from_bytes(&res[..32]), Err(FsError::Deserialize(_))));
+        assert!(idx.is_committed() && idx.has_key());
+        assert!(!idx.is_erased() && !idx.is_compressed() && !idx.is_inlined());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_reads_older_layouts() {
+        let idx = IndexBlock { flags: IndexBlock::COMMITTED, ..idx() };
+        let unflagged = bincode::serialize(&(1u64, 100u64, 200u64, 300u64, 123456789u64)).unwrap();
+        assert_eq!(IndexBlock::from_unflagged_bytes(&unflagged).unwrap(), idx);
+        assert_eq!(IndexBlock::from_entry(&unflagged).unwrap(), idx);
+        assert!(IndexBlock::from_unflagged_bytes(&unflagged[8..]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+#[cfg(feature = "jobs")]
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "jobs")]
+use crate::error::FsError;
+
+#[cfg(feature = "jobs")]
+pub const MAX_EXPORT_JOBS: usize = 8;
+
+#[cfg(feature = "jobs")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExportKind {
+    // Chunks are bincode encoded Vec<(IndexExportEntry, Vec<u8>)>, payloads as serialized, empty for markers.
+    Snapshot,
+    // Chunks are IndexExportEntry JSON objects, one per line.
+    IndexJson,
+    // Chains the topic's hash over every payload, digest = hash(digest | payload). Chunks are empty
+    // except the last, which holds the digest.
+    Digest,
+}
+
+// Progress of an export, kept in the meta zone between calls. It covers the messages committed when
+// it was started and expires `ttl` after the last chunk was taken.
+#[cfg(feature = "jobs")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportJob {
+    pub id: u64,
+    pub kind: ExportKind,
+    pub end: u64,
+    // Next height to export, everything below is in the chunks handed out so far.
+    pub next: u64,
+    // Sequence number of the next chunk.
+    pub chunks: u64,
+    // Payload bytes a chunk covers before it is cut, it always covers one message at least.
+    pub chunk_size: u64,
+    pub ttl: u64,
+    pub expires_at: u64,
+    pub digest: Vec<u8>,
+}
+
+#[cfg(feature = "jobs")]
+impl ExportJob {
+    pub fn is_done(&self) -> bool {
+        self.next == self.end
+    }
+}
+
+#[cfg(feature = "jobs")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportChunk {
+    pub job_id: u64,
+    pub sequence: u64,
+    pub bytes: Vec<u8>,
+    pub done: bool,
+}
+
+// An import of ExportKind::Snapshot chunks, kept in the meta zone between calls. Chunks are appended
+// past the committed height and only become visible when the import is committed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportJob {
+    pub id: u64,
+    // Committed height when the import started, the first imported message lands here.
+    pub start: u64,
+    pub(crate) start_data_block: u64,
+    // Height and data block the next staged message is appended at.
+    pub next: u64,
+    pub(crate) next_data_block: u64,
+    // Sequence number of the next chunk.
+    pub chunks: u64,
+    // Whether the chunk marked done has been staged.
+    pub received_all: bool,
+    // Checked against the chained digest of the staged payloads on commit, see ExportKind::Digest.
+    pub expected_digest: Option<Vec<u8>>,
+    pub(crate) digest: Vec<u8>,
+    pub ttl: u64,
+    pub expires_at: u64,
+}
+
+#[cfg(feature = "jobs")]
+impl ImportJob {
+    pub(crate) fn new(id: u64, height: u64, data_block: u64, expected_digest: Option<Vec<u8>>, ttl: u64, now: u64) -> Self {
+        ImportJob {
+            id,
+            start: height,
+            start_data_block: data_block,
+            next: height,
+            next_data_block: data_block,
+            chunks: 0,
+            received_all: false,
+            expected_digest,
+            digest: Vec::new(),
+            ttl,
+            expires_at: now.saturating_add(ttl),
+        }
+    }
+
+    pub fn staged(&self) -> u64 {
+        self.next - self.start
+    }
+}
+
+// One import runs at a time, it owns the end of the topic until it is committed or aborted.
+// Kept without the jobs feature, so builds without it still respect an import staged by one with it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Imports {
+    pub(crate) next_id: u64,
+    pub(crate) job: Option<ImportJob>,
+}
+
+#[cfg(feature = "jobs")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ExportJobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, ExportJob>,
+}
+
+#[cfg(feature = "jobs")]
+impl ExportJobs {
+    pub(crate) fn start(&mut self, kind: ExportKind, end: u64, chunk_size: u64, ttl: u64, now: u64) -> Result<u64, FsError> {
+        if chunk_size == 0 {
+            return Err(FsError::InvalidArgument("Export chunk size must be positive".to_string()));
+        }
+        if self.jobs.len() == MAX_EXPORT_JOBS {
+            return Err(FsError::OutOfSpace(format!("At most {} exports can run at once", MAX_EXPORT_JOBS)));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let expires_at = now.saturating_add(ttl);
+        self.jobs.insert(id, ExportJob { id, kind, end, next: 0, chunks: 0, chunk_size, ttl, expires_at, digest: Vec::new() });
+        Ok(id)
+    }
+
+    pub(crate) fn expire(&mut self, now: u64) {
+        self.jobs.retain(|_, job| job.expires_at > now);
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Result<&ExportJob, FsError> {
+        self.jobs.get(&id).ok_or_else(|| FsError::InvalidArgument(format!("Export job {} is unknown or expired", id)))
+    }
+
+    pub(crate) fn update(&mut self, job: ExportJob) {
+        self.jobs.insert(job.id, job);
+    }
+
+    pub(crate) fn remove(&mut self, id: u64) {
+        self.jobs.remove(&id);
+    }
+}
+
+#[cfg(all(test, feature = "jobs"))]
+mod test {
+    use crate::error::FsError;
+    use crate::jobs::{ExportJobs, ExportKind, MAX_EXPORT_JOBS};
+
+    #[test]
+    fn it_expires_and_limits_jobs() {
+        let mut jobs = ExportJobs::default();
+        let first = jobs.start(ExportKind::Digest, 10, 1024, 100, 0).unwrap();
+        for _ in 1..MAX_EXPORT_JOBS {
+            jobs.start(ExportKind::Snapshot, 10, 1024, 500, 0).unwrap();
+        }
+        assert!(matches!(jobs.start(ExportKind::Snapshot, 10, 1024, 500, 0), Err(FsError::OutOfSpace(_))));
+
+        jobs.expire(100);
+        assert!(jobs.get(first).is_err());
+        assert_eq!(jobs.start(ExportKind::IndexJson, 10, 1024, 500, 100), Ok(MAX_EXPORT_JOBS as u64));
+    }
+}
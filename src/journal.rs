@@ -0,0 +1,55 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: u64,
+    // What failed, e.g. "read", "write" or "check".
+    pub operation: String,
+    // The message the operation was on, if it was on one.
+    pub height: Option<u64>,
+    pub error: FsError,
+}
+
+// The last `capacity` errors, oldest first. Kept in the meta zone so they survive the call that failed,
+// unlike canister logs. Errors that trap are rolled back with everything else and never show up here.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ErrorJournal {
+    capacity: usize,
+    entries: VecDeque<JournalEntry>,
+}
+
+impl ErrorJournal {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ErrorJournal { capacity, entries: VecDeque::new() }
+    }
+
+    pub(crate) fn push(&mut self, entry: JournalEntry) {
+        self.entries.push_back(entry);
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &JournalEntry> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::FsError;
+    use crate::journal::{ErrorJournal, JournalEntry};
+
+    #[test]
+    fn it_keeps_the_latest_errors() {
+        let mut journal = ErrorJournal::new(2);
+        for timestamp in 0..3 {
+            journal.push(JournalEntry { timestamp, operation: "read".to_string(), height: None, error: FsError::InvalidState("x".to_string()) });
+        }
+        assert_eq!(journal.entries().map(|entry| entry.timestamp).collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
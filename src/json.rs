@@ -0,0 +1,62 @@
+use serde::Serialize;
+
+use crate::alarms::AlarmBreach;
+use crate::arena::ArenaStats;
+use crate::codec::BincodeCodec;
+use crate::error::FsError;
+use crate::events::EventFilesystemEvent;
+use crate::export::IndexExportEntry;
+use crate::heat_map::HeatMapSegment;
+#[cfg(feature = "jobs")]
+use crate::jobs::{ExportJob, ImportJob};
+use crate::layout::{LayoutConfig, LayoutDescriptor};
+use crate::journal::JournalEntry;
+use crate::metrics::InstructionHistogram;
+use crate::read_write::{AllocStats, BlockCacheStats};
+use crate::times::MessageTimes;
+use crate::topic_header_block::TopicHeaderBlock;
+
+// JSON view of the metadata types for admin endpoints and tools. Field names are the struct field
+// names, so the output only changes when the types do.
+pub trait ToJson: Serialize {
+    fn to_json(&self) -> Result<String, FsError> {
+        serde_json::to_string(self).map_err(|e| FsError::Serialize(e.to_string()))
+    }
+}
+
+impl ToJson for AlarmBreach {}
+impl ToJson for AllocStats {}
+impl ToJson for ArenaStats {}
+impl ToJson for BincodeCodec {}
+impl ToJson for BlockCacheStats {}
+impl ToJson for InstructionHistogram {}
+impl ToJson for JournalEntry {}
+impl ToJson for EventFilesystemEvent {}
+#[cfg(feature = "jobs")]
+impl ToJson for ExportJob {}
+impl ToJson for FsError {}
+impl ToJson for HeatMapSegment {}
+#[cfg(feature = "jobs")]
+impl ToJson for ImportJob {}
+impl ToJson for IndexExportEntry {}
+impl ToJson for LayoutConfig {}
+impl ToJson for LayoutDescriptor {}
+impl ToJson for MessageTimes {}
+impl ToJson for TopicHeaderBlock {}
+
+#[cfg(test)]
+mod test {
+    use crate::error::FsError;
+    use crate::events::{EventFilesystemEvent, IntegrityChecked};
+    use crate::export::IndexExportEntry;
+    use crate::json::ToJson;
+
+    #[test]
+    fn it_renders_metadata_as_json() {
+        let entry = IndexExportEntry { height: 3, timestamp: 7, data_size: 12, flags: 1, key_hash: Some(5) };
+        assert_eq!(entry.to_json().unwrap(), r#"{"height":3,"timestamp":7,"data_size":12,"flags":1,"key_hash":5}"#);
+
+        let event = EventFilesystemEvent::IntegrityChecked(IntegrityChecked { error: Some(FsError::OutOfSpace("index".to_string())), timestamp: 1 });
+        assert_eq!(event.to_json().unwrap(), r#"{"IntegrityChecked":{"error":{"OutOfSpace":"index"},"timestamp":1}}"#);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use sha2::{Digest, Sha256};
+
+use crate::constants::U64_SIZE;
+use crate::error::FsError;
+use crate::regions::StableRegion;
+use crate::storage::Storage;
+
+pub const KEY_INDEX_REGION: &str = "keys";
+// Key hash and height + 1, so a zeroed slot is free.
+pub const KEY_INDEX_SLOT_SIZE: u64 = 16;
+// Inserts fail above this share of used slots, probes get long in a fuller table.
+const MAX_LOAD_PERCENT: u64 = 90;
+
+// Heights by message key, as an open addressing hash table over the fixed slots of a stable region.
+// The region's length slot counts the slots in use. Keys are kept as 64 bit SHA-256 prefixes, a
+// collision would list another key's messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyIndex {
+    region: StableRegion,
+    used: u64,
+}
+
+impl KeyIndex {
+    // Clears the region, it may hold the tail of an earlier stable_store.
+    pub(crate) fn create(region: StableRegion, storage: &Storage) -> Self {
+        let zeroes = vec![0u8; 64 * 1024];
+        let mut offset = region.offset;
+        while offset < region.data_offset() + region.capacity {
+            let len = (region.data_offset() + region.capacity - offset).min(zeroes.len() as u64);
+            storage.write(offset, &zeroes[..len as usize]);
+            offset += len;
+        }
+        KeyIndex { region, used: 0 }
+    }
+
+    pub(crate) fn open(region: StableRegion, storage: &Storage) -> Self {
+        let mut used = [0u8; 8];
+        storage.read(region.offset, &mut used);
+        KeyIndex { region, used: u64::from_le_bytes(used) }
+    }
+
+    pub(crate) fn slots(&self) -> u64 {
+        self.region.capacity / KEY_INDEX_SLOT_SIZE
+    }
+
+    pub(crate) fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub(crate) fn check_room(&self) -> Result<(), FsError> {
+        if (self.used + 1) * 100 > self.slots() * MAX_LOAD_PERCENT {
+            return Err(FsError::OutOfSpace(format!("The key index holds {} of {} slots", self.used, self.slots())));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8], height: u64, storage: &Storage) -> Result<(), FsError> {
+        self.check_room()?;
+        let hash = key_hash(key);
+        let slot = self.probe(hash, storage).find(|(_, _, entry)| entry.is_none()).map(|(slot, _, _)| slot).expect("a free slot below the load limit");
+        let mut bytes = [0u8; KEY_INDEX_SLOT_SIZE as usize];
+        bytes[..8].copy_from_slice(&hash.to_le_bytes());
+        bytes[8..].copy_from_slice(&(height + 1).to_le_bytes());
+        storage.write(self.slot_offset(slot), &bytes);
+        self.used += 1;
+        storage.write(self.region.offset, &self.used.to_le_bytes());
+        Ok(())
+    }
+
+    // Ascending.
+    pub(crate) fn heights(&self, key: &[u8], storage: &Storage) -> Vec<u64> {
+        let hash = key_hash(key);
+        let mut heights: Vec<u64> = self.probe(hash, storage)
+            .map_while(|(_, slot_hash, height)| height.map(|height| (slot_hash, height)))
+            .filter(|(slot_hash, _)| *slot_hash == hash)
+            .map(|(_, height)| height)
+            .collect();
+        heights.sort_unstable();
+        heights
+    }
+
+    // Key hashes by height for the heights in `range`, from a scan of every slot.
+    pub(crate) fn hashes_in(&self, range: Range<u64>, storage: &Storage) -> BTreeMap<u64, u64> {
+        (0..self.slots())
+            .filter_map(|slot| {
+                let mut bytes = [0u8; KEY_INDEX_SLOT_SIZE as usize];
+                storage.read(self.slot_offset(slot), &mut bytes);
+                let height = u64::from_le_bytes(bytes[8..].try_into().unwrap()).checked_sub(1)?;
+                range.contains(&height).then(|| (height, u64::from_le_bytes(bytes[..8].try_into().unwrap())))
+            })
+            .collect()
+    }
+
+    // Slots from the key's home slot on, with their hash and height, None for a free one.
+    fn probe<'a>(&'a self, hash: u64, storage: &'a Storage) -> impl Iterator<Item = (u64, u64, Option<u64>)> + 'a {
+        let slots = self.slots();
+        (0..slots).map(move |i| {
+            let slot = (hash % slots + i) % slots;
+            let mut bytes = [0u8; KEY_INDEX_SLOT_SIZE as usize];
+            storage.read(self.slot_offset(slot), &mut bytes);
+            let slot_hash = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+            let height = u64::from_le_bytes(bytes[8..].try_into().unwrap()).checked_sub(1);
+            (slot, slot_hash, height)
+        })
+    }
+
+    fn slot_offset(&self, slot: u64) -> u64 {
+        self.region.data_offset() + slot * KEY_INDEX_SLOT_SIZE
+    }
+}
+
+pub(crate) fn key_hash(key: &[u8]) -> u64 {
+    u64::from_le_bytes(Sha256::digest(key)[..U64_SIZE as usize].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::key_index::{KEY_INDEX_SLOT_SIZE, KeyIndex, key_hash};
+    use crate::regions::StableRegion;
+    use crate::storage::{Storage, VecStorage};
+
+    #[test]
+    fn it_finds_every_height_of_a_key() {
+        let storage = Storage::new(VecStorage { bytes: vec![0xff; 256] });
+        let region = StableRegion { offset: 8, capacity: 10 * KEY_INDEX_SLOT_SIZE };
+        let mut index = KeyIndex::create(region, &storage);
+        for (height, key) in [b"a", b"b", b"a", b"c", b"a"].iter().enumerate() {
+            index.insert(*key, height as u64, &storage).unwrap();
+        }
+        assert_eq!(index.heights(b"a", &storage), vec![0, 2, 4]);
+        assert_eq!(index.heights(b"c", &storage), vec![3]);
+        assert!(index.heights(b"d", &storage).is_empty());
+        assert_eq!(index.hashes_in(1..4, &storage).into_iter().collect::<Vec<_>>(), vec![(1, key_hash(b"b")), (2, key_hash(b"a")), (3, key_hash(b"c"))]);
+
+        let mut index = KeyIndex::open(region, &storage);
+        assert_eq!(index.used(), 5);
+        for height in 5..9 {
+            index.insert(b"d", height, &storage).unwrap();
+        }
+        assert!(index.insert(b"d", 9, &storage).is_err());
+        assert_eq!(index.heights(b"d", &storage), vec![5, 6, 7, 8]);
+    }
+}
@@ -0,0 +1,449 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use crate::budget::InstructionBudget;
+use crate::error::FsError;
+use crate::{BlockStorage, EventFilesystem, FnStorage, StableEncode, Writable};
+
+// A value of `None` records a delete.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KvEvent<K, V> {
+    pub key: K,
+    pub value: Option<V>,
+}
+
+impl<K: StableEncode, V: StableEncode> StableEncode for KvEvent<K, V> {}
+
+// Live keys and the height of their latest put, as of `height`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KvSnapshot<K: Ord> {
+    pub height: u64,
+    pub live: BTreeMap<K, u64>,
+}
+
+// Progress of an interrupted `state_at` replay, pass it back to `resume_state_at`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplayCursor<K: Ord> {
+    pub target_height: u64,
+    pub next_height: u64,
+    live: BTreeMap<K, u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StateAt<K: Ord> {
+    Complete(KvSnapshot<K>),
+    Partial(ReplayCursor<K>),
+}
+
+// Snapshots are taken after `every_events` events or `every_bytes` serialized event bytes, whichever
+// comes first. The `keep_recent` newest are kept, older ones are thinned so the spacing of kept ones
+// doubles with age, which keeps about `keep_recent` plus log2 of all checkpoints taken. The oldest are
+// dropped when they no longer fit the `capacity` byte region.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckpointPolicy {
+    pub every_events: u64,
+    pub every_bytes: u64,
+    pub keep_recent: usize,
+    pub capacity: u64,
+}
+
+pub const CHECKPOINT_REGION: &str = "kv.checkpoints";
+
+struct Checkpoints<K: Ord> {
+    policy: CheckpointPolicy,
+    // Oldest first, each with its sequence number among all checkpoints taken.
+    snapshots: Vec<(u64, KvSnapshot<K>)>,
+    events: u64,
+    bytes: u64,
+}
+
+impl<K: Ord + Clone + Serialize> Checkpoints<K> {
+    fn is_due(&self) -> bool {
+        self.events >= self.policy.every_events || self.bytes >= self.policy.every_bytes
+    }
+
+    fn latest_at(&self, height: u64) -> Option<&KvSnapshot<K>> {
+        self.snapshots.iter().rev().map(|(_, snapshot)| snapshot).find(|snapshot| snapshot.height <= height)
+    }
+
+    fn push(&mut self, snapshot: KvSnapshot<K>) -> Result<(), FsError> {
+        let sequence = self.snapshots.last().map_or(0, |(sequence, _)| sequence + 1);
+        self.snapshots.push((sequence, snapshot));
+        self.thin();
+        while bincode::serialized_size(&self.snapshots).map_err(|e| FsError::Serialize(e.to_string()))? > self.policy.capacity {
+            if self.snapshots.len() == 1 {
+                return Err(FsError::OutOfSpace(format!("A checkpoint doesn't fit the {} byte checkpoint region", self.policy.capacity)));
+            }
+            self.snapshots.remove(0);
+        }
+        self.events = 0;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    // A checkpoint `age` checkpoints old is kept when its sequence number is a multiple of a power of two
+    // that grows with its age. The spacing only ever grows, so a dropped checkpoint is never needed again.
+    fn thin(&mut self) {
+        let newest = self.snapshots.last().map_or(0, |(sequence, _)| *sequence);
+        let recent = self.policy.keep_recent.max(1) as u64;
+        self.snapshots.retain(|(sequence, _)| {
+            let age = newest - sequence;
+            age < recent || sequence % (2u64 << (age / recent).ilog2()) == 0
+        });
+    }
+}
+
+// Key-value store layered over a topic. Every put/delete is appended as a `KvEvent`, the heap
+// only keeps key -> height of the latest put, which is rebuilt from the log in `new`.
+pub struct KvOnLog<K: Ord, V, S: BlockStorage = FnStorage> {
+    fs: EventFilesystem<S>,
+    index: RefCell<BTreeMap<K, u64>>,
+    checkpoints: RefCell<Option<Checkpoints<K>>>,
+    _value: PhantomData<V>,
+}
+
+impl<K, V, S: BlockStorage> KvOnLog<K, V, S>
+    where K: Serialize + DeserializeOwned + Ord + Clone,
+          V: Serialize + DeserializeOwned,
+          KvEvent<K, V>: Writable {
+    pub fn new(fs: EventFilesystem<S>) -> Result<Self, FsError> {
+        let kv = KvOnLog {
+            fs,
+            index: RefCell::new(BTreeMap::new()),
+            checkpoints: RefCell::new(None),
+            _value: PhantomData,
+        };
+        kv.rebuild_index()?;
+        Ok(kv)
+    }
+
+    // Checkpoints the index in the CHECKPOINT_REGION region of the topic as events are appended, so
+    // rebuilding the index and state_at only replay from the closest checkpoint. The region is created
+    // with the policy's capacity on first use, later opens keep its capacity.
+    pub fn with_checkpoints(fs: EventFilesystem<S>, policy: CheckpointPolicy) -> Result<Self, FsError> {
+        if policy.every_events == 0 || policy.every_bytes == 0 {
+            return Err(FsError::InvalidArgument(format!("Checkpoint spacing must not be zero, got {:?}", policy)));
+        }
+        let snapshots = match fs.regions().iter().find(|(name, _)| name == CHECKPOINT_REGION) {
+            Some((_, region)) if region.capacity < policy.capacity => {
+                return Err(FsError::InvalidArgument(format!("Checkpoint region holds {} bytes, not {}", region.capacity, policy.capacity)));
+            }
+            Some(_) => fs.stable_restore_in(CHECKPOINT_REGION)?,
+            None => {
+                fs.create_region(CHECKPOINT_REGION, policy.capacity)?;
+                Vec::new()
+            }
+        };
+        let kv = KvOnLog {
+            fs,
+            index: RefCell::new(BTreeMap::new()),
+            checkpoints: RefCell::new(Some(Checkpoints { policy, snapshots, events: 0, bytes: 0 })),
+            _value: PhantomData,
+        };
+        kv.rebuild_index()?;
+        Ok(kv)
+    }
+
+    pub fn checkpoint_heights(&self) -> Vec<u64> {
+        self.checkpoints.borrow().as_ref().map_or_else(Vec::new, |checkpoints| checkpoints.snapshots.iter().map(|(_, snapshot)| snapshot.height).collect())
+    }
+
+    pub fn rebuild_index(&self) -> Result<(), FsError> {
+        let height = self.fs.get_topic_height();
+        let (start, mut index) = match self.checkpoints.borrow().as_ref().and_then(|checkpoints| checkpoints.latest_at(height)) {
+            Some(snapshot) => (snapshot.height, snapshot.live.clone()),
+            None => (0, BTreeMap::new()),
+        };
+        if let Some(checkpoints) = self.checkpoints.borrow_mut().as_mut() {
+            checkpoints.events = height - start;
+        }
+        for h in start..height {
+            let event: KvEvent<K, V> = self.fs.read_topic_message(h)?;
+            match event.value {
+                Some(_) => index.insert(event.key, h),
+                None => index.remove(&event.key),
+            };
+        }
+        debug!("KvOnLog rebuilt index with {} keys from {} events", index.len(), height);
+        *self.index.borrow_mut() = index;
+        Ok(())
+    }
+
+    pub fn put(&self, key: K, value: V) -> Result<u64, FsError> {
+        let event = KvEvent { key, value: Some(value) };
+        let height = self.fs.write_topic_message(&event)?;
+        self.index.borrow_mut().insert(event.key.clone(), height);
+        self.record_event(&event)?;
+        Ok(height)
+    }
+
+    // Deleting a missing key is a no-op and appends nothing.
+    pub fn delete(&self, key: &K) -> Result<Option<u64>, FsError> {
+        if !self.contains_key(key) {
+            return Ok(None);
+        }
+        let event: KvEvent<K, V> = KvEvent { key: key.clone(), value: None };
+        let height = self.fs.write_topic_message(&event)?;
+        self.index.borrow_mut().remove(key);
+        self.record_event(&event)?;
+        Ok(Some(height))
+    }
+
+    fn record_event(&self, event: &KvEvent<K, V>) -> Result<(), FsError> {
+        let mut checkpoints = self.checkpoints.borrow_mut();
+        let checkpoints = match checkpoints.as_mut() {
+            Some(checkpoints) => checkpoints,
+            None => return Ok(()),
+        };
+        checkpoints.events += 1;
+        checkpoints.bytes += bincode::serialized_size(event).map_err(|e| FsError::Serialize(e.to_string()))?;
+        if checkpoints.is_due() {
+            checkpoints.push(KvSnapshot { height: self.fs.get_topic_height(), live: self.index.borrow().clone() })?;
+            self.fs.stable_store_in(CHECKPOINT_REGION, &checkpoints.snapshots)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, FsError> {
+        let height = match self.index.borrow().get(key) {
+            Some(height) => *height,
+            None => return Ok(None),
+        };
+        let event: KvEvent<K, V> = self.fs.read_topic_message(height)?;
+        Ok(event.value)
+    }
+
+    // Value of `key` once the first `height` events were applied. Walks the log backwards from `height`.
+    pub fn get_at(&self, key: &K, height: u64) -> Result<Option<V>, FsError> {
+        let height = height.min(self.fs.get_topic_height());
+        for h in (0..height).rev() {
+            let event: KvEvent<K, V> = self.fs.read_topic_message(h)?;
+            if &event.key == key {
+                return Ok(event.value);
+            }
+        }
+        Ok(None)
+    }
+
+    // Replays the log up to `height`, stopping with a cursor once the budget runs out.
+    // At least one event is applied per call so repeated resumes always finish.
+    pub fn state_at(&self, height: u64, budget: &InstructionBudget) -> Result<StateAt<K>, FsError> {
+        let target_height = height.min(self.fs.get_topic_height());
+        let cursor = match self.checkpoints.borrow().as_ref().and_then(|checkpoints| checkpoints.latest_at(target_height)) {
+            Some(snapshot) => ReplayCursor { target_height, next_height: snapshot.height, live: snapshot.live.clone() },
+            None => ReplayCursor { target_height, next_height: 0, live: BTreeMap::new() },
+        };
+        self.resume_state_at(cursor, budget)
+    }
+
+    pub fn resume_state_at(&self, mut cursor: ReplayCursor<K>, budget: &InstructionBudget) -> Result<StateAt<K>, FsError> {
+        while cursor.next_height < cursor.target_height {
+            let event: KvEvent<K, V> = self.fs.read_topic_message(cursor.next_height)?;
+            match event.value {
+                Some(_) => cursor.live.insert(event.key, cursor.next_height),
+                None => cursor.live.remove(&event.key),
+            };
+            cursor.next_height += 1;
+
+            if cursor.next_height < cursor.target_height && budget.is_exhausted() {
+                debug!("state_at paused at {} of {}", cursor.next_height, cursor.target_height);
+                return Ok(StateAt::Partial(cursor));
+            }
+        }
+        Ok(StateAt::Complete(KvSnapshot {
+            height: cursor.target_height,
+            live: cursor.live,
+        }))
+    }
+
+    pub fn get_in(&self, snapshot: &KvSnapshot<K>, key: &K) -> Result<Option<V>, FsError> {
+        match snapshot.live.get(key) {
+            Some(height) => {
+                let event: KvEvent<K, V> = self.fs.read_topic_message(*height)?;
+                Ok(event.value)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.borrow().contains_key(key)
+    }
+
+    pub fn keys(&self) -> Vec<K> {
+        self.index.borrow().keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.borrow().is_empty()
+    }
+
+    pub fn height(&self) -> u64 {
+        self.fs.get_topic_height()
+    }
+
+    pub fn filesystem(&self) -> &EventFilesystem<S> {
+        &self.fs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+
+    use crate::{BlockRead, BlockWrite, EventFilesystem, IDX_ZONE_END};
+    use crate::budget::InstructionBudget;
+    use crate::kv_on_log::{CheckpointPolicy, KvOnLog, StateAt};
+
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; IDX_ZONE_END as usize + 1024 * 1024]);
+        static COUNTER: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn tick() -> u64 {
+        COUNTER.with(|c| {
+            c.set(c.get() + 1);
+            c.get()
+        })
+    }
+
+    fn get_write() -> BlockWrite {
+        |offset, bytes| {
+            MEMORY.with(|mem| {
+                let offset = offset as usize;
+                mem.borrow_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+            });
+        }
+    }
+
+    fn get_read() -> BlockRead {
+        |offset, bytes| {
+            MEMORY.with(|mem| {
+                let offset = offset as usize;
+                bytes.copy_from_slice(&mem.borrow()[offset..offset + bytes.len()]);
+            });
+        }
+    }
+
+    fn get_kv() -> KvOnLog<String, u64> {
+        let fs = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "kv".to_string());
+        KvOnLog::new(fs).unwrap()
+    }
+
+    #[test]
+    fn it_puts_gets_and_deletes() {
+        let kv = get_kv();
+        kv.put("a".to_string(), 1).unwrap();
+        kv.put("b".to_string(), 2).unwrap();
+        kv.put("a".to_string(), 3).unwrap();
+
+        assert_eq!(kv.get(&"a".to_string()).unwrap(), Some(3));
+        assert_eq!(kv.get(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(kv.len(), 2);
+
+        assert_eq!(kv.delete(&"b".to_string()).unwrap(), Some(3));
+        assert_eq!(kv.delete(&"b".to_string()).unwrap(), None);
+        assert_eq!(kv.get(&"b".to_string()).unwrap(), None);
+        assert_eq!(kv.keys(), vec!["a".to_string()]);
+        assert_eq!(kv.height(), 4);
+    }
+
+    #[test]
+    fn it_tracks_heights_of_multi_block_values() {
+        let fs = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "kv".to_string());
+        let kv: KvOnLog<String, Vec<u8>> = KvOnLog::new(fs).unwrap();
+        kv.put("a".to_string(), vec![1u8; 2000]).unwrap();
+        assert_eq!(kv.put("b".to_string(), vec![2u8; 10]).unwrap(), 1);
+        assert_eq!(kv.get(&"b".to_string()).unwrap(), Some(vec![2u8; 10]));
+    }
+
+    #[test]
+    fn it_rebuilds_index_on_init() {
+        let kv = get_kv();
+        kv.put("a".to_string(), 1).unwrap();
+        kv.put("b".to_string(), 2).unwrap();
+        kv.delete(&"a".to_string()).unwrap();
+
+        let kv = get_kv();
+        assert_eq!(kv.keys(), vec!["b".to_string()]);
+        assert_eq!(kv.get(&"b".to_string()).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn it_reads_values_at_past_heights() {
+        let kv = get_kv();
+        kv.put("a".to_string(), 1).unwrap();
+        kv.put("a".to_string(), 2).unwrap();
+        kv.delete(&"a".to_string()).unwrap();
+
+        let key = "a".to_string();
+        assert_eq!(kv.get_at(&key, 0).unwrap(), None);
+        assert_eq!(kv.get_at(&key, 1).unwrap(), Some(1));
+        assert_eq!(kv.get_at(&key, 2).unwrap(), Some(2));
+        assert_eq!(kv.get_at(&key, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn it_replays_state_at_height_across_budgeted_calls() {
+        let kv = get_kv();
+        for i in 0..10 {
+            kv.put(format!("k{}", i % 3), i).unwrap();
+        }
+        kv.delete(&"k0".to_string()).unwrap();
+
+        let mut state = kv.state_at(9, &InstructionBudget::new(tick, 3)).unwrap();
+        let mut calls = 1;
+        let snapshot = loop {
+            match state {
+                StateAt::Complete(snapshot) => break snapshot,
+                StateAt::Partial(cursor) => {
+                    calls += 1;
+                    state = kv.resume_state_at(cursor, &InstructionBudget::new(tick, 3)).unwrap();
+                }
+            }
+        };
+        assert!(calls > 1);
+        assert_eq!(snapshot.height, 9);
+        assert_eq!(kv.get_in(&snapshot, &"k0".to_string()).unwrap(), Some(6));
+        assert_eq!(kv.get_in(&snapshot, &"k2".to_string()).unwrap(), Some(8));
+
+        let latest = match kv.state_at(100, &InstructionBudget::unlimited()).unwrap() {
+            StateAt::Complete(snapshot) => snapshot,
+            StateAt::Partial(_) => panic!("unlimited budget should complete"),
+        };
+        assert_eq!(latest.height, 11);
+        assert_eq!(kv.get_in(&latest, &"k0".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn it_thins_checkpoints_exponentially() {
+        let policy = CheckpointPolicy { every_events: 2, every_bytes: u64::MAX, keep_recent: 2, capacity: 64 * 1024 };
+        let fs = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "kv".to_string());
+        let kv: KvOnLog<String, u64> = KvOnLog::with_checkpoints(fs, policy).unwrap();
+        for i in 0..32 {
+            kv.put(format!("k{}", i % 5), i).unwrap();
+        }
+        kv.delete(&"k1".to_string()).unwrap();
+        assert_eq!(kv.checkpoint_heights(), vec![2, 18, 26, 30, 32]);
+
+        let fs = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "kv".to_string());
+        let kv: KvOnLog<String, u64> = KvOnLog::with_checkpoints(fs, policy).unwrap();
+        assert_eq!(kv.checkpoint_heights(), vec![2, 18, 26, 30, 32]);
+        assert_eq!(kv.keys(), vec!["k0", "k2", "k3", "k4"]);
+        assert_eq!(kv.get(&"k4".to_string()).unwrap(), Some(29));
+
+        let snapshot = match kv.state_at(27, &InstructionBudget::new(tick, 1)).unwrap() {
+            StateAt::Complete(snapshot) => snapshot,
+            StateAt::Partial(_) => panic!("replay from the checkpoint at 26 should fit"),
+        };
+        assert_eq!(kv.get_in(&snapshot, &"k1".to_string()).unwrap(), Some(26));
+    }
+}
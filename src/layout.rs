@@ -0,0 +1,233 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::canary::canaries;
+use crate::constants::*;
+use crate::error::FsError;
+
+const MIN_META_ZONE_SIZE: u64 = 64 * 1024;
+const MIN_BLOCK_SIZE: u64 = 64;
+const MAX_BLOCK_SIZE: u64 = 64 * 1024;
+
+// Zone sizes picked when a topic is created and recorded in its header. The slots in front of the
+// free memory block are fixed, everything after it moves with these sizes. Payloads take whole data
+// blocks, small blocks waste less on small messages and large ones keep the block cache effective
+// for large messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutConfig {
+    pub free_memory_block_size: u64,
+    pub meta_zone_size: u64,
+    pub index_zone_size: u64,
+    pub block_size: u64,
+    // Follows the topic's format rather than its header, see format::index_entry_size.
+    #[serde(skip, default = "default_index_entry_size")]
+    pub(crate) index_entry_size: u64,
+}
+
+fn default_index_entry_size() -> u64 {
+    IDX_BLOCK_SIZE
+}
+
+// LayoutConfig as headers recorded it before the block size was configurable.
+#[derive(Deserialize)]
+pub(crate) struct LegacyLayoutConfig {
+    free_memory_block_size: u64,
+    meta_zone_size: u64,
+    index_zone_size: u64,
+}
+
+impl From<LegacyLayoutConfig> for LayoutConfig {
+    fn from(legacy: LegacyLayoutConfig) -> Self {
+        LayoutConfig {
+            free_memory_block_size: legacy.free_memory_block_size,
+            meta_zone_size: legacy.meta_zone_size,
+            index_zone_size: legacy.index_zone_size,
+            block_size: BLOCK_SIZE,
+            index_entry_size: IDX_BLOCK_SIZE,
+        }
+    }
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        LayoutConfig {
+            free_memory_block_size: FREE_MEMORY_BLOCK_SIZE,
+            meta_zone_size: META_ZONE_SIZE,
+            index_zone_size: IDX_ZONE_END - IDX_ZONE_IDX,
+            block_size: BLOCK_SIZE,
+            index_entry_size: IDX_BLOCK_SIZE,
+        }
+    }
+}
+
+impl LayoutConfig {
+    // Sizes the index zone to hold `max_messages` entries.
+    pub fn with_max_messages(self, max_messages: u64) -> Self {
+        LayoutConfig { index_zone_size: max_messages.saturating_mul(self.index_entry_size).saturating_add(CANARY_SIZE), ..self }
+    }
+
+    pub fn validate(&self) -> Result<(), FsError> {
+        let invalid = |reason: &str| Err(FsError::InvalidArgument(format!("Layout {:?}: {}", self, reason)));
+        if [self.free_memory_block_size, self.meta_zone_size, self.index_zone_size].iter().any(|size| size % U64_SIZE != 0) {
+            return invalid("zone sizes must be multiples of 8");
+        }
+        if self.meta_zone_size < MIN_META_ZONE_SIZE {
+            return invalid("meta zone is too small");
+        }
+        if self.free_memory_block_size < self.meta_zone_size + CANARY_SIZE {
+            return invalid("free memory block can't hold the meta zone");
+        }
+        if self.index_zone_size < self.index_entry_size + CANARY_SIZE {
+            return invalid("index zone can't hold a single entry");
+        }
+        if !self.block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return invalid("block size must be a power of two from 64 bytes to 64 KiB");
+        }
+        if FREE_MEMORY_BLOCK_START_IDX.checked_add(self.free_memory_block_size).and_then(|end| end.checked_add(self.index_zone_size)).is_none() {
+            return invalid("zones overflow the address space");
+        }
+        Ok(())
+    }
+
+    pub fn descriptor(&self) -> LayoutDescriptor {
+        LayoutDescriptor {
+            block_size: self.block_size,
+            index_entry_size: self.index_entry_size,
+            magic_number_idx: MAGIC_NUMBER_IDX,
+            topic_block_size_idx: TOPIC_BLOCK_SIZE_IDX,
+            data_block_height_idx: DATA_BLOCK_HEIGHT_IDX,
+            index_height_idx: INDEX_HEIGHT_IDX,
+            topic_header_zone: TOPIC_BLOCK_DATA_START_IDX..FREE_MEMORY_BLOCK_SIZE_IDX,
+            stable_store_size_idx: FREE_MEMORY_BLOCK_SIZE_IDX,
+            stable_store_zone: FREE_MEMORY_BLOCK_START_IDX..self.meta_zone_idx(),
+            meta_zone: self.meta_zone_idx()..self.idx_zone_idx(),
+            index_zone: self.idx_zone_idx()..self.idx_zone_end(),
+            data_zone_start: self.idx_zone_end(),
+            canaries: canaries(self).iter().map(|(offset, _)| *offset).collect(),
+            max_index_entries: self.max_index_entries(),
+        }
+    }
+
+    pub(crate) fn stable_store_max_size(&self) -> u64 {
+        self.free_memory_block_size - self.meta_zone_size - CANARY_SIZE
+    }
+
+    pub(crate) fn meta_zone_idx(&self) -> u64 {
+        FREE_MEMORY_BLOCK_START_IDX + self.free_memory_block_size - self.meta_zone_size
+    }
+
+    pub(crate) fn idx_zone_idx(&self) -> u64 {
+        FREE_MEMORY_BLOCK_START_IDX + self.free_memory_block_size
+    }
+
+    pub(crate) fn idx_zone_end(&self) -> u64 {
+        self.idx_zone_idx() + self.index_zone_size
+    }
+
+    pub(crate) fn max_index_entries(&self) -> u64 {
+        (self.index_zone_size - CANARY_SIZE) / self.index_entry_size
+    }
+
+    pub(crate) fn index_entry_offset(&self, height: u64) -> u64 {
+        self.idx_zone_idx() + height * self.index_entry_size
+    }
+
+    pub(crate) fn index_entry_size(&self) -> u64 {
+        self.index_entry_size
+    }
+
+    pub(crate) fn data_block_offset(&self, block: u64) -> u64 {
+        self.idx_zone_end() + block * self.block_size
+    }
+
+    // Data blocks a payload of `data_size` bytes takes.
+    pub(crate) fn block_count(&self, data_size: u64) -> u64 {
+        data_size.div_ceil(self.block_size)
+    }
+}
+
+// Where everything lives in stable memory. Zones are half open byte ranges, the data zone is
+// unbounded and only its start is fixed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutDescriptor {
+    pub block_size: u64,
+    pub index_entry_size: u64,
+    pub magic_number_idx: u64,
+    pub topic_block_size_idx: u64,
+    pub data_block_height_idx: u64,
+    pub index_height_idx: u64,
+    pub topic_header_zone: Range<u64>,
+    pub stable_store_size_idx: u64,
+    pub stable_store_zone: Range<u64>,
+    pub meta_zone: Range<u64>,
+    pub index_zone: Range<u64>,
+    pub data_zone_start: u64,
+    pub canaries: Vec<u64>,
+    pub max_index_entries: u64,
+}
+
+impl LayoutDescriptor {
+    pub fn index_entry_offset(&self, height: u64) -> u64 {
+        self.index_zone.start + height * self.index_entry_size
+    }
+
+    pub fn data_block_offset(&self, block: u64) -> u64 {
+        self.data_zone_start + block * self.block_size
+    }
+}
+
+// The default layout, topics created with a custom LayoutConfig report theirs through EventFilesystem::layout.
+pub fn layout() -> LayoutDescriptor {
+    LayoutConfig::default().descriptor()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::constants::*;
+    use crate::fixtures::small_layout;
+    use crate::layout::{layout, LayoutConfig};
+
+    #[test]
+    fn it_describes_contiguous_zones() {
+        let layout = layout();
+        assert_eq!(layout.topic_header_zone.end, layout.stable_store_size_idx);
+        assert_eq!(layout.stable_store_zone.end, layout.meta_zone.start);
+        assert_eq!(layout.meta_zone.end, layout.index_zone.start);
+        assert_eq!(layout.index_zone.end, layout.data_zone_start);
+
+        for zone in [&layout.topic_header_zone, &layout.stable_store_zone, &layout.meta_zone, &layout.index_zone] {
+            assert!(layout.canaries.contains(&(zone.end - 8)));
+        }
+        assert!(layout.index_entry_offset(layout.max_index_entries) <= layout.canaries[3]);
+        assert_eq!(layout.data_block_offset(2), layout.data_zone_start + 1024);
+    }
+
+    #[test]
+    fn it_matches_default_constants() {
+        let config = LayoutConfig::default();
+        assert_eq!(config.meta_zone_idx(), IDX_ZONE_IDX - META_ZONE_SIZE);
+        assert_eq!(config.idx_zone_idx(), IDX_ZONE_IDX);
+        assert_eq!(config.idx_zone_end(), IDX_ZONE_END);
+        assert_eq!(config.max_index_entries(), 11_184_810);
+        assert_eq!(config.stable_store_max_size(), FREE_MEMORY_BLOCK_SIZE - META_ZONE_SIZE - CANARY_SIZE);
+    }
+
+    #[test]
+    fn it_validates_zone_sizes() {
+        LayoutConfig::default().validate().unwrap();
+        let small = small_layout();
+        small.validate().unwrap();
+        assert_eq!(small.descriptor().data_zone_start, FREE_MEMORY_BLOCK_START_IDX + 1024 * 1024 + 4096);
+
+        assert!(LayoutConfig { meta_zone_size: 1024, ..small }.validate().is_err());
+        assert!(LayoutConfig { free_memory_block_size: 64 * 1024, ..small }.validate().is_err());
+        assert!(LayoutConfig { index_zone_size: 44, ..small }.validate().is_err());
+        assert!(LayoutConfig { index_zone_size: 4100, ..small }.validate().is_err());
+        assert!(LayoutConfig { index_zone_size: u64::MAX - 7, ..small }.validate().is_err());
+        assert!(LayoutConfig { block_size: 4096, ..small }.validate().is_ok());
+        assert!(LayoutConfig { block_size: 32, ..small }.validate().is_err());
+        assert!(LayoutConfig { block_size: 1000, ..small }.validate().is_err());
+        assert_eq!(small.with_max_messages(100).max_index_entries(), 100);
+    }
+}
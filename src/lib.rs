@@ -1,290 +1,5688 @@
-use std::cell::RefCell;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::rc::Rc;
 
-use ic_cdk::api::stable::StableWriter;
-use log::{debug};
-use serde::{Deserialize, Serialize};
+use candid::Principal;
+use serde::Serialize;
 use serde::de::DeserializeOwned;
+#[cfg(feature = "aggregates")]
+use serde_json::Value;
 
+use crate::access_control::AccessControl;
+#[cfg(feature = "aggregates")]
+use crate::aggregates::Aggregates;
+use crate::aliases::AliasTable;
+use crate::alarms::Alarms;
 use crate::constants::*;
-use crate::constants::*;
-use crate::index_block::IndexBlock;
-use crate::read_write::{BlockRead, BlockWrite, MemoryReader, MemoryWriter};
-use crate::topic_header_block::{TOPIC_HEADER_MAGIC, TopicHeaderBlock};
+use crate::dedup::Deduplication;
+use crate::heat_map::HeatMap;
+use crate::key_index::{KEY_INDEX_REGION, KEY_INDEX_SLOT_SIZE, KeyIndex};
+#[cfg(feature = "jobs")]
+use crate::jobs::ExportJobs;
+use crate::jobs::Imports;
+use crate::journal::ErrorJournal;
+use crate::arena::ARENA_HEADER_SIZE;
+use crate::meta::MetaStore;
+use crate::metrics::Counters;
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsText;
+use crate::read_write::{MemoryReader, MemoryWriter, write_idx};
+use crate::regions::RegionRegistry;
+use crate::reports::StatsReports;
+use crate::segments::SegmentManifests;
+use crate::compaction_policy::CompactionPlan;
+use crate::settings::SettingsHistory;
+use crate::subscribers::{SUBSCRIBER_REGION_SIZE, SubscriberRegistry};
+use crate::times::TimeCorrections;
+use crate::topic_header_block::{TOPIC_HEADER_MAGIC, TOPIC_INITIALIZING_MAGIC};
+#[cfg(feature = "access-log")]
+pub use crate::access_log::{AccessLog, AccessLogConfig, AccessRecord};
+#[cfg(feature = "aggregates")]
+pub use crate::aggregates::FoldFn;
+pub use crate::alarms::{ALARM_WINDOW_NANOS, AlarmBreach, AlarmCallback, AlarmKind};
+#[cfg(feature = "assets")]
+pub use crate::assets::{AssetExport, AssetFile, AssetLine, AssetManifest, AssetManifestFile, MAX_ASSET_CHUNK_SIZE};
+pub use crate::budget::InstructionBudget;
+pub use crate::builder::EventFilesystemBuilder;
+pub use crate::codec::{BincodeCodec, Codec, DEFAULT_SIZE_LIMIT, IntEncoding};
+#[cfg(feature = "candid")]
+pub use crate::codec::CandidCodec;
+pub use crate::compaction_policy::{CompactionPolicy, SegmentAction, TopicStats};
+pub use crate::consumer::{ConsumerCursor, MAX_BOOKMARK_NAME_SIZE, MAX_BOOKMARKS};
+#[cfg(feature = "stream")]
+pub use crate::cursor::Cursor;
+pub use crate::dedup::{DeduplicationConfig, DuplicateMode};
+pub use crate::error::FsError;
+pub use crate::compression::{CompressionDictionary, DEFAULT_COMPRESSION_LEVEL, PayloadCompression};
+#[cfg(feature = "testing")]
+pub use crate::evolution::{EvolutionCheck, EvolutionReport, load_fixtures};
+pub use crate::events::{CompactionCompleted, ControllerAdded, ControllerRemoved, DuplicateWritten, EventFilesystemEvent, IntegrityChecked, SubscriberAdded, SubscriberOffsetModified, SubscriberRemoved, TopicCreated, TopicOpened, VersionMigrated};
+pub use crate::export::IndexExportEntry;
+#[cfg(feature = "kv")]
+pub use crate::kv_on_log::{CHECKPOINT_REGION, CheckpointPolicy, KvEvent, KvOnLog, KvSnapshot, ReplayCursor, StateAt};
+pub use crate::layout::{layout, LayoutConfig, LayoutDescriptor};
+#[cfg(feature = "manager")]
+pub use crate::manager::{MAX_TOPICS, PartitionStorage, TopicManager, TopicPartition};
+#[cfg(feature = "merge")]
+pub use crate::merge::{merge_topics, MergedMessage, MergeOrder};
+pub use crate::metrics::{InstructionHistogram, Operation};
+pub use crate::migration::{Compaction, IndexGrowth, TopicSplit};
+pub use crate::pressure::{Pressure, PressureCause, PressureLevel, Watermarks};
+#[cfg(feature = "query")]
+pub use crate::query::{Query, QueryOrder, QueryPlan};
+pub use crate::read_write::{AllocStats, BlockCacheStats, BlockGrow, BlockRead, BlockWrite, MAX_PAGE_BYTES, MessageReader, MessageWriter, Page, PartialRange};
+pub use crate::regions::StableRegion;
+#[cfg(feature = "replication")]
+pub use crate::replication::{Follower, PayloadTransform, REPLICATED_MARKER, TransformFn};
+pub use crate::recovery::{HeightPolicy, HeightReport};
+pub use crate::reports::{MAX_OUTBOX_SUMMARIES, StatsReportConfig, StatsSummary};
+pub use crate::retention::{ConsumerRetention, RetentionPolicy, Truncation};
+pub use crate::reverse::ReverseMessages;
+#[cfg(feature = "schema")]
+pub use crate::schema::MessageSchema;
+pub use crate::segments::SegmentManifest;
+pub use crate::stable_encode::{StableEncode, Writable};
+pub use crate::storage::{BlockStorage, FileStorage, FnStorage, Storage, VecStorage};
+#[cfg(feature = "ic")]
+pub use crate::storage::StableMemoryStorage;
+#[cfg(all(unix, feature = "mmap"))]
+pub use crate::storage::MmapStorage;
+pub use crate::telemetry::{Telemetry, UsageCounters};
+#[cfg(feature = "snapshot")]
+pub use crate::snapshot::{SNAPSHOT_FORMAT_VERSION, SNAPSHOT_MAGIC, SnapshotManifest, SnapshotSection, SnapshotSectionKind};
+#[cfg(feature = "stream")]
+pub use crate::stream::{MAX_STREAM_BATCH, MAX_STREAM_BATCH_BYTES, StreamEvent, StreamRequest, StreamResponse};
+#[cfg(all(feature = "ic", feature = "stream"))]
+pub use crate::stream::StreamClient;
+pub use crate::times::{MessageTimes, TimeDomain, TimestampRepair};
+#[cfg(feature = "filter")]
+pub use crate::filter::MessageFilter;
+pub use crate::format::{BINARY_VERSION, FormatMigration};
+pub use crate::hash::{HashAlgorithm, Hasher, Sha256Hasher};
+pub use crate::health::{ErrorSummary, HealthReport, ZoneUsage};
+pub use crate::heat_map::HeatMapSegment;
+pub use crate::ids::{IdGenerator, id_to_string, ulid_id};
+pub use crate::index_block::{IndexBlock, MessageMeta};
+#[cfg(feature = "jobs")]
+pub use crate::jobs::{ExportChunk, ExportJob, ExportKind, ImportJob, MAX_EXPORT_JOBS};
+pub use crate::journal::JournalEntry;
+#[cfg(feature = "json")]
+pub use crate::json::ToJson;
+#[cfg(feature = "blake3")]
+pub use crate::hash::Blake3Hasher;
+#[cfg(feature = "crc32")]
+pub use crate::hash::Crc32Hasher;
+pub use crate::topic_header_block::TopicHeaderBlock;
+pub use crate::verify::CorruptionReport;
+#[cfg(feature = "jobs")]
+pub use crate::verify::RestoreReport;
+pub use crate::topic::{Batch, Topic};
 pub use crate::topic_message::TopicMessage;
+pub use crate::upgrade::{post_upgrade_restore, pre_upgrade_save, UPGRADE_MAGIC};
+#[doc(hidden)]
+pub use serde as __serde;
+#[cfg(feature = "macros")]
+pub use ic_cdk_macros;
+
+// The optional features this build carries, e.g. to report next to the canister's wasm size.
+#[cfg(feature = "size-audit")]
+pub fn compiled_features() -> Vec<&'static str> {
+    [
+        ("json", cfg!(feature = "json")),
+        ("aggregates", cfg!(feature = "aggregates")),
+        ("schema", cfg!(feature = "schema")),
+        ("filter", cfg!(feature = "filter")),
+        ("query", cfg!(feature = "query")),
+        ("jobs", cfg!(feature = "jobs")),
+        ("snapshot", cfg!(feature = "snapshot")),
+        ("assets", cfg!(feature = "assets")),
+        ("stream", cfg!(feature = "stream")),
+        ("replication", cfg!(feature = "replication")),
+        ("merge", cfg!(feature = "merge")),
+        ("kv", cfg!(feature = "kv")),
+        ("access-log", cfg!(feature = "access-log")),
+        ("manager", cfg!(feature = "manager")),
+        ("ic", cfg!(feature = "ic")),
+        ("macros", cfg!(feature = "macros")),
+        ("log", cfg!(feature = "log")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("compression", cfg!(feature = "compression")),
+        ("blake3", cfg!(feature = "blake3")),
+        ("crc32", cfg!(feature = "crc32")),
+        ("zstd", cfg!(feature = "zstd")),
+        ("candid", cfg!(feature = "candid")),
+        ("strict", cfg!(feature = "strict")),
+        ("testing", cfg!(feature = "testing")),
+        ("mmap", cfg!(feature = "mmap")),
+    ].into_iter().filter_map(|(name, enabled)| enabled.then_some(name)).collect()
+}
+
+// Tracing goes through the log crate with the `log` feature and compiles away without it.
+#[cfg(feature = "log")]
+macro_rules! debug {
+    ($($arg:tt)*) => { log::debug!($($arg)*) };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! debug {
+    ($($arg:tt)*) => { if false { let _ = format_args!($($arg)*); } };
+}
 
+mod access_control;
+#[cfg(feature = "access-log")]
+mod access_log;
+#[cfg(feature = "aggregates")]
+mod aggregates;
+mod aliases;
+mod alarms;
+pub mod arena;
+#[cfg(feature = "assets")]
+mod assets;
+mod budget;
+mod builder;
+mod canary;
+mod codec;
+mod compaction_policy;
+mod compression;
+mod consumer;
+#[cfg(feature = "stream")]
+mod cursor;
+mod dedup;
+mod error;
 mod events;
+#[cfg(feature = "testing")]
+mod evolution;
+mod export;
+#[cfg(feature = "filter")]
+mod filter;
+#[cfg(test)]
+mod fixtures;
+mod format;
+mod hash;
+mod health;
+mod ids;
+mod heat_map;
 mod index_block;
+mod jobs;
+mod journal;
+#[cfg(feature = "json")]
+mod json;
+mod key_index;
+#[cfg(feature = "kv")]
+mod kv_on_log;
+mod layout;
+#[cfg(feature = "manager")]
+mod manager;
+#[cfg(feature = "merge")]
+mod merge;
+mod meta;
+mod metrics;
+mod migration;
+mod pressure;
+#[cfg(feature = "query")]
+mod query;
 mod topic_header_block;
 mod read_write;
+mod regions;
+#[cfg(feature = "replication")]
+mod replication;
+mod recovery;
+mod reports;
+mod retention;
+mod reverse;
+#[cfg(feature = "schema")]
+mod schema;
+mod segments;
+mod settings;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+mod stable_encode;
+mod storage;
+#[cfg(feature = "stream")]
+mod stream;
+mod subscribers;
+mod telemetry;
 mod constants;
+mod times;
+mod topic;
 mod topic_message;
+mod upgrade;
+mod verify;
+mod versioned;
+
+pub struct EventFilesystem<S: BlockStorage = FnStorage> {
+    backend: Rc<RefCell<S>>,
+    // The backend behind a handle the zones and the meta arena share.
+    storage: Storage,
+    writer: RefCell<MemoryWriter>,
+    reader: MemoryReader,
+    topic_header: TopicHeaderBlock,
+    clock: fn() -> u64,
+    alarms: RefCell<Alarms>,
+    #[cfg(feature = "aggregates")]
+    aggregates: RefCell<Aggregates>,
+    admin_events: RefCell<Vec<EventFilesystemEvent>>,
+    meta: MetaStore,
+    keyed_store: Option<MetaStore>,
+    codecs: SettingsHistory<BincodeCodec>,
+    event_times: SettingsHistory<bool>,
+    message_ids: SettingsHistory<bool>,
+    compressions: SettingsHistory<PayloadCompression>,
+    checksums: SettingsHistory<bool>,
+    time_corrections: RefCell<TimeCorrections>,
+    // Height of the latest marker per kind, mirrored in the meta zone.
+    markers: RefCell<BTreeMap<String, u64>>,
+    counters: RefCell<Counters>,
+    instruction_counter: Option<fn() -> u64>,
+    histograms: RefCell<BTreeMap<Operation, InstructionHistogram>>,
+    heat_map: RefCell<Option<HeatMap>>,
+    stats_reports: RefCell<Option<StatsReports>>,
+    segments: RefCell<Option<SegmentManifests>>,
+    deduplication: RefCell<Option<Deduplication>>,
+    index_growth: Option<IndexGrowth>,
+    compaction: Option<Compaction>,
+    // Height the last completed compaction left the topic at.
+    last_compaction_height: u64,
+    truncation: Option<Truncation>,
+    retention: Cell<Option<RetentionPolicy>>,
+    consumer_retention: Cell<Option<ConsumerRetention>>,
+    watermarks: Cell<Watermarks>,
+    page_bytes: Cell<u64>,
+    regions: RefCell<RegionRegistry>,
+    aliases: RefCell<AliasTable>,
+    #[cfg(feature = "schema")]
+    schema: RefCell<Option<MessageSchema>>,
+    journal: RefCell<ErrorJournal>,
+    imports: RefCell<Imports>,
+    subscribers: RefCell<SubscriberRegistry>,
+    // Committed positions of the named consumer cursors.
+    cursors: RefCell<BTreeMap<String, u64>>,
+    bookmarks: RefCell<BTreeMap<String, u64>>,
+    // Only kept while telemetry is enabled.
+    usage: RefCell<Option<UsageCounters>>,
+    key_index: RefCell<Option<KeyIndex>>,
+    access_control: RefCell<AccessControl>,
+    // Where writes get their caller from while access control is enforced.
+    caller: Cell<Option<fn() -> Principal>>,
+    // Set while a MessageWriter owns the end of the topic.
+    streaming: Cell<bool>,
+    // How open repaired the heights, if they disagreed with the index.
+    height_repair: Option<HeightReport>,
+    // Height last written to the index height slot, reads are bounded by it.
+    committed_height: Cell<u64>,
+}
+
+const MAX_ADMIN_EVENTS: usize = 100;
+// Topics from before the dictionary history kept a single dictionary here.
+const COMPRESSION_DICTIONARY_RECORD: &str = "compression.dictionary";
+const COMPRESSION_DICTIONARIES_RECORD: &str = "compression.dictionaries";
+const CODEC_RECORD: &str = "codec.bincode";
+const CODEC_HISTORY_RECORD: &str = "codec.history";
+const MARKERS_RECORD: &str = "markers.last";
+const INDEX_CACHE_PAGES: usize = 8;
+const BLOCK_CACHE_BLOCKS: usize = 256;
+const HEAT_MAP_RECORD: &str = "stats.heat_map";
+const STATS_REPORTS_RECORD: &str = "stats.reports";
+const SEGMENTS_RECORD: &str = "segments.manifests";
+const DEDUPLICATION_RECORD: &str = "dedup.config";
+const INDEX_GROWTH_RECORD: &str = "migration.index_growth";
+const COMPACTION_RECORD: &str = "migration.compaction";
+const LAST_COMPACTION_RECORD: &str = "migration.last_compaction";
+const TRUNCATION_RECORD: &str = "retention.truncation";
+const RETENTION_RECORD: &str = "retention.policy";
+const CONSUMER_RETENTION_RECORD: &str = "retention.consumers";
+const WATERMARKS_RECORD: &str = "pressure.watermarks";
+const PAGE_BYTES_RECORD: &str = "read.page_bytes";
+const CURSORS_RECORD: &str = "consumer.cursors";
+const BOOKMARKS_RECORD: &str = "consumer.bookmarks";
+const TELEMETRY_RECORD: &str = "telemetry.usage";
+const REGIONS_RECORD: &str = "stable.regions";
+const ALIASES_RECORD: &str = "aliases";
+const SPLIT_RECORD: &str = "migration.split";
+#[cfg(feature = "schema")]
+const SCHEMA_RECORD: &str = "schema.json";
+const ERROR_JOURNAL_RECORD: &str = "errors.journal";
+const ADMIN_EVENTS_RECORD: &str = "admin.events";
+const ERROR_JOURNAL_SIZE: usize = 32;
+const EVENT_TIMES_RECORD: &str = "envelope.event_times";
+const MESSAGE_IDS_RECORD: &str = "envelope.message_ids";
+const COMPRESSION_HISTORY_RECORD: &str = "compression.history";
+const CHECKSUMS_RECORD: &str = "envelope.checksums";
+const TIME_CORRECTIONS_RECORD: &str = "time.corrections";
+const EXPORT_JOBS_RECORD: &str = "export.jobs";
+const IMPORTS_RECORD: &str = "import.jobs";
+const SUBSCRIBERS_REGION: &str = "subscribers";
+const KEYED_STORE_REGION: &str = "stable_store.keyed";
+const CONTROLLERS_RECORD: &str = "access.controllers";
+#[cfg(feature = "aggregates")]
+const AGGREGATE_RECORD_PREFIX: &str = "aggregate.";
+
+// What the aggregates and the schema see of a message, nothing when neither is compiled in.
+#[cfg(any(feature = "aggregates", feature = "schema"))]
+type Inspected = serde_json::Value;
+#[cfg(not(any(feature = "aggregates", feature = "schema")))]
+type Inspected = ();
+
+#[cfg(any(feature = "aggregates", feature = "schema"))]
+fn inspect_writable<T: Writable>(message: &T) -> Result<Inspected, String> {
+    serde_json::to_value(message).map_err(|e| e.to_string())
+}
+
+#[cfg(not(any(feature = "aggregates", feature = "schema")))]
+fn inspect_writable<T: Writable>(_: &T) -> Result<Inspected, String> {
+    Ok(())
+}
+
+// The constructors without a Result keep the stored heights like before open checked them, repairing
+// the index to match instead of refusing to open.
+impl EventFilesystem<FnStorage> {
+    pub fn get_file_system(write_fn: BlockWrite,
+                           read_fn: BlockRead,
+                           clock: fn() -> u64) -> EventFilesystem {
+        Self::open(Rc::new(RefCell::new(FnStorage::new(write_fn, read_fn))), clock, None, HeightPolicy::TrustHeight, false)
+            .map(Self::log_height_repair)
+            .unwrap()
+    }
+
+    pub fn get_or_create(write_fn: BlockWrite,
+                         read_fn: BlockRead,
+                         clock: fn() -> u64,
+                         event_stream_name: String,
+    ) -> Self {
+        Self::get_or_create_with_hash(write_fn, read_fn, clock, event_stream_name, HashAlgorithm::default())
+    }
+
+    // The algorithm is only applied when the topic is created, existing topics keep the one in their header.
+    // Like before names were checked, this opens whatever topic the memory holds, use the builder for the check.
+    pub fn get_or_create_with_hash(write_fn: BlockWrite,
+                                   read_fn: BlockRead,
+                                   clock: fn() -> u64,
+                                   event_stream_name: String,
+                                   hash_algorithm: HashAlgorithm,
+    ) -> Self {
+        EventFilesystemBuilder::new(write_fn, read_fn, clock)
+            .hash_algorithm(hash_algorithm)
+            .allow_name_mismatch(true)
+            .height_policy(HeightPolicy::TrustHeight)
+            .get_or_create(event_stream_name)
+            .map(Self::log_height_repair)
+            .unwrap()
+    }
+
+    fn log_height_repair(self) -> Self {
+        if let Some(report) = &self.height_repair {
+            debug!("Repaired the topic heights at open {:?}", report);
+        }
+        self
+    }
+}
+
+impl<S: BlockStorage> EventFilesystem<S> {
+    // Opens an initialized topic, `expected_layout` is checked against the layout in its header. A
+    // `read_only` open makes no writes.
+    pub(crate) fn open(backend: Rc<RefCell<S>>,
+                       clock: fn() -> u64,
+                       expected_layout: Option<LayoutConfig>,
+                       height_policy: HeightPolicy,
+                       read_only: bool,
+    ) -> Result<Self, FsError> {
+        let storage = &Storage::shared(&backend);
+        if read_magic_number(storage) == TOPIC_INITIALIZING_MAGIC {
+            return Err(FsError::InvalidState("Topic initialization was interrupted, reopen it with get_or_create to finish it".to_string()));
+        }
+        if !is_magic_number_valid(storage) {
+            return Err(FsError::InvalidState("No topic has been created in this memory".to_string()));
+        }
+        let (mut topic_header, records_layout) = read_topic_header(storage)?;
+        if !records_layout {
+            check_meta_zone(&topic_header, storage, read_only)?;
+        }
+        let stored_version = topic_header.binary_version;
+        let migrated = match read_only {
+            true => format::check_format(&mut topic_header).map(|_| false)?,
+            false => format::migrate(&mut topic_header, storage)?,
+        };
+        if migrated {
+            debug!("Migrated topic to format {}", topic_header.binary_version);
+        }
+        if let Some(expected) = expected_layout {
+            // Entry size follows the format, not the caller.
+            if (LayoutConfig { index_entry_size: topic_header.layout.index_entry_size, ..expected }) != topic_header.layout {
+                return Err(FsError::InvalidArgument(format!("Topic was created with layout {:?}, not {:?}", topic_header.layout, expected)));
+            }
+        }
+        let data_block_height = read_data_block_height(storage);
+        let index_height = read_index_height(storage);
+
+        debug!("EventFilesystem data_block_height {} index_height {}", data_block_height, index_height);
+        if !read_only {
+            canary::install_missing_canaries(&topic_header.layout, storage);
+        }
+        let mut fs = Self::assemble(backend, clock, topic_header, index_height, data_block_height)?;
+        // Staged imports are past the heights on purpose.
+        let importing = fs.meta.get_value::<Imports>(IMPORTS_RECORD)?.is_some_and(|imports| imports.job.is_some());
+        let report = recovery::check_heights(index_height, data_block_height, &fs.reader, &fs.topic_header.layout, &fs.storage).filter(|_| !importing);
+        let repair = match &report {
+            // FailFast only refuses or leaves entries uncommitted, the other policies rewrite the index.
+            Some(report) if read_only && height_policy != HeightPolicy::FailFast => return Err(FsError::HeightMismatch(report.clone())),
+            Some(report) => recovery::repair_heights(report, height_policy, &fs.reader, &fs.topic_header.layout, &fs.storage)?,
+            None => None,
+        };
+        if let (Some(mut report), Some((index_height, data_block_height))) = (report, repair) {
+            write_data_block_height(data_block_height, &fs.storage);
+            write_index_height(index_height, &fs.storage);
+            fs.writer.get_mut().set_offsets(index_height, data_block_height);
+            fs.committed_height.set(index_height);
+            report.repaired_with = Some(height_policy);
+            fs.height_repair = Some(report);
+        }
+        if let Some(markers) = fs.meta.get_value(MARKERS_RECORD)? {
+            fs.markers = RefCell::new(markers);
+        }
+        // Topics from before the history kept a single codec for every message.
+        if let Some(codecs) = fs.meta.get_value(CODEC_HISTORY_RECORD)? {
+            fs.apply_codecs(codecs);
+        } else if let Some(codec) = fs.meta.get_value::<BincodeCodec>(CODEC_RECORD)? {
+            fs.apply_codecs(SettingsHistory::new(codec));
+        }
+        if let Some(event_times) = fs.meta.get_value(EVENT_TIMES_RECORD)? {
+            fs.apply_event_times(event_times);
+        }
+        if let Some(message_ids) = fs.meta.get_value(MESSAGE_IDS_RECORD)? {
+            fs.apply_message_ids(message_ids)?;
+        }
+        if let Some(compressions) = fs.meta.get_value(COMPRESSION_HISTORY_RECORD)? {
+            fs.apply_compressions(compressions);
+        }
+        if let Some(dictionaries) = fs.meta.get_value(COMPRESSION_DICTIONARIES_RECORD)? {
+            fs.apply_dictionaries(dictionaries);
+        } else if let Some(dictionary) = fs.meta.get_value(COMPRESSION_DICTIONARY_RECORD)? {
+            fs.apply_dictionaries(vec![dictionary]);
+        }
+        if let Some(checksums) = fs.meta.get_value(CHECKSUMS_RECORD)? {
+            fs.apply_checksums(checksums);
+        }
+        if let Some(time_corrections) = fs.meta.get_value(TIME_CORRECTIONS_RECORD)? {
+            fs.time_corrections = RefCell::new(time_corrections);
+        }
+        if let Some(heat_map) = fs.meta.get_value(HEAT_MAP_RECORD)? {
+            fs.heat_map = RefCell::new(heat_map);
+        }
+        if let Some(reports) = fs.meta.get_value(STATS_REPORTS_RECORD)? {
+            fs.stats_reports = RefCell::new(reports);
+        }
+        // Manifests saved before the last commit of a call that failed afterwards start over.
+        if let Some(segments) = fs.meta.get_value::<Option<SegmentManifests>>(SEGMENTS_RECORD)?.flatten() {
+            let height = fs.committed_height.get();
+            let segments = match segments.next() == height {
+                true => segments,
+                false => SegmentManifests::new(segments.segment_size(), height)?,
+            };
+            fs.segments = RefCell::new(Some(segments));
+        }
+        if let Some(index_growth) = fs.meta.get_value(INDEX_GROWTH_RECORD)? {
+            fs.index_growth = index_growth;
+        }
+        if let Some(compaction) = fs.meta.get_value(COMPACTION_RECORD)? {
+            fs.compaction = compaction;
+        }
+        if let Some(height) = fs.meta.get_value(LAST_COMPACTION_RECORD)? {
+            fs.last_compaction_height = height;
+        }
+        if let Some(truncation) = fs.meta.get_value(TRUNCATION_RECORD)? {
+            fs.truncation = truncation;
+        }
+        if let Some(retention) = fs.meta.get_value(RETENTION_RECORD)? {
+            fs.retention = Cell::new(retention);
+        }
+        if let Some(consumer_retention) = fs.meta.get_value(CONSUMER_RETENTION_RECORD)? {
+            fs.consumer_retention = Cell::new(consumer_retention);
+        }
+        if let Some(watermarks) = fs.meta.get_value(WATERMARKS_RECORD)? {
+            fs.watermarks = Cell::new(watermarks);
+        }
+        if let Some(page_bytes) = fs.meta.get_value(PAGE_BYTES_RECORD)? {
+            fs.page_bytes = Cell::new(page_bytes);
+        }
+        if let Some(cursors) = fs.meta.get_value(CURSORS_RECORD)? {
+            fs.cursors = RefCell::new(cursors);
+        }
+        if let Some(bookmarks) = fs.meta.get_value(BOOKMARKS_RECORD)? {
+            fs.bookmarks = RefCell::new(bookmarks);
+        }
+        if let Some(usage) = fs.meta.get_value(TELEMETRY_RECORD)? {
+            fs.usage = RefCell::new(usage);
+            if !read_only {
+                fs.record_usage(|usage| usage.opens += 1);
+            }
+        }
+        if let Some(regions) = fs.meta.get_value(REGIONS_RECORD)? {
+            fs.regions = RefCell::new(regions);
+        }
+        if let Some(aliases) = fs.meta.get_value(ALIASES_RECORD)? {
+            fs.aliases = RefCell::new(aliases);
+        }
+        #[cfg(feature = "schema")]
+        if let Some(schema) = fs.meta.get_value::<Option<String>>(SCHEMA_RECORD)?.flatten() {
+            fs.schema = RefCell::new(Some(MessageSchema::parse(&schema)?));
+        }
+        if let Some(journal) = fs.meta.get_value(ERROR_JOURNAL_RECORD)? {
+            fs.journal = RefCell::new(journal);
+        }
+        if let Some(admin_events) = fs.meta.get_value(ADMIN_EVENTS_RECORD)? {
+            fs.admin_events = RefCell::new(admin_events);
+        }
+        if let Some(imports) = fs.meta.get_value::<Imports>(IMPORTS_RECORD)? {
+            // Staged messages are past the persisted heights, appending continues after them.
+            if let Some(job) = &imports.job {
+                fs.writer.get_mut().set_offsets(job.next, job.next_data_block);
+            }
+            fs.imports = RefCell::new(imports);
+        }
+        if let Some(access_control) = fs.meta.get_value(CONTROLLERS_RECORD)? {
+            fs.access_control = RefCell::new(access_control);
+        }
+        if let Some(region) = fs.regions.borrow().get(KEY_INDEX_REGION) {
+            fs.key_index = RefCell::new(Some(KeyIndex::open(region, &fs.storage)));
+        }
+        if let Some(region) = fs.regions.borrow().get(KEYED_STORE_REGION) {
+            fs.keyed_store = Some(MetaStore::open_region(region, &fs.storage)?);
+        }
+        if fs.regions.borrow().get(SUBSCRIBERS_REGION).is_some() {
+            fs.subscribers = RefCell::new(fs.stable_restore_in(SUBSCRIBERS_REGION)?);
+        }
+        if let Some(Some(config)) = fs.meta.get_value(DEDUPLICATION_RECORD)? {
+            fs.deduplication = RefCell::new(Some(fs.load_deduplication(config)?));
+        }
+        if read_only {
+            return Ok(fs);
+        }
+        if migrated {
+            fs.record_admin_event(EventFilesystemEvent::VersionMigrated(VersionMigrated {
+                from: stored_version,
+                to: fs.topic_header.binary_version,
+                timestamp: clock(),
+            }));
+        }
+        fs.record_admin_event(EventFilesystemEvent::TopicOpened(TopicOpened {
+            height: fs.committed_height.get(),
+            binary_version: fs.topic_header.binary_version,
+            timestamp: clock(),
+        }));
+        Ok(fs)
+    }
+
+    pub(crate) fn create(backend: Rc<RefCell<S>>,
+                         clock: fn() -> u64,
+                         event_stream_name: String,
+                         hash_algorithm: HashAlgorithm,
+                         layout: LayoutConfig,
+    ) -> Result<Self, FsError> {
+        layout.validate()?;
+        let storage = &Storage::shared(&backend);
+        if read_magic_number(storage) == TOPIC_INITIALIZING_MAGIC {
+            debug!("Redoing interrupted initialization of {}", event_stream_name);
+        }
+        write_magic_number(TOPIC_INITIALIZING_MAGIC, storage);
+        write_index_height(0, storage);
+        write_data_block_height(0, storage);
+
+        let topic_block = TopicHeaderBlock {
+            event_stream_name,
+            first_message_ptr: 0,
+            binary_version: BINARY_VERSION,
+            hash_algorithm: hash_algorithm.id(),
+            layout,
+            registers: BTreeMap::new(),
+        };
+
+        write_topic_block(&topic_block, storage);
+        canary::write_canaries(&layout, storage);
+        write_magic_number(TOPIC_HEADER_MAGIC, storage);
+
+        let fs = Self::assemble(backend, clock, topic_block, 0, 0)?;
+        fs.record_admin_event(EventFilesystemEvent::TopicCreated(TopicCreated {
+            event_stream_name: fs.topic_header.event_stream_name.clone(),
+            binary_version: fs.topic_header.binary_version,
+            timestamp: clock(),
+        }));
+        Ok(fs)
+    }
+
+    fn assemble(backend: Rc<RefCell<S>>,
+                clock: fn() -> u64,
+                topic_header: TopicHeaderBlock,
+                index_height: u64,
+                data_block_height: u64,
+    ) -> Result<Self, FsError> {
+        let layout = topic_header.layout;
+        let storage = Storage::shared(&backend);
+        let mut writer = MemoryWriter::new(index_height, data_block_height, clock);
+        writer.set_layout(layout);
+        let mut reader = MemoryReader::new();
+        reader.set_layout(layout);
+        reader.set_index_cache_pages(INDEX_CACHE_PAGES);
+        reader.set_block_cache_blocks(BLOCK_CACHE_BLOCKS);
+
+        Ok(EventFilesystem {
+            meta: MetaStore::open(&layout, &storage)?,
+            keyed_store: None,
+            storage,
+            backend,
+            writer: RefCell::new(writer),
+            reader,
+            topic_header,
+            clock,
+            alarms: RefCell::new(Alarms::default()),
+            #[cfg(feature = "aggregates")]
+            aggregates: RefCell::new(Aggregates::default()),
+            admin_events: RefCell::new(Vec::new()),
+            codecs: SettingsHistory::new(BincodeCodec::default()),
+            event_times: SettingsHistory::new(false),
+            message_ids: SettingsHistory::new(false),
+            compressions: SettingsHistory::new(PayloadCompression::None),
+            checksums: SettingsHistory::new(false),
+            time_corrections: RefCell::new(TimeCorrections::default()),
+            markers: RefCell::new(BTreeMap::new()),
+            counters: RefCell::new(Counters::default()),
+            instruction_counter: None,
+            histograms: RefCell::new(BTreeMap::new()),
+            heat_map: RefCell::new(None),
+            stats_reports: RefCell::new(None),
+            segments: RefCell::new(None),
+            deduplication: RefCell::new(None),
+            index_growth: None,
+            compaction: None,
+            last_compaction_height: 0,
+            truncation: None,
+            retention: Cell::new(None),
+            consumer_retention: Cell::new(None),
+            watermarks: Cell::new(Watermarks::default()),
+            page_bytes: Cell::new(MAX_PAGE_BYTES),
+            regions: RefCell::new(RegionRegistry::default()),
+            aliases: RefCell::new(AliasTable::default()),
+            #[cfg(feature = "schema")]
+            schema: RefCell::new(None),
+            journal: RefCell::new(ErrorJournal::new(ERROR_JOURNAL_SIZE)),
+            imports: RefCell::new(Imports::default()),
+            subscribers: RefCell::new(SubscriberRegistry::default()),
+            cursors: RefCell::new(BTreeMap::new()),
+            bookmarks: RefCell::new(BTreeMap::new()),
+            streaming: Cell::new(false),
+            height_repair: None,
+            usage: RefCell::new(None),
+            key_index: RefCell::new(None),
+            access_control: RefCell::new(AccessControl::default()),
+            caller: Cell::new(None),
+            committed_height: Cell::new(index_height),
+        })
+    }
+
+    // The backend the topic was opened on, e.g. to snapshot a VecStorage.
+    pub fn storage(&self) -> Ref<'_, S> {
+        self.backend.borrow()
+    }
+
+    // A typed view of the topic, see Topic::batch for atomic batches.
+    pub fn topic<T: Writable + DeserializeOwned>(&self) -> Topic<'_, T, S> {
+        Topic::new(self)
+    }
+
+    pub fn get_topic_height(&self) -> u64 {
+        read_index_height(&self.storage)
+    }
+
+    // The newest message whose height has been committed, which is what reads and a reopened topic see.
+    // A successful write_topic_message commits before returning, so a read in the same call observes it.
+    pub fn last_committed_height(&self) -> Option<u64> {
+        self.committed_height.get().checked_sub(1)
+    }
+
+    // How open repaired heights that disagreed with the index, None when they agreed.
+    pub fn height_repair(&self) -> Option<&HeightReport> {
+        self.height_repair.as_ref()
+    }
+
+    // The newest message the writer has appended. It is ahead of last_committed_height only when a write
+    // failed after its entry was appended, e.g. on a red zone check.
+    pub fn last_appended_height(&self) -> Option<u64> {
+        self.writer.borrow().index_block_offset().checked_sub(1)
+    }
+
+    // Messages that can still be appended before writes fail with IndexZoneFull.
+    pub fn remaining_capacity(&self) -> u64 {
+        self.topic_header.layout.max_index_entries().saturating_sub(self.writer.borrow().index_block_offset())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.remaining_capacity() == 0
+    }
+
+    pub fn get_topic_header(&self) -> &TopicHeaderBlock {
+        &self.topic_header
+    }
+
+    // Stores immutable configuration like a deployment id or genesis parameters in the topic header.
+    // A register can't be changed or removed once set, not even by controllers. They share the header
+    // zone, so keep them to a few small values.
+    pub fn set_once(&mut self, name: &str, bytes: &[u8]) -> Result<(), FsError> {
+        if let Some(caller) = self.caller.get() {
+            self.guard(caller())?;
+        }
+        if self.topic_header.registers.contains_key(name) {
+            return Err(FsError::InvalidState(format!("Register {} is already set", name)));
+        }
+        let mut header = self.topic_header.clone();
+        header.registers.insert(name.to_string(), bytes.to_vec());
+        let size = bincode::serialized_size(&header).map_err(|e| FsError::Serialize(e.to_string()))?;
+        let limit = TOPIC_BLOCK_CANARY_IDX - TOPIC_BLOCK_DATA_START_IDX;
+        if size > limit {
+            return Err(FsError::OutOfSpace(format!("Topic header would take {} bytes, the zone holds {}", size, limit)));
+        }
+        write_topic_block(&header, &self.storage);
+        self.topic_header = header;
+        Ok(())
+    }
+
+    pub fn get_register(&self, name: &str) -> Option<&[u8]> {
+        self.topic_header.registers.get(name).map(Vec::as_slice)
+    }
+
+    pub fn layout(&self) -> LayoutDescriptor {
+        self.topic_header.layout.descriptor()
+    }
+
+    pub fn hasher(&self) -> Result<Box<dyn Hasher>, FsError> {
+        self.topic_header.hash_algorithm()?.hasher()
+    }
+
+    pub fn stable_store<T: Serialize>(&self, data: T) -> Result<(), FsError> {
+        let data = bincode::serialize(&data).map_err(|e| FsError::Serialize(e.to_string()))?;
+        self.store_bytes(data)
+    }
+
+    // Like stable_store, restore it with stable_restore_with and the same codec.
+    pub fn stable_store_with<T, C: Codec<T>>(&self, data: &T, codec: &C) -> Result<(), FsError> {
+        self.store_bytes(codec.encode(data)?)
+    }
+
+    pub(crate) fn store_bytes(&self, data: Vec<u8>) -> Result<(), FsError> {
+        let layout = &self.topic_header.layout;
+        let limit = self.stable_store_limit();
+        if data.len() as u64 > limit {
+            return Err(FsError::MessageTooLarge { size: data.len() as u64, limit });
+        }
+        self.storage.write(FREE_MEMORY_BLOCK_SIZE_IDX, &data.len().to_le_bytes());
+        self.storage.write(FREE_MEMORY_BLOCK_START_IDX, data.as_slice());
+        canary::check_canaries_near(layout, FREE_MEMORY_BLOCK_START_IDX, FREE_MEMORY_BLOCK_START_IDX + data.len() as u64, &self.storage)
+    }
+
+    pub fn stable_restore<T: DeserializeOwned>(&self) -> Result<T, FsError> {
+        let bytes = self.restore_bytes()?;
+        bincode::deserialize(&bytes).map_err(|e| FsError::Deserialize(e.to_string()))
+    }
+
+    pub fn stable_restore_with<T, C: Codec<T>>(&self, codec: &C) -> Result<T, FsError> {
+        codec.decode(&self.restore_bytes()?)
+    }
+
+    pub(crate) fn restore_bytes(&self) -> Result<Vec<u8>, FsError> {
+        let mut size = [0u8; 8];
+        self.storage.read(FREE_MEMORY_BLOCK_SIZE_IDX, &mut size);
+        let size = u64::from_le_bytes(size);
+        let limit = self.stable_store_limit();
+        if size > limit {
+            return Err(FsError::InvalidState(format!("Stable store claims {} bytes, above the {} byte limit", size, limit)));
+        }
+
+        let mut bytes = vec![0u8; size as usize];
+        self.storage.read(FREE_MEMORY_BLOCK_START_IDX, bytes.as_mut_slice());
+        Ok(bytes)
+    }
+
+    // Reserves `capacity` bytes of the stable store zone for values stored under keys, managed by an
+    // allocator so they can grow, shrink and be removed independently.
+    pub fn enable_keyed_store(&mut self, capacity: u64) -> Result<(), FsError> {
+        if self.keyed_store.is_some() {
+            return Err(FsError::InvalidState("The keyed stable store is already enabled".to_string()));
+        }
+        if capacity < ARENA_HEADER_SIZE {
+            return Err(FsError::InvalidArgument(format!("The keyed stable store needs {} bytes at least", ARENA_HEADER_SIZE)));
+        }
+        let region = self.create_region(KEYED_STORE_REGION, capacity)?;
+        self.keyed_store = Some(MetaStore::create_in_region(region, &self.storage)?);
+        Ok(())
+    }
+
+    pub fn stable_store_keyed<T: Serialize>(&self, key: &str, data: T) -> Result<(), FsError> {
+        let data = bincode::serialize(&data).map_err(|e| FsError::Serialize(e.to_string()))?;
+        self.keyed_store()?.put(key, &data)
+    }
+
+    // None for keys nothing was stored under.
+    pub fn stable_restore_keyed<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, FsError> {
+        match self.keyed_store()?.get(key)? {
+            Some(bytes) => bincode::deserialize(&bytes).map(Some).map_err(|e| FsError::Deserialize(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    // Frees the value's space, returns whether there was one.
+    pub fn stable_remove_keyed(&self, key: &str) -> Result<bool, FsError> {
+        self.keyed_store()?.remove(key)
+    }
+
+    pub fn stable_store_keys(&self) -> Result<Vec<String>, FsError> {
+        Ok(self.keyed_store()?.names())
+    }
+
+    fn keyed_store(&self) -> Result<&MetaStore, FsError> {
+        self.keyed_store.as_ref().ok_or_else(|| FsError::InvalidState("The keyed stable store isn't enabled".to_string()))
+    }
+
+    // What stable_store can hold once the regions are taken out.
+    fn stable_store_limit(&self) -> u64 {
+        self.topic_header.layout.stable_store_max_size() - self.regions.borrow().reserved()
+    }
+
+    // Reserves `capacity` bytes of the stable store zone for stable_store_in, taken from what
+    // stable_store can hold. Fails if that would overlap what stable_store currently holds.
+    pub fn create_region(&self, name: &str, capacity: u64) -> Result<StableRegion, FsError> {
+        let mut size = [0u8; 8];
+        self.storage.read(FREE_MEMORY_BLOCK_SIZE_IDX, &mut size);
+        let floor = FREE_MEMORY_BLOCK_START_IDX.saturating_add(u64::from_le_bytes(size));
+        let zone_end = FREE_MEMORY_BLOCK_START_IDX + self.topic_header.layout.stable_store_max_size();
+
+        let mut regions = self.regions.borrow().clone();
+        let region = regions.create(name, capacity, zone_end, floor)?;
+        // The slot may still hold the tail of an earlier, larger stable_store.
+        self.storage.write(region.offset, &0u64.to_le_bytes());
+        self.meta.put_value(REGIONS_RECORD, &regions)?;
+        *self.regions.borrow_mut() = regions;
+        Ok(region)
+    }
+
+    pub fn regions(&self) -> Vec<(String, StableRegion)> {
+        self.regions.borrow().regions().iter().map(|(name, region)| (name.clone(), *region)).collect()
+    }
+
+    pub fn stable_store_in<T: Serialize>(&self, name: &str, data: T) -> Result<(), FsError> {
+        let region = self.region(name)?;
+        let data = bincode::serialize(&data).map_err(|e| FsError::Serialize(e.to_string()))?;
+        if data.len() as u64 > region.capacity {
+            return Err(FsError::MessageTooLarge { size: data.len() as u64, limit: region.capacity });
+        }
+        self.storage.write(region.offset, &data.len().to_le_bytes());
+        self.storage.write(region.data_offset(), data.as_slice());
+        canary::check_canaries_near(&self.topic_header.layout, region.offset, region.data_offset() + data.len() as u64, &self.storage)
+    }
+
+    // A region that was never stored to holds an empty payload, which fails to decode for most types.
+    pub fn stable_restore_in<T: DeserializeOwned>(&self, name: &str) -> Result<T, FsError> {
+        let region = self.region(name)?;
+        let mut size = [0u8; 8];
+        self.storage.read(region.offset, &mut size);
+        let size = u64::from_le_bytes(size);
+        if size > region.capacity {
+            return Err(FsError::InvalidState(format!("Region {} claims {} bytes, above its {} byte capacity", name, size, region.capacity)));
+        }
+
+        let mut bytes = vec![0u8; size as usize];
+        self.storage.read(region.data_offset(), bytes.as_mut_slice());
+        bincode::deserialize(&bytes).map_err(|e| FsError::Deserialize(e.to_string()))
+    }
+
+    fn region(&self, name: &str) -> Result<StableRegion, FsError> {
+        self.regions.borrow().get(name).ok_or_else(|| FsError::InvalidArgument(format!("Unknown region {}", name)))
+    }
+
+    // Registers a subscriber that will read from `offset` on. The registry lives in its own stable region,
+    // which is created on the first subscription.
+    pub fn add_subscriber(&self, subscriber: Principal, offset: u64) -> Result<(), FsError> {
+        self.check_offset(offset)?;
+        let event = EventFilesystemEvent::SubscriberAdded(SubscriberAdded { subscriber, offset });
+        self.update_subscribers(vec![event], |registry| registry.add(subscriber, offset))
+    }
+
+    pub fn remove_subscriber(&self, subscriber: Principal) -> Result<(), FsError> {
+        let event = EventFilesystemEvent::SubscriberRemoved(SubscriberRemoved { subscriber });
+        self.update_subscribers(vec![event], |registry| registry.remove(&subscriber))
+    }
+
+    // Records that `subscriber` has processed every message below `offset`. Offsets may move back, e.g.
+    // to replay after a consumer lost its state.
+    pub fn commit_offset(&self, subscriber: Principal, offset: u64) -> Result<(), FsError> {
+        self.check_offset(offset)?;
+        let event = EventFilesystemEvent::SubscriberOffsetModified(SubscriberOffsetModified { subscriber, offset });
+        self.update_subscribers(vec![event], |registry| registry.commit(&subscriber, offset))
+    }
+
+    // Commits the offsets of many subscribers with a single write of the subscriber region, e.g. for a
+    // gateway that proxies many consumers. Commits all of them or, if any is unknown or past the topic
+    // height, none. A subscriber listed twice ends up at its last offset.
+    pub fn commit_offsets(&self, commits: &[(Principal, u64)]) -> Result<(), FsError> {
+        self.check_commits(commits)?;
+        let events = commits.iter()
+            .map(|(subscriber, offset)| EventFilesystemEvent::SubscriberOffsetModified(SubscriberOffsetModified { subscriber: *subscriber, offset: *offset }))
+            .collect();
+        self.update_subscribers(events, |registry| commits.iter().try_for_each(|(subscriber, offset)| registry.commit(subscriber, *offset)))
+    }
+
+    pub(crate) fn check_commits(&self, commits: &[(Principal, u64)]) -> Result<(), FsError> {
+        let registry = self.subscribers.borrow();
+        commits.iter().try_for_each(|(subscriber, offset)| {
+            self.check_offset(*offset)?;
+            registry.offset(subscriber).map(|_| ()).ok_or_else(|| FsError::InvalidArgument(format!("{} is not subscribed", subscriber)))
+        })
+    }
+
+    pub fn subscriber_offset(&self, subscriber: Principal) -> Option<u64> {
+        self.subscribers.borrow().offset(&subscriber)
+    }
+
+    pub fn subscribers(&self) -> Vec<(Principal, u64)> {
+        self.subscribers.borrow().subscribers()
+    }
+
+    // Subscribers more than `max_lag` messages behind the committed height, with how many messages they
+    // have left to read.
+    pub fn get_lagging_subscribers(&self, max_lag: u64) -> Vec<(Principal, u64)> {
+        self.subscribers.borrow().lagging(self.committed_height.get(), max_lag)
+    }
+
+    // Reserves a stable region of `slots` key index slots, 16 bytes each, for write_topic_message_keyed.
+    // Writes fail once 90% of them are used.
+    pub fn enable_key_index(&self, slots: u64) -> Result<(), FsError> {
+        if self.key_index.borrow().is_some() {
+            return Err(FsError::InvalidState("The key index is already enabled".to_string()));
+        }
+        let capacity = slots.checked_mul(KEY_INDEX_SLOT_SIZE).ok_or_else(|| FsError::InvalidArgument(format!("{} key index slots", slots)))?;
+        let region = self.create_region(KEY_INDEX_REGION, capacity)?;
+        *self.key_index.borrow_mut() = Some(KeyIndex::create(region, &self.storage));
+        Ok(())
+    }
+
+    // Writes the message and records its height under `key`, e.g. the id of the aggregate it belongs to.
+    pub fn write_topic_message_keyed<T: Writable>(&self, key: &[u8], data: &T) -> Result<u64, FsError> {
+        let mut key_index = self.key_index.borrow_mut();
+        let key_index = key_index.as_mut().ok_or_else(|| FsError::InvalidState("The key index isn't enabled".to_string()))?;
+        key_index.check_room()?;
+        let height = self.write_topic_message(data)?;
+        key_index.insert(key, height, &self.storage)?;
+        self.add_index_flags(height, IndexBlock::HAS_KEY)?;
+        self.record_segments(|manifests| manifests.record_key(height, key));
+        Ok(height)
+    }
+
+    // Heights written under `key`, ascending. Truncated ones are left out.
+    pub fn heights_by_key(&self, key: &[u8]) -> Result<Vec<u64>, FsError> {
+        let key_index = self.key_index.borrow();
+        let key_index = key_index.as_ref().ok_or_else(|| FsError::InvalidState("The key index isn't enabled".to_string()))?;
+        let first = self.first_message_height();
+        Ok(key_index.heights(key, &self.storage).into_iter().filter(|height| *height >= first).collect())
+    }
+
+    // Key index slots in use, None while the index isn't enabled.
+    pub fn key_index_usage(&self) -> Option<ZoneUsage> {
+        self.key_index.borrow().map(|key_index| ZoneUsage { used: key_index.used(), capacity: key_index.slots() })
+    }
+
+    pub fn read_by_key<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Vec<(u64, T)>, FsError> {
+        self.heights_by_key(key)?.into_iter().map(|height| Ok((height, self.read_topic_message(height)?))).collect()
+    }
+
+    // Starts at the first message the topic still holds.
+    pub fn create_cursor(&self, name: &str) -> Result<ConsumerCursor<'_, S>, FsError> {
+        let first = self.first_message_height();
+        self.check_offset(first)?;
+        self.update_cursors(|cursors| match cursors.insert(name.to_string(), first) {
+            Some(_) => Err(FsError::InvalidArgument(format!("Cursor {} already exists", name))),
+            None => Ok(()),
+        })?;
+        Ok(ConsumerCursor::new(self, name.to_string(), first))
+    }
+
+    // Continues from the cursor's committed position.
+    pub fn cursor(&self, name: &str) -> Result<ConsumerCursor<'_, S>, FsError> {
+        let committed = self.cursors.borrow().get(name).copied().ok_or_else(|| FsError::InvalidArgument(format!("No cursor {}", name)))?;
+        Ok(ConsumerCursor::new(self, name.to_string(), committed))
+    }
+
+    pub fn remove_cursor(&self, name: &str) -> Result<(), FsError> {
+        self.update_cursors(|cursors| cursors.remove(name).map(|_| ()).ok_or_else(|| FsError::InvalidArgument(format!("No cursor {}", name))))
+    }
+
+    // Every cursor with its committed position.
+    pub fn cursors(&self) -> Vec<(String, u64)> {
+        self.cursors.borrow().iter().map(|(name, position)| (name.clone(), *position)).collect()
+    }
+
+    pub(crate) fn commit_cursor(&self, name: &str, position: u64) -> Result<(), FsError> {
+        self.check_offset(position)?;
+        self.update_cursors(|cursors| {
+            let committed = cursors.get_mut(name).ok_or_else(|| FsError::InvalidArgument(format!("No cursor {}", name)))?;
+            *committed = position;
+            Ok(())
+        })
+    }
+
+    fn update_cursors(&self, update: impl FnOnce(&mut BTreeMap<String, u64>) -> Result<(), FsError>) -> Result<(), FsError> {
+        let mut cursors = self.cursors.borrow().clone();
+        update(&mut cursors)?;
+        self.meta.put_value(CURSORS_RECORD, &cursors)?;
+        *self.cursors.borrow_mut() = cursors;
+        Ok(())
+    }
+
+    // Durable named heights for timers or projections that keep their own read loop. Unlike cursors they
+    // need no registration, setting one creates it.
+    pub fn set_bookmark(&self, name: &str, height: u64) -> Result<(), FsError> {
+        self.check_offset(height)?;
+        if name.len() > MAX_BOOKMARK_NAME_SIZE {
+            return Err(FsError::InvalidArgument(format!("Bookmark names are at most {} bytes", MAX_BOOKMARK_NAME_SIZE)));
+        }
+        let mut bookmarks = self.bookmarks.borrow().clone();
+        if bookmarks.insert(name.to_string(), height).is_none() && bookmarks.len() > MAX_BOOKMARKS {
+            return Err(FsError::OutOfSpace(format!("At most {} bookmarks can be kept", MAX_BOOKMARKS)));
+        }
+        self.meta.put_value(BOOKMARKS_RECORD, &bookmarks)?;
+        *self.bookmarks.borrow_mut() = bookmarks;
+        Ok(())
+    }
+
+    pub fn get_bookmark(&self, name: &str) -> Option<u64> {
+        self.bookmarks.borrow().get(name).copied()
+    }
+
+    // Returns the height the bookmark was at.
+    pub fn remove_bookmark(&self, name: &str) -> Result<Option<u64>, FsError> {
+        let mut bookmarks = self.bookmarks.borrow().clone();
+        let removed = bookmarks.remove(name);
+        if removed.is_some() {
+            self.meta.put_value(BOOKMARKS_RECORD, &bookmarks)?;
+            *self.bookmarks.borrow_mut() = bookmarks;
+        }
+        Ok(removed)
+    }
+
+    pub fn bookmarks(&self) -> Vec<(String, u64)> {
+        self.bookmarks.borrow().iter().map(|(name, height)| (name.clone(), *height)).collect()
+    }
+
+    // Positions can't move while a compaction is mapping them to new heights.
+    fn check_offset(&self, offset: u64) -> Result<(), FsError> {
+        if let Some(compaction) = &self.compaction {
+            return Err(FsError::InvalidState(format!("Topic is being compacted, {} of {} messages scanned", compaction.scanned, compaction.height)));
+        }
+        let height = self.committed_height.get();
+        if offset > height {
+            return Err(FsError::InvalidArgument(format!("Offset {} is past the topic height {}", offset, height)));
+        }
+        Ok(())
+    }
+
+    // Unlike other admin events, subscriber events are saved before the registry and a change whose events
+    // can't be saved fails.
+    fn update_subscribers(&self,
+                          events: Vec<EventFilesystemEvent>,
+                          update: impl FnOnce(&mut SubscriberRegistry) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let mut registry = self.subscribers.borrow().clone();
+        update(&mut registry)?;
+        if self.regions.borrow().get(SUBSCRIBERS_REGION).is_none() {
+            self.create_region(SUBSCRIBERS_REGION, SUBSCRIBER_REGION_SIZE)?;
+        }
+        let admin_events = self.admin_events.borrow().clone();
+        self.save_admin_events(events)?;
+        if let Err(e) = self.stable_store_in(SUBSCRIBERS_REGION, &registry) {
+            if let Err(e) = self.meta.put_value(ADMIN_EVENTS_RECORD, &admin_events) {
+                debug!("Failed to restore the admin events: {}", e);
+            }
+            *self.admin_events.borrow_mut() = admin_events;
+            return Err(e);
+        }
+        *self.subscribers.borrow_mut() = registry;
+        Ok(())
+    }
+
+    pub fn add_controller(&self, controller: Principal) -> Result<(), FsError> {
+        self.update_access_control(|access| access.add(controller))?;
+        self.record_admin_event(EventFilesystemEvent::ControllerAdded(ControllerAdded { controller }));
+        Ok(())
+    }
+
+    pub fn remove_controller(&self, controller: Principal) -> Result<(), FsError> {
+        self.update_access_control(|access| access.remove(&controller))?;
+        self.record_admin_event(EventFilesystemEvent::ControllerRemoved(ControllerRemoved { controller }));
+        Ok(())
+    }
+
+    pub fn controllers(&self) -> Vec<Principal> {
+        self.access_control.borrow().controllers()
+    }
+
+    // Fails unless `caller` is a controller, for canister methods to check before touching the topic.
+    pub fn guard(&self, caller: Principal) -> Result<(), FsError> {
+        self.access_control.borrow().guard(&caller)
+    }
+
+    // With a caller source, e.g. `ic_cdk::caller`, every write fails unless the caller is a controller.
+    // Not persisted, set it again after an upgrade.
+    pub fn enforce_access_control(&self, caller: Option<fn() -> Principal>) {
+        self.caller.set(caller);
+    }
+
+    fn update_access_control(&self, update: impl FnOnce(&mut AccessControl) -> Result<(), FsError>) -> Result<(), FsError> {
+        let mut access_control = self.access_control.borrow().clone();
+        update(&mut access_control)?;
+        self.meta.put_value(CONTROLLERS_RECORD, &access_control)?;
+        *self.access_control.borrow_mut() = access_control;
+        Ok(())
+    }
+
+    // Verifies the canaries at every zone boundary are intact. The outcome is kept as an admin event.
+    pub fn check(&self) -> Result<(), FsError> {
+        let result = canary::check_canaries(&self.topic_header.layout, &self.storage);
+        if let Err(e) = &result {
+            self.journal_error("check", None, e);
+        }
+        self.record_admin_event(EventFilesystemEvent::IntegrityChecked(IntegrityChecked {
+            error: result.clone().err(),
+            timestamp: (self.clock)(),
+        }));
+        result
+    }
+
+    pub fn read_topic_message<T : DeserializeOwned>(&self, id: u64) -> Result<T, FsError> {
+        self.measured(Operation::Read, || {
+            let result = self.check_written(id, 1).and_then(|_| self.reader.read_topic_message(id, &self.storage));
+            match &result {
+                Ok(_) => self.record_reads(id, 1),
+                Err(e) => self.record_error("read", Some(id), e),
+            }
+            result
+        })
+    }
+
+    // The message with its index entry, e.g. to show publish times or account for stored bytes.
+    pub fn read_topic_message_with_meta<T: DeserializeOwned>(&self, id: u64) -> Result<(T, MessageMeta), FsError> {
+        self.measured(Operation::Read, || {
+            let result = self.check_written(id, 1).and_then(|_| {
+                let idx = self.reader.read_idx(id, &self.storage)?;
+                let message = self.reader.read_topic_message(id, &self.storage)?;
+                let event_time = self.reader.read_event_time(id, &self.storage)?;
+                Ok((message, MessageMeta::new(&idx, event_time, self.reader.read_id(id, &self.storage)?)))
+            });
+            match &result {
+                Ok(_) => self.record_reads(id, 1),
+                Err(e) => self.record_error("read", Some(id), e),
+            }
+            result
+        })
+    }
+
+    // Reads a message written with write_topic_message_with, `codec` has to be the one it was written with.
+    pub fn read_topic_message_with<T, C: Codec<T>>(&self, id: u64, codec: &C) -> Result<T, FsError> {
+        self.measured(Operation::Read, || {
+            let result = self.check_written(id, 1)
+                .and_then(|_| self.reader.read_payload(id, &self.storage))
+                .and_then(|payload| codec.decode(&payload));
+            match &result {
+                Ok(_) => self.record_reads(id, 1),
+                Err(e) => self.record_error("read", Some(id), e),
+            }
+            result
+        })
+    }
+
+    // The bytes a message was stored with, decompressed, e.g. a blob written with write_raw. Messages
+    // written with write_topic_message come back bincode encoded.
+    pub fn read_raw(&self, id: u64) -> Result<Vec<u8>, FsError> {
+        self.measured(Operation::Read, || {
+            let result = self.check_written(id, 1).and_then(|_| self.reader.read_payload(id, &self.storage));
+            match &result {
+                Ok(_) => self.record_reads(id, 1),
+                Err(e) => self.record_error("read", Some(id), e),
+            }
+            result
+        })
+    }
+
+    // Reads the bytes read_raw returns piece by piece, e.g. to pipe a large payload into a decoder.
+    // Markers read as empty.
+    pub fn open_message(&self, id: u64) -> Result<MessageReader<'_, S>, FsError> {
+        let result = self.check_written(id, 1).and_then(|_| self.reader.payload_span(id, &self.storage));
+        match result {
+            Ok((offset, size)) => {
+                self.record_reads(id, 1);
+                Ok(MessageReader::new(self, offset, size))
+            }
+            Err(e) => {
+                self.record_error("read", Some(id), &e);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn write_topic_message<T: Writable>(&self, data: &T) -> Result<u64, FsError> {
+        self.measured(Operation::Write, || self.append_one(data, None))
+    }
+
+    // Stores `event_time` as the time the event happened, next to the ingestion time the topic records
+    // for every message. Event times have to be enabled with set_event_times.
+    pub fn write_topic_message_at<T: Writable>(&self, data: &T, event_time: u64) -> Result<u64, FsError> {
+        self.measured(Operation::Write, || self.append_one(data, Some(event_time)))
+    }
+
+    // Writes all messages or none of them. The heights are committed once after the last one, a
+    // message that fails leaves the committed topic as it was before the batch.
+    pub fn write_topic_messages<T: Writable>(&self, messages: &[T]) -> Result<Vec<u64>, FsError> {
+        self.measured(Operation::Write, || self.append(messages, None))
+    }
+
+    // Encodes the message with `codec` instead of the topic's bincode settings. Compression, checksums
+    // and deduplication still apply, topics with aggregates or a schema only take codecs they can inspect.
+    pub fn write_topic_message_with<T, C: Codec<T>>(&self, data: &T, codec: &C) -> Result<u64, FsError> {
+        let inspect = |_: &T| Err("messages written with another codec can't be inspected".to_string());
+        self.measured(Operation::Write, || {
+            self.append_one_with(data, None, inspect, |buf, message| codec.encode_into(buf, message))
+        })
+    }
+
+    // Stores already serialized bytes, e.g. Candid or protobuf payloads, without the bincode round trip.
+    // Compression, checksums and deduplication still apply, topics with aggregates or a schema refuse
+    // them. An empty blob is stored like a marker.
+    pub fn write_raw(&self, bytes: &[u8]) -> Result<u64, FsError> {
+        let inspect = |_: &&[u8]| Err("raw messages can't be inspected".to_string());
+        self.measured(Operation::Write, || {
+            self.append_one_with(&bytes, None, inspect, |buf, bytes| {
+                buf.extend_from_slice(bytes);
+                Ok(())
+            })
+        })
+    }
+
+    fn append<T: Writable>(&self, messages: &[T], event_time: Option<u64>) -> Result<Vec<u64>, FsError> {
+        let codec = *self.codecs.current();
+        self.append_with(messages, event_time, inspect_writable, |buf, message| codec.serialize_into(buf, message))
+    }
+
+    fn append_one<T: Writable>(&self, message: &T, event_time: Option<u64>) -> Result<u64, FsError> {
+        let codec = *self.codecs.current();
+        self.append_one_with(message, event_time, inspect_writable, |buf, message| codec.serialize_into(buf, message))
+    }
+
+    fn append_with<T>(&self, messages: &[T], event_time: Option<u64>, inspect: impl Fn(&T) -> Result<Inspected, String>, encode: impl Fn(&mut Vec<u8>, &T) -> Result<(), FsError>) -> Result<Vec<u64>, FsError> {
+        self.check_writable()?;
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+        let values = self.inspect_all(messages, inspect)?;
+        let mut writer = self.writer.borrow_mut();
+        let (index_start, data_start) = (writer.index_block_offset(), writer.data_block_offset());
+        let mut staged = Vec::with_capacity(messages.len());
+        for (i, message) in messages.iter().enumerate() {
+            match self.stage(&mut writer, message, event_time, values.get(i), &encode, &staged) {
+                Ok(entry) => staged.push(entry),
+                Err(e) => return Err(self.abort_staged(&mut writer, index_start, data_start, staged.len() as u64, e)),
+            }
+        }
+        self.commit_staged(&mut writer, index_start, data_start, &mut staged, &values)?;
+        Ok((index_start..writer.index_block_offset()).collect())
+    }
+
+    // append_with for a single message, it stages its entry on the stack and returns the height itself.
+    // Once the scratch buffer has warmed up it doesn't allocate unless the topic deduplicates, compresses
+    // or inspects messages.
+    fn append_one_with<T>(&self, message: &T, event_time: Option<u64>, inspect: impl Fn(&T) -> Result<Inspected, String>, encode: impl Fn(&mut Vec<u8>, &T) -> Result<(), FsError>) -> Result<u64, FsError> {
+        self.check_writable()?;
+        let values = self.inspect_all(std::slice::from_ref(message), inspect)?;
+        let mut writer = self.writer.borrow_mut();
+        let (index_start, data_start) = (writer.index_block_offset(), writer.data_block_offset());
+        let mut staged = match self.stage(&mut writer, message, event_time, values.first(), &encode, &[]) {
+            Ok(entry) => [entry],
+            Err(e) => return Err(self.abort_staged(&mut writer, index_start, data_start, 0, e)),
+        };
+        self.commit_staged(&mut writer, index_start, data_start, &mut staged, &values)?;
+        Ok(index_start)
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if let Some(caller) = self.caller.get() {
+            self.guard(caller())?;
+        }
+        self.check_not_migrating()?;
+        self.check_not_importing()?;
+        self.check_not_streaming()
+    }
+
+    // Messages the aggregates or the schema can't inspect are rejected before anything is written.
+    fn inspect_all<T>(&self, messages: &[T], inspect: impl Fn(&T) -> Result<Inspected, String>) -> Result<Vec<Inspected>, FsError> {
+        if !self.inspects_messages() {
+            return Ok(Vec::new());
+        }
+        messages.iter().map(|message| inspect(message).map_err(|e| {
+            let e = FsError::Serialize(format!("Failed to inspect message: {}", e));
+            self.record_error("write", None, &e);
+            e
+        })).collect()
+    }
+
+    // Appends one message of a write, `batch` holds the messages of the write appended before it.
+    #[cfg_attr(not(feature = "schema"), allow(unused_variables))]
+    fn stage<T>(&self,
+                writer: &mut MemoryWriter,
+                message: &T,
+                event_time: Option<u64>,
+                value: Option<&Inspected>,
+                encode: &impl Fn(&mut Vec<u8>, &T) -> Result<(), FsError>,
+                batch: &[(IndexBlock, Option<Vec<u8>>)],
+    ) -> Result<(IndexBlock, Option<Vec<u8>>), FsError> {
+        let height = writer.index_block_offset();
+        let mut digest = None;
+        let written = writer.write_with(|buf| encode(buf, message), event_time, &self.storage, |payload| {
+            #[cfg(feature = "schema")]
+            if let (Some(schema), Some(value)) = (self.schema.borrow().as_ref(), value.filter(|_| !payload.is_empty())) {
+                schema.validate(value)?;
+            }
+            if let Some(deduplication) = self.deduplication.borrow().as_ref() {
+                digest = deduplication.check(payload)?;
+                // Earlier messages of the batch aren't recorded yet.
+                let batch_duplicate = batch.iter().find(|(_, staged)| staged.is_some() && *staged == digest);
+                if let (Some((idx, _)), DuplicateMode::Reject) = (batch_duplicate, deduplication.config().mode) {
+                    return Err(FsError::Duplicate { height: idx.height });
+                }
+            }
+            Ok(())
+        });
+        self.reader.invalidate_index(height);
+        let idx = written?;
+        let layout = &self.topic_header.layout;
+        let entry = layout.index_entry_offset(idx.height);
+        canary::check_canaries_near(layout, entry, entry + layout.index_entry_size(), &self.storage)?;
+        Ok((idx, digest))
+    }
+
+    // Drops a write that failed after `staged` of its messages were appended. A message that was appended
+    // before a canary check failed stays appended but uncommitted, like a single write. Otherwise the
+    // write is dropped.
+    fn abort_staged(&self, writer: &mut MemoryWriter, index_start: u64, data_start: u64, staged: u64, e: FsError) -> FsError {
+        if writer.index_block_offset() == index_start + staged {
+            writer.set_offsets(index_start, data_start);
+        }
+        self.record_error("write", Some(index_start + staged), &e);
+        e
+    }
+
+    // Whether writes have to inspect messages for the aggregates or the schema.
+    fn inspects_messages(&self) -> bool {
+        #[cfg(feature = "aggregates")]
+        if !self.aggregates.borrow().is_empty() {
+            return true;
+        }
+        #[cfg(feature = "schema")]
+        if self.schema.borrow().is_some() {
+            return true;
+        }
+        false
+    }
+
+    // A message written in chunks, for payloads too large to serialize in one go. Topics that compress,
+    // checksum, deduplicate or inspect messages can't take them.
+    pub fn begin_message(&self) -> Result<MessageWriter<'_, S>, FsError> {
+        if let Some(caller) = self.caller.get() {
+            self.guard(caller())?;
+        }
+        self.check_not_migrating()?;
+        self.check_not_importing()?;
+        self.check_not_streaming()?;
+        if self.inspects_messages() || self.deduplication.borrow().is_some() {
+            return Err(FsError::Unsupported("Streamed messages can't be deduplicated or inspected".to_string()));
+        }
+        let (idx, envelope, id) = self.writer.borrow_mut().begin_stream()?;
+        MessageWriter::new(self, idx, &envelope, id)
+    }
+
+    pub(crate) fn finish_message(&self, idx: IndexBlock, id: Option<u128>) -> Result<u64, FsError> {
+        self.measured(Operation::Write, || {
+            let mut writer = self.writer.borrow_mut();
+            let (index_start, data_start) = (writer.index_block_offset(), writer.data_block_offset());
+            let finished = writer.finish_stream(idx, id, &self.storage);
+            self.reader.invalidate_index(index_start);
+            let idx = finished.inspect_err(|e| self.record_error("write", Some(index_start), e))?;
+            self.commit_staged(&mut writer, index_start, data_start, &mut [(idx, None)], &[])?;
+            Ok(idx.height)
+        })
+    }
+
+    // Commits messages the writer appended after `index_start` and runs what follows a write.
+    #[cfg_attr(not(feature = "aggregates"), allow(unused_variables))]
+    fn commit_staged(&self, writer: &mut MemoryWriter, index_start: u64, data_start: u64, staged: &mut [(IndexBlock, Option<Vec<u8>>)], values: &[Inspected]) -> Result<(), FsError> {
+        // The data height goes first, one left ahead of the index height only leaks blocks.
+        let write_heights = || self.storage.try_write(DATA_BLOCK_HEIGHT_IDX, &writer.data_block_offset().to_le_bytes())
+            .and_then(|_| self.storage.try_write(INDEX_HEIGHT_IDX, &writer.index_block_offset().to_le_bytes()));
+        // Accumulators are persisted first, so a batch is folded exactly when it is committed.
+        #[cfg(feature = "aggregates")]
+        let folded = self.fold_aggregates(staged, values);
+        #[cfg(feature = "aggregates")]
+        let committed = folded.as_ref().map_err(FsError::clone).and_then(|_| write_heights());
+        #[cfg(not(feature = "aggregates"))]
+        let committed = write_heights();
+        if let Err(e) = committed {
+            #[cfg(feature = "aggregates")]
+            if let Ok(Some((_, changed))) = &folded {
+                self.restore_accumulators(changed);
+            }
+            writer.set_offsets(index_start, data_start);
+            self.record_error("write", Some(index_start), &e);
+            return Err(e);
+        }
+        #[cfg(feature = "aggregates")]
+        if let Ok(Some((aggregates, _))) = folded {
+            *self.aggregates.borrow_mut() = aggregates;
+        }
+        self.committed_height.set(writer.index_block_offset());
+        self.record_segments(|manifests| staged.iter().for_each(|(idx, _)| manifests.record(idx)));
+        self.record_usage(|usage| staged.iter().for_each(|(idx, _)| usage.record_write(idx.data_size)));
+        for (idx, digest) in staged {
+            debug!("Wrote topic_message at index {:?}", idx);
+            self.counters.borrow_mut().bytes_written += idx.data_size;
+            let breaches = self.alarms.borrow_mut().record_write((self.clock)(), idx.data_size);
+            self.record_breaches(breaches);
+            if let Some(digest) = digest.take() {
+                self.record_digest(idx.height, digest);
+            }
+        }
+        Ok(())
+    }
+
+    // Prometheus exposition text, e.g. to serve from a canister's http_request as /metrics.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_text(&self) -> String {
+        let alloc_stats = self.alloc_stats();
+        let meta_stats = self.meta.stats();
+        let counters = *self.counters.borrow();
+        let block_cache = self.block_cache_stats();
+        let histograms: Vec<_> = self.histograms.borrow().iter().map(|(operation, histogram)| (*operation, histogram.clone())).collect();
+        let mut stable_store_size = [0u8; 8];
+        self.storage.read(FREE_MEMORY_BLOCK_SIZE_IDX, &mut stable_store_size);
+        let (segments_compacted, segments_pending) = self.compaction_segments();
+
+        MetricsText::new(&self.topic_header.event_stream_name)
+            .gauge("topic_height", "Messages written to the topic.", self.get_topic_height())
+            .gauge("data_blocks", "Data blocks in use.", read_data_block_height(&self.storage))
+            .gauge("data_bytes", "Bytes of stable memory used by the data zone.", read_data_block_height(&self.storage) * self.topic_header.layout.block_size)
+            .gauge("index_capacity", "Messages the index zone can hold.", self.topic_header.layout.max_index_entries())
+            .gauge("stable_store_bytes", "Size of the last stable_store value.", u64::from_le_bytes(stable_store_size))
+            .gauge("meta_allocated_bytes", "Bytes allocated in the meta zone.", meta_stats.allocated_bytes)
+            .gauge("meta_free_bytes", "Bytes still available in the meta zone.", meta_stats.free_list_bytes + meta_stats.unallocated_bytes)
+            .gauge("scratch_capacity_bytes", "Capacity of the write serialization buffer.", alloc_stats.scratch_capacity)
+            .gauge("compaction_segments_compacted", "Segments the running compaction has scanned.", segments_compacted)
+            .gauge("compaction_segments_pending", "Segments the running compaction has yet to scan.", segments_pending)
+            .gauge("last_compaction_height", "Topic height the last completed compaction left.", self.last_compaction_height)
+            .counter("messages_written", "Messages written since the topic was opened.", alloc_stats.writes)
+            .counter("bytes_written", "Payload bytes written since the topic was opened.", counters.bytes_written)
+            .counter("errors", "Failed reads and writes since the topic was opened.", counters.errors)
+            .counter("alarm_breaches", "Alarm breaches since the topic was opened.", counters.alarm_breaches)
+            .counter("block_cache_hits", "Data blocks read from the block cache.", block_cache.hits)
+            .counter("block_cache_misses", "Data blocks read from stable memory.", block_cache.misses)
+            .histogram("instructions", "Instructions per operation since the topic was opened.", &histograms)
+            .finish()
+    }
+
+    // Segment manifests below and past what the running compaction has scanned, without manifests the
+    // topic counts as one segment.
+    #[cfg(feature = "metrics")]
+    fn compaction_segments(&self) -> (u64, u64) {
+        let Some(compaction) = &self.compaction else {
+            return (0, 0);
+        };
+        match self.segments.borrow().as_ref() {
+            Some(manifests) => {
+                let segments = manifests.segments();
+                let compacted = segments.iter().filter(|segment| segment.heights().end <= compaction.scanned).count();
+                (compacted as u64, (segments.len() - compacted) as u64)
+            }
+            None => (0, 1),
+        }
+    }
+
+    // Zone usage, meta zone fragmentation, pending compaction work and the error journal in one report.
+    // Walks the meta zone's chunks, so it costs more than metrics_text's counters.
+    pub fn health(&self) -> HealthReport {
+        let layout = &self.topic_header.layout;
+        let meta_stats = self.meta.stats();
+        let mut stable_store_size = [0u8; 8];
+        self.storage.read(FREE_MEMORY_BLOCK_SIZE_IDX, &mut stable_store_size);
+        HealthReport {
+            stable_store: ZoneUsage {
+                used: u64::from_le_bytes(stable_store_size) + self.regions.borrow().reserved(),
+                capacity: layout.stable_store_max_size(),
+            },
+            meta: ZoneUsage { used: meta_stats.allocated_bytes, capacity: meta_stats.region_size },
+            meta_fragmentation: health::meta_fragmentation(&meta_stats),
+            meta_free_chunks: meta_stats.free_chunks,
+            index_slots: ZoneUsage { used: self.get_topic_height(), capacity: layout.max_index_entries() },
+            data_bytes: read_data_block_height(&self.storage) * layout.block_size,
+            compaction_debt: self.topic_header.first_message_ptr,
+            pending_migration: self.check_not_migrating().err().map(|e| e.to_string()),
+            errors: ErrorSummary::new(self.journal.borrow().entries().cloned()),
+        }
+    }
+
+    // Whether producers should hold back, cheap enough to poll before every batch. Zones and alarm windows
+    // are held against the watermarks, anything that rejects writes is Hard.
+    pub fn pressure(&self) -> Pressure {
+        let layout = &self.topic_header.layout;
+        let meta_stats = self.meta.stats();
+        let now = (self.clock)();
+        let mut causes = vec![
+            PressureCause::IndexSlots(ZoneUsage { used: self.get_topic_height(), capacity: layout.max_index_entries() }),
+            PressureCause::Meta(ZoneUsage { used: meta_stats.allocated_bytes, capacity: meta_stats.region_size }),
+        ];
+        for (kind, observed, threshold) in self.alarms.borrow().usage(now) {
+            causes.push(PressureCause::Alarm { kind, observed, threshold });
+        }
+        if let Err(e) = self.check_not_migrating() {
+            causes.push(PressureCause::Blocked(e.to_string()));
+        }
+        if let Some(job) = self.imports.borrow().job.as_ref().filter(|job| job.expires_at > now) {
+            causes.push(PressureCause::Blocked(format!("Import {} owns the end of the topic", job.id)));
+        }
+        Pressure::new(&self.watermarks.get(), causes)
+    }
+
+    pub fn set_watermarks(&self, watermarks: Watermarks) -> Result<(), FsError> {
+        watermarks.validate()?;
+        self.meta.put_value(WATERMARKS_RECORD, &watermarks)?;
+        self.watermarks.set(watermarks);
+        Ok(())
+    }
+
+    pub fn watermarks(&self) -> Watermarks {
+        self.watermarks.get()
+    }
+
+    // Counting usage costs a meta zone write per committed batch and per error.
+    pub fn set_telemetry(&self, enabled: bool) -> Result<(), FsError> {
+        let usage = match (enabled, *self.usage.borrow()) {
+            (true, Some(usage)) => Some(usage),
+            (true, None) => Some(UsageCounters::new((self.clock)())),
+            (false, _) => None,
+        };
+        self.meta.put_value(TELEMETRY_RECORD, &usage)?;
+        *self.usage.borrow_mut() = usage;
+        Ok(())
+    }
+
+    pub fn telemetry(&self) -> Telemetry {
+        Telemetry {
+            binary_version: self.topic_header.binary_version,
+            hash_algorithm: self.topic_header.hash_algorithm().unwrap_or_default(),
+            codec: self.codec(),
+            compression: self.compression(),
+            checksums: self.checksums(),
+            event_times: self.event_times(),
+            message_ids: self.message_ids(),
+            deduplication: self.deduplication.borrow().is_some(),
+            #[cfg(feature = "schema")]
+            schema: self.schema.borrow().is_some(),
+            #[cfg(not(feature = "schema"))]
+            schema: false,
+            #[cfg(feature = "aggregates")]
+            aggregates: !self.aggregates.borrow().is_empty(),
+            #[cfg(not(feature = "aggregates"))]
+            aggregates: false,
+            heat_map: self.heat_map.borrow().is_some(),
+            retention: self.retention.get(),
+            access_control: self.caller.get().is_some(),
+            controllers: self.controllers().len() as u64,
+            subscribers: self.subscribers.borrow().subscribers().len() as u64,
+            cursors: self.cursors.borrow().len() as u64,
+            registers: self.topic_header.registers.len() as u64,
+            regions: self.regions.borrow().regions().len() as u64,
+            usage: *self.usage.borrow(),
+        }
+    }
+
+    // Best effort like the error journal, counting mustn't fail what is being counted.
+    fn record_usage(&self, update: impl FnOnce(&mut UsageCounters)) {
+        let mut usage = self.usage.borrow_mut();
+        if let Some(counters) = usage.as_mut() {
+            update(counters);
+            if let Err(e) = self.meta.put_value(TELEMETRY_RECORD, &*usage) {
+                debug!("Failed to save usage counters: {}", e);
+            }
+        }
+    }
+
+    // Lets callers confirm the scratch buffer stopped growing. Single message writes then don't allocate
+    // unless the topic deduplicates, compresses or inspects messages, batches allocate their heights.
+    pub fn alloc_stats(&self) -> AllocStats {
+        self.writer.borrow().alloc_stats()
+    }
+
+    // Data blocks served from memory instead of stable reads, counted per block.
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.reader.block_cache_stats()
+    }
+
+    // The codec new messages are written with.
+    pub fn codec(&self) -> BincodeCodec {
+        *self.codecs.current()
+    }
+
+    // Every codec change with the height it took effect at, oldest first.
+    pub fn codec_history(&self) -> Vec<(u64, BincodeCodec)> {
+        self.codecs.changes().to_vec()
+    }
+
+    // Takes effect from the current height. Messages already written keep being read with the codec
+    // they were written with, so the encoding can change on a topic that has messages.
+    pub fn set_codec(&mut self, codec: BincodeCodec) -> Result<(), FsError> {
+        let mut codecs = self.codecs.clone();
+        codecs.set(self.get_topic_height(), codec);
+        self.meta.put_value(CODEC_HISTORY_RECORD, &codecs)?;
+        self.apply_codecs(codecs);
+        Ok(())
+    }
+
+    fn apply_codecs(&mut self, codecs: SettingsHistory<BincodeCodec>) {
+        self.writer.get_mut().set_codec(*codecs.current());
+        self.reader.set_codecs(codecs.clone());
+        self.codecs = codecs;
+    }
+
+    // Messages written while enabled carry an event time, write_topic_message uses the ingestion time
+    // for it. Messages already written keep whatever they were written with.
+    pub fn set_event_times(&mut self, enabled: bool) -> Result<(), FsError> {
+        if *self.event_times.current() == enabled {
+            return Ok(());
+        }
+        let mut event_times = self.event_times.clone();
+        event_times.set(self.get_topic_height(), enabled);
+        self.meta.put_value(EVENT_TIMES_RECORD, &event_times)?;
+        self.apply_event_times(event_times);
+        Ok(())
+    }
+
+    pub fn event_times(&self) -> bool {
+        *self.event_times.current()
+    }
+
+    fn apply_event_times(&mut self, event_times: SettingsHistory<bool>) {
+        self.writer.get_mut().set_event_times(*event_times.current());
+        self.reader.set_event_times(event_times.clone());
+        self.event_times = event_times;
+    }
+
+    // Messages written while enabled carry an id from the id generator, ulid_id unless replaced, so
+    // other systems can refer to them without the topic and height. Markers get none.
+    pub fn set_message_ids(&mut self, enabled: bool) -> Result<(), FsError> {
+        if *self.message_ids.current() == enabled {
+            return Ok(());
+        }
+        let mut message_ids = self.message_ids.clone();
+        message_ids.set(self.get_topic_height(), enabled);
+        self.meta.put_value(MESSAGE_IDS_RECORD, &message_ids)?;
+        self.apply_message_ids(message_ids)
+    }
+
+    pub fn message_ids(&self) -> bool {
+        *self.message_ids.current()
+    }
+
+    // Not persisted, set it again after every open. Ids already written are kept.
+    pub fn set_id_generator(&mut self, generator: IdGenerator) {
+        self.writer.get_mut().set_id_generator(generator);
+    }
+
+    fn apply_message_ids(&mut self, message_ids: SettingsHistory<bool>) -> Result<(), FsError> {
+        self.reader.set_ids(message_ids.clone());
+        self.message_ids = message_ids;
+        let last_id = self.last_message_id()?;
+        self.writer.get_mut().set_ids(*self.message_ids.current(), last_id.unwrap_or(0));
+        Ok(())
+    }
+
+    // The id of the latest message that has one, markers in between are skipped.
+    fn last_message_id(&self) -> Result<Option<u128>, FsError> {
+        let first = self.topic_header.first_message_ptr;
+        for height in (first..self.get_topic_height()).rev() {
+            if !*self.message_ids.at(height) {
+                break;
+            }
+            if let Some(id) = self.reader.read_id(height, &self.storage)? {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    // None for markers and messages written while message ids were disabled.
+    pub fn message_id(&self, height: u64) -> Result<Option<u128>, FsError> {
+        self.check_written(height, 1)?;
+        self.reader.read_id(height, &self.storage)
+    }
+
+    // Height of the message with the id. Ids increase with height, so it is a binary search that steps
+    // over markers and messages without an id.
+    pub fn find_by_id(&self, id: u128) -> Result<Option<u64>, FsError> {
+        self.check_not_migrating()?;
+        let (mut low, mut high) = (self.topic_header.first_message_ptr, self.committed_height.get());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let mut probe = middle;
+            let mut found = None;
+            while probe < high && found.is_none() {
+                found = self.reader.read_id(probe, &self.storage)?;
+                probe += 1;
+            }
+            match found {
+                Some(found) if found == id => return Ok(Some(probe - 1)),
+                Some(found) if found < id => low = probe,
+                _ => high = middle,
+            }
+        }
+        Ok(None)
+    }
+
+    // Compresses the payloads of messages written from now on. Messages already written keep being read
+    // the way they were stored, so compression can be turned on and off on a topic that has messages.
+    pub fn set_compression(&mut self, compression: PayloadCompression) -> Result<(), FsError> {
+        compression.validate()?;
+        if *self.compressions.current() == compression {
+            return Ok(());
+        }
+        let mut compressions = self.compressions.clone();
+        compressions.set(self.get_topic_height(), compression);
+        self.meta.put_value(COMPRESSION_HISTORY_RECORD, &compressions)?;
+        self.apply_compressions(compressions);
+        Ok(())
+    }
+
+    pub fn compression(&self) -> PayloadCompression {
+        *self.compressions.current()
+    }
+
+    fn apply_compressions(&mut self, compressions: SettingsHistory<PayloadCompression>) {
+        self.writer.get_mut().set_compression(*compressions.current());
+        self.reader.set_compressions(compressions.clone());
+        self.compressions = compressions;
+    }
+
+    // Messages written while enabled carry a CRC32 of their payload, which every read verifies. Needs the
+    // crc32 feature. Messages already written keep whatever they were written with.
+    pub fn set_checksums(&mut self, enabled: bool) -> Result<(), FsError> {
+        if *self.checksums.current() == enabled {
+            return Ok(());
+        }
+        if enabled {
+            hash::crc32(&[])?;
+        }
+        let mut checksums = self.checksums.clone();
+        checksums.set(self.get_topic_height(), enabled);
+        self.meta.put_value(CHECKSUMS_RECORD, &checksums)?;
+        self.apply_checksums(checksums);
+        Ok(())
+    }
+
+    pub fn checksums(&self) -> bool {
+        *self.checksums.current()
+    }
+
+    fn apply_checksums(&mut self, checksums: SettingsHistory<bool>) {
+        self.writer.get_mut().set_checksums(*checksums.current());
+        self.reader.set_checksums(checksums.clone());
+        self.checksums = checksums;
+    }
+
+    // Reads every committed message and checks its index entry and, where it has one, its checksum. Each
+    // message is read from stable memory, so on large topics call it from an update call with plenty of
+    // instructions left, or from a heartbeat.
+    pub fn verify_all(&self) -> Result<CorruptionReport, FsError> {
+        self.check_not_migrating()?;
+        let mut report = CorruptionReport::default();
+        for height in self.topic_header.first_message_ptr..self.committed_height.get() {
+            report.scanned += 1;
+            let verified = self.reader.read_raw_payload(height, &self.storage)
+                .and_then(|payload| self.reader.verify_checksum(height, &payload));
+            match verified {
+                Ok(true) => report.verified += 1,
+                Ok(false) => report.unchecked += 1,
+                Err(e @ (FsError::CorruptData { .. } | FsError::CorruptIndex { .. })) => {
+                    self.journal_error("verify", Some(height), &e);
+                    report.corrupt.push((height, e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(report)
+    }
+
+    // Folds every message written from now on into `name`. An accumulator persisted under the same name
+    // is resumed, so registering again after an upgrade continues where it left off, `initial` is used
+    // otherwise. Markers are not folded.
+    #[cfg(feature = "aggregates")]
+    pub fn register_aggregate(&self, name: &str, initial: Value, fold: FoldFn) -> Result<(), FsError> {
+        let record = format!("{}{}", AGGREGATE_RECORD_PREFIX, name);
+        let accumulator = match self.meta.get_value::<String>(&record)? {
+            Some(json) => serde_json::from_str(&json).map_err(|e| FsError::Deserialize(format!("meta record {}: {}", record, e)))?,
+            None => initial,
+        };
+        self.aggregates.borrow_mut().register(name, fold, accumulator);
+        Ok(())
+    }
+
+    #[cfg(feature = "aggregates")]
+    pub fn aggregate(&self, name: &str) -> Option<Value> {
+        self.aggregates.borrow().get(name).cloned()
+    }
+
+    // Folds a staged batch into a copy of the aggregates and persists the accumulators it changed,
+    // returning the copy and their names. A failed write puts back the ones written before it.
+    #[cfg(feature = "aggregates")]
+    fn fold_aggregates(&self, staged: &[(IndexBlock, Option<Vec<u8>>)], values: &[Value]) -> Result<Option<(Aggregates, Vec<String>)>, FsError> {
+        let current = self.aggregates.borrow();
+        if current.is_empty() {
+            return Ok(None);
+        }
+        let mut folded = current.clone();
+        for (_, value) in staged.iter().zip(values).filter(|((idx, _), _)| !idx.is_marker()) {
+            folded.apply(value);
+        }
+        let changed = folded.changed_from(&current);
+        for (i, name) in changed.iter().enumerate() {
+            if let Err(e) = self.save_accumulator(name, folded.get(name)) {
+                self.restore_accumulators(&changed[..i]);
+                return Err(e);
+            }
+        }
+        Ok(Some((folded, changed)))
+    }
+
+    #[cfg(feature = "aggregates")]
+    fn save_accumulator(&self, name: &str, accumulator: Option<&Value>) -> Result<(), FsError> {
+        let accumulator = accumulator.map(Value::to_string).unwrap_or_default();
+        self.meta.put_value(&format!("{}{}", AGGREGATE_RECORD_PREFIX, name), &accumulator)
+    }
+
+    // Best effort, the batch that changed them failed anyway.
+    #[cfg(feature = "aggregates")]
+    fn restore_accumulators(&self, names: &[String]) {
+        let aggregates = self.aggregates.borrow();
+        for name in names {
+            let _ = self.save_accumulator(name, aggregates.get(name));
+        }
+    }
+
+    // Every message written from now on has to conform to `schema`, None stops validating. Messages
+    // already in the topic aren't checked. Markers are never validated.
+    #[cfg(feature = "schema")]
+    pub fn set_schema(&self, schema: Option<MessageSchema>) -> Result<(), FsError> {
+        self.meta.put_value(SCHEMA_RECORD, &schema.as_ref().map(MessageSchema::source))?;
+        *self.schema.borrow_mut() = schema;
+        Ok(())
+    }
+
+    #[cfg(feature = "schema")]
+    pub fn schema(&self) -> Option<MessageSchema> {
+        self.schema.borrow().clone()
+    }
+
+    pub fn set_alarm(&self, kind: AlarmKind, threshold: u64, callback: AlarmCallback) {
+        self.alarms.borrow_mut().set(kind, threshold, callback);
+    }
+
+    pub fn clear_alarm(&self, kind: AlarmKind) {
+        self.alarms.borrow_mut().clear(kind);
+    }
+
+    // Most recent storage-level events, oldest first. Kept in the meta zone and capped at MAX_ADMIN_EVENTS.
+    pub fn admin_events(&self) -> Vec<EventFilesystemEvent> {
+        self.admin_events.borrow().clone()
+    }
+
+    // zstd compresses payloads written from now on with `dictionary` and names it in their header. The
+    // 512 byte topic header can't hold dictionaries, so they are kept in the meta zone, earlier ones too
+    // so what was compressed with them stays readable.
+    pub fn set_compression_dictionary(&mut self, dictionary: &CompressionDictionary) -> Result<(), FsError> {
+        let mut dictionaries = self.reader.dictionaries().to_vec();
+        if dictionaries.iter().any(|known| known.id == dictionary.id && known.bytes != dictionary.bytes) {
+            return Err(FsError::InvalidArgument(format!("Another dictionary with id {} is in use", dictionary.id)));
+        }
+        dictionaries.retain(|known| known.id != dictionary.id);
+        dictionaries.push(dictionary.clone());
+        self.meta.put_value(COMPRESSION_DICTIONARIES_RECORD, &dictionaries)?;
+        self.apply_dictionaries(dictionaries);
+        Ok(())
+    }
+
+    pub fn compression_dictionary(&self) -> Result<Option<CompressionDictionary>, FsError> {
+        Ok(self.reader.dictionaries().last().cloned())
+    }
+
+    fn apply_dictionaries(&mut self, dictionaries: Vec<CompressionDictionary>) {
+        self.writer.get_mut().set_dictionary(dictionaries.last().cloned());
+        self.reader.set_dictionaries(dictionaries);
+    }
+
+    // Checks every payload written from now on against the most recent ones, which are read back from
+    // the topic when enabling and on every open.
+    pub fn enable_deduplication(&self, config: DeduplicationConfig) -> Result<(), FsError> {
+        let deduplication = self.load_deduplication(config)?;
+        self.meta.put_value(DEDUPLICATION_RECORD, &Some(config))?;
+        *self.deduplication.borrow_mut() = Some(deduplication);
+        Ok(())
+    }
+
+    pub fn disable_deduplication(&self) -> Result<(), FsError> {
+        *self.deduplication.borrow_mut() = None;
+        self.meta.put_value(DEDUPLICATION_RECORD, &None::<DeduplicationConfig>)
+    }
+
+    pub fn deduplication(&self) -> Option<DeduplicationConfig> {
+        self.deduplication.borrow().as_ref().map(Deduplication::config)
+    }
+
+    fn load_deduplication(&self, config: DeduplicationConfig) -> Result<Deduplication, FsError> {
+        let mut deduplication = Deduplication::new(config, self.hasher()?)?;
+        let mut recent = Vec::new();
+        // Payloads can't be read while the data zone moves, the window is rebuilt once it's done.
+        let height = if self.check_not_migrating().is_err() { 0 } else { self.get_topic_height() };
+        for height in (0..height).rev() {
+            if recent.len() as u64 == config.window {
+                break;
+            }
+            let payload = self.reader.read_payload(height, &self.storage)?;
+            if let Some(digest) = deduplication.digest(&payload) {
+                recent.push((height, digest));
+            }
+        }
+        for (height, digest) in recent.into_iter().rev() {
+            deduplication.record(height, digest);
+        }
+        Ok(deduplication)
+    }
+
+    fn record_digest(&self, height: u64, digest: Vec<u8>) {
+        let duplicate_of = self.deduplication.borrow_mut().as_mut().and_then(|deduplication| deduplication.record(height, digest));
+        if let Some(duplicate_of) = duplicate_of {
+            self.record_admin_event(EventFilesystemEvent::DuplicateWritten(DuplicateWritten { height, duplicate_of, timestamp: (self.clock)() }));
+        }
+    }
+
+    // Starts counting reads per `segment_size` heights. Re-enabling with the same size keeps the counts.
+    pub fn enable_heat_map(&self, segment_size: u64) -> Result<(), FsError> {
+        let mut heat_map = self.heat_map.borrow_mut();
+        if heat_map.as_ref().map(|heat| heat.segment_size()) != Some(segment_size) {
+            *heat_map = Some(HeatMap::new(segment_size)?);
+        }
+        self.meta.put_value(HEAT_MAP_RECORD, &*heat_map)
+    }
+
+    pub fn disable_heat_map(&self) -> Result<(), FsError> {
+        *self.heat_map.borrow_mut() = None;
+        self.meta.put_value(HEAT_MAP_RECORD, &None::<HeatMap>)
+    }
+
+    // Segments read since the heat map was enabled, empty when it is disabled.
+    pub fn heat_map(&self) -> Vec<HeatMapSegment> {
+        self.heat_map.borrow().as_ref().map(HeatMap::segments).unwrap_or_default()
+    }
+
+    // Reads happen in query calls whose writes are discarded, so counts are only persisted here.
+    // Call it from a heartbeat or pre_upgrade to keep them across upgrades.
+    pub fn save_heat_map(&self) -> Result<(), FsError> {
+        self.meta.put_value(HEAT_MAP_RECORD, &*self.heat_map.borrow())
+    }
+
+    // Summaries of the topic for fleet monitoring, see report_stats. Reconfiguring keeps the summaries
+    // waiting for delivery unless the outbox is switched off.
+    pub fn enable_stats_reports(&self, config: StatsReportConfig) -> Result<(), FsError> {
+        let mut reports = self.stats_reports.borrow().clone();
+        match reports.as_mut() {
+            Some(reports) => reports.reconfigure(config)?,
+            None => reports = Some(StatsReports::new(config)?),
+        }
+        self.meta.put_value(STATS_REPORTS_RECORD, &reports)?;
+        *self.stats_reports.borrow_mut() = reports;
+        Ok(())
+    }
+
+    // Drops the summaries waiting for delivery.
+    pub fn disable_stats_reports(&self) -> Result<(), FsError> {
+        self.meta.put_value(STATS_REPORTS_RECORD, &None::<StatsReports>)?;
+        *self.stats_reports.borrow_mut() = None;
+        Ok(())
+    }
+
+    pub fn stats_report_config(&self) -> Option<StatsReportConfig> {
+        self.stats_reports.borrow().as_ref().map(StatsReports::config)
+    }
+
+    // Call it from a timer or heartbeat. Once the interval has passed it appends a summary to `ops`, if
+    // given, and queues it in the outbox if that is enabled. Returns the summary, None when none was due.
+    pub fn report_stats<A: BlockStorage>(&self, ops: Option<&EventFilesystem<A>>) -> Result<Option<StatsSummary>, FsError> {
+        let now = (self.clock)();
+        let Some(mut reports) = self.stats_reports.borrow().clone().filter(|reports| reports.is_due(now)) else {
+            return Ok(None);
+        };
+        let counters = *self.counters.borrow();
+        let summary = StatsSummary {
+            sequence: reports.next_sequence(),
+            topic: self.topic_header.event_stream_name.clone(),
+            timestamp: now,
+            height: self.committed_height.get(),
+            first_message: self.first_message_height(),
+            data_bytes: read_data_block_height(&self.storage) * self.topic_header.layout.block_size,
+            bytes_written: counters.bytes_written,
+            errors: counters.errors,
+        };
+        if let Some(ops) = ops {
+            ops.write_topic_message(&summary)?;
+        }
+        reports.record(&summary);
+        self.meta.put_value(STATS_REPORTS_RECORD, &Some(&reports))?;
+        *self.stats_reports.borrow_mut() = Some(reports);
+        Ok(Some(summary))
+    }
+
+    // Up to `max` summaries waiting for delivery, oldest first. Acknowledge them once delivered.
+    pub fn pending_stats(&self, max: usize) -> Vec<StatsSummary> {
+        self.stats_reports.borrow().as_ref().map(|reports| reports.pending(max)).unwrap_or_default()
+    }
+
+    // Drops the summaries up to and including `sequence` from the outbox, returns how many.
+    pub fn acknowledge_stats(&self, sequence: u64) -> Result<usize, FsError> {
+        let Some(mut reports) = self.stats_reports.borrow().clone() else {
+            return Ok(0);
+        };
+        let delivered = reports.acknowledge(sequence);
+        if delivered > 0 {
+            self.meta.put_value(STATS_REPORTS_RECORD, &Some(&reports))?;
+            *self.stats_reports.borrow_mut() = Some(reports);
+        }
+        Ok(delivered)
+    }
+
+    // Describes segments of `segment_size` heights, starting with the next segment. Re-enabling with the
+    // same size keeps the manifests. Each commit then saves them, keep segments large enough for the
+    // record to stay small.
+    pub fn enable_segment_manifests(&self, segment_size: u64) -> Result<(), FsError> {
+        let mut segments = self.segments.borrow_mut();
+        if segments.as_ref().map(SegmentManifests::segment_size) != Some(segment_size) {
+            *segments = Some(SegmentManifests::new(segment_size, self.committed_height.get())?);
+        }
+        self.meta.put_value(SEGMENTS_RECORD, &*segments)
+    }
+
+    pub fn disable_segment_manifests(&self) -> Result<(), FsError> {
+        *self.segments.borrow_mut() = None;
+        self.meta.put_value(SEGMENTS_RECORD, &None::<SegmentManifests>)
+    }
+
+    // Ascending, the last one may still be filling. Empty while the manifests are disabled.
+    pub fn segment_manifests(&self) -> Vec<SegmentManifest> {
+        self.segments.borrow().as_ref().map(|manifests| manifests.segments().to_vec()).unwrap_or_default()
+    }
+
+    // Height ranges of the topic within `heights` that may hold messages ingested within `time` and
+    // written under `key`. Heights no manifest describes are kept, all of them while disabled.
+    pub fn prune_segments(&self, heights: Range<u64>, time: Option<Range<u64>>, key: Option<&[u8]>) -> Vec<Range<u64>> {
+        let heights = heights.start.max(self.first_message_height())..heights.end.min(self.committed_height.get());
+        match self.segments.borrow().as_ref() {
+            Some(manifests) => manifests.candidates(heights, time.as_ref(), key),
+            None => Some(heights).filter(|heights| !heights.is_empty()).into_iter().collect(),
+        }
+    }
+
+    fn record_segments(&self, update: impl FnOnce(&mut SegmentManifests)) {
+        let mut segments = self.segments.borrow_mut();
+        if let Some(manifests) = segments.as_mut() {
+            update(manifests);
+            if let Err(e) = self.meta.put_value(SEGMENTS_RECORD, &*segments) {
+                debug!("Failed to save segment manifests: {}", e);
+            }
+        }
+    }
+
+    fn record_reads(&self, start: u64, count: u64) {
+        if let Some(heat_map) = self.heat_map.borrow_mut().as_mut() {
+            heat_map.record(start, count);
+        }
+    }
+
+    fn record_error(&self, operation: &str, height: Option<u64>, error: &FsError) {
+        self.counters.borrow_mut().errors += 1;
+        self.record_usage(|usage| usage.errors += 1);
+        let breaches = self.alarms.borrow_mut().record_error((self.clock)());
+        self.record_breaches(breaches);
+        self.journal_error(operation, height, error);
+    }
+
+    // Best effort, a journal that can't be saved mustn't hide the error being journaled.
+    fn journal_error(&self, operation: &str, height: Option<u64>, error: &FsError) {
+        let mut journal = self.journal.borrow_mut();
+        journal.push(JournalEntry { timestamp: (self.clock)(), operation: operation.to_string(), height, error: error.clone() });
+        if let Err(e) = self.meta.put_value(ERROR_JOURNAL_RECORD, &*journal) {
+            debug!("Failed to save the error journal: {}", e);
+        }
+    }
+
+    // The last errors of failed reads, writes and integrity checks, oldest first. Kept across upgrades.
+    pub fn recent_errors(&self) -> Vec<JournalEntry> {
+        self.journal.borrow().entries().cloned().collect()
+    }
+
+    fn record_breaches(&self, breaches: Vec<AlarmBreach>) {
+        self.counters.borrow_mut().alarm_breaches += breaches.len() as u64;
+        for breach in breaches {
+            self.record_admin_event(EventFilesystemEvent::AlarmTriggered(breach));
+        }
+    }
+
+    // Best effort like the error journal, an event that can't be saved is still kept until the topic closes.
+    fn record_admin_event(&self, event: EventFilesystemEvent) {
+        let mut events = self.admin_events.borrow_mut();
+        events.push(event);
+        let overflow = events.len().saturating_sub(MAX_ADMIN_EVENTS);
+        events.drain(..overflow);
+        if let Err(e) = self.meta.put_value(ADMIN_EVENTS_RECORD, &*events) {
+            debug!("Failed to save the admin events: {}", e);
+        }
+    }
+
+    // Keeps nothing unless the events were saved.
+    fn save_admin_events(&self, new_events: Vec<EventFilesystemEvent>) -> Result<(), FsError> {
+        let mut events = self.admin_events.borrow().clone();
+        events.extend(new_events);
+        let overflow = events.len().saturating_sub(MAX_ADMIN_EVENTS);
+        events.drain(..overflow);
+        self.meta.put_value(ADMIN_EVENTS_RECORD, &events)?;
+        *self.admin_events.borrow_mut() = events;
+        Ok(())
+    }
+
+    pub fn read_topic_messages<T : DeserializeOwned>(&self, start: u64, take: u64) -> Result<Vec<T>, FsError> {
+        self.measured(Operation::ReadRange, || {
+            self.check_written(start, take)?;
+            let messages = self.reader.read_range::<T>(start, take, &self.storage)?;
+            self.record_reads(start, take);
+            Ok(messages)
+        })
+    }
+
+    // Messages newest first, e.g. for most recent first activity feeds. Markers are skipped.
+    pub fn iter_rev<T: DeserializeOwned>(&self) -> ReverseMessages<'_, T, S> {
+        ReverseMessages::new(self)
+    }
+
+    // The last `n` messages that aren't markers with their heights, newest first.
+    pub fn read_last<T: DeserializeOwned>(&self, n: u64) -> Result<Vec<(u64, T)>, FsError> {
+        self.iter_rev().take(n.try_into().unwrap_or(usize::MAX)).collect()
+    }
+
+    // Reads like read_topic_messages and records `caller` reading the range in `log`, subject to its
+    // sampling. Reads that fail aren't logged.
+    #[cfg(feature = "access-log")]
+    pub fn read_topic_messages_logged<T: DeserializeOwned, A: BlockStorage>(&self, caller: Principal, start: u64, take: u64, log: &AccessLog<A>) -> Result<Vec<T>, FsError> {
+        let messages = self.read_topic_messages(start, take)?;
+        log.record(caller, start..start.saturating_add(take))?;
+        Ok(messages)
+    }
+
+    fn measured<R>(&self, operation: Operation, f: impl FnOnce() -> R) -> R {
+        let counter = match self.instruction_counter {
+            Some(counter) => counter,
+            None => return f(),
+        };
+        let start = counter();
+        let result = f();
+        let instructions = counter().saturating_sub(start);
+        self.histograms.borrow_mut().entry(operation).or_default().record(instructions);
+        result
+    }
+
+    // Instructions per call of `operation` since the topic was opened, None without an instruction
+    // counter or before the first call.
+    pub fn instruction_histogram(&self, operation: Operation) -> Option<InstructionHistogram> {
+        self.histograms.borrow().get(&operation).cloned()
+    }
+
+    // Appends a payload-free event such as a heartbeat or settlement checkpoint. It uses no data
+    // blocks but gets a height, timestamp and index entry like any other message, and becomes the
+    // latest marker of its kind.
+    pub fn write_marker(&self, kind: &str) -> Result<u64, FsError> {
+        let height = self.write_topic_message(&())?;
+        let mut markers = self.markers.borrow_mut();
+        markers.insert(kind.to_string(), height);
+        self.meta.put_value(MARKERS_RECORD, &*markers)?;
+        Ok(height)
+    }
+
+    // Maps heights a message had in `source`, e.g. the topic or shard it was imported from, to its local
+    // height so references from upstream keep resolving. Saved once per call, so record imports in batches.
+    pub fn record_aliases(&self, source: &str, aliases: impl IntoIterator<Item = (u64, u64)>) -> Result<(), FsError> {
+        let height = self.committed_height.get();
+        let mut table = self.aliases.borrow().clone();
+        for (source_height, local_height) in aliases {
+            if local_height >= height {
+                return Err(FsError::InvalidArgument(format!("Message {} is past the topic height {}", local_height, height)));
+            }
+            table.insert(source, source_height, local_height)?;
+        }
+        self.meta.put_value(ALIASES_RECORD, &table)?;
+        *self.aliases.borrow_mut() = table;
+        Ok(())
+    }
+
+    pub fn resolve_alias(&self, source: &str, source_height: u64) -> Option<u64> {
+        self.aliases.borrow().resolve(source, source_height)
+    }
+
+    // The sources and source heights recorded for a local message.
+    pub fn aliases_of(&self, local_height: u64) -> Vec<(String, u64)> {
+        self.aliases.borrow().aliases_of(local_height)
+    }
+
+    pub fn last_marker(&self, kind: &str) -> Option<u64> {
+        self.markers.borrow().get(kind).copied()
+    }
+
+    // Messages written after the latest marker of `kind`, or all of them if there is none yet.
+    // Markers in between are skipped.
+    pub fn read_since_marker<T: DeserializeOwned>(&self, kind: &str) -> Result<Vec<(u64, T)>, FsError> {
+        let start = self.last_marker(kind).map_or(0, |height| height + 1);
+        let mut messages = Vec::new();
+        for height in start..self.get_topic_height() {
+            if !self.is_marker(height)? {
+                messages.push((height, self.read_topic_message(height)?));
+            }
+        }
+        Ok(messages)
+    }
+
+    pub fn is_marker(&self, height: u64) -> Result<bool, FsError> {
+        self.check_written(height, 1)?;
+        Ok(self.reader.read_idx(height, &self.storage)?.is_marker())
+    }
+
+    // The index entry with its flags. Truncated heights are still there, their entries are erased.
+    pub fn index_entry(&self, height: u64) -> Result<IndexBlock, FsError> {
+        self.check_not_migrating()?;
+        if height >= self.committed_height.get() {
+            return Err(FsError::InvalidArgument(format!("Message {} is past the topic height {}", height, self.committed_height.get())));
+        }
+        self.reader.read_idx(height, &self.storage)
+    }
+
+    // Entries are written before the rest of the filesystem knows about the message, what it learns
+    // later is added to the flags.
+    fn add_index_flags(&self, height: u64, flags: u32) -> Result<(), FsError> {
+        let idx = self.reader.read_idx(height, &self.storage)?;
+        write_idx(&IndexBlock { flags: idx.flags | flags, ..idx }, &self.topic_header.layout, &self.storage)?;
+        self.reader.invalidate_index(height);
+        Ok(())
+    }
+
+    // Unwritten index slots are zeroed and would otherwise read back as markers.
+    // Uses the writer's height rather than the persisted one to save a stable read per message.
+    fn check_written(&self, start: u64, take: u64) -> Result<(), FsError> {
+        self.check_not_migrating()?;
+        let height = self.committed_height.get();
+        if start.saturating_add(take) > height {
+            return Err(FsError::InvalidArgument(format!("Messages {}..{} are past the topic height {}", start, start.saturating_add(take), height)));
+        }
+        let first = self.topic_header.first_message_ptr;
+        if start < first && take > 0 {
+            return Err(FsError::Truncated { height: start, first });
+        }
+        Ok(())
+    }
+
+    fn check_not_migrating(&self) -> Result<(), FsError> {
+        if let Some(growth) = self.index_growth {
+            return Err(FsError::InvalidState(format!("Index zone is growing to {} bytes, {} data bytes left to move", growth.index_zone_size, growth.remaining)));
+        }
+        if let Some(compaction) = &self.compaction {
+            return Err(FsError::InvalidState(format!("Topic is being compacted, {} of {} messages scanned", compaction.scanned, compaction.height)));
+        }
+        if let Some(truncation) = &self.truncation {
+            return Err(FsError::InvalidState(format!("Topic is being truncated before {}, {} of {} entries scanned", truncation.before, truncation.scanned, truncation.height)));
+        }
+        Ok(())
+    }
+
+    fn reload_deduplication(&mut self) -> Result<(), FsError> {
+        if let Some(config) = self.deduplication() {
+            *self.deduplication.get_mut() = Some(self.load_deduplication(config)?);
+        }
+        Ok(())
+    }
+
+    // Grows the index zone of an existing topic in place by moving the data zone up. Runs until done or
+    // the budget is spent, call again with the same size to resume, also after an upgrade. Messages
+    // can't be read or written until the returned progress is done.
+    pub fn grow_index_zone(&mut self, index_zone_size: u64, budget: &InstructionBudget) -> Result<IndexGrowth, FsError> {
+        if self.index_growth.is_none() {
+            self.check_not_migrating()?;
+            self.check_not_importing()?;
+        }
+        let current = self.topic_header.layout;
+        let target = LayoutConfig { index_zone_size, ..current };
+        let mut growth = match self.index_growth {
+            Some(growth) if growth.index_zone_size != index_zone_size => {
+                return Err(FsError::InvalidState(format!("Index zone is already growing to {} bytes", growth.index_zone_size)));
+            }
+            Some(growth) => growth,
+            None if index_zone_size < current.index_zone_size => {
+                return Err(FsError::InvalidArgument(format!("Index zone can't shrink from {} to {} bytes", current.index_zone_size, index_zone_size)));
+            }
+            None if index_zone_size == current.index_zone_size => return Ok(IndexGrowth { index_zone_size, remaining: 0 }),
+            None => {
+                target.validate()?;
+                IndexGrowth { index_zone_size, remaining: read_data_block_height(&self.storage) * current.block_size }
+            }
+        };
+
+        let shift = index_zone_size - current.index_zone_size;
+        migration::move_data_zone(&mut growth, current.idx_zone_end(), shift, budget, &self.storage);
+        if !growth.is_done() {
+            self.meta.put_value(INDEX_GROWTH_RECORD, &Some(growth))?;
+            self.index_growth = Some(growth);
+            return Ok(growth);
+        }
+
+        self.topic_header.layout = target;
+        write_topic_block(&self.topic_header, &self.storage);
+        canary::write_canaries(&target, &self.storage);
+        self.writer.get_mut().set_layout(target);
+        self.reader.set_layout(target);
+        self.meta.put_value(INDEX_GROWTH_RECORD, &None::<IndexGrowth>)?;
+        self.index_growth = None;
+        self.reload_deduplication()?;
+        Ok(growth)
+    }
+
+    // Rewrites the topic without the messages `keep` rejects, e.g. to purge a buggy event kind. `keep`
+    // gets each message's index entry and serialized payload. Kept messages are renumbered from zero,
+    // so heights held outside the topic no longer line up. Markers follow their messages, subscriber
+    // offsets, cursors and bookmarks move to the next kept message, aggregates keep what they folded
+    // and the heat map starts over. Runs until done or the budget is spent, call
+    // again with the same filter to resume. Messages can't be read or written until it is done.
+    pub fn compact_with_filter(&mut self, keep: impl Fn(&IndexExportEntry, &[u8]) -> bool, budget: &InstructionBudget) -> Result<Compaction, FsError> {
+        let mut compaction = match &self.compaction {
+            Some(compaction) => compaction.clone(),
+            None => {
+                self.check_not_migrating()?;
+                self.check_not_importing()?;
+                if self.codecs.changes().len() > 1 {
+                    return Err(FsError::Unsupported("Topics whose codec changed can't be compacted, the codec history is kept by height".to_string()));
+                }
+                if self.event_times.changes().len() > 1 {
+                    return Err(FsError::Unsupported("Topics whose event times were toggled can't be compacted, the setting is kept by height".to_string()));
+                }
+                if self.message_ids.changes().len() > 1 {
+                    return Err(FsError::Unsupported("Topics whose message ids were toggled can't be compacted, the setting is kept by height".to_string()));
+                }
+                if self.checksums.changes().len() > 1 {
+                    return Err(FsError::Unsupported("Topics whose checksums were toggled can't be compacted, the setting is kept by height".to_string()));
+                }
+                if self.compressions.changes().len() > 1 {
+                    return Err(FsError::Unsupported("Topics whose compression changed can't be compacted, the setting is kept by height".to_string()));
+                }
+                if !self.aliases.borrow().is_empty() {
+                    return Err(FsError::Unsupported("Topics with height aliases can't be compacted, the aliases would no longer resolve".to_string()));
+                }
+                if self.key_index.borrow().is_some() {
+                    return Err(FsError::Unsupported("Topics with a key index can't be compacted, the indexed heights would no longer resolve".to_string()));
+                }
+                Compaction::new(self.committed_height.get(), self.markers.borrow().clone(), self.consumer_positions())
+            }
+        };
+
+        // Entries truncate_before left behind are always dropped.
+        let first = self.topic_header.first_message_ptr;
+        let keep = |entry: &IndexExportEntry, payload: &[u8]| entry.height >= first && keep(entry, payload);
+        let compacted = migration::compact(&mut compaction, keep, &self.reader, &self.topic_header.layout, budget, &self.storage);
+        if compacted.is_err() || !compaction.is_done() {
+            self.meta.put_value(COMPACTION_RECORD, &Some(compaction.clone()))?;
+            self.compaction = Some(compaction.clone());
+            return compacted.map(|_| compaction);
+        }
+
+        let freed_blocks = self.writer.get_mut().data_block_offset() - compaction.data_blocks();
+        compaction.reclaimed_bytes = freed_blocks * self.topic_header.layout.block_size + (compaction.height - compaction.kept) * self.topic_header.layout.index_entry_size();
+        // The first entry past the new height still describes a message, open would take it for one.
+        if compaction.kept < compaction.height {
+            let entry_size = self.topic_header.layout.index_entry_size() as usize;
+            self.storage.try_write(self.topic_header.layout.index_entry_offset(compaction.kept), &[0u8; IDX_BLOCK_SIZE as usize][..entry_size])?;
+        }
+        write_index_height(compaction.kept, &self.storage);
+        write_data_block_height(compaction.data_blocks(), &self.storage);
+        self.writer.get_mut().set_offsets(compaction.kept, compaction.data_blocks());
+        self.reader.clear_block_cache();
+        self.committed_height.set(compaction.kept);
+        if first > 0 {
+            self.topic_header.first_message_ptr = 0;
+            write_topic_block(&self.topic_header, &self.storage);
+        }
+        self.meta.put_value(MARKERS_RECORD, compaction.markers())?;
+        *self.markers.get_mut() = compaction.markers().clone();
+        self.remap_consumers(&compaction)?;
+        if let Some(heat_map) = self.heat_map.get_mut() {
+            *heat_map = HeatMap::new(heat_map.segment_size())?;
+        }
+        self.save_heat_map()?;
+        if let Some(segments) = self.segments.get_mut() {
+            *segments = SegmentManifests::new(segments.segment_size(), compaction.kept)?;
+        }
+        self.meta.put_value(SEGMENTS_RECORD, &*self.segments.borrow())?;
+        self.meta.put_value(COMPACTION_RECORD, &None::<Compaction>)?;
+        self.compaction = None;
+        self.meta.put_value(LAST_COMPACTION_RECORD, &compaction.kept)?;
+        self.last_compaction_height = compaction.kept;
+        self.reload_deduplication()?;
+        // Heights were renumbered under running exports and timestamp corrections.
+        self.meta.remove(EXPORT_JOBS_RECORD)?;
+        self.meta.put_value(TIME_CORRECTIONS_RECORD, &TimeCorrections::default())?;
+        *self.time_corrections.get_mut() = TimeCorrections::default();
+        self.record_admin_event(EventFilesystemEvent::CompactionCompleted(CompactionCompleted {
+            height: compaction.height,
+            kept: compaction.kept,
+            reclaimed_bytes: compaction.reclaimed_bytes,
+            timestamp: (self.clock)(),
+        }));
+        Ok(compaction)
+    }
+
+    fn consumer_positions(&self) -> BTreeSet<u64> {
+        let subscribers = self.subscribers.borrow().subscribers().into_iter().map(|(_, offset)| offset).collect::<Vec<_>>();
+        let cursors = self.cursors.borrow().values().copied().collect::<Vec<_>>();
+        let bookmarks = self.bookmarks.borrow().values().copied().collect::<Vec<_>>();
+        subscribers.into_iter().chain(cursors).chain(bookmarks).collect()
+    }
+
+    fn remap_consumers(&self, compaction: &Compaction) -> Result<(), FsError> {
+        let subscribers = self.subscribers.borrow().subscribers();
+        if !subscribers.is_empty() {
+            let events = subscribers.iter()
+                .filter(|(_, offset)| compaction.remap(*offset) != *offset)
+                .map(|(subscriber, offset)| EventFilesystemEvent::SubscriberOffsetModified(SubscriberOffsetModified { subscriber: *subscriber, offset: compaction.remap(*offset) }))
+                .collect();
+            self.update_subscribers(events, |registry| {
+                registry.remap(|offset| compaction.remap(offset));
+                Ok(())
+            })?;
+        }
+        if !self.cursors.borrow().is_empty() {
+            self.update_cursors(|cursors| {
+                cursors.values_mut().for_each(|position| *position = compaction.remap(*position));
+                Ok(())
+            })?;
+        }
+        let mut bookmarks = self.bookmarks.borrow().clone();
+        if !bookmarks.is_empty() {
+            bookmarks.values_mut().for_each(|height| *height = compaction.remap(*height));
+            self.meta.put_value(BOOKMARKS_RECORD, &bookmarks)?;
+            *self.bookmarks.borrow_mut() = bookmarks;
+        }
+        Ok(())
+    }
+
+    // Compacts by segment as `policy` plans it, from the segment manifests and the topic's stats at the
+    // time of the call. Resumes like compact_with_filter, call again with the same policy.
+    pub fn compact_with_policy(&mut self, policy: &impl CompactionPolicy, budget: &InstructionBudget) -> Result<Compaction, FsError> {
+        let Some((segment_size, segments)) = self.segments.borrow().as_ref().map(|manifests| (manifests.segment_size(), manifests.segments().to_vec())) else {
+            return Err(FsError::InvalidState("Compaction policies need the segment manifests, enable them first".to_string()));
+        };
+        let stats = TopicStats {
+            height: self.committed_height.get(),
+            first_message: self.first_message_height(),
+            data_bytes: read_data_block_height(&self.storage) * self.topic_header.layout.block_size,
+            segment_size,
+            now: (self.clock)(),
+        };
+        let plan = CompactionPlan::new(policy, &stats, &segments);
+        self.compact_with_filter(|entry, payload| match plan.action(entry.height) {
+            SegmentAction::Keep => true,
+            SegmentAction::Drop => false,
+            SegmentAction::Rewrite => policy.keep(entry, payload),
+        }, budget)
+    }
+
+    // Drops the messages below `height` and reclaims their data blocks for new writes. Heights don't
+    // change, reads below the new first message fail with Truncated. The payloads that are kept move
+    // down, so this runs until done or the budget is spent, call again with the same height to resume.
+    // Messages can't be read or written until it is done.
+    pub fn truncate_before(&mut self, height: u64, budget: &InstructionBudget) -> Result<Truncation, FsError> {
+        let mut truncation = match self.truncation {
+            Some(truncation) if truncation.before == height => truncation,
+            Some(truncation) => return Err(FsError::InvalidState(format!("Topic is being truncated before {}, not {}", truncation.before, height))),
+            None => {
+                self.check_not_migrating()?;
+                self.check_not_importing()?;
+                let (first, committed) = (self.topic_header.first_message_ptr, self.committed_height.get());
+                if height > committed {
+                    return Err(FsError::InvalidArgument(format!("Can't truncate before {}, past the topic height {}", height, committed)));
+                }
+                if height <= first {
+                    return Ok(Truncation::new(first, first, first, 0));
+                }
+                let shift = match height < committed {
+                    true => self.reader.read_idx(height, &self.storage)?.start_idx,
+                    false => self.writer.get_mut().data_block_offset(),
+                };
+                Truncation::new(first, height, committed, shift)
+            }
+        };
+
+        let truncated = retention::truncate(&mut truncation, &self.reader, &self.topic_header.layout, budget, &self.storage);
+        if truncated.is_err() || !truncation.is_done() {
+            self.meta.put_value(TRUNCATION_RECORD, &Some(truncation))?;
+            self.truncation = Some(truncation);
+            return truncated.map(|_| truncation);
+        }
+
+        let writer = self.writer.get_mut();
+        let data_blocks = writer.data_block_offset() - truncation.shift();
+        write_data_block_height(data_blocks, &self.storage);
+        writer.set_offsets(writer.index_block_offset(), data_blocks);
+        self.reader.clear_block_cache();
+        self.topic_header.first_message_ptr = truncation.before;
+        write_topic_block(&self.topic_header, &self.storage);
+        self.record_segments(|manifests| manifests.drop_before(truncation.before));
+        self.meta.put_value(TRUNCATION_RECORD, &None::<Truncation>)?;
+        self.truncation = None;
+        Ok(truncation)
+    }
+
+    // The lowest height that can still be read, everything below was truncated.
+    pub fn first_message_height(&self) -> u64 {
+        self.topic_header.first_message_ptr
+    }
+
+    // Kept until replaced, apply_retention enforces it.
+    pub fn set_retention_policy(&self, policy: Option<RetentionPolicy>) -> Result<(), FsError> {
+        self.meta.put_value(RETENTION_RECORD, &policy)?;
+        self.retention.set(policy);
+        Ok(())
+    }
+
+    pub fn retention_policy(&self) -> Option<RetentionPolicy> {
+        self.retention.get()
+    }
+
+    // Holds apply_retention back at the slowest consumer, None lets it drop what the policy says.
+    pub fn set_consumer_retention(&self, consumer_retention: Option<ConsumerRetention>) -> Result<(), FsError> {
+        self.meta.put_value(CONSUMER_RETENTION_RECORD, &consumer_retention)?;
+        self.consumer_retention.set(consumer_retention);
+        Ok(())
+    }
+
+    pub fn consumer_retention(&self) -> Option<ConsumerRetention> {
+        self.consumer_retention.get()
+    }
+
+    // The lowest subscriber offset or cursor position that holds messages back, see ConsumerRetention.
+    pub fn slowest_consumer(&self) -> Option<u64> {
+        let consumer_retention = self.consumer_retention.get()?;
+        let committed = self.committed_height.get();
+        let subscribers = self.subscribers.borrow().subscribers().into_iter().map(|(_, offset)| offset);
+        let cursors = self.cursors.borrow().values().copied().collect::<Vec<_>>();
+        subscribers.chain(cursors)
+            .filter(|position| consumer_retention.override_lag.is_none_or(|max| committed.saturating_sub(*position) <= max))
+            .min()
+    }
+
+    // Truncates what the retention policy no longer keeps, e.g. from a heartbeat. Ages go by ingestion
+    // time. Resumes a truncation that ran out of budget before it looks at the policy again. Messages
+    // the slowest consumer holds back are kept and counted against the ConsumerLag alarm.
+    pub fn apply_retention(&mut self, budget: &InstructionBudget) -> Result<Truncation, FsError> {
+        if let Some(truncation) = self.truncation {
+            return self.truncate_before(truncation.before, budget);
+        }
+        let committed = self.committed_height.get();
+        let before = match self.retention.get() {
+            Some(RetentionPolicy::MaxMessages(max)) => committed.saturating_sub(max),
+            Some(RetentionPolicy::MaxAgeNanos(max)) => self.find_by_timestamp((self.clock)().saturating_sub(max))?.min(committed),
+            None => return Err(FsError::InvalidState("No retention policy is set".to_string())),
+        };
+        let before = before.max(self.topic_header.first_message_ptr);
+        let held = self.slowest_consumer().map_or(before, |slowest| before.min(slowest)).max(self.topic_header.first_message_ptr);
+        let breaches = self.alarms.borrow_mut().record_blocked((self.clock)(), before - held);
+        self.record_breaches(breaches);
+        self.truncate_before(held, budget)
+    }
+
+    // Rewrites the live messages contiguously, dropping what truncate_before left behind, and reports the
+    // bytes reclaimed once done. Renumbers heights like compact_with_filter and resumes the same way.
+    pub fn compact(&mut self, budget: &InstructionBudget) -> Result<Compaction, FsError> {
+        self.compact_with_filter(|_, _| true, budget)
+    }
+
+    // Routes every message into `matching` or `rest` by `pred`, the inverse of merge_topics, e.g. to
+    // separate event families that were mixed into one log. Both have to be empty when the split starts.
+    // Markers go to both with their kind, and source heights are recorded as aliases in each. The topic
+    // stays writable, later messages aren't routed. Runs until done or the budget is spent, call again
+    // with the same topics to resume.
+    pub fn split_topic<T: DeserializeOwned + Writable, M: BlockStorage, R: BlockStorage>(&self,
+                                                                                          pred: impl Fn(&T) -> bool,
+                                                                                          matching: &EventFilesystem<M>,
+                                                                                          rest: &EventFilesystem<R>,
+                                                        budget: &InstructionBudget,
+    ) -> Result<TopicSplit, FsError> {
+        let matching_name = &matching.topic_header.event_stream_name;
+        let rest_name = &rest.topic_header.event_stream_name;
+        let mut split = match self.meta.get_value::<Option<TopicSplit>>(SPLIT_RECORD)?.flatten() {
+            Some(split) if (&split.matching, &split.rest) != (matching_name, rest_name) => {
+                return Err(FsError::InvalidArgument(format!("The split was started into {} and {}, not {} and {}", split.matching, split.rest, matching_name, rest_name)));
+            }
+            Some(split) => split,
+            None => {
+                if matching.get_topic_height() > 0 || rest.get_topic_height() > 0 {
+                    return Err(FsError::InvalidArgument("Topics can only be split into empty topics".to_string()));
+                }
+                TopicSplit::new(self.committed_height.get(), matching_name.clone(), rest_name.clone())
+            }
+        };
+
+        let markers = self.markers.borrow().clone();
+        let mut aliases = [Vec::new(), Vec::new()];
+        // Progress is saved with each routed message, so a later failure never routes one twice.
+        let mut route = || -> Result<(), FsError> {
+            while !split.is_done() {
+                let routed = self.route_split_message(&pred, &markers, matching, rest, &mut split, &mut aliases);
+                self.meta.put_value(SPLIT_RECORD, &Some(&split).filter(|split| !split.is_done()))?;
+                routed?;
+                if budget.is_exhausted() {
+                    break;
+                }
+            }
+            Ok(())
+        };
+        let routed = route();
+
+        let source = &self.topic_header.event_stream_name;
+        let [matching_aliases, rest_aliases] = aliases;
+        matching.record_aliases(source, matching_aliases)?;
+        rest.record_aliases(source, rest_aliases)?;
+        routed.map(|_| split)
+    }
+
+    // Routes the next message of `split` and moves it past what reached each destination, collecting
+    // the aliases for matching and rest.
+    fn route_split_message<T: DeserializeOwned + Writable, M: BlockStorage, R: BlockStorage>(&self,
+                                                                                            pred: impl Fn(&T) -> bool,
+                                                                                            markers: &BTreeMap<String, u64>,
+                                                                                            matching: &EventFilesystem<M>,
+                                                                                            rest: &EventFilesystem<R>,
+                                                                                            split: &mut TopicSplit,
+                                                                                            aliases: &mut [Vec<(u64, u64)>; 2],
+    ) -> Result<(), FsError> {
+        let height = split.scanned;
+        if self.is_marker(height)? {
+            let kind = markers.iter().find(|(_, marker)| **marker == height).map(|(kind, _)| kind);
+            if split.matching_scanned == height {
+                let local = match kind {
+                    Some(kind) => matching.write_marker(kind)?,
+                    None => matching.write_topic_message(&())?,
+                };
+                aliases[0].push((height, local));
+                split.matching_scanned += 1;
+            }
+            if split.rest_scanned == height {
+                let local = match kind {
+                    Some(kind) => rest.write_marker(kind)?,
+                    None => rest.write_topic_message(&())?,
+                };
+                aliases[1].push((height, local));
+                split.rest_scanned += 1;
+            }
+        } else {
+            let message: T = self.read_topic_message(height)?;
+            if pred(&message) {
+                aliases[0].push((height, matching.write_topic_message(&message)?));
+                split.matched += 1;
+            } else {
+                aliases[1].push((height, rest.write_topic_message(&message)?));
+            }
+        }
+        split.scanned += 1;
+        split.matching_scanned = split.scanned;
+        split.rest_scanned = split.scanned;
+        Ok(())
+    }
+
+    // Like read_topic_messages, but hands back what was decoded plus a resume height instead of
+    // running past the budget, so large ranges can be paged across query calls.
+    pub fn read_topic_messages_budgeted<T : DeserializeOwned>(&self, start: u64, take: u64, budget: &InstructionBudget) -> Result<PartialRange<T>, FsError> {
+        self.measured(Operation::ReadRange, || {
+            self.check_not_migrating()?;
+            let take = take.min(self.committed_height.get().saturating_sub(start));
+            let range = self.reader.read_range_budgeted::<T>(start, take, &self.storage, budget)?;
+            self.record_reads(start, range.messages.len() as u64);
+            Ok(range)
+        })
+    }
+
+    // Up to `limit` messages after height `after`, from the first one left when None, for query calls
+    // that hand out a topic page by page. Markers are skipped. The page ends before its payloads pass
+    // the page byte budget, a single message above it is refused so no response can grow past the
+    // limit, read it alone with read_topic_message.
+    pub fn read_page<T: DeserializeOwned>(&self, after: Option<u64>, limit: u64) -> Result<Page<T>, FsError> {
+        self.measured(Operation::ReadRange, || {
+            self.check_not_migrating()?;
+            let height = self.committed_height.get();
+            let start = after.map_or(0, |after| after.saturating_add(1)).max(self.topic_header.first_message_ptr);
+            if start > height {
+                return Err(FsError::InvalidArgument(format!("Page after {:?} is past the topic height {}", after, height)));
+            }
+            let end = height.min(start.saturating_add(limit));
+            let budget = self.page_bytes.get();
+            let (mut messages, mut size, mut next) = (Vec::new(), 0, start);
+            while next < end {
+                let payload = self.reader.read_payload(next, &self.storage)?;
+                if size + payload.len() as u64 > budget {
+                    if messages.is_empty() {
+                        return Err(FsError::MessageTooLarge { size: payload.len() as u64, limit: budget });
+                    }
+                    break;
+                }
+                if !payload.is_empty() {
+                    size += payload.len() as u64;
+                    messages.push((next, self.codecs.at(next).deserialize::<T>(&payload)?));
+                }
+                next += 1;
+            }
+            self.record_reads(start, next - start);
+            Ok(Page { messages, next: next.checked_sub(1).filter(|_| next > start).or(after), has_more: next < height })
+        })
+    }
+
+    // Payload bytes read_page puts into a page at most, up to MAX_PAGE_BYTES.
+    pub fn set_page_bytes(&self, page_bytes: u64) -> Result<(), FsError> {
+        if page_bytes == 0 || page_bytes > MAX_PAGE_BYTES {
+            return Err(FsError::InvalidArgument(format!("Pages hold 1 to {} bytes, not {}", MAX_PAGE_BYTES, page_bytes)));
+        }
+        self.meta.put_value(PAGE_BYTES_RECORD, &page_bytes)?;
+        self.page_bytes.set(page_bytes);
+        Ok(())
+    }
+
+    pub fn page_bytes(&self) -> u64 {
+        self.page_bytes.get()
+    }
+
+    // Scans `take` messages from `start` and returns the heights and values that match the filter.
+    #[cfg(feature = "filter")]
+    pub fn read_filtered<T: DeserializeOwned + Serialize>(&self, start: u64, take: u64, filter: &MessageFilter) -> Result<Vec<(u64, T)>, FsError> {
+        let end = (start + take).min(self.get_topic_height());
+        let mut messages = Vec::new();
+        for height in start..end {
+            let message: T = self.read_topic_message(height)?;
+            if filter.matches(&message)? {
+                messages.push((height, message));
+            }
+        }
+        Ok(messages)
+    }
+
+    // Messages meeting every condition of the query, in its order and up to its limit.
+    #[cfg(feature = "query")]
+    pub fn query<T: DeserializeOwned + Serialize>(&self, query: &Query) -> Result<Vec<(u64, T)>, FsError> {
+        self.check_not_migrating()?;
+        let heights = query.heights.clone().unwrap_or(0..u64::MAX);
+        let start = heights.start.max(self.first_message_height());
+        let end = heights.end.min(self.committed_height.get());
+        let mut candidates: Box<dyn DoubleEndedIterator<Item = u64>> = match query.plan() {
+            QueryPlan::KeyIndex => {
+                let key = query.key.as_deref().unwrap_or_default();
+                Box::new(self.heights_by_key(key)?.into_iter().filter(move |height| (start..end).contains(height)))
+            }
+            QueryPlan::TimeSeek => {
+                let range = query.time.as_ref().map(|(_, range)| range.clone()).unwrap_or_default();
+                let seek = |time| Ok::<_, FsError>(self.seek_time(TimeDomain::Ingestion, time)?.unwrap_or(end));
+                Box::new(seek(range.start)?.max(start)..seek(range.end)?.min(end).max(start))
+            }
+            QueryPlan::Scan => Box::new(start..end.max(start)),
+        };
+        if query.order == QueryOrder::Descending {
+            candidates = Box::new(candidates.rev());
+        }
+        let limit = query.limit.unwrap_or(u64::MAX);
+        let mut messages = Vec::new();
+        for height in candidates {
+            if messages.len() as u64 >= limit {
+                break;
+            }
+            if self.is_marker(height)? {
+                continue;
+            }
+            if let Some((domain, range)) = &query.time {
+                let time = match domain {
+                    TimeDomain::Ingestion => Some(self.ordered_ingestion_time(height)?),
+                    TimeDomain::Event => self.message_times(height)?.event_time,
+                };
+                if !time.is_some_and(|time| range.contains(&time)) {
+                    continue;
+                }
+            }
+            let message: T = self.read_topic_message(height)?;
+            if query.filter.as_ref().map_or(Ok(true), |filter| filter.matches(&message))? {
+                messages.push((height, message));
+            }
+        }
+        Ok(messages)
+    }
+
+    pub fn message_times(&self, height: u64) -> Result<MessageTimes, FsError> {
+        self.check_written(height, 1)?;
+        Ok(MessageTimes {
+            height,
+            ingestion_time: self.reader.read_idx(height, &self.storage)?.timestamp,
+            event_time: self.reader.read_event_time(height, &self.storage)?,
+        })
+    }
+
+    // The first height whose time in `domain` is at or after `time`. Ingestion times are binary searched,
+    // event times aren't ordered and are scanned.
+    pub fn seek_time(&self, domain: TimeDomain, time: u64) -> Result<Option<u64>, FsError> {
+        let height = self.get_topic_height();
+        let first = self.topic_header.first_message_ptr;
+        if domain == TimeDomain::Ingestion {
+            let (mut low, mut high) = (first.min(height), height);
+            while low < high {
+                let middle = low + (high - low) / 2;
+                if self.ordered_ingestion_time(middle)? < time {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            return Ok((low < height).then_some(low));
+        }
+        for height in first..height {
+            if self.message_times(height)?.event_time.is_some_and(|event_time| event_time >= time) {
+                return Ok(Some(height));
+            }
+        }
+        Ok(None)
+    }
+
+    // Messages whose time in `domain` falls in `range`. Markers are skipped, as are messages without an
+    // event time when reading by event time.
+    pub fn read_time_range<T: DeserializeOwned>(&self, domain: TimeDomain, range: Range<u64>) -> Result<Vec<(u64, T)>, FsError> {
+        let start = match domain {
+            TimeDomain::Ingestion => self.seek_time(domain, range.start)?.unwrap_or(self.get_topic_height()),
+            TimeDomain::Event => self.topic_header.first_message_ptr,
+        };
+        let mut messages = Vec::new();
+        for height in start..self.get_topic_height() {
+            let time = match domain {
+                TimeDomain::Ingestion => Some(self.ordered_ingestion_time(height)?),
+                TimeDomain::Event => self.message_times(height)?.event_time,
+            };
+            if domain == TimeDomain::Ingestion && time.is_some_and(|time| time >= range.end) {
+                break;
+            }
+            if time.is_some_and(|time| range.contains(&time)) && !self.is_marker(height)? {
+                messages.push((height, self.read_topic_message(height)?));
+            }
+        }
+        Ok(messages)
+    }
+
+    // The ingestion time time seeks go by, corrected where repair_timestamps found the clock went back.
+    fn ordered_ingestion_time(&self, height: u64) -> Result<u64, FsError> {
+        let timestamp = self.message_times(height)?.ingestion_time;
+        Ok(self.time_corrections.borrow().corrected(height, timestamp))
+    }
+
+    // Scans ingestion times for messages stored while the clock went back and records corrections that
+    // keep seek_time and read_time_range ordered. Picks up where the last scan stopped, so call it
+    // again after writes when the clock may have regressed. Runs until done or the budget is spent.
+    pub fn repair_timestamps(&self, budget: &InstructionBudget) -> Result<TimestampRepair, FsError> {
+        self.check_not_migrating()?;
+        let height = self.committed_height.get();
+        let mut corrections = self.time_corrections.borrow().clone();
+        while corrections.scanned() < height {
+            corrections.scan(self.reader.read_idx(corrections.scanned(), &self.storage)?.timestamp);
+            if budget.is_exhausted() {
+                break;
+            }
+        }
+        self.meta.put_value(TIME_CORRECTIONS_RECORD, &corrections)?;
+        let repair = TimestampRepair { scanned: corrections.scanned(), height, corrected: corrections.corrected_count() };
+        *self.time_corrections.borrow_mut() = corrections;
+        Ok(repair)
+    }
+
+    // Height ranges whose ingestion time is corrected for time seeks, with the time they are seeked by.
+    pub fn timestamp_corrections(&self) -> Vec<(Range<u64>, u64)> {
+        self.time_corrections.borrow().corrections().collect()
+    }
+
+    // The first height stored at or after `timestamp`, the topic height when every message is older.
+    // Lookups by ingestion time, see seek_time for event times.
+    pub fn find_by_timestamp(&self, timestamp: u64) -> Result<u64, FsError> {
+        Ok(self.seek_time(TimeDomain::Ingestion, timestamp)?.unwrap_or(self.get_topic_height()))
+    }
+
+    // Messages stored from `from_timestamp` up to, not including, `to_timestamp`, markers skipped.
+    pub fn read_range_by_time<T: DeserializeOwned>(&self, from_timestamp: u64, to_timestamp: u64) -> Result<Vec<(u64, T)>, FsError> {
+        self.read_time_range(TimeDomain::Ingestion, from_timestamp..to_timestamp)
+    }
+
+    // Starts exporting the committed messages in chunks of about `chunk_size` payload bytes, e.g. for
+    // topics too large to export in one call. The job expires `ttl` after it was started or its last
+    // chunk was taken. Chunks are taken with export_next_chunk in update calls, queries can't keep the
+    // progress.
+    #[cfg(feature = "jobs")]
+    pub fn start_export(&self, kind: ExportKind, chunk_size: u64, ttl: u64) -> Result<u64, FsError> {
+        self.check_not_migrating()?;
+        if kind == ExportKind::Digest {
+            self.hasher()?;
+        }
+        let mut jobs = self.export_jobs()?;
+        let id = jobs.start(kind, self.committed_height.get(), chunk_size, ttl, (self.clock)())?;
+        self.meta.put_value(EXPORT_JOBS_RECORD, &jobs)?;
+        Ok(id)
+    }
+
+    // The next chunk of `job_id` in sequence. Once done the job keeps answering with empty chunks until it expires.
+    #[cfg(feature = "jobs")]
+    pub fn export_next_chunk(&self, job_id: u64) -> Result<ExportChunk, FsError> {
+        let mut jobs = self.export_jobs()?;
+        let mut job = jobs.get(job_id)?.clone();
+        let hasher = match job.kind {
+            ExportKind::Digest => Some(self.hasher()?),
+            _ => None,
+        };
+        let mut entries = Vec::new();
+        let mut bytes = Vec::new();
+        let mut size = 0;
+        while !job.is_done() && size < job.chunk_size {
+            self.check_written(job.next, 1)?;
+            let entry = IndexExportEntry::from(self.reader.read_idx(job.next, &self.storage)?);
+            match job.kind {
+                ExportKind::Snapshot => {
+                    let payload = self.reader.read_payload(job.next, &self.storage)?;
+                    size += payload.len().max(1) as u64;
+                    entries.push((entry, payload));
+                }
+                ExportKind::IndexJson => {
+                    let line = entry.to_json()?;
+                    size += line.len() as u64 + 1;
+                    bytes.extend_from_slice(line.as_bytes());
+                    bytes.push(b'\n');
+                }
+                ExportKind::Digest => {
+                    let payload = self.reader.read_payload(job.next, &self.storage)?;
+                    size += payload.len().max(1) as u64;
+                    job.digest = hasher.as_ref().map(|hasher| hasher.digest(&[job.digest.as_slice(), &payload].concat())).unwrap_or_default();
+                }
+            }
+            job.next += 1;
+        }
+        match job.kind {
+            ExportKind::Snapshot => bytes = bincode::serialize(&entries).map_err(|e| FsError::Serialize(e.to_string()))?,
+            ExportKind::Digest if job.is_done() => bytes = job.digest.clone(),
+            _ => {}
+        }
+        let chunk = ExportChunk { job_id, sequence: job.chunks, bytes, done: job.is_done() };
+        job.chunks += 1;
+        job.expires_at = (self.clock)().saturating_add(job.ttl);
+        jobs.update(job);
+        self.meta.put_value(EXPORT_JOBS_RECORD, &jobs)?;
+        Ok(chunk)
+    }
+
+    #[cfg(feature = "jobs")]
+    pub fn export_status(&self, job_id: u64) -> Result<ExportJob, FsError> {
+        self.export_jobs()?.get(job_id).cloned()
+    }
+
+    #[cfg(feature = "jobs")]
+    pub fn cancel_export(&self, job_id: u64) -> Result<(), FsError> {
+        let mut jobs = self.export_jobs()?;
+        jobs.get(job_id)?;
+        jobs.remove(job_id);
+        self.meta.put_value(EXPORT_JOBS_RECORD, &jobs)
+    }
+
+    // Expired jobs are dropped on every access.
+    #[cfg(feature = "jobs")]
+    fn export_jobs(&self) -> Result<ExportJobs, FsError> {
+        let mut jobs = self.meta.get_value::<ExportJobs>(EXPORT_JOBS_RECORD)?.unwrap_or_default();
+        jobs.expire((self.clock)());
+        Ok(jobs)
+    }
+
+    // Starts importing the chunks of an ExportKind::Snapshot export, e.g. from another canister. Nothing
+    // is visible until commit_import, writes are refused until the import is committed or aborted. An
+    // import that gets no chunk for `ttl` is aborted. Imported messages bypass the schema, aggregates and
+    // deduplication and get new timestamps.
+    #[cfg(feature = "jobs")]
+    pub fn start_import(&self, expected_digest: Option<Vec<u8>>, ttl: u64) -> Result<u64, FsError> {
+        self.check_not_migrating()?;
+        self.check_not_importing()?;
+        self.check_not_streaming()?;
+        if expected_digest.is_some() {
+            self.hasher()?;
+        }
+        let height = self.committed_height.get();
+        let data_block = read_data_block_height(&self.storage);
+        // Drops anything appended after a failed canary check, it was never committed.
+        self.writer.borrow_mut().set_offsets(height, data_block);
+        let mut imports = self.imports.borrow_mut();
+        let id = imports.next_id;
+        imports.next_id += 1;
+        imports.job = Some(ImportJob::new(id, height, data_block, expected_digest, ttl, (self.clock)()));
+        self.meta.put_value(IMPORTS_RECORD, &*imports)?;
+        Ok(id)
+    }
+
+    // Stages the next chunk in sequence. A chunk that was already staged is ignored, so a chunk whose
+    // reply got lost can be sent again. A chunk that fails to stage leaves nothing behind.
+    #[cfg(feature = "jobs")]
+    pub fn import_chunk(&self, job_id: u64, chunk: &ExportChunk) -> Result<ImportJob, FsError> {
+        let mut job = self.import_job(job_id)?;
+        if chunk.sequence < job.chunks {
+            return Ok(job);
+        }
+        if chunk.sequence > job.chunks || job.received_all {
+            return Err(FsError::InvalidArgument(format!("Import {} expects chunk {}, not {}", job_id, job.chunks, chunk.sequence)));
+        }
+        let entries: Vec<(IndexExportEntry, Vec<u8>)> = bincode::deserialize(&chunk.bytes).map_err(|e| FsError::Deserialize(format!("import chunk {}: {}", chunk.sequence, e)))?;
+        let hasher = match job.expected_digest {
+            Some(_) => Some(self.hasher()?),
+            None => None,
+        };
+        let mut writer = self.writer.borrow_mut();
+        let mut digest = job.digest.clone();
+        for (_, payload) in &entries {
+            let staged = writer.write_payload(payload, &self.storage);
+            if let Err(e) = staged {
+                writer.set_offsets(job.next, job.next_data_block);
+                self.record_error("import", Some(job.next), &e);
+                return Err(e);
+            }
+            if let Some(hasher) = &hasher {
+                digest = hasher.digest(&[digest.as_slice(), payload].concat());
+            }
+        }
+        for height in job.next..=writer.index_block_offset() {
+            self.reader.invalidate_index(height);
+        }
+        job.next = writer.index_block_offset();
+        job.next_data_block = writer.data_block_offset();
+        job.digest = digest;
+        job.chunks += 1;
+        job.received_all = chunk.done;
+        job.expires_at = (self.clock)().saturating_add(job.ttl);
+        let mut imports = self.imports.borrow_mut();
+        imports.job = Some(job.clone());
+        self.meta.put_value(IMPORTS_RECORD, &*imports)?;
+        Ok(job)
+    }
+
+    // Parses and validates snapshot chunks, e.g. from a backup, the way importing them here would,
+    // without writing anything. Payloads are checked against this topic's size limit and the digest
+    // is chained with its hash algorithm.
+    #[cfg(feature = "jobs")]
+    pub fn verify_restorable<'a>(&self, chunks: impl IntoIterator<Item = &'a ExportChunk>, expected_digest: Option<&[u8]>) -> Result<RestoreReport, FsError> {
+        let hasher = self.hasher()?;
+        Ok(verify::verify_snapshot(chunks, hasher.as_ref(), expected_digest, self.codecs.current().size_limit, self.remaining_capacity()))
+    }
+
+    // Validates the staged messages and makes them visible with a single height update. An import that
+    // doesn't match its expected digest or ran into a zone boundary is aborted. Returns the imported heights.
+    #[cfg(feature = "jobs")]
+    pub fn commit_import(&self, job_id: u64) -> Result<Range<u64>, FsError> {
+        let job = self.import_job(job_id)?;
+        if !job.received_all {
+            return Err(FsError::InvalidState(format!("Import {} is still waiting for chunk {}", job_id, job.chunks)));
+        }
+        let layout = &self.topic_header.layout;
+        let mut validated = canary::check_canaries_near(layout, layout.index_entry_offset(job.start), layout.index_entry_offset(job.next), &self.storage);
+        if validated.is_ok() && job.expected_digest.as_ref().is_some_and(|expected| *expected != job.digest) {
+            validated = Err(FsError::InvalidArgument(format!("Import {} doesn't match the expected digest", job_id)));
+        }
+        if let Err(e) = validated {
+            self.record_error("import", None, &e);
+            self.abort_import(job_id)?;
+            return Err(e);
+        }
+
+        write_index_height(job.next, &self.storage);
+        write_data_block_height(job.next_data_block, &self.storage);
+        self.committed_height.set(job.next);
+        if self.segments.borrow().is_some() {
+            let entries = (job.start..job.next).map(|height| self.reader.read_idx(height, &self.storage)).collect::<Result<Vec<_>, _>>()?;
+            self.record_segments(|manifests| entries.iter().for_each(|idx| manifests.record(idx)));
+        }
+        let mut imports = self.imports.borrow_mut();
+        imports.job = None;
+        self.meta.put_value(IMPORTS_RECORD, &*imports)?;
+        Ok(job.start..job.next)
+    }
+
+    // Drops the staged messages, the topic is left as it was before the import started.
+    #[cfg(feature = "jobs")]
+    pub fn abort_import(&self, job_id: u64) -> Result<(), FsError> {
+        let job = self.import_job(job_id)?;
+        self.drop_import(&job)
+    }
+
+    #[cfg(feature = "jobs")]
+    pub fn import_status(&self, job_id: u64) -> Result<ImportJob, FsError> {
+        self.import_job(job_id)
+    }
+
+    // Streams the whole committed topic, e.g. to a file for a backup, see SnapshotManifest for the
+    // format. Besides the index and data zones it takes the header, the stable store, the regions and
+    // the meta records, which hold the settings needed to read the payloads back. Only the used part
+    // of each zone is written. EventFilesystemBuilder::import_snapshot restores it.
+    #[cfg(feature = "snapshot")]
+    pub fn export_snapshot(&self, mut writer: impl std::io::Write) -> Result<SnapshotManifest, FsError> {
+        let mut manifest = self.snapshot_manifest()?;
+        snapshot::write_snapshot(&mut manifest, &self.storage, self.hasher()?.as_ref(), &mut writer)?;
+        Ok(manifest)
+    }
+
+    // Chunks of `chunk_size` bytes the snapshot stream of export_chunk takes.
+    #[cfg(feature = "snapshot")]
+    pub fn snapshot_chunk_count(&self, chunk_size: u64) -> Result<u64, FsError> {
+        if chunk_size == 0 {
+            return Err(FsError::InvalidArgument("Chunks must hold one byte at least".to_string()));
+        }
+        let size = snapshot::stream_size(&self.snapshot_manifest()?, self.hasher()?.as_ref())?;
+        Ok(size.div_ceil(chunk_size))
+    }
+
+    // Bytes `chunk_id * chunk_size` onwards of the stream export_snapshot writes, so a controller can
+    // pull a topic too large for one response in query calls, e.g. 2 MB at a time, and restore the
+    // concatenated chunks with import_snapshot. Chunks only line up while the topic doesn't change, the
+    // digest import_snapshot checks catches a topic that changed in between. The last chunk carries the
+    // digest, computing it reads the whole topic.
+    #[cfg(feature = "snapshot")]
+    pub fn export_chunk(&self, chunk_id: u64, chunk_size: u64) -> Result<Vec<u8>, FsError> {
+        let count = self.snapshot_chunk_count(chunk_size)?;
+        if chunk_id >= count {
+            return Err(FsError::InvalidArgument(format!("Chunk {} is past the {} chunks of the snapshot", chunk_id, count)));
+        }
+        let manifest = self.snapshot_manifest()?;
+        let hasher = self.hasher()?;
+        let size = snapshot::stream_size(&manifest, hasher.as_ref())?;
+        let start = chunk_id * chunk_size;
+        snapshot::read_stream(&manifest, &self.storage, hasher.as_ref(), start..size.min(start + chunk_size))
+    }
+
+    // What export_snapshot would write, without the digest.
+    #[cfg(feature = "snapshot")]
+    pub fn snapshot_manifest(&self) -> Result<SnapshotManifest, FsError> {
+        self.check_not_migrating()?;
+        self.check_not_importing()?;
+        let layout = &self.topic_header.layout;
+        let height = self.committed_height.get();
+        let data_blocks = read_data_block_height(&self.storage);
+        let mut size = [0u8; 8];
+        self.storage.read(FREE_MEMORY_BLOCK_SIZE_IDX, &mut size);
+        let stored = u64::from_le_bytes(size).min(self.stable_store_limit());
+        let section = |kind, bytes| SnapshotSection { kind, bytes };
+        let mut sections = vec![section(SnapshotSectionKind::StableStore, FREE_MEMORY_BLOCK_START_IDX..FREE_MEMORY_BLOCK_START_IDX + stored)];
+        for region in self.regions.borrow().regions().values() {
+            sections.push(section(SnapshotSectionKind::Region, region.offset..region.data_offset() + region.capacity));
+        }
+        sections.push(section(SnapshotSectionKind::Meta, layout.meta_zone_idx()..self.meta.used_end()));
+        sections.push(section(SnapshotSectionKind::Index, layout.idx_zone_idx()..layout.index_entry_offset(height)));
+        sections.push(section(SnapshotSectionKind::Data, layout.data_block_offset(0)..layout.data_block_offset(data_blocks)));
+        sections.push(section(SnapshotSectionKind::Header, MAGIC_NUMBER_IDX..FREE_MEMORY_BLOCK_START_IDX));
+        Ok(SnapshotManifest {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            binary_version: self.topic_header.binary_version,
+            event_stream_name: self.topic_header.event_stream_name.clone(),
+            hash_algorithm: self.topic_header.hash_algorithm()?,
+            height,
+            first_message: self.topic_header.first_message_ptr,
+            data_blocks,
+            sections,
+            digest: Vec::new(),
+        })
+    }
+
+    #[cfg(feature = "jobs")]
+    fn import_job(&self, job_id: u64) -> Result<ImportJob, FsError> {
+        self.expire_import()?;
+        self.imports.borrow().job.clone().filter(|job| job.id == job_id)
+            .ok_or_else(|| FsError::InvalidArgument(format!("Import job {} is unknown or expired", job_id)))
+    }
+
+    fn drop_import(&self, job: &jobs::ImportJob) -> Result<(), FsError> {
+        self.writer.borrow_mut().set_offsets(job.start, job.start_data_block);
+        for height in job.start..=job.next {
+            self.reader.invalidate_index(height);
+        }
+        let mut imports = self.imports.borrow_mut();
+        imports.job = None;
+        self.meta.put_value(IMPORTS_RECORD, &*imports)
+    }
+
+    fn expire_import(&self) -> Result<(), FsError> {
+        let expired = self.imports.borrow().job.clone().filter(|job| job.expires_at <= (self.clock)());
+        match expired {
+            Some(job) => self.drop_import(&job),
+            None => Ok(()),
+        }
+    }
+
+    fn check_not_streaming(&self) -> Result<(), FsError> {
+        if self.streaming.get() {
+            return Err(FsError::InvalidState("A streamed message owns the end of the topic, finish or drop it first".to_string()));
+        }
+        Ok(())
+    }
+
+    fn check_not_importing(&self) -> Result<(), FsError> {
+        self.expire_import()?;
+        if let Some(job) = &self.imports.borrow().job {
+            return Err(FsError::InvalidState(format!("Import {} owns the end of the topic, commit or abort it first", job.id)));
+        }
+        Ok(())
+    }
+
+    // Handler for the streaming protocol, see StreamClient for the calling side. Cursors are bound to
+    // this topic and signed with `key` when given. In a canister pass `ic_cdk::api::data_certificate()`
+    // as the certificate.
+    #[cfg(feature = "stream")]
+    pub fn serve_stream(&self, request: &StreamRequest, key: Option<&[u8]>, certificate: Option<Vec<u8>>) -> Result<StreamResponse, FsError> {
+        self.check_not_migrating()?;
+        let topic = &self.topic_header.event_stream_name;
+        let start = match &request.cursor {
+            Some(token) => Cursor::decode(token, topic, key)?.height,
+            None => 0,
+        };
+        // Cursors into truncated messages continue at the first one that is left.
+        let start = start.max(self.topic_header.first_message_ptr);
+        let height = self.committed_height.get();
+        if start > height {
+            return Err(FsError::InvalidArgument(format!("Cursor {} is past the topic height {}", start, height)));
+        }
+        let end = height.min(start.saturating_add(request.limit.min(MAX_STREAM_BATCH) as u64));
+        let mut batch: Vec<StreamEvent> = Vec::new();
+        let mut size = 0;
+        for height in start..end {
+            if !batch.is_empty() && size >= MAX_STREAM_BATCH_BYTES {
+                break;
+            }
+            let idx = self.reader.read_idx(height, &self.storage)?;
+            let payload = self.reader.read_payload(height, &self.storage)?;
+            size += payload.len() as u64;
+            batch.push(StreamEvent { height, timestamp: idx.timestamp, payload });
+        }
+        let next = start + batch.len() as u64;
+        self.record_reads(start, batch.len() as u64);
+        Ok(StreamResponse { batch, next_cursor: Cursor::new(next).encode(topic, key), height, certificate })
+    }
+
+    // The topic as files for an asset canister, so it can be downloaded over HTTP. Covers the messages
+    // committed now, file by file, see AssetExport.
+    #[cfg(feature = "assets")]
+    pub fn export_assets(&self, prefix: &str, file_size: u64) -> Result<AssetExport<'_, S>, FsError> {
+        self.check_not_migrating()?;
+        AssetExport::new(self, prefix, file_size)
+    }
+
+    // Index entries only, no payload reads. The range is clamped to the committed messages the topic still
+    // holds, keyed entries carry their key index hash.
+    pub fn export_index(&self, range: Range<u64>) -> Result<Vec<IndexExportEntry>, FsError> {
+        let range = range.start.max(self.first_message_height())..range.end.min(self.committed_height.get());
+        let mut entries = Vec::new();
+        for height in range.clone() {
+            let idx = self.reader.read_idx(height, &self.storage)?;
+            entries.push(IndexExportEntry::from(idx));
+        }
+        if entries.iter().any(|entry| entry.flags & IndexBlock::HAS_KEY != 0) {
+            if let Some(key_index) = self.key_index.borrow().as_ref() {
+                let hashes = key_index.hashes_in(range, &self.storage);
+                for entry in entries.iter_mut().filter(|entry| entry.flags & IndexBlock::HAS_KEY != 0) {
+                    entry.key_hash = hashes.get(&entry.height).copied();
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+fn is_magic_number_valid(storage: &Storage) -> bool {
+    read_magic_number(storage) == TOPIC_HEADER_MAGIC
+}
+
+fn read_magic_number(storage: &Storage) -> u64 {
+    let mut bytes = [0u8; 8];
+    storage.read(MAGIC_NUMBER_IDX, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_magic_number(magic: u64, storage: &Storage) {
+    storage.write(MAGIC_NUMBER_IDX, &magic.to_le_bytes());
+}
+
+fn read_topic_block(storage: &Storage) -> Result<TopicHeaderBlock, FsError> {
+    read_topic_header(storage).map(|(header, _)| header)
+}
+
+// Also tells whether the header recorded its layout, see TopicHeaderBlock::parse.
+fn read_topic_header(storage: &Storage) -> Result<(TopicHeaderBlock, bool), FsError> {
+    let topic_block_size = &mut [0u8; 8];
+    storage.read(TOPIC_BLOCK_SIZE_IDX, topic_block_size);
+    let topic_block_size = u64::from_le_bytes(*topic_block_size).min(TOPIC_BLOCK_CANARY_IDX - TOPIC_BLOCK_DATA_START_IDX);
+
+    let mut bytes = vec![0u8; topic_block_size as usize];
+    storage.read(TOPIC_BLOCK_DATA_START_IDX, &mut bytes);
+    TopicHeaderBlock::parse(&bytes)
+}
+
+// Topics whose header predates the layout may have stored into what is now the meta zone. Refuses
+// those, and records the layout in the others so the check runs once.
+fn check_meta_zone(header: &TopicHeaderBlock, storage: &Storage, read_only: bool) -> Result<(), FsError> {
+    let mut size = [0u8; 8];
+    storage.read(FREE_MEMORY_BLOCK_SIZE_IDX, &mut size);
+    let size = u64::from_le_bytes(size);
+    let limit = header.layout.stable_store_max_size();
+    if size > limit {
+        return Err(FsError::InvalidState(format!("Stable store holds {} bytes, reaching into the meta zone at {}. Store at most {} bytes with the previous version before upgrading", size, header.layout.meta_zone_idx(), limit)));
+    }
+    if !read_only {
+        write_topic_block(header, storage);
+    }
+    Ok(())
+}
+
+fn write_topic_block(header: &TopicHeaderBlock, storage: &Storage) {
+    let topic_block_bytes = bincode::serialize(header).unwrap();
+    assert!(topic_block_bytes.len() as u64 <= TOPIC_BLOCK_CANARY_IDX - TOPIC_BLOCK_DATA_START_IDX);
+    let topic_block_size = (topic_block_bytes.len() as u64).to_le_bytes();
+
+    storage.write(TOPIC_BLOCK_SIZE_IDX, &topic_block_size);
+    storage.write(TOPIC_BLOCK_DATA_START_IDX, &topic_block_bytes);
+}
+
+fn write_index_height(height: u64, storage: &Storage) {
+    debug!("Writing block height to stable {}", height);
+    storage.write(INDEX_HEIGHT_IDX, &height.to_le_bytes());
+}
+
+fn write_data_block_height(height: u64, storage: &Storage) {
+    debug!("Writing block height to stable {}", height);
+    storage.write(DATA_BLOCK_HEIGHT_IDX, &height.to_le_bytes());
+}
+
+fn read_index_height(storage: &Storage) -> u64 {
+    let mut bytes = [0u8; 8];
+    storage.read(INDEX_HEIGHT_IDX, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn read_data_block_height(storage: &Storage) -> u64 {
+    let mut bytes = [0u8; 8];
+    storage.read(DATA_BLOCK_HEIGHT_IDX, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    #[cfg(feature = "aggregates")]
+    use std::collections::BTreeMap;
+    use std::io::Read;
+
+    use candid::Principal;
+    #[cfg(feature = "aggregates")]
+    use serde_json::{json, Value};
+
+    use crate::{AlarmBreach, AlarmKind, BincodeCodec, BINARY_VERSION, BLOCK_SIZE, BlockStorage, BlockCacheStats, BlockRead, BlockWrite, CompactionPolicy, ConsumerRetention, ControllerAdded, DATA_BLOCK_HEIGHT_IDX, HeightPolicy, DeduplicationConfig, DuplicateMode, DuplicateWritten, EventFilesystem, EventFilesystemBuilder, EventFilesystemEvent, FnStorage, FREE_MEMORY_BLOCK_SIZE_IDX, FsError, HashAlgorithm, HeatMapSegment, IDX_BLOCK_SIZE, IDX_ZONE_END, IndexBlock, INDEX_HEIGHT_IDX, IndexExportEntry, InstructionBudget, IntegrityChecked, IntEncoding, layout, LayoutConfig, MAGIC_NUMBER_IDX, MAX_PAGE_BYTES, Page, MessageMeta, id_to_string, ulid_id, Operation, PayloadCompression, read_data_block_height, read_topic_block, read_topic_header, RetentionPolicy, SegmentAction, write_topic_block, Storage, SubscriberAdded, TimestampRepair, SubscriberOffsetModified, SubscriberRemoved, TimeDomain, TOPIC_BLOCK_DATA_START_IDX, TOPIC_BLOCK_SIZE_IDX, TOPIC_HEADER_MAGIC, TOPIC_INITIALIZING_MAGIC, PressureCause, PressureLevel, SegmentManifest, StatsReportConfig, StatsSummary, TopicCreated, TopicOpened, TopicStats, UsageCounters, VecStorage, Watermarks, WASM_PAGE_SIZE, ZoneUsage};
+    use crate::fixtures::{count_allocations, now, set_now, small_layout};
+    #[cfg(feature = "filter")]
+    use crate::MessageFilter;
+    #[cfg(feature = "jobs")]
+    use crate::{ExportChunk, ExportKind};
+    #[cfg(feature = "merge")]
+    use crate::{merge_topics, MergedMessage, MergeOrder};
+    #[cfg(any(feature = "query", feature = "schema"))]
+    use crate::StableEncode;
+    #[cfg(feature = "query")]
+    use crate::{Query, QueryOrder, QueryPlan};
+    #[cfg(feature = "schema")]
+    use crate::MessageSchema;
+    #[cfg(feature = "stream")]
+    use crate::{Cursor, StreamRequest};
+
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; IDX_ZONE_END as usize + 1024 * 1024]);
+    }
+
+    #[test]
+    #[allow(unused_variables, clippy::get_first, clippy::needless_borrow)]
+    fn it_gets_filesystem_and_rw() {
+        let writer = get_write();
+        let reader = get_read();
+
+        let file_system = EventFilesystem::get_or_create(
+            writer,
+            reader,
+            || 0,
+            "test".to_string(),
+        );
+
+        MEMORY.with(|v| {
+            let v = v.borrow();
+            let magic_idx = layout().magic_number_idx as usize;
+            let magic_maybe: &[u8] = &v[magic_idx..magic_idx + 8];
+            let test = (&v[8..16]).to_vec().clone().get(0);
+            let u64_magic = u64::from_le_bytes(magic_maybe.try_into().unwrap());
+            assert_eq!(u64_magic, TOPIC_HEADER_MAGIC);
+        });
+
+        let topic_block = read_topic_block(&memory()).unwrap();
+        assert_eq!(topic_block.event_stream_name, "test");
+
+        let message : String = "hello world".to_string();
+
+        let res = file_system.write_topic_message::<String>(&message).unwrap();
+
+        let file_system = EventFilesystem::get_file_system(
+            writer,
+            reader,
+            || 0,
+        );
+
+        assert_eq!(file_system.read_topic_messages::<String>(res, 1).unwrap()[0], message);
+
+        let message2 = "hello world2".to_string();
+        let res = file_system.write_topic_message(&message2).unwrap();
+
+        assert_eq!(res, 1);
+        assert_eq!(file_system.read_topic_message::<String>(res).unwrap(), message2);
+    }
+
+    #[test]
+    #[allow(unused_variables)]
+    fn it_updates_topic_height() {
+        let writer = get_write();
+        let reader = get_read();
+
+        let file_system = EventFilesystem::get_or_create(
+            writer,
+            reader,
+            || 0,
+            "test".to_string(),
+        );
+
+        assert_eq!(file_system.get_topic_height(), 0);
+        for i in 0..100 {
+            let message : String = format!("hello world {}", i);
+            let res = file_system.write_topic_message::<String>(&message).unwrap();
+        }
+        assert_eq!(file_system.get_topic_height(), 100);
+
+        let file_system = EventFilesystem::get_file_system(
+            writer,
+            reader,
+            || 0,
+        );
+
+        assert_eq!(file_system.get_topic_height(), 100);
+    }
+
+    #[test]
+    #[allow(unused_must_use)]
+    fn it_writes_and_gets_free_blockstore() {
+        let file_system = EventFilesystem::get_or_create(
+            get_write(),
+            get_read(),
+            || 0,
+            "test".to_string(),
+        );
+
+        let data = "hello world";
+
+        file_system.stable_store(data);
+
+        assert_eq!(file_system.stable_restore::<String>().unwrap(), data);
+    }
+
+    #[test]
+    fn it_keeps_values_under_keys_in_the_stable_store() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let mut file_system = builder.clone().get_or_create("test".to_string()).unwrap();
+        // stable_store spans as many blocks as the zone has room for
+        file_system.stable_store(vec![7u8; 300 * 1024]).unwrap();
+        assert!(matches!(file_system.stable_store_keyed("config", 1u64), Err(FsError::InvalidState(_))));
+        assert!(file_system.enable_keyed_store(8).is_err());
+        file_system.enable_keyed_store(64 * 1024).unwrap();
+        assert!(file_system.enable_keyed_store(64 * 1024).is_err());
+
+        file_system.stable_store_keyed("config", "v1".to_string()).unwrap();
+        file_system.stable_store_keyed("heap", vec![1u8; 20_000]).unwrap();
+        file_system.stable_store_keyed("config", "v2".repeat(100)).unwrap();
+        assert!(matches!(file_system.stable_store_keyed("too_large", vec![0u8; 64 * 1024]), Err(FsError::OutOfSpace(_))));
+
+        let reopened = builder.open().unwrap();
+        assert_eq!(reopened.stable_store_keys().unwrap(), vec!["config".to_string(), "heap".to_string()]);
+        assert_eq!(reopened.stable_restore_keyed::<String>("config").unwrap(), Some("v2".repeat(100)));
+        assert_eq!(reopened.stable_restore_keyed::<Vec<u8>>("heap").unwrap(), Some(vec![1u8; 20_000]));
+        assert_eq!(reopened.stable_restore_keyed::<u64>("missing").unwrap(), None);
+        assert!(reopened.stable_remove_keyed("heap").unwrap());
+        assert!(!reopened.stable_remove_keyed("heap").unwrap());
+        reopened.stable_store_keyed("heap", vec![2u8; 40_000]).unwrap();
+        assert_eq!(reopened.stable_restore::<Vec<u8>>().unwrap(), vec![7u8; 300 * 1024]);
+    }
+
+    #[test]
+    fn it_exports_index_without_payloads() {
+        let file_system = EventFilesystem::get_or_create(
+            get_write(),
+            get_read(),
+            || 42,
+            "test".to_string(),
+        );
+
+        for i in 0..5 {
+            file_system.write_topic_message(&vec![1u8; i * 100]).unwrap();
+        }
+
+        let entries = file_system.export_index(1..10).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0], IndexExportEntry { height: 1, timestamp: 42, data_size: 108, flags: IndexBlock::COMMITTED, key_hash: None });
+        assert_eq!(entries[3].height, 4);
+        assert!(file_system.export_index(5..10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_writes_single_messages_without_allocating() {
+        let layout = small_layout();
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("test".to_string()).unwrap();
+        file_system.write_topic_message(&"warm up".to_string()).unwrap();
+        let warm = file_system.alloc_stats();
+        let messages = (0..10).map(|i| format!("event {}", i)).collect::<Vec<_>>();
+        let allocations = count_allocations(|| {
+            for message in &messages {
+                file_system.write_topic_message(message).unwrap();
+            }
+            file_system.write_raw(b"raw").unwrap();
+        });
+        assert_eq!(allocations, 0);
+        assert_eq!(file_system.alloc_stats().scratch_grows, warm.scratch_grows);
+        assert_eq!(file_system.read_topic_message::<String>(10).unwrap(), "event 9");
+    }
+
+    #[test]
+    fn it_exports_the_committed_index_with_key_hashes() {
+        let layout = small_layout();
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("test".to_string()).unwrap();
+        file_system.enable_key_index(16).unwrap();
+        file_system.write_topic_message(&"plain".to_string()).unwrap();
+        file_system.write_topic_message(&"plain".to_string()).unwrap();
+        file_system.write_topic_message_keyed(b"order-1", &"keyed".to_string()).unwrap();
+        file_system.truncate_before(1, &InstructionBudget::unlimited()).unwrap();
+        // An index height ahead of the committed one, as a failed commit can leave it.
+        file_system.storage.write(INDEX_HEIGHT_IDX, &5u64.to_le_bytes());
+
+        let entries = file_system.export_index(0..10).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.height).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(entries[0].key_hash, None);
+        assert_eq!(entries[1].flags, IndexBlock::COMMITTED | IndexBlock::HAS_KEY);
+        assert_eq!(entries[1].key_hash, Some(crate::key_index::key_hash(b"order-1")));
+    }
+
+    #[test]
+    fn it_records_hash_algorithm_in_header() {
+        let file_system = EventFilesystem::get_or_create_with_hash(
+            get_write(),
+            get_read(),
+            || 0,
+            "test".to_string(),
+            HashAlgorithm::Crc32,
+        );
+        assert_eq!(file_system.get_topic_header().hash_algorithm().unwrap(), HashAlgorithm::Crc32);
+
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        assert_eq!(read_topic_block(&memory()).unwrap().hash_algorithm().unwrap(), HashAlgorithm::Crc32);
+        assert_eq!(file_system.hasher().is_ok(), cfg!(feature = "crc32"));
+    }
+
+    #[test]
+    fn it_refuses_to_open_a_topic_under_another_name() {
+        EventFilesystem::get_or_create(get_write(), get_read(), || 0, "orders".to_string());
+        let builder = EventFilesystemBuilder::new(get_write(), get_read(), || 0);
+        let mismatch = builder.clone().get_or_create("payments".to_string()).err();
+        assert_eq!(mismatch, Some(FsError::TopicNameMismatch { expected: "payments".to_string(), found: "orders".to_string() }));
+        let file_system = builder.allow_name_mismatch(true).get_or_create("payments".to_string()).unwrap();
+        assert_eq!(file_system.get_topic_header().event_stream_name, "orders");
+        let legacy = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "payments".to_string());
+        assert_eq!(legacy.get_topic_header().event_stream_name, "orders");
+
+        // The name is checked before open writes anything.
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let storage = builder.clone().get_or_create("orders".to_string()).unwrap().storage.clone();
+        let memory = |storage: &Storage| {
+            let mut bytes = vec![0u8; 2 * 1024 * 1024];
+            storage.read(0, &mut bytes);
+            bytes
+        };
+        // A topic from before canaries, open would install them.
+        storage.write(layout.descriptor().canaries[0], &[0u8; 8]);
+        let before = memory(&storage);
+        assert!(builder.get_or_create("payments".to_string()).is_err());
+        assert!(memory(&storage) == before);
+    }
+
+    #[test]
+    fn it_checks_the_stable_store_of_topics_without_a_meta_zone() {
+        EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        // The header as format 1_000_000 wrote it, without a layout.
+        let header = bincode::serialize(&("test".to_string(), 0u64, 1_000_000u32)).unwrap();
+        memory().write(TOPIC_BLOCK_SIZE_IDX, &(header.len() as u64).to_le_bytes());
+        memory().write(TOPIC_BLOCK_DATA_START_IDX, &header);
+        let builder = EventFilesystemBuilder::new(get_write(), get_read(), || 0);
+
+        let limit = LayoutConfig::default().stable_store_max_size();
+        memory().write(FREE_MEMORY_BLOCK_SIZE_IDX, &(limit + 1).to_le_bytes());
+        assert!(matches!(builder.clone().open().err(), Some(FsError::InvalidState(_))));
+
+        memory().write(FREE_MEMORY_BLOCK_SIZE_IDX, &limit.to_le_bytes());
+        assert!(builder.clone().open_read_only().is_ok());
+        assert!(!read_topic_header(&memory()).unwrap().1);
+        builder.open().unwrap();
+        let (header, records_layout) = read_topic_header(&memory()).unwrap();
+        assert!(records_layout);
+        assert_eq!(header.layout, LayoutConfig::default());
+    }
+
+    #[test]
+    fn it_refuses_to_open_newer_formats() {
+        EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        let mut header = read_topic_block(&memory()).unwrap();
+        assert_eq!(header.binary_version, BINARY_VERSION);
+        header.binary_version += 1;
+        write_topic_block(&header, &memory());
+        let opened = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open();
+        assert!(matches!(opened.err(), Some(FsError::Unsupported(_))));
+    }
+
+    #[test]
+    fn it_flags_index_entries() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let mut file_system = builder.clone().get_or_create("test".to_string()).unwrap();
+        file_system.enable_key_index(16).unwrap();
+        file_system.write_topic_message(&"plain".to_string()).unwrap();
+        file_system.write_topic_message_keyed(b"order-1", &"keyed".to_string()).unwrap();
+        file_system.write_marker("done").unwrap();
+        file_system.truncate_before(1, &InstructionBudget::unlimited()).unwrap();
+
+        let flags = |file_system: &EventFilesystem<VecStorage>| (0..3).map(|height| file_system.index_entry(height).unwrap().flags).collect::<Vec<_>>();
+        assert_eq!(flags(&file_system), vec![IndexBlock::COMMITTED | IndexBlock::ERASED, IndexBlock::COMMITTED | IndexBlock::HAS_KEY, IndexBlock::COMMITTED]);
+        assert!(file_system.index_entry(0).unwrap().is_erased());
+        assert!(file_system.index_entry(3).is_err());
+
+        // Rewrite the index as format 1_000_000 left it.
+        let storage = file_system.storage.clone();
+        let entries: Vec<_> = (0..3).map(|height| file_system.index_entry(height).unwrap().to_bytes()).collect();
+        storage.write(layout.idx_zone_idx(), &[0u8; 3 * IDX_BLOCK_SIZE as usize]);
+        for (height, entry) in entries.iter().enumerate() {
+            storage.write(layout.idx_zone_idx() + height as u64 * 40, &entry[..40]);
+        }
+        let mut header = read_topic_block(&storage).unwrap();
+        header.binary_version = 1_000_000;
+        write_topic_block(&header, &storage);
+
+        // Legacy entries are read in place and carry no flags beyond COMMITTED.
+        let legacy = builder.clone().open().unwrap();
+        assert_eq!(read_topic_block(&storage).unwrap().binary_version, 1_000_000);
+        assert_eq!(flags(&legacy), vec![IndexBlock::COMMITTED; 3]);
+        assert_eq!(legacy.read_topic_message::<String>(1).unwrap(), "keyed");
+        assert!(legacy.is_marker(2).unwrap());
+        assert_eq!(legacy.write_topic_message(&"next".to_string()).unwrap(), 3);
+        let mut bytes = [0u8; 40];
+        storage.read(layout.idx_zone_idx() + 3 * 40, &mut bytes);
+        assert_eq!(IndexBlock::from_unflagged_bytes(&bytes).unwrap().height, 3);
+
+        let reopened = builder.open().unwrap();
+        assert_eq!(reopened.read_topic_message::<String>(3).unwrap(), "next");
+        assert_eq!(reopened.get_topic_height(), 4);
+    }
+
+    #[test]
+    fn it_reads_pages_within_the_byte_budget() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let file_system = builder.clone().get_or_create("test".to_string()).unwrap();
+        for message in [vec![1u8; 1000], vec![2u8; 1000]] {
+            file_system.write_topic_message(&message).unwrap();
+        }
+        file_system.write_marker("half").unwrap();
+        for message in [vec![3u8; 1000], vec![4u8; 3000]] {
+            file_system.write_topic_message(&message).unwrap();
+        }
+        assert!(file_system.set_page_bytes(0).is_err());
+        assert!(file_system.set_page_bytes(MAX_PAGE_BYTES + 1).is_err());
+        file_system.set_page_bytes(2500).unwrap();
+
+        let page = file_system.read_page::<Vec<u8>>(None, 10).unwrap();
+        assert_eq!(page.messages, vec![(0, vec![1u8; 1000]), (1, vec![2u8; 1000])]);
+        assert_eq!((page.next, page.has_more), (Some(2), true));
+        let page = file_system.read_page::<Vec<u8>>(page.next, 10).unwrap();
+        assert_eq!(page.messages, vec![(3, vec![3u8; 1000])]);
+        assert_eq!((page.next, page.has_more), (Some(3), true));
+        assert!(matches!(file_system.read_page::<Vec<u8>>(page.next, 10), Err(FsError::MessageTooLarge { size: 3008, limit: 2500 })));
+
+        assert_eq!(file_system.read_page::<Vec<u8>>(None, 1).unwrap().next, Some(0));
+        let last = file_system.read_page::<Vec<u8>>(Some(4), 10).unwrap();
+        assert_eq!(last, Page { messages: Vec::new(), next: Some(4), has_more: false });
+        assert!(file_system.read_page::<Vec<u8>>(Some(5), 10).is_err());
+        assert_eq!(builder.open().unwrap().page_bytes(), 2500);
+    }
+
+    #[test]
+    fn it_stores_raw_bytes() {
+        let layout = small_layout();
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("test".to_string()).unwrap();
+        assert_eq!(file_system.write_raw(b"DIDL\x00\x01\x71\x02hi").unwrap(), 0);
+        file_system.write_topic_message(&7u32).unwrap();
+        assert_eq!(file_system.write_raw(&[]).unwrap(), 2);
+
+        assert_eq!(file_system.read_raw(0).unwrap(), b"DIDL\x00\x01\x71\x02hi");
+        assert_eq!(file_system.read_raw(1).unwrap(), bincode::serialize(&7u32).unwrap());
+        assert!(file_system.is_marker(2).unwrap());
+        assert!(file_system.read_raw(3).is_err());
+
+        #[cfg(feature = "schema")]
+        {
+            file_system.set_schema(Some(MessageSchema::parse(r#"{ "type": "array" }"#).unwrap())).unwrap();
+            assert!(matches!(file_system.write_raw(b"blob"), Err(FsError::Serialize(_))));
+            assert_eq!(file_system.get_topic_height(), 3);
+        }
+    }
+
+    #[test]
+    fn it_streams_large_messages_in_chunks() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let file_system = builder.clone().get_or_create("test".to_string()).unwrap();
+        file_system.write_topic_message(&"before".to_string()).unwrap();
+
+        let mut message = file_system.begin_message().unwrap();
+        assert_eq!(message.height(), 1);
+        for chunk in [vec![1u8; 700], vec![2u8; 700], vec![3u8; 100]] {
+            message.append(&chunk).unwrap();
+        }
+        assert_eq!(message.size(), 1500);
+        assert!(matches!(file_system.write_topic_message(&"during".to_string()), Err(FsError::InvalidState(_))));
+        assert!(file_system.begin_message().is_err());
+        assert_eq!(file_system.get_topic_height(), 1);
+        assert_eq!(message.finish(), Ok(1));
+
+        // Dropped messages leave nothing behind.
+        let mut dropped = file_system.begin_message().unwrap();
+        dropped.append(&[9u8; 600]).unwrap();
+        drop(dropped);
+        assert_eq!(file_system.write_topic_message(&"after".to_string()), Ok(2));
+        assert_eq!(file_system.begin_message().unwrap().finish(), Ok(3));
+
+        let reopened = builder.open().unwrap();
+        assert_eq!(reopened.read_raw(1).unwrap(), [vec![1u8; 700], vec![2u8; 700], vec![3u8; 100]].concat());
+        assert_eq!(reopened.index_entry(1).unwrap().end_idx - reopened.index_entry(1).unwrap().start_idx, 3);
+        assert_eq!(reopened.read_topic_message::<String>(2).unwrap(), "after");
+        assert!(reopened.is_marker(3).unwrap());
+
+        #[cfg(feature = "schema")]
+        {
+            file_system.set_schema(Some(MessageSchema::parse(r#"{ "type": "array" }"#).unwrap())).unwrap();
+            assert!(matches!(file_system.begin_message(), Err(FsError::Unsupported(_))));
+        }
+    }
+
+    #[test]
+    fn it_reports_stats_to_an_ops_topic_and_the_outbox() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), now).layout(layout);
+        let file_system = builder.clone().get_or_create("orders".to_string()).unwrap();
+        let ops = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("ops".to_string()).unwrap();
+        assert_eq!(file_system.report_stats(Some(&ops)), Ok(None));
+
+        file_system.enable_stats_reports(StatsReportConfig { interval: 100, outbox: true }).unwrap();
+        file_system.write_topic_message(&vec![1u8; 600]).unwrap();
+        set_now(50);
+        let first = file_system.report_stats(Some(&ops)).unwrap().unwrap();
+        assert_eq!((first.sequence, first.topic.as_str(), first.timestamp, first.height), (0, "orders", 50, 1));
+        assert_eq!((first.data_bytes, first.bytes_written, first.errors), (2 * BLOCK_SIZE, 608, 0));
+        assert_eq!(ops.read_topic_message::<StatsSummary>(0).unwrap(), first);
+
+        set_now(149);
+        assert_eq!(file_system.report_stats(None::<&EventFilesystem<VecStorage>>), Ok(None));
+        set_now(150);
+        assert_eq!(file_system.report_stats(None::<&EventFilesystem<VecStorage>>).unwrap().unwrap().sequence, 1);
+        assert_eq!(ops.get_topic_height(), 1);
+
+        let reopened = builder.open().unwrap();
+        assert_eq!(reopened.pending_stats(10).iter().map(|summary| summary.sequence).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(reopened.acknowledge_stats(0), Ok(1));
+        assert_eq!(reopened.pending_stats(10).len(), 1);
+        assert!(reopened.enable_stats_reports(StatsReportConfig { interval: 0, outbox: true }).is_err());
+        reopened.enable_stats_reports(StatsReportConfig { interval: 100, outbox: false }).unwrap();
+        assert!(reopened.pending_stats(10).is_empty());
+        reopened.disable_stats_reports().unwrap();
+        assert_eq!(reopened.stats_report_config(), None);
+    }
+
+    #[test]
+    fn it_reads_messages_piece_by_piece() {
+        let layout = small_layout();
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 7).layout(layout).get_or_create("test".to_string()).unwrap();
+        file_system.set_event_times(true).unwrap();
+        let large: Vec<u64> = (0..5000).collect();
+        file_system.write_topic_message(&large).unwrap();
+        file_system.write_marker("done").unwrap();
+
+        let mut reader = file_system.open_message(0).unwrap();
+        assert_eq!(reader.size(), 8 + 5000 * 8);
+        let mut length = [0u8; 8];
+        reader.read_exact(&mut length).unwrap();
+        assert_eq!((u64::from_le_bytes(length), reader.remaining()), (5000, 5000 * 8));
+        let decoded: Vec<u64> = bincode::deserialize_from(file_system.open_message(0).unwrap()).unwrap();
+        assert_eq!(decoded, large);
+
+        let mut bytes = Vec::new();
+        file_system.open_message(1).unwrap().read_to_end(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+        assert!(file_system.open_message(2).is_err());
+    }
+
+    #[test]
+    fn it_keeps_segment_manifests() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), now).layout(layout);
+        let file_system = builder.clone().get_or_create("test".to_string()).unwrap();
+        file_system.enable_key_index(16).unwrap();
+        file_system.write_topic_message(&0u64).unwrap();
+        file_system.enable_segment_manifests(4).unwrap();
+        for height in 1..10u64 {
+            set_now(height * 10);
+            match height {
+                5 => file_system.write_topic_message_keyed(b"a", &height).unwrap(),
+                _ => file_system.write_topic_message(&height).unwrap(),
+            };
+        }
+
+        let segments = file_system.segment_manifests();
+        assert_eq!(segments.iter().map(SegmentManifest::heights).collect::<Vec<_>>(), vec![4..8, 8..10]);
+        assert_eq!((segments[1].entries, segments[1].min_timestamp, segments[1].max_timestamp), (2, 80, 90));
+        assert_eq!(file_system.prune_segments(0..100, Some(80..100), None), vec![0..4, 8..10]);
+        assert_eq!(file_system.prune_segments(2..100, None, Some(b"a")), vec![2..8]);
+
+        let mut reopened = builder.open().unwrap();
+        assert_eq!(reopened.segment_manifests(), segments);
+        reopened.truncate_before(8, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!(reopened.prune_segments(0..100, None, None), vec![8..10]);
+        assert_eq!(reopened.segment_manifests().len(), 1);
+        reopened.disable_segment_manifests().unwrap();
+        assert!(reopened.segment_manifests().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "filter")]
+    fn it_reads_filtered_messages() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        for i in 0..10u64 {
+            file_system.write_topic_message(&(format!("event {}", i), i)).unwrap();
+        }
+
+        let filter = MessageFilter::parse("$.1 == 4 || $.0 contains \"7\"").unwrap();
+        let matches = file_system.read_filtered::<(String, u64)>(2, 100, &filter).unwrap();
+        assert_eq!(matches, vec![(4, ("event 4".to_string(), 4)), (7, ("event 7".to_string(), 7))]);
+        assert!(file_system.read_filtered::<(String, u64)>(5, 2, &filter).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_records_alarm_breaches_as_admin_events() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 7, "test".to_string());
+        file_system.set_alarm(AlarmKind::MessagesPerMinute, 3, |_| {});
+        file_system.set_alarm(AlarmKind::ErrorsPerMinute, 0, |_| {});
+
+        for i in 0..5 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        assert!(file_system.read_topic_message::<String>(0).is_err());
+
+        let events = file_system.admin_events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1], EventFilesystemEvent::AlarmTriggered(AlarmBreach {
+            kind: AlarmKind::MessagesPerMinute,
+            observed: 4,
+            threshold: 3,
+            timestamp: 7,
+        }));
+    }
+
+    #[test]
+    fn it_records_lifecycle_as_admin_events() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 3, "test".to_string());
+        file_system.write_topic_message(&1u64).unwrap();
+        assert_eq!(file_system.admin_events(), vec![EventFilesystemEvent::TopicCreated(TopicCreated {
+            event_stream_name: "test".to_string(),
+            binary_version: BINARY_VERSION,
+            timestamp: 3,
+        })]);
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 5);
+        let index_canary = layout().canaries[3];
+        get_write()(index_canary, &[0; 8]);
+        assert!(file_system.check().is_err());
+        assert_eq!(file_system.admin_events(), vec![
+            EventFilesystemEvent::TopicCreated(TopicCreated { event_stream_name: "test".to_string(), binary_version: BINARY_VERSION, timestamp: 3 }),
+            EventFilesystemEvent::TopicOpened(TopicOpened { height: 1, binary_version: BINARY_VERSION, timestamp: 5 }),
+            EventFilesystemEvent::IntegrityChecked(IntegrityChecked {
+                error: Some(FsError::RedZoneOverwritten { offset: index_canary, boundary: "index/data".to_string() }),
+                timestamp: 5,
+            }),
+        ]);
+    }
+
+    #[test]
+    fn it_persists_compression_dictionary() {
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        assert_eq!(file_system.compression_dictionary().unwrap(), None);
+
+        let dictionary = crate::CompressionDictionary::from_bytes(vec![3u8; 2048]);
+        file_system.set_compression_dictionary(&dictionary).unwrap();
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.compression_dictionary().unwrap(), Some(dictionary));
+    }
+
+    #[test]
+    fn it_persists_codec_options() {
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        let codec = BincodeCodec { int_encoding: IntEncoding::Varint, size_limit: 1024, reject_trailing_bytes: true };
+        file_system.set_codec(codec).unwrap();
+        file_system.write_topic_message(&(1u64, 2u64)).unwrap();
+        assert!(file_system.write_topic_message(&vec![0u8; 2048]).is_err());
+
+        let mut file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.codec(), codec);
+        assert_eq!(file_system.read_topic_message::<(u64, u64)>(0).unwrap(), (1, 2));
+        file_system.set_codec(BincodeCodec { size_limit: 4096, ..codec }).unwrap();
+    }
+
+    #[test]
+    fn it_reads_messages_with_the_codec_they_were_written_with() {
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        file_system.write_topic_message(&(1u64, 2u64)).unwrap();
+        let varint = BincodeCodec { int_encoding: IntEncoding::Varint, ..BincodeCodec::default() };
+        file_system.set_codec(varint).unwrap();
+        file_system.set_codec(BincodeCodec { size_limit: 1024, ..varint }).unwrap();
+        file_system.write_topic_message(&(3u64, 4u64)).unwrap();
+        assert!(file_system.write_topic_message(&vec![0u8; 2048]).is_err());
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.codec_history(), vec![
+            (0, BincodeCodec::default()),
+            (1, BincodeCodec { size_limit: 1024, ..varint }),
+        ]);
+        assert_eq!(file_system.read_topic_messages::<(u64, u64)>(0, 2).unwrap(), vec![(1, 2), (3, 4)]);
+        assert_eq!(file_system.read_topic_message::<(u64, u64)>(1).unwrap(), (3, 4));
+    }
+
+    #[test]
+    fn it_writes_and_stores_with_a_given_codec() {
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("test".to_string()).unwrap();
+        let varint = BincodeCodec { int_encoding: IntEncoding::Varint, ..BincodeCodec::default() };
+        let height = file_system.write_topic_message_with(&(1u64, 2u64), &varint).unwrap();
+        assert_eq!(file_system.read_topic_message_with::<(u64, u64), _>(height, &varint).unwrap(), (1, 2));
+        assert!(file_system.read_topic_message::<(u64, u64)>(height).is_err());
+        file_system.stable_store_with(&"state".to_string(), &varint).unwrap();
+        assert_eq!(file_system.stable_restore_with::<String, _>(&varint).unwrap(), "state");
+
+        #[cfg(feature = "candid")]
+        {
+            use crate::CandidCodec;
+            let height = file_system.write_topic_message_with(&("candid".to_string(), 3u64), &CandidCodec).unwrap();
+            assert_eq!(file_system.read_topic_message_with::<(String, u64), _>(height, &CandidCodec).unwrap(), ("candid".to_string(), 3));
+            assert!(file_system.read_topic_message_with::<(String, u64), _>(height, &varint).is_err());
+            file_system.stable_store_with(&vec![1u32, 2], &CandidCodec).unwrap();
+            assert_eq!(file_system.stable_restore_with::<Vec<u32>, _>(&CandidCodec).unwrap(), vec![1, 2]);
+        }
+
+        #[cfg(feature = "schema")]
+        {
+            file_system.set_schema(Some(MessageSchema::parse(r#"{ "type": "array" }"#).unwrap())).unwrap();
+            assert!(matches!(file_system.write_topic_message_with(&(1u64, 2u64), &varint), Err(FsError::Serialize(_))));
+            assert_eq!(file_system.write_topic_message(&(1u64, 2u64)).unwrap(), file_system.get_topic_height() - 1);
+        }
+    }
+
+    #[test]
+    fn it_reports_writes_across_zone_boundaries() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        file_system.check().unwrap();
+
+        let layout = layout();
+        get_write()(layout.canaries[2], &0u64.to_le_bytes());
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        file_system.check().unwrap();
+
+        get_write()(layout.index_zone.end - 16, &[0xff; 32]);
+        let expected = FsError::RedZoneOverwritten { offset: layout.canaries[3], boundary: "index/data".to_string() };
+        assert_eq!(file_system.check(), Err(expected.clone()));
+
+        let last = IndexBlock { height: layout.max_index_entries - 2, data_size: 0, start_idx: 0, end_idx: 0, timestamp: 0, flags: IndexBlock::COMMITTED };
+        get_write()(layout.index_entry_offset(last.height), &last.to_bytes());
+        get_write()(INDEX_HEIGHT_IDX, &(layout.max_index_entries - 1).to_le_bytes());
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.write_topic_message(&1u64), Err(expected));
+        assert_eq!(file_system.write_topic_message(&1u64), Err(FsError::IndexZoneFull { capacity: layout.max_index_entries }));
+        assert!(file_system.is_full());
+    }
+
+    #[test]
+    fn it_writes_markers_without_data_blocks() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 7, "test".to_string());
+        assert_eq!(file_system.write_marker("heartbeat").unwrap(), 0);
+        file_system.write_topic_message(&"payload".to_string()).unwrap();
+        let data_height = read_data_block_height(&memory());
+        assert_eq!(file_system.write_marker("heartbeat").unwrap(), 2);
+        assert_eq!(read_data_block_height(&memory()), data_height);
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 7);
+        assert!(file_system.is_marker(0).unwrap());
+        assert!(!file_system.is_marker(1).unwrap());
+        file_system.read_topic_message::<()>(2).unwrap();
+        assert_eq!(file_system.export_index(0..3).unwrap()[2].timestamp, 7);
+
+        assert!(file_system.is_marker(3).is_err());
+        assert!(file_system.read_topic_message::<()>(3).is_err());
+        assert!(file_system.read_topic_messages::<()>(2, 2).is_err());
+    }
+
+    #[test]
+    fn it_reads_since_last_marker_of_a_kind() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        file_system.write_topic_message(&1u64).unwrap();
+        assert_eq!(file_system.last_marker("settlement"), None);
+        assert_eq!(file_system.read_since_marker::<u64>("settlement").unwrap(), vec![(0, 1)]);
+
+        file_system.write_marker("settlement").unwrap();
+        file_system.write_topic_message(&2u64).unwrap();
+        file_system.write_marker("heartbeat").unwrap();
+        file_system.write_topic_message(&3u64).unwrap();
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.last_marker("settlement"), Some(1));
+        assert_eq!(file_system.last_marker("heartbeat"), Some(3));
+        assert_eq!(file_system.read_since_marker::<u64>("settlement").unwrap(), vec![(2, 2), (4, 3)]);
+        assert_eq!(file_system.read_since_marker::<u64>("heartbeat").unwrap(), vec![(4, 3)]);
+    }
+
+    #[test]
+    fn it_redoes_interrupted_initialization() {
+        get_write()(MAGIC_NUMBER_IDX, &TOPIC_INITIALIZING_MAGIC.to_le_bytes());
+        get_write()(INDEX_HEIGHT_IDX, &42u64.to_le_bytes());
+        get_write()(TOPIC_BLOCK_SIZE_IDX, &400u64.to_le_bytes());
+        assert!(std::panic::catch_unwind(|| EventFilesystem::get_file_system(get_write(), get_read(), || 0)).is_err());
+
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "resumed".to_string());
+        assert_eq!(file_system.get_topic_height(), 0);
+        assert_eq!(file_system.get_topic_header().event_stream_name, "resumed");
+        file_system.check().unwrap();
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(read_topic_block(&memory()).unwrap().event_stream_name, "resumed");
+        assert_eq!(file_system.get_topic_height(), 0);
+    }
+
+    #[test]
+    fn it_compacts_with_a_filter() {
+        thread_local! {
+            static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counter() -> u64 {
+            INSTRUCTIONS.with(|c| {
+                c.set(c.get() + 10);
+                c.get()
+            })
+        }
+
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        for i in 0..10u64 {
+            file_system.write_topic_message(&format!("{}-{}", if i % 3 == 0 { "buggy" } else { "good" }, i)).unwrap();
+        }
+        file_system.write_marker("checkpoint").unwrap();
+        file_system.write_marker("dropped").unwrap();
+        file_system.write_topic_message(&"good-12".to_string()).unwrap();
+
+        let codec = file_system.codec();
+        let keep = move |entry: &IndexExportEntry, payload: &[u8]| {
+            entry.height != 11 && codec.deserialize::<String>(payload).map_or(true, |message| !message.starts_with("buggy"))
+        };
+        let progress = file_system.compact_with_filter(keep, &InstructionBudget::new(counter, 25)).unwrap();
+        assert_eq!((progress.scanned, progress.kept, progress.is_done()), (3, 2, false));
+        assert!(matches!(file_system.read_topic_message::<String>(0), Err(FsError::InvalidState(_))));
+        assert!(file_system.grow_index_zone(IDX_ZONE_END, &InstructionBudget::unlimited()).is_err());
+
+        let mut file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        let progress = file_system.compact_with_filter(keep, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!((progress.height, progress.kept, progress.is_done()), (13, 8, true));
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.get_topic_height(), 8);
+        let goods = (0..10).filter(|i| i % 3 != 0).map(|i| format!("good-{}", i));
+        assert_eq!(file_system.read_topic_messages::<String>(0, 6).unwrap(), goods.collect::<Vec<_>>());
+        assert_eq!(file_system.last_marker("checkpoint"), Some(6));
+        assert_eq!(file_system.last_marker("dropped"), None);
+        assert_eq!(file_system.read_topic_message::<String>(7).unwrap(), "good-12");
+        assert_eq!(file_system.write_topic_message(&"after".to_string()).unwrap(), 8);
+        assert_eq!(file_system.read_topic_message::<String>(8).unwrap(), "after");
+    }
+
+    #[test]
+    fn it_compacts_with_a_policy() {
+        // Thins out all but the latest segment to every third event, errors are kept forever.
+        struct Thinning(BincodeCodec);
+        impl CompactionPolicy for Thinning {
+            fn plan(&self, stats: &TopicStats, segment: &SegmentManifest) -> SegmentAction {
+                if segment.heights().end + stats.segment_size <= stats.height { SegmentAction::Rewrite } else { SegmentAction::Keep }
+            }
+
+            fn keep(&self, entry: &IndexExportEntry, payload: &[u8]) -> bool {
+                entry.height.is_multiple_of(3) || self.0.deserialize::<String>(payload).is_ok_and(|message| message.starts_with("error"))
+            }
+        }
+
+        let layout = small_layout();
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("thinned".to_string()).unwrap();
+        let policy = Thinning(file_system.codec());
+        assert!(matches!(file_system.compact_with_policy(&policy, &InstructionBudget::unlimited()), Err(FsError::InvalidState(_))));
+        file_system.enable_segment_manifests(10).unwrap();
+        for i in 0..30u64 {
+            file_system.write_topic_message(&format!("{}-{}", if i % 5 == 0 { "error" } else { "info" }, i)).unwrap();
+        }
+
+        let progress = file_system.compact_with_policy(&policy, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!((progress.height, progress.kept), (30, 19));
+        let kept = [0, 3, 5, 6, 9, 10, 12, 15, 18].into_iter().chain(20..30);
+        let expected = kept.map(|i| format!("{}-{}", if i % 5 == 0 { "error" } else { "info" }, i)).collect::<Vec<_>>();
+        assert_eq!(file_system.read_topic_messages::<String>(0, 19).unwrap(), expected);
+
+        file_system.write_topic_message(&"info-next".to_string()).unwrap();
+        let progress = file_system.compact_with_policy(&RetentionPolicy::MaxMessages(5), &InstructionBudget::unlimited()).unwrap();
+        assert_eq!(progress.kept, 20);
+    }
+
+    #[test]
+    fn it_applies_a_height_policy_at_open() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 3).layout(layout);
+        let file_system = builder.clone().get_or_create("heights".to_string()).unwrap();
+        for i in 0..3u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        file_system.storage.write(INDEX_HEIGHT_IDX, &5u64.to_le_bytes());
+        let Err(FsError::HeightMismatch(report)) = builder.clone().open() else { panic!("opened a topic missing entries") };
+        assert_eq!((report.stored_height, report.index_height, report.repaired_with), (5, 3, None));
+
+        let file_system = builder.clone().height_policy(HeightPolicy::TrustHeight).open().unwrap();
+        assert_eq!(file_system.height_repair().map(|report| report.repaired_with), Some(Some(HeightPolicy::TrustHeight)));
+        assert_eq!(file_system.get_topic_height(), 5);
+        assert!(file_system.is_marker(4).unwrap() && file_system.index_entry(4).unwrap().is_aborted());
+        assert_eq!(file_system.write_topic_message(&5u64).unwrap(), 5);
+
+        // What a write that failed before its commit leaves behind stays uncommitted unless the index is trusted.
+        file_system.storage.write(INDEX_HEIGHT_IDX, &5u64.to_le_bytes());
+        let file_system = builder.clone().open().unwrap();
+        assert_eq!((file_system.get_topic_height(), file_system.height_repair()), (5, None));
+        let file_system = builder.clone().height_policy(HeightPolicy::TrustIndex).open().unwrap();
+        assert_eq!(file_system.get_topic_height(), 6);
+        assert_eq!(file_system.read_topic_message::<u64>(5).unwrap(), 5);
+
+        file_system.storage.write(DATA_BLOCK_HEIGHT_IDX, &0u64.to_le_bytes());
+        assert!(matches!(builder.clone().open(), Err(FsError::HeightMismatch(_))));
+        let file_system = builder.clone().height_policy(HeightPolicy::TrustIndex).open().unwrap();
+        assert_eq!(read_data_block_height(&file_system.storage), 4);
+        assert!(builder.open().unwrap().height_repair().is_none());
+    }
+
+    #[test]
+    fn it_repairs_heights_in_the_constructors_without_a_result() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "heights".to_string());
+        for i in 0..3u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        memory().write(INDEX_HEIGHT_IDX, &5u64.to_le_bytes());
+        assert!(matches!(EventFilesystemBuilder::new(get_write(), get_read(), || 0).open(), Err(FsError::HeightMismatch(_))));
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.height_repair().map(|report| report.repaired_with), Some(Some(HeightPolicy::TrustHeight)));
+        assert_eq!(file_system.get_topic_height(), 5);
+        memory().write(INDEX_HEIGHT_IDX, &7u64.to_le_bytes());
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "heights".to_string());
+        assert_eq!(file_system.get_topic_height(), 7);
+        assert_eq!(file_system.read_topic_message::<u64>(2).unwrap(), 2);
+    }
+
+    #[test]
+    fn it_reads_its_own_writes() {
+        let small = small_layout().with_max_messages(102);
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).layout(small).get_or_create("small".to_string()).unwrap();
+        assert_eq!(file_system.last_committed_height(), None);
+        for i in 0..100u64 {
+            let height = file_system.write_topic_message(&i).unwrap();
+            assert_eq!(file_system.read_topic_message::<u64>(height).unwrap(), i);
+            assert_eq!(file_system.last_committed_height(), Some(height));
+            assert_eq!(file_system.last_appended_height(), Some(height));
+        }
+
+        get_write()(file_system.layout().canaries[3], &0u64.to_le_bytes());
+        assert!(matches!(file_system.write_topic_message(&100u64), Err(FsError::RedZoneOverwritten { .. })));
+        assert_eq!(file_system.last_appended_height(), Some(100));
+        assert_eq!(file_system.last_committed_height(), Some(99));
+        assert!(file_system.read_topic_message::<u64>(100).is_err());
+        let reopened = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        assert_eq!(reopened.last_appended_height(), Some(99));
+    }
+
+    #[test]
+    fn it_grows_the_index_zone_in_place() {
+        thread_local! {
+            static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counter() -> u64 {
+            INSTRUCTIONS.with(|c| {
+                c.set(c.get() + 10);
+                c.get()
+            })
+        }
+
+        let small = small_layout().with_max_messages(102);
+        let mut file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).layout(small).get_or_create("small".to_string()).unwrap();
+        for i in 0..102u64 {
+            file_system.write_topic_message(&vec![i as u8; 30_000]).unwrap();
+        }
+        assert_eq!(file_system.write_topic_message(&0u8), Err(FsError::IndexZoneFull { capacity: 102 }));
+        assert_eq!(file_system.remaining_capacity(), 0);
+        assert!(file_system.grow_index_zone(2048, &InstructionBudget::unlimited()).is_err());
+
+        let progress = file_system.grow_index_zone(8192, &InstructionBudget::new(counter, 5)).unwrap();
+        assert!(!progress.is_done());
+        assert!(matches!(file_system.read_topic_message::<Vec<u8>>(0), Err(FsError::InvalidState(_))));
+        assert!(matches!(file_system.write_topic_message(&0u8), Err(FsError::InvalidState(_))));
+
+        let mut file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        assert!(file_system.grow_index_zone(16384, &InstructionBudget::unlimited()).is_err());
+        assert!(file_system.grow_index_zone(8192, &InstructionBudget::unlimited()).unwrap().is_done());
+        assert_eq!(file_system.layout(), LayoutConfig { index_zone_size: 8192, ..small }.descriptor());
+
+        file_system.write_topic_message(&vec![102u8; 30_000]).unwrap();
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        file_system.check().unwrap();
+        for i in [0u64, 50, 101, 102] {
+            assert_eq!(file_system.read_topic_message::<Vec<u8>>(i).unwrap(), vec![i as u8; 30_000]);
+        }
+    }
+
+    #[test]
+    fn it_grows_memory_before_writing() {
+        thread_local! {
+            static PAGES: Cell<u64> = const { Cell::new(IDX_ZONE_END / WASM_PAGE_SIZE) };
+        }
+        fn grow(pages: u64) -> Result<u64, String> {
+            PAGES.with(|size| match size.get() + pages {
+                total if total > IDX_ZONE_END / WASM_PAGE_SIZE + 1 => Err("limit reached".to_string()),
+                total => Ok(size.replace(total)),
+            })
+        }
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).grow_fn(grow).get_or_create("test".to_string()).unwrap();
+        assert_eq!(file_system.write_topic_message(&vec![1u8; 30_000]), Ok(0));
+        assert_eq!(PAGES.with(|size| size.get()), IDX_ZONE_END / WASM_PAGE_SIZE + 1);
+        assert!(matches!(file_system.write_topic_message(&vec![2u8; 40_000]), Err(FsError::GrowFailed { .. })));
+        assert_eq!(file_system.get_topic_height(), 1);
+        assert_eq!(file_system.write_topic_message(&vec![3u8; 30_000]), Ok(1));
+        assert_eq!(file_system.read_topic_message::<Vec<u8>>(1).unwrap(), vec![3u8; 30_000]);
+    }
+
+    #[test]
+    fn it_stays_usable_after_failed_writes() {
+        thread_local! {
+            static FAILING: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+        }
+        // Panics on writes starting in the failing range, like a file that can't be written.
+        #[derive(Default)]
+        struct Failing(VecStorage);
+        impl BlockStorage for Failing {
+            fn read(&self, offset: u64, buf: &mut [u8]) {
+                self.0.read(offset, buf)
+            }
+
+            fn write(&mut self, offset: u64, data: &[u8]) {
+                let (start, end) = FAILING.with(|failing| failing.get());
+                assert!(!(start..end).contains(&offset), "disk full");
+                self.0.write(offset, data)
+            }
+        }
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(Failing::default(), || 0).layout(layout);
+        let file_system = builder.clone().get_or_create("test".to_string()).unwrap();
+        assert_eq!(file_system.write_topic_message(&"a".to_string()), Ok(0));
+
+        FAILING.with(|failing| failing.set((layout.idx_zone_end(), u64::MAX)));
+        let failed = file_system.write_topic_messages(&["b".to_string(), "c".to_string()]);
+        assert!(matches!(failed, Err(FsError::WriteFailed { reason, .. }) if reason == "disk full"));
+        assert_eq!(file_system.get_topic_height(), 1);
+        assert_eq!(file_system.read_topic_message::<String>(0).unwrap(), "a");
+
+        // The data height is committed, the index height isn't.
+        FAILING.with(|failing| failing.set((INDEX_HEIGHT_IDX, INDEX_HEIGHT_IDX + 1)));
+        assert!(matches!(file_system.write_topic_message(&"b".to_string()), Err(FsError::WriteFailed { offset: INDEX_HEIGHT_IDX, .. })));
+        assert_eq!(file_system.get_topic_height(), 1);
+        assert!(file_system.read_topic_message::<String>(1).is_err());
+
+        FAILING.with(|failing| failing.set((0, 0)));
+        assert_eq!(file_system.write_topic_message(&"d".to_string()), Ok(1));
+        let reopened = builder.open().unwrap();
+        assert_eq!(reopened.read_topic_messages::<String>(0, 2).unwrap(), vec!["a".to_string(), "d".to_string()]);
+    }
+
+    #[test]
+    #[cfg(feature = "merge")]
+    fn it_merges_topics_by_timestamp() {
+        let other = second_topic(now);
+        let target = third_topic(now);
+
+        let orders = EventFilesystem::get_or_create(get_write(), get_read(), now, "orders".to_string());
+        let payments = other.get_or_create("payments".to_string()).unwrap();
+        for (now, topic, message) in [(1, &orders, "o-0"), (2, &payments, "p-0"), (2, &orders, "o-1"), (5, &orders, "o-2"), (3, &payments, "p-1")] {
+            set_now(now);
+            topic.write_topic_message(&message.to_string()).unwrap();
+        }
+        payments.write_marker("settled").unwrap();
+        assert!(merge_topics(&orders, &orders, MergeOrder::Timestamp, target.clone(), "all".to_string()).is_err());
+
+        let merged = merge_topics(&orders, &payments, MergeOrder::Timestamp, target.clone(), "all".to_string()).unwrap();
+        let messages = merged.read_topic_messages::<MergedMessage>(0, 6).unwrap();
+        let tags: Vec<(&str, u64)> = messages.iter().map(|m| (m.origin.as_str(), m.height)).collect();
+        assert_eq!(tags, vec![("orders", 0), ("orders", 1), ("payments", 0), ("payments", 1), ("payments", 2), ("orders", 2)]);
+        assert_eq!(messages[3].decode::<String>(&payments.codec()).unwrap(), "p-1");
+        assert_eq!(messages[3].timestamp, 3);
+        assert!(messages[4].is_marker());
+        assert_eq!(merged.resolve_alias("orders", 2), Some(5));
+        assert_eq!(merged.aliases_of(2), vec![("payments".to_string(), 0)]);
+        assert!(merge_topics(&orders, &payments, MergeOrder::Concatenate, target, "all".to_string()).is_err());
+    }
+
+    #[test]
+    fn it_splits_a_topic_in_budgeted_steps() {
+        thread_local! {
+            static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counter() -> u64 {
+            INSTRUCTIONS.with(|c| {
+                c.set(c.get() + 10);
+                c.get()
+            })
+        }
+
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "mixed".to_string());
+        for i in 0..6u64 {
+            file_system.write_topic_message(&format!("{}-{}", if i % 2 == 0 { "order" } else { "payment" }, i)).unwrap();
+        }
+        file_system.write_marker("checkpoint").unwrap();
+        file_system.write_topic_message(&"order-7".to_string()).unwrap();
+
+        let orders = second_topic(|| 0).get_or_create("orders".to_string()).unwrap();
+        let rest = third_topic(|| 0).get_or_create("rest".to_string()).unwrap();
+        let is_order = |message: &String| message.starts_with("order");
+        let progress = file_system.split_topic(is_order, &orders, &rest, &InstructionBudget::new(counter, 25)).unwrap();
+        assert_eq!((progress.scanned, progress.matched, progress.is_done()), (3, 2, false));
+        file_system.write_topic_message(&"order-8".to_string()).unwrap();
+
+        let orders = second_topic(|| 0).open().unwrap();
+        let rest = third_topic(|| 0).open().unwrap();
+        let progress = file_system.split_topic(is_order, &orders, &rest, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!((progress.height, progress.matched, progress.is_done()), (8, 4, true));
+
+        assert_eq!(orders.read_since_marker::<String>("checkpoint").unwrap(), vec![(4, "order-7".to_string())]);
+        assert_eq!(orders.read_topic_messages::<String>(0, 3).unwrap(), vec!["order-0", "order-2", "order-4"]);
+        assert_eq!(rest.read_topic_messages::<String>(0, 3).unwrap(), vec!["payment-1", "payment-3", "payment-5"]);
+        assert_eq!(rest.last_marker("checkpoint"), Some(3));
+        assert_eq!(orders.resolve_alias("mixed", 7), Some(4));
+        assert_eq!(rest.resolve_alias("mixed", 6), Some(3));
+        assert!(file_system.split_topic(is_order, &orders, &rest, &InstructionBudget::unlimited()).is_err());
+    }
+
+    #[test]
+    fn it_resumes_a_split_after_one_destination_failed() {
+        let layout = small_layout();
+        let topic = |name: &str| EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create(name.to_string()).unwrap();
+        let (file_system, orders, rest) = (topic("mixed"), topic("orders"), topic("rest"));
+        file_system.write_marker("checkpoint").unwrap();
+        file_system.write_topic_message(&"order-1".to_string()).unwrap();
+        file_system.write_topic_message(&"payment-2".to_string()).unwrap();
+
+        // A streamed message holds off the rest topic's writes, the marker only reaches orders.
+        let streamed = rest.begin_message().unwrap();
+        let is_order = |message: &String| message.starts_with("order");
+        assert!(file_system.split_topic(is_order, &orders, &rest, &InstructionBudget::unlimited()).is_err());
+        drop(streamed);
+        let mismatch = file_system.split_topic(is_order, &rest, &orders, &InstructionBudget::unlimited()).err();
+        assert_eq!(mismatch, Some(FsError::InvalidArgument("The split was started into orders and rest, not rest and orders".to_string())));
+        let progress = file_system.split_topic(is_order, &orders, &rest, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!((progress.scanned, progress.matched, progress.is_done()), (3, 1, true));
+
+        assert_eq!(orders.get_topic_height(), 2);
+        assert_eq!(orders.last_marker("checkpoint"), Some(0));
+        assert_eq!(orders.read_since_marker::<String>("checkpoint").unwrap(), vec![(1, "order-1".to_string())]);
+        assert_eq!(rest.read_since_marker::<String>("checkpoint").unwrap(), vec![(1, "payment-2".to_string())]);
+        assert_eq!((orders.resolve_alias("mixed", 0), rest.resolve_alias("mixed", 0)), (Some(0), Some(0)));
+    }
+
+    #[test]
+    fn it_writes_batches_with_one_height_update() {
+        thread_local! {
+            static HEIGHT_WRITES: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counting_write(offset: u64, bytes: &[u8]) {
+            if offset == INDEX_HEIGHT_IDX {
+                HEIGHT_WRITES.with(|c| c.set(c.get() + 1));
+            }
+            get_write()(offset, bytes)
+        }
+
+        let file_system = EventFilesystem::get_or_create(counting_write, get_read(), || 0, "test".to_string());
+        let before = HEIGHT_WRITES.with(Cell::get);
+        let messages: Vec<String> = (0..5).map(|i| format!("event {}", i)).collect();
+        assert_eq!(file_system.write_topic_messages(&messages), Ok((0..5).collect()));
+        assert_eq!(HEIGHT_WRITES.with(Cell::get) - before, 1);
+        assert_eq!(file_system.write_topic_messages::<String>(&[]), Ok(Vec::new()));
+
+        file_system.enable_deduplication(DeduplicationConfig { window: 10, mode: DuplicateMode::Reject }).unwrap();
+        let batch = ["a".to_string(), "b".to_string(), "a".to_string()];
+        assert_eq!(file_system.write_topic_messages(&batch), Err(FsError::Duplicate { height: 5 }));
+        assert_eq!(file_system.get_topic_height(), 5);
+        assert_eq!(file_system.write_topic_messages(&batch[..2]), Ok(vec![5, 6]));
+        assert_eq!(file_system.read_topic_messages::<String>(4, 3).unwrap(), vec!["event 4", "a", "b"]);
+    }
+
+    #[test]
+    fn it_separates_event_and_ingestion_time() {
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), now, "test".to_string());
+        set_now(20);
+        file_system.write_topic_message(&"before".to_string()).unwrap();
+        assert!(matches!(file_system.write_topic_message_at(&"late".to_string(), 5), Err(FsError::InvalidState(_))));
+        file_system.set_event_times(true).unwrap();
+        set_now(30);
+        file_system.write_topic_message_at(&"late".to_string(), 5).unwrap();
+        set_now(40);
+        file_system.write_marker("checkpoint").unwrap();
+        set_now(50);
+        file_system.write_topic_message(&"on time".to_string()).unwrap();
+        set_now(60);
+        file_system.write_topic_message_at(&"early".to_string(), 100).unwrap();
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), now);
+        assert!(file_system.event_times());
+        let times: Vec<_> = (0..5).map(|height| file_system.message_times(height).unwrap()).collect();
+        assert_eq!(times.iter().map(|t| t.ingestion_time).collect::<Vec<_>>(), vec![20, 30, 40, 50, 60]);
+        assert_eq!(times.iter().map(|t| t.event_time).collect::<Vec<_>>(), vec![None, Some(5), None, Some(50), Some(100)]);
+        assert_eq!(times[1].clock_skew(), Some(25));
+        assert_eq!(file_system.read_topic_messages::<String>(3, 2).unwrap(), vec!["on time", "early"]);
+
+        assert_eq!(file_system.seek_time(TimeDomain::Ingestion, 35).unwrap(), Some(2));
+        assert_eq!(file_system.seek_time(TimeDomain::Ingestion, 61).unwrap(), None);
+        assert_eq!(file_system.seek_time(TimeDomain::Event, 40).unwrap(), Some(3));
+        let by_ingestion = file_system.read_time_range::<String>(TimeDomain::Ingestion, 20..51).unwrap();
+        assert_eq!(by_ingestion, vec![(0, "before".to_string()), (1, "late".to_string()), (3, "on time".to_string())]);
+        let by_event = file_system.read_time_range::<String>(TimeDomain::Event, 0..60).unwrap();
+        assert_eq!(by_event, vec![(1, "late".to_string()), (3, "on time".to_string())]);
+        assert_eq!(file_system.find_by_timestamp(31).unwrap(), 2);
+        assert_eq!(file_system.find_by_timestamp(61).unwrap(), 5);
+        assert_eq!(file_system.read_range_by_time::<String>(30, 60).unwrap(), vec![(1, "late".to_string()), (3, "on time".to_string())]);
+    }
+
+    #[test]
+    fn it_reads_messages_with_their_meta() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 7).get_or_create("meta".to_string()).unwrap();
+        file_system.write_topic_message(&vec![1u8; 600]).unwrap();
+        file_system.set_event_times(true).unwrap();
+        file_system.write_topic_message_at(&"late".to_string(), 5).unwrap();
+
+        let (message, meta) = file_system.read_topic_message_with_meta::<Vec<u8>>(0).unwrap();
+        assert_eq!(message, vec![1u8; 600]);
+        assert_eq!(meta, MessageMeta { height: 0, timestamp: 7, event_time: None, id: None, data_size: 608, blocks: 0..2 });
+        let (message, meta) = file_system.read_topic_message_with_meta::<String>(1).unwrap();
+        assert_eq!((message.as_str(), meta.event_time, meta.data_size, meta.blocks), ("late", Some(5), 20, 2..3));
+        assert!(file_system.read_topic_message_with_meta::<String>(2).is_err());
+    }
+
+    #[test]
+    fn it_finds_messages_by_id() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 3_000_000).get_or_create("ids".to_string()).unwrap();
+        file_system.write_topic_message(&0u64).unwrap();
+        file_system.set_message_ids(true).unwrap();
+        file_system.set_event_times(true).unwrap();
+        file_system.write_topic_message_at(&1u64, 9).unwrap();
+        file_system.write_marker("checkpoint").unwrap();
+        file_system.write_topic_message(&3u64).unwrap();
+
+        assert_eq!(file_system.message_id(0).unwrap(), None);
+        assert_eq!(file_system.message_id(1).unwrap(), Some(ulid_id(3_000_000, 1)));
+        assert_eq!(file_system.message_id(2).unwrap(), None);
+        let id = file_system.message_id(3).unwrap().unwrap();
+        assert_eq!(id_to_string(id), "00000000030000000000000003");
+        assert_eq!(file_system.find_by_id(id).unwrap(), Some(3));
+        assert_eq!(file_system.find_by_id(ulid_id(3_000_000, 1)).unwrap(), Some(1));
+        assert_eq!(file_system.find_by_id(ulid_id(3_000_000, 2)).unwrap(), None);
+        let (message, meta) = file_system.read_topic_message_with_meta::<u64>(1).unwrap();
+        assert_eq!((message, meta.event_time, meta.id), (1, Some(9), Some(ulid_id(3_000_000, 1))));
+
+        // Ids keep increasing after a reopen even when the generator hands out lower ones.
+        let mut file_system = EventFilesystemBuilder::with_storage(file_system.storage().clone(), || 3_000_000).open().unwrap();
+        file_system.set_id_generator(|_, _| 1);
+        let height = file_system.write_topic_message(&4u64).unwrap();
+        assert_eq!(file_system.message_id(height).unwrap(), Some(id + 1));
+        assert!(file_system.telemetry().message_ids);
+        assert!(matches!(file_system.compact(&InstructionBudget::unlimited()), Err(FsError::Unsupported(_))));
+    }
+
+    #[test]
+    fn it_repairs_seeks_over_a_regressed_clock() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), now, "regressed".to_string());
+        for (i, time) in [10, 20, 30, 5, 6, 40, 50].into_iter().enumerate() {
+            set_now(time);
+            file_system.write_topic_message(&(i as u64)).unwrap();
+        }
+        // Binary search lands past message 2 after running into the regressed times.
+        assert_eq!(file_system.seek_time(TimeDomain::Ingestion, 25).unwrap(), Some(5));
+
+        let repair = file_system.repair_timestamps(&InstructionBudget::new(|| 1, 0)).unwrap();
+        assert_eq!(repair, TimestampRepair { scanned: 1, height: 7, corrected: 0 });
+        let repair = file_system.repair_timestamps(&InstructionBudget::unlimited()).unwrap();
+        assert!(repair.is_done());
+        assert_eq!(repair.corrected, 2);
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.timestamp_corrections(), vec![(3..5, 30)]);
+        assert_eq!(file_system.seek_time(TimeDomain::Ingestion, 25).unwrap(), Some(2));
+        assert_eq!(file_system.find_by_timestamp(31).unwrap(), 5);
+        assert_eq!(file_system.read_range_by_time::<u64>(30, 40).unwrap(), vec![(2, 2), (3, 3), (4, 4)]);
+        assert_eq!(file_system.message_times(3).unwrap().ingestion_time, 5);
+    }
+
+    #[test]
+    fn it_truncates_and_applies_retention() {
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), now);
+        let mut file_system = builder.get_or_create("retained".to_string()).unwrap();
+        for i in 0..6u64 {
+            set_now(i * 10);
+            file_system.write_topic_message(&"x".repeat(100 * i as usize)).unwrap();
+        }
+        let data_blocks = read_data_block_height(&file_system.storage);
+
+        let progress = file_system.truncate_before(3, &InstructionBudget::new(|| 1, 0)).unwrap();
+        assert!(!progress.is_done());
+        assert!(matches!(file_system.read_topic_message::<String>(4), Err(FsError::InvalidState(_))));
+        assert!(file_system.truncate_before(2, &InstructionBudget::unlimited()).is_err());
+        assert!(file_system.truncate_before(3, &InstructionBudget::unlimited()).unwrap().is_done());
+
+        assert_eq!(file_system.first_message_height(), 3);
+        assert_eq!(file_system.read_topic_message::<String>(2), Err(FsError::Truncated { height: 2, first: 3 }));
+        assert_eq!(file_system.read_topic_messages::<String>(3, 3).unwrap(), vec!["x".repeat(300), "x".repeat(400), "x".repeat(500)]);
+        assert!(read_data_block_height(&file_system.storage) < data_blocks);
+        assert_eq!(file_system.find_by_timestamp(15).unwrap(), 3);
+        set_now(60);
+        assert_eq!(file_system.write_topic_message(&"new".to_string()).unwrap(), 6);
+        assert_eq!(file_system.verify_all().unwrap().scanned, 4);
+
+        file_system.set_retention_policy(Some(RetentionPolicy::MaxMessages(3))).unwrap();
+        assert!(file_system.apply_retention(&InstructionBudget::unlimited()).unwrap().is_done());
+        assert_eq!(file_system.first_message_height(), 4);
+        file_system.set_retention_policy(Some(RetentionPolicy::MaxAgeNanos(5))).unwrap();
+        file_system.apply_retention(&InstructionBudget::unlimited()).unwrap();
+        assert_eq!(file_system.first_message_height(), 6);
+
+        let storage = file_system.storage().clone();
+        let file_system = EventFilesystemBuilder::with_storage(storage, || 0).get_or_create("retained".to_string()).unwrap();
+        assert_eq!(file_system.first_message_height(), 6);
+        assert_eq!(file_system.retention_policy(), Some(RetentionPolicy::MaxAgeNanos(5)));
+        assert_eq!(file_system.read_topic_message::<String>(6).unwrap(), "new");
+        assert!(file_system.read_topic_message::<String>(5).is_err());
+    }
+
+    #[test]
+    fn it_retains_until_the_slowest_consumer() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("retained".to_string()).unwrap();
+        file_system.write_topic_messages(&(0..10u64).collect::<Vec<_>>()).unwrap();
+        file_system.set_retention_policy(Some(RetentionPolicy::MaxMessages(2))).unwrap();
+        file_system.set_consumer_retention(Some(ConsumerRetention { override_lag: Some(8) })).unwrap();
+        file_system.set_alarm(AlarmKind::ConsumerLag, 2, |_| {});
+        let (slow, stalled) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        file_system.add_subscriber(slow, 5).unwrap();
+        file_system.add_subscriber(stalled, 1).unwrap();
+        let mut cursor = file_system.create_cursor("projection").unwrap();
+        cursor.next_batch::<u64>(6).unwrap();
+        cursor.commit().unwrap();
+
+        // The stalled subscriber lags past the override, the slow one holds retention back at 5.
+        assert_eq!(file_system.slowest_consumer(), Some(5));
+        file_system.apply_retention(&InstructionBudget::unlimited()).unwrap();
+        assert_eq!(file_system.first_message_height(), 5);
+        assert!(file_system.admin_events().iter().any(|event| matches!(event,
+            EventFilesystemEvent::AlarmTriggered(AlarmBreach { kind: AlarmKind::ConsumerLag, observed: 3, threshold: 2, .. }))));
+
+        file_system.commit_offsets(&[(slow, 9)]).unwrap();
+        file_system.apply_retention(&InstructionBudget::unlimited()).unwrap();
+        assert_eq!(file_system.first_message_height(), 6);
+        file_system.set_consumer_retention(None).unwrap();
+        file_system.apply_retention(&InstructionBudget::unlimited()).unwrap();
+        assert_eq!(file_system.first_message_height(), 8);
+
+        file_system.set_consumer_retention(Some(ConsumerRetention::default())).unwrap();
+        let storage = file_system.storage().clone();
+        let reopened = EventFilesystemBuilder::with_storage(storage, || 0).open().unwrap();
+        assert_eq!(reopened.consumer_retention(), Some(ConsumerRetention { override_lag: None }));
+        assert_eq!(reopened.slowest_consumer(), Some(1));
+    }
+
+    #[test]
+    fn it_compacts_away_truncated_entries() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("compacted".to_string()).unwrap();
+        for i in 0..6u64 {
+            file_system.write_topic_message(&"x".repeat(600 * i as usize)).unwrap();
+        }
+        file_system.truncate_before(4, &InstructionBudget::unlimited()).unwrap();
+        let subscriber = Principal::from_slice(&[1]);
+        file_system.add_subscriber(subscriber, 5).unwrap();
+        file_system.create_cursor("projection").unwrap();
+        file_system.set_bookmark("caught up", 6).unwrap();
+
+        let progress = file_system.compact(&InstructionBudget::new(|| 1, 0)).unwrap();
+        assert!(!progress.is_done());
+        let mut file_system = EventFilesystemBuilder::with_storage(file_system.storage().clone(), || 0).open().unwrap();
+        assert!(matches!(file_system.commit_offset(subscriber, 6), Err(FsError::InvalidState(_))));
+        let progress = file_system.compact(&InstructionBudget::unlimited()).unwrap();
+        // truncate_before already gave the data blocks back, only the index entries are left.
+        assert_eq!((progress.kept, progress.reclaimed_bytes), (2, 4 * IDX_BLOCK_SIZE));
+        assert_eq!(file_system.first_message_height(), 0);
+        assert_eq!(file_system.read_topic_messages::<String>(0, 2).unwrap(), vec!["x".repeat(2400), "x".repeat(3000)]);
+        assert_eq!((file_system.subscriber_offset(subscriber), file_system.cursors(), file_system.get_bookmark("caught up")),
+                   (Some(1), vec![("projection".to_string(), 0)], Some(2)));
+        assert_eq!(file_system.write_topic_message(&"next".to_string()).unwrap(), 2);
+        assert_eq!(file_system.compact(&InstructionBudget::unlimited()).unwrap().reclaimed_bytes, 0);
+
+        // Dropping messages that weren't truncated gives back their data blocks too.
+        let data_blocks = read_data_block_height(&file_system.storage);
+        let progress = file_system.compact_with_filter(|entry, _| entry.height != 0, &InstructionBudget::unlimited()).unwrap();
+        let freed_blocks = data_blocks - read_data_block_height(&file_system.storage);
+        assert!(freed_blocks > 0);
+        assert_eq!(progress.reclaimed_bytes, freed_blocks * BLOCK_SIZE + IDX_BLOCK_SIZE);
+        assert_eq!(file_system.read_topic_messages::<String>(0, 2).unwrap(), vec!["x".repeat(3000), "next".to_string()]);
+        assert_eq!((file_system.subscriber_offset(subscriber), file_system.get_bookmark("caught up")), (Some(0), Some(1)));
+        let completed: Vec<_> = file_system.admin_events().into_iter().filter_map(|event| match event {
+            EventFilesystemEvent::CompactionCompleted(completed) => Some((completed.height, completed.kept)),
+            _ => None,
+        }).collect();
+        assert_eq!(completed, vec![(6, 2), (3, 3), (3, 2)]);
+    }
+
+    #[test]
+    fn it_keeps_registers_write_once() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("configured".to_string()).unwrap();
+        file_system.set_once("deployment", b"prod-1").unwrap();
+        assert!(matches!(file_system.set_once("deployment", b"prod-2"), Err(FsError::InvalidState(_))));
+        assert!(matches!(file_system.set_once("genesis", &[0; 512]), Err(FsError::OutOfSpace(_))));
+        assert_eq!(file_system.get_register("genesis"), None);
+        file_system.write_topic_message(&1u64).unwrap();
+        file_system.truncate_before(1, &InstructionBudget::unlimited()).unwrap();
+
+        let storage = file_system.storage().clone();
+        let file_system = EventFilesystemBuilder::with_storage(storage, || 0).get_or_create("configured".to_string()).unwrap();
+        assert_eq!(file_system.get_register("deployment"), Some(&b"prod-1"[..]));
+    }
+
+    #[test]
+    #[cfg(feature = "jobs")]
+    fn it_exports_in_chunks_across_calls() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), now, "test".to_string());
+        for i in 0..10u64 {
+            file_system.write_topic_message(&format!("event {}", i)).unwrap();
+        }
+        let snapshot = file_system.start_export(ExportKind::Snapshot, 45, 100).unwrap();
+        let index = file_system.start_export(ExportKind::IndexJson, 1, 100).unwrap();
+        let digest = file_system.start_export(ExportKind::Digest, 1024, 100).unwrap();
+        file_system.write_topic_message(&"after the start".to_string()).unwrap();
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), now);
+        let mut messages = Vec::new();
+        for sequence in 0..4 {
+            let chunk = file_system.export_next_chunk(snapshot).unwrap();
+            assert_eq!((chunk.sequence, chunk.done), (sequence, sequence == 3));
+            let entries: Vec<(IndexExportEntry, Vec<u8>)> = bincode::deserialize(&chunk.bytes).unwrap();
+            messages.extend(entries.into_iter().map(|(_, payload)| bincode::deserialize::<String>(&payload).unwrap()));
+        }
+        assert_eq!(messages, (0..10).map(|i| format!("event {}", i)).collect::<Vec<_>>());
+        let drained = file_system.export_next_chunk(snapshot).unwrap();
+        assert!(drained.done && bincode::deserialize::<Vec<(IndexExportEntry, Vec<u8>)>>(&drained.bytes).unwrap().is_empty());
+
+        let line = String::from_utf8(file_system.export_next_chunk(index).unwrap().bytes).unwrap();
+        assert_eq!(line, "{\"height\":0,\"timestamp\":0,\"data_size\":15,\"flags\":1,\"key_hash\":null}\n");
+        assert_eq!(file_system.export_status(index).unwrap().next, 1);
 
-pub struct EventFilesystem {
-    write_fn: BlockWrite,
-    writer: RefCell<MemoryWriter>,
-    read_fn: BlockRead,
-    reader: MemoryReader,
-    topic_header: TopicHeaderBlock,
-}
+        set_now(50);
+        let hasher = file_system.hasher().unwrap();
+        let expected = (0..10u64).fold(Vec::new(), |digest, i| {
+            hasher.digest(&[digest, bincode::serialize(&format!("event {}", i)).unwrap()].concat())
+        });
+        assert_eq!(file_system.export_next_chunk(digest).unwrap(), ExportChunk { job_id: digest, sequence: 0, bytes: expected, done: true });
 
-impl EventFilesystem {
-    pub fn get_file_system(write_fn: BlockWrite,
-                           read_fn: BlockRead,
-                           clock: fn() -> u64) -> EventFilesystem {
-        let data_block_height = read_data_block_height(read_fn);
-        let index_height = read_index_height(read_fn);
+        set_now(100);
+        assert!(file_system.export_status(index).is_err());
+        assert!(file_system.export_status(digest).is_ok());
+    }
 
-        debug!("EventFilesystem data_block_height {} index_height {}", data_block_height, index_height);
-        let mut writer = RefCell::new(
-            MemoryWriter::new(index_height, data_block_height,  clock)
-        );
+    #[test]
+    #[cfg(feature = "jobs")]
+    fn it_imports_atomically() {
+        let small = small_layout();
+        let source = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(small).get_or_create("source".to_string()).unwrap();
+        for i in 0..6u64 {
+            source.write_topic_message(&format!("event {}", i)).unwrap();
+        }
+        let export = source.start_export(ExportKind::Snapshot, 45, 100).unwrap();
+        let chunks: Vec<ExportChunk> = (0..2).map(|_| source.export_next_chunk(export).unwrap()).collect();
+        let digest_job = source.start_export(ExportKind::Digest, u64::MAX, 100).unwrap();
+        let digest = source.export_next_chunk(digest_job).unwrap().bytes;
 
-        let reader = MemoryReader::new();
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        file_system.write_topic_message(&"local".to_string()).unwrap();
+        let rejected = file_system.start_import(Some(vec![0; 32]), 100).unwrap();
+        assert!(matches!(file_system.start_import(None, 100), Err(FsError::InvalidState(_))));
+        assert!(matches!(file_system.import_chunk(rejected, &chunks[1]), Err(FsError::InvalidArgument(_))));
+        file_system.import_chunk(rejected, &chunks[0]).unwrap();
+        assert_eq!(file_system.import_chunk(rejected, &chunks[0]).unwrap().staged(), 3);
+        assert!(matches!(file_system.write_topic_message(&"blocked".to_string()), Err(FsError::InvalidState(_))));
+        assert!(matches!(file_system.commit_import(rejected), Err(FsError::InvalidState(_))));
+        file_system.import_chunk(rejected, &chunks[1]).unwrap();
+        assert!(matches!(file_system.commit_import(rejected), Err(FsError::InvalidArgument(_))));
+        assert_eq!(file_system.get_topic_height(), 1);
+        assert!(file_system.import_status(rejected).is_err());
 
-        let topic_header = read_topic_block(read_fn);
-        EventFilesystem {
-            write_fn,
-            writer,
-            read_fn,
-            reader,
-            topic_header,
+        let import = file_system.start_import(Some(digest), 100).unwrap();
+        file_system.import_chunk(import, &chunks[0]).unwrap();
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.get_topic_height(), 1);
+        file_system.import_chunk(import, &chunks[1]).unwrap();
+        assert_eq!(file_system.commit_import(import), Ok(1..7));
+        let messages = file_system.read_topic_messages::<String>(0, 7).unwrap();
+        assert_eq!(messages[0], "local");
+        assert_eq!(messages[1..], (0..6).map(|i| format!("event {}", i)).collect::<Vec<_>>());
+        assert_eq!(file_system.write_topic_message(&"after".to_string()), Ok(7));
+    }
+
+    #[test]
+    #[cfg(feature = "jobs")]
+    fn it_verifies_snapshots_without_writing() {
+        let small = small_layout();
+        let source = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(small).get_or_create("source".to_string()).unwrap();
+        for i in 0..6u64 {
+            source.write_topic_message(&format!("event {}", i)).unwrap();
         }
+        let export = source.start_export(ExportKind::Snapshot, 45, 100).unwrap();
+        let chunks: Vec<ExportChunk> = (0..2).map(|_| source.export_next_chunk(export).unwrap()).collect();
+        let digest_job = source.start_export(ExportKind::Digest, u64::MAX, 100).unwrap();
+        let digest = source.export_next_chunk(digest_job).unwrap().bytes;
+
+        let target = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(small).get_or_create("target".to_string()).unwrap();
+        let report = target.verify_restorable(&chunks, Some(&digest)).unwrap();
+        assert!(report.is_restorable(), "{:?}", report);
+        assert_eq!((report.chunks, report.heights.clone(), report.messages, report.markers), (2, 0..6, 6, 0));
+        assert_eq!(report.digest_matches, Some(true));
+        assert_eq!(target.get_topic_height(), 0);
+
+        assert!(!target.verify_restorable(&chunks[..1], None).unwrap().is_restorable());
+        assert_eq!(target.verify_restorable(&chunks, Some(&[0; 32])).unwrap().digest_matches, Some(false));
+        let reordered = target.verify_restorable([&chunks[1], &chunks[0]], None).unwrap();
+        assert!(matches!(reordered.problems[..], [(1, FsError::InvalidArgument(_))]));
+        let mut tampered = chunks.clone();
+        tampered[1].bytes.truncate(5);
+        assert!(matches!(target.verify_restorable(&tampered, None).unwrap().problems[..], [(1, FsError::Deserialize(_))]));
     }
 
-    pub fn get_topic_height(&self) -> u64 {
-        return read_index_height(self.read_fn);
+    #[test]
+    #[cfg(feature = "stream")]
+    fn it_streams_between_topics() {
+        let small = small_layout();
+        let source = EventFilesystemBuilder::with_storage(VecStorage::default(), || 3).layout(small).get_or_create("source".to_string()).unwrap();
+        for i in 0..5u64 {
+            source.write_topic_message(&format!("event {}", i)).unwrap();
+        }
+        let sink = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(small).get_or_create("sink".to_string()).unwrap();
+
+        let key = Some(b"secret".as_slice());
+        let mut request = StreamRequest { cursor: None, limit: 3 };
+        let mut heights = Vec::new();
+        loop {
+            let response = source.serve_stream(&request, key, Some(vec![7])).unwrap();
+            assert_eq!((response.height, response.certificate.as_deref()), (5, Some([7u8].as_slice())));
+            if response.batch.is_empty() {
+                break;
+            }
+            assert!(response.batch.iter().all(|event| event.timestamp == 3));
+            let messages = response.batch.iter().map(|event| event.decode::<String>(&BincodeCodec::default())).collect::<Result<Vec<_>, _>>().unwrap();
+            heights.extend(sink.write_topic_messages(&messages).unwrap());
+            request.cursor = Some(response.next_cursor);
+        }
+        assert_eq!(heights, (0..5).collect::<Vec<_>>());
+        assert_eq!(sink.read_topic_message::<String>(4).unwrap(), "event 4");
+
+        let foreign = StreamRequest { cursor: Some(Cursor::new(1).encode("sink", key)), limit: 3 };
+        assert!(matches!(source.serve_stream(&foreign, key, None), Err(FsError::InvalidArgument(_))));
+        let ahead = StreamRequest { cursor: Some(Cursor::new(6).encode("source", None)), limit: 3 };
+        assert!(matches!(source.serve_stream(&ahead, None, None), Err(FsError::InvalidArgument(_))));
     }
 
-    pub fn stable_store<T: Serialize>(&self, data: T) -> Result<(), String> {
-        let data = bincode::serialize(&data).map_err(|e| format!("Failed to serialize: {}", e))?;
-        if data.len() > FREE_MEMORY_BLOCK_SIZE as usize {
-            return Err(format!("Data is too large: {}", data.len()));
+    #[test]
+    #[cfg(feature = "schema")]
+    fn it_rejects_messages_that_violate_the_schema() {
+        #[derive(serde::Serialize)]
+        struct Transfer {
+            kind: &'static str,
+            amount: i64,
         }
-        (self.write_fn)(FREE_MEMORY_BLOCK_SIZE_IDX, &data.len().to_le_bytes());
-        (self.write_fn)(FREE_MEMORY_BLOCK_START_IDX, data.as_slice());
-        Ok(())
+        impl StableEncode for Transfer {}
+
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        file_system.write_topic_message(&"before the schema".to_string()).unwrap();
+        let schema = MessageSchema::parse(r#"{ "type": "object", "required": ["amount"], "properties": { "amount": { "minimum": 0 } } }"#).unwrap();
+        file_system.set_schema(Some(schema.clone())).unwrap();
+
+        assert_eq!(file_system.write_topic_message(&Transfer { kind: "transfer", amount: 5 }), Ok(1));
+        let rejected = file_system.write_topic_message(&Transfer { kind: "transfer", amount: -5 });
+        assert_eq!(rejected, Err(FsError::SchemaViolation { path: "$.amount".to_string(), reason: "-5 is below the minimum 0".to_string() }));
+        assert!(file_system.write_topic_message(&"not a transfer".to_string()).is_err());
+        assert_eq!(file_system.write_marker("checkpoint"), Ok(2));
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.schema(), Some(schema));
+        assert!(file_system.write_topic_message(&Transfer { kind: "transfer", amount: -1 }).is_err());
+        file_system.set_schema(None).unwrap();
+        assert_eq!(file_system.write_topic_message(&Transfer { kind: "transfer", amount: -1 }), Ok(3));
+        assert_eq!(EventFilesystem::get_file_system(get_write(), get_read(), || 0).schema(), None);
     }
 
-    pub fn stable_restore<T: DeserializeOwned>(&self) -> Result<T, String> {
-        let mut size = [0u8; 8];
-        (self.read_fn)(FREE_MEMORY_BLOCK_SIZE_IDX, &mut size);
-        let size = u64::from_le_bytes(size);
+    #[test]
+    fn it_resolves_imported_heights() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        let imported: Vec<(u64, u64)> = (100..110u64).map(|source_height| {
+            (source_height, file_system.write_topic_message(&source_height).unwrap())
+        }).collect();
+        file_system.record_aliases("shard-a", imported).unwrap();
+        assert!(file_system.record_aliases("shard-b", [(0, 10)]).is_err());
+        assert!(file_system.record_aliases("shard-a", [(100, 3)]).is_err());
 
-        let mut bytes = vec![0u8; size as usize];
-        (self.read_fn)(FREE_MEMORY_BLOCK_START_IDX, bytes.as_mut_slice());
-        bincode::deserialize(&bytes).map_err(|e| format!("Failed to deserialize: {}", e))
+        let mut file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        let local = file_system.resolve_alias("shard-a", 104).unwrap();
+        assert_eq!(file_system.read_topic_message::<u64>(local).unwrap(), 104);
+        assert_eq!(file_system.resolve_alias("shard-a", 110), None);
+        assert_eq!(file_system.aliases_of(9), vec![("shard-a".to_string(), 109)]);
+        assert!(file_system.compact_with_filter(|_, _| true, &InstructionBudget::unlimited()).is_err());
     }
 
-    pub fn get_or_create(write_fn: BlockWrite,
-                         read_fn: BlockRead,
-                         clock: fn() -> u64,
-                         event_stream_name: String,
-    ) -> Self {
-        let mut writer = RefCell::new(
-            MemoryWriter::new(0, 0, clock)
-        );
-        let reader = MemoryReader::new();
+    #[test]
+    fn it_isolates_stable_regions() {
+        let small = small_layout();
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).layout(small).get_or_create("small".to_string()).unwrap();
+        file_system.stable_store(vec![1u8; 900 * 1024]).unwrap();
+        assert!(matches!(file_system.create_region("heap", 100 * 1024), Err(FsError::OutOfSpace(_))));
+        file_system.stable_store(vec![1u8; 2048]).unwrap();
 
-        if is_magic_number_valid(read_fn) {
-            return Self::get_file_system(write_fn, read_fn, clock);
-        } else {
-            write_magic_number(write_fn);
-            write_index_height(0, write_fn);
-            write_data_block_height(0, write_fn);
-
-            let topic_block = TopicHeaderBlock {
-                event_stream_name,
-                first_message_ptr: 0,
-                binary_version: 1_000_000,
-            };
+        let tree = file_system.create_region("tree", 1024).unwrap();
+        file_system.create_region("heap", 100 * 1024).unwrap();
+        assert!(file_system.create_region("tree", 1024).is_err());
+        assert_eq!(tree.offset + 8 + 1024, file_system.layout().stable_store_zone.end - 8);
 
-            write_topic_block(&topic_block, write_fn);
+        file_system.stable_store_in("tree", "root".to_string()).unwrap();
+        file_system.stable_store_in("heap", vec![2u8; 50 * 1024]).unwrap();
+        assert!(matches!(file_system.stable_store_in("tree", vec![0u8; 2048]), Err(FsError::MessageTooLarge { .. })));
+        assert!(file_system.stable_store_in("missing", 1u8).is_err());
+        assert!(file_system.stable_store(vec![1u8; 900 * 1024]).is_err());
+
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        assert_eq!(file_system.regions().len(), 2);
+        assert_eq!(file_system.stable_restore_in::<String>("tree").unwrap(), "root");
+        assert_eq!(file_system.stable_restore_in::<Vec<u8>>("heap").unwrap(), vec![2u8; 50 * 1024]);
+        assert_eq!(file_system.stable_restore::<Vec<u8>>().unwrap(), vec![1u8; 2048]);
+        file_system.check().unwrap();
+    }
 
-            EventFilesystem {
-                write_fn,
-                writer,
-                read_fn,
-                reader,
-                topic_header: topic_block,
+    #[test]
+    fn it_fails_subscriber_changes_whose_events_cant_be_saved() {
+        let layout = small_layout();
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("test".to_string()).unwrap();
+        file_system.write_topic_messages(&[1u64, 2, 3]).unwrap();
+        let a = Principal::from_slice(&[1]);
+        file_system.add_subscriber(a, 0).unwrap();
+        let (mut size, mut filler) = (32 * 1024, 0);
+        while size > 0 {
+            match file_system.meta.put_value(&format!("filler {}", filler), &vec![0u8; size]) {
+                Ok(_) => filler += 1,
+                Err(_) => size /= 2,
             }
         }
+        let events = file_system.admin_events();
+        assert!(matches!(file_system.commit_offset(a, 2), Err(FsError::OutOfSpace(_))));
+        assert_eq!(file_system.subscriber_offset(a), Some(0));
+        assert_eq!(file_system.admin_events(), events);
+        let reopened = EventFilesystemBuilder::with_storage(file_system.storage().clone(), || 0).open().unwrap();
+        assert_eq!(reopened.subscriber_offset(a), Some(0));
     }
 
-    pub fn read_topic_message<T : DeserializeOwned>(&self, id: u64) -> Result<T, String> {
-        self.reader.read_topic_message(id, self.read_fn)
+    #[test]
+    fn it_persists_subscribers_in_a_region() {
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).get_or_create("subscribers".to_string()).unwrap();
+        for i in 0..10u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        assert!(file_system.add_subscriber(a, 11).is_err());
+        assert!(file_system.regions().is_empty());
+        file_system.add_subscriber(a, 0).unwrap();
+        file_system.add_subscriber(b, 4).unwrap();
+        file_system.commit_offset(b, 9).unwrap();
+        assert_eq!(file_system.get_lagging_subscribers(5), vec![(a, 10)]);
+        assert_eq!(file_system.admin_events()[1..], [
+            EventFilesystemEvent::SubscriberAdded(SubscriberAdded { subscriber: a, offset: 0 }),
+            EventFilesystemEvent::SubscriberAdded(SubscriberAdded { subscriber: b, offset: 4 }),
+            EventFilesystemEvent::SubscriberOffsetModified(SubscriberOffsetModified { subscriber: b, offset: 9 }),
+        ]);
+
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        assert_eq!(file_system.subscribers(), vec![(a, 0), (b, 9)]);
+        assert_eq!(file_system.admin_events()[3], EventFilesystemEvent::SubscriberOffsetModified(SubscriberOffsetModified { subscriber: b, offset: 9 }));
+        file_system.remove_subscriber(a).unwrap();
+        assert!(file_system.remove_subscriber(a).is_err());
+        assert_eq!(file_system.admin_events().last(), Some(&EventFilesystemEvent::SubscriberRemoved(SubscriberRemoved { subscriber: a })));
+        assert_eq!(file_system.get_lagging_subscribers(0), vec![(b, 1)]);
+        assert_eq!(file_system.subscriber_offset(b), Some(9));
+
+        let c = Principal::from_slice(&[3]);
+        file_system.add_subscriber(c, 0).unwrap();
+        assert!(file_system.commit_offsets(&[(b, 10), (a, 10)]).is_err());
+        assert!(file_system.commit_offsets(&[(b, 10), (c, 11)]).is_err());
+        assert_eq!(file_system.subscribers(), vec![(b, 9), (c, 0)]);
+        file_system.commit_offsets(&[(b, 10), (c, 3), (c, 7)]).unwrap();
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        assert_eq!(file_system.subscribers(), vec![(b, 10), (c, 7)]);
     }
 
-    pub fn write_topic_message<S: Serialize>(&self, data: &S) -> Result<u64, String> {
-        let mut writer = self.writer.borrow_mut();
-        return match writer.write(data, self.write_fn) {
-            Ok(idx) => {
-                debug!("Wrote topic_message at index {:?}", idx);
-                write_index_height(writer.index_block_offset(), self.write_fn);
-                write_data_block_height(writer.data_block_offset(), self.write_fn);
+    thread_local! {
+        static CALLER: Cell<u8> = const { Cell::new(0) };
+    }
 
-                Ok(idx.start_idx)
-            }
-            Err(e) => {
-                Err(e)
+    #[test]
+    fn it_enforces_controllers_on_writes() {
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).get_or_create("guarded".to_string()).unwrap();
+        file_system.add_controller(Principal::from_slice(&[1])).unwrap();
+        assert_eq!(file_system.admin_events().last(), Some(&EventFilesystemEvent::ControllerAdded(ControllerAdded { controller: Principal::from_slice(&[1]) })));
+        file_system.write_topic_message(&1u64).unwrap();
+
+        file_system.enforce_access_control(Some(|| Principal::from_slice(&[CALLER.with(Cell::get)])));
+        assert_eq!(file_system.write_topic_message(&2u64), Err(FsError::Unauthorized(Principal::from_slice(&[0]).to_string())));
+        CALLER.with(|caller| caller.set(1));
+        assert_eq!(file_system.write_topic_message(&2u64).unwrap(), 1);
+
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        assert_eq!(file_system.controllers(), vec![Principal::from_slice(&[1])]);
+        file_system.remove_controller(Principal::from_slice(&[1])).unwrap();
+        assert!(file_system.guard(Principal::from_slice(&[1])).is_err());
+        file_system.write_topic_message(&3u64).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_compresses_payloads_from_the_height_it_was_enabled() {
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "compressed".to_string());
+        file_system.write_topic_message(&"plain".repeat(200)).unwrap();
+        file_system.set_compression(PayloadCompression::Zstd { level: 3 }).unwrap();
+        file_system.write_topic_message(&"packed".repeat(200)).unwrap();
+        file_system.write_marker("checkpoint").unwrap();
+        file_system.write_topic_message(&"x".to_string()).unwrap();
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.compression(), PayloadCompression::Zstd { level: 3 });
+        assert_eq!(file_system.export_index(0..2).unwrap().iter().map(|entry| entry.data_size < 100).collect::<Vec<_>>(), vec![false, true]);
+        assert_eq!(file_system.read_topic_message::<String>(0).unwrap(), "plain".repeat(200));
+        assert_eq!(file_system.read_topic_message::<String>(1).unwrap(), "packed".repeat(200));
+        assert_eq!(file_system.read_topic_message::<String>(3).unwrap(), "x");
+        assert!(matches!(file_system.open_message(1), Err(FsError::Unsupported(_))));
+        assert_eq!(file_system.open_message(0).unwrap().size(), 1008);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_compresses_with_the_newest_dictionary() {
+        let samples = (0..500u32)
+            .map(|i| format!("transfer from account-{} to account-{} of {}", i % 17, i % 23, i * 31).into_bytes())
+            .collect::<Vec<_>>();
+        let message = |i: u32| format!("transfer from account-{} to account-{} of {}", i % 7, i % 5, i);
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "dictionaries".to_string());
+        file_system.set_compression(PayloadCompression::Zstd { level: 3 }).unwrap();
+        file_system.write_topic_message(&message(0)).unwrap();
+        let first = crate::CompressionDictionary::train(&samples, 4096).unwrap();
+        file_system.set_compression_dictionary(&first).unwrap();
+        file_system.write_topic_message(&message(1)).unwrap();
+        let second = crate::CompressionDictionary::train(&samples[..300], 2048).unwrap();
+        file_system.set_compression_dictionary(&second).unwrap();
+        file_system.write_topic_message(&message(2)).unwrap();
+
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.compression_dictionary().unwrap(), Some(second.clone()));
+        assert_eq!(file_system.read_topic_messages::<String>(0, 3).unwrap(), (0..3).map(message).collect::<Vec<_>>());
+        let header = |height: u64| file_system.reader.read_raw_payload(height, &file_system.storage).unwrap()[..13].to_vec();
+        assert_eq!(header(1)[9..], first.id.to_le_bytes());
+        assert_eq!(header(2)[9..], second.id.to_le_bytes());
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn it_refuses_compression_without_the_feature() {
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "compressed".to_string());
+        assert!(matches!(file_system.set_compression(PayloadCompression::Zstd { level: 3 }), Err(FsError::Unsupported(_))));
+        assert_eq!(file_system.compression(), PayloadCompression::None);
+    }
+
+    #[cfg(feature = "crc32")]
+    #[test]
+    fn it_detects_corrupt_payloads_by_checksum() {
+        let mut file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "checked".to_string());
+        file_system.write_topic_message(&"unchecked".to_string()).unwrap();
+        file_system.set_checksums(true).unwrap();
+        file_system.set_event_times(true).unwrap();
+        file_system.write_topic_message(&"checked".to_string()).unwrap();
+        file_system.write_marker("checkpoint").unwrap();
+        file_system.write_topic_message(&"also checked".to_string()).unwrap();
+        assert_eq!(file_system.verify_all().unwrap(), crate::CorruptionReport { scanned: 4, verified: 2, unchecked: 2, corrupt: vec![] });
+
+        let corrupted = file_system.layout().data_zone_start + layout().block_size + 20;
+        MEMORY.with(|memory| memory.borrow_mut()[corrupted as usize] ^= 1);
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert!(file_system.checksums());
+        assert_eq!(file_system.read_topic_message::<String>(1), Err(FsError::CorruptData { height: 1 }));
+        assert_eq!(file_system.read_topic_message::<String>(3).unwrap(), "also checked");
+        let report = file_system.verify_all().unwrap();
+        assert_eq!(report.corrupt, vec![(1, FsError::CorruptData { height: 1 })]);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn it_creates_topics_with_a_custom_layout() {
+        let small = small_layout();
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0)
+            .layout(small)
+            .get_or_create("small".to_string())
+            .unwrap();
+        file_system.write_topic_message(&"in the small data zone".to_string()).unwrap();
+        file_system.stable_store(vec![1u8; 2048]).unwrap();
+        assert!(file_system.stable_store(vec![1u8; 1024 * 1024]).is_err());
+        file_system.check().unwrap();
+
+        let layout = file_system.layout();
+        assert_eq!(layout, small.descriptor());
+        assert_eq!(layout.max_index_entries, 85);
+        MEMORY.with(|v| {
+            let start = layout.data_zone_start as usize + 8;
+            assert_eq!(&v.borrow()[start..start + 22], b"in the small data zone");
+        });
+
+        assert!(EventFilesystemBuilder::new(get_write(), get_read(), || 0).layout(LayoutConfig::default()).open().is_err());
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).open().unwrap();
+        assert_eq!(file_system.read_topic_message::<String>(0).unwrap(), "in the small data zone");
+        assert_eq!(file_system.stable_restore::<Vec<u8>>().unwrap(), vec![1u8; 2048]);
+
+        let invalid = LayoutConfig { meta_zone_size: 8, ..small };
+        MEMORY.with(|v| v.borrow_mut().fill(0));
+        assert!(EventFilesystemBuilder::new(get_write(), get_read(), || 0).layout(invalid).get_or_create("bad".to_string()).is_err());
+    }
+
+    #[test]
+    fn it_sizes_blocks_and_the_index_per_topic() {
+        let tiny = LayoutConfig { block_size: 64, ..small_layout() }.with_max_messages(4);
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(tiny).get_or_create("tiny".to_string()).unwrap();
+        let heights = file_system.write_topic_messages(&[vec![1u8; 100], vec![2u8; 10], vec![3u8; 300]]).unwrap();
+        assert_eq!(heights, vec![0, 1, 2]);
+        assert_eq!(read_data_block_height(&file_system.storage), 2 + 1 + 5);
+        assert_eq!(file_system.remaining_capacity(), 1);
+        file_system.write_topic_message(&vec![4u8; 1]).unwrap();
+        assert!(matches!(file_system.write_topic_message(&vec![5u8; 1]), Err(FsError::IndexZoneFull { capacity: 4 })));
+
+        let file_system = EventFilesystemBuilder::with_storage(file_system.storage().clone(), || 0).open().unwrap();
+        assert_eq!(file_system.layout().block_size, 64);
+        assert_eq!(file_system.layout().max_index_entries, 4);
+        assert_eq!(file_system.read_topic_messages::<Vec<u8>>(1, 2).unwrap(), vec![vec![2u8; 10], vec![3u8; 300]]);
+        assert!(EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(LayoutConfig { block_size: 100, ..tiny }).get_or_create("odd".to_string()).is_err());
+    }
+
+    #[test]
+    fn it_reports_health() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 3).get_or_create("health".to_string()).unwrap();
+        for i in 0..4u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        file_system.stable_store(vec![0u8; 100]).unwrap();
+        assert!(file_system.read_topic_message::<u64>(9).is_err());
+        file_system.truncate_before(2, &InstructionBudget::unlimited()).unwrap();
+        assert!(file_system.read_topic_message::<u64>(0).is_err());
+
+        let health = file_system.health();
+        assert_eq!(health.index_slots, ZoneUsage { used: 4, capacity: layout().max_index_entries });
+        assert_eq!(health.stable_store.used, 108);
+        assert_eq!(health.data_bytes, 2 * BLOCK_SIZE);
+        assert_eq!(health.compaction_debt, 2);
+        assert_eq!(health.pending_migration, None);
+        assert!(health.meta.used > 0 && health.meta.utilization() < 1.0);
+        assert_eq!(health.errors.total, 2);
+        assert_eq!(health.errors.by_operation.get("read"), Some(&2));
+        assert_eq!(health.errors.latest.map(|entry| entry.height), Some(Some(0)));
+
+        file_system.compact(&InstructionBudget::new(|| 1, 0)).unwrap();
+        assert!(file_system.health().pending_migration.unwrap().contains("compacted"));
+    }
+
+    #[test]
+    fn it_signals_pressure() {
+        let layout = LayoutConfig::default().with_max_messages(10);
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 3).layout(layout).get_or_create("pressure".to_string()).unwrap();
+        for i in 0..7u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        assert!(file_system.pressure().is_ok());
+        file_system.write_topic_message(&7u64).unwrap();
+        let pressure = file_system.pressure();
+        assert_eq!(pressure.level, PressureLevel::Soft);
+        assert_eq!(pressure.causes, vec![(PressureLevel::Soft, PressureCause::IndexSlots(ZoneUsage { used: 8, capacity: 10 }))]);
+
+        file_system.set_alarm(AlarmKind::MessagesPerMinute, 8, |_| {});
+        assert_eq!(file_system.pressure().level, PressureLevel::Hard);
+        file_system.clear_alarm(AlarmKind::MessagesPerMinute);
+        assert!(file_system.set_watermarks(Watermarks { soft: 1.0, hard: 0.9 }).is_err());
+        file_system.set_watermarks(Watermarks { soft: 0.9, hard: 1.0 }).unwrap();
+        assert!(file_system.pressure().is_ok());
+
+        let file_system = EventFilesystemBuilder::with_storage(file_system.storage().clone(), || 3).open().unwrap();
+        assert_eq!(file_system.watermarks(), Watermarks { soft: 0.9, hard: 1.0 });
+        #[cfg(feature = "jobs")]
+        {
+            file_system.start_import(None, 100).unwrap();
+            let pressure = file_system.pressure();
+            assert_eq!(pressure.level, PressureLevel::Hard);
+            assert!(matches!(pressure.causes[..], [(PressureLevel::Hard, PressureCause::Blocked(_))]));
+        }
+    }
+
+    #[test]
+    fn it_inventories_features_and_counts_usage() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 9).get_or_create("telemetry".to_string()).unwrap();
+        file_system.write_topic_message(&1u64).unwrap();
+        let telemetry = file_system.telemetry();
+        assert!(!telemetry.event_times && telemetry.retention.is_none() && telemetry.usage.is_none());
+
+        file_system.set_event_times(true).unwrap();
+        file_system.set_retention_policy(Some(RetentionPolicy::MaxMessages(10))).unwrap();
+        file_system.create_cursor("indexer").unwrap();
+        file_system.set_telemetry(true).unwrap();
+        file_system.write_topic_messages(&[2u64, 3]).unwrap();
+        file_system.write_marker("checkpoint").unwrap();
+        assert!(file_system.read_topic_message::<u64>(10).is_err());
+
+        let file_system = EventFilesystemBuilder::with_storage(file_system.storage().clone(), || 9).open().unwrap();
+        let telemetry = file_system.telemetry();
+        assert!(telemetry.event_times);
+        assert_eq!((telemetry.retention, telemetry.cursors, telemetry.hash_algorithm), (Some(RetentionPolicy::MaxMessages(10)), 1, HashAlgorithm::Sha256));
+        assert_eq!(telemetry.usage, Some(UsageCounters { since: 9, opens: 1, messages_written: 2, markers_written: 1, bytes_written: 32, errors: 1 }));
+
+        file_system.set_telemetry(false).unwrap();
+        file_system.write_topic_message(&4u64).unwrap();
+        assert_eq!(file_system.telemetry().usage, None);
+    }
+
+    #[test]
+    fn it_looks_up_messages_by_key() {
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("keys".to_string()).unwrap();
+        assert!(matches!(file_system.write_topic_message_keyed(b"order-1", &"created".to_string()), Err(FsError::InvalidState(_))));
+        file_system.write_topic_message(&"unkeyed".to_string()).unwrap();
+        file_system.enable_key_index(4).unwrap();
+        assert!(file_system.enable_key_index(4).is_err());
+        for (key, event) in [(b"order-1", "created"), (b"order-2", "created"), (b"order-1", "paid")] {
+            file_system.write_topic_message_keyed(key, &event.to_string()).unwrap();
+        }
+        assert!(matches!(file_system.write_topic_message_keyed(b"order-3", &"created".to_string()), Err(FsError::OutOfSpace(_))));
+        assert_eq!(file_system.get_topic_height(), 4);
+        assert_eq!(file_system.key_index_usage(), Some(ZoneUsage { used: 3, capacity: 4 }));
+
+        let file_system_reopened = EventFilesystemBuilder::with_storage(file_system.storage().clone(), || 0).open().unwrap();
+        assert_eq!(file_system_reopened.read_by_key::<String>(b"order-1").unwrap(), vec![(1, "created".to_string()), (3, "paid".to_string())]);
+        assert!(file_system_reopened.read_by_key::<String>(b"order-3").unwrap().is_empty());
+
+        file_system.truncate_before(2, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!(file_system.heights_by_key(b"order-1").unwrap(), vec![3]);
+        assert!(matches!(file_system.compact(&InstructionBudget::unlimited()), Err(FsError::Unsupported(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "query")]
+    fn it_runs_queries_with_the_narrowest_plan() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Order {
+            kind: String,
+            tags: Vec<String>,
+            producer: String,
+        }
+        impl StableEncode for Order {}
+        let layout = small_layout();
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), now).layout(layout).get_or_create("orders".to_string()).unwrap();
+        file_system.enable_key_index(16).unwrap();
+        let orders = [("order-1", "created", "vip", "web"), ("order-2", "created", "new", "app"), ("order-1", "paid", "vip", "web"), ("order-2", "refunded", "vip", "app"), ("order-1", "shipped", "vip", "app")];
+        for (i, (key, kind, tag, producer)) in orders.into_iter().enumerate() {
+            set_now(10 * i as u64);
+            let order = Order { kind: kind.to_string(), tags: vec![tag.to_string()], producer: producer.to_string() };
+            file_system.write_topic_message_keyed(key.as_bytes(), &order).unwrap();
+            if i == 1 {
+                file_system.write_marker("checkpoint").unwrap();
             }
-        };
+        }
+        let heights = |query: Query| file_system.query::<Order>(&query).unwrap().into_iter().map(|(height, _)| height).collect::<Vec<_>>();
+
+        assert_eq!(heights(Query::new()), vec![0, 1, 3, 4, 5]);
+        assert_eq!(heights(Query::new().key(b"order-1").producer("web")), vec![0, 3]);
+        assert_eq!(heights(Query::new().kinds(["paid", "refunded", "shipped"]).tag("vip").order(QueryOrder::Descending).limit(2)), vec![5, 4]);
+        let query = Query::new().time(TimeDomain::Ingestion, 10..40).heights(2..10);
+        assert_eq!(query.plan(), QueryPlan::TimeSeek);
+        assert_eq!(heights(query), vec![3, 4]);
+        let (_, order) = file_system.query::<Order>(&Query::new().key(b"order-2").order(QueryOrder::Descending).limit(1)).unwrap().remove(0);
+        assert_eq!(order.kind, "refunded");
+        assert!(heights(Query::new().heights(6..9)).is_empty());
     }
 
-    pub fn read_topic_messages<T : DeserializeOwned>(&self, start: u64, take: u64) -> Result<Vec<T>, String> {
-        self.reader.read_range::<T>(start, take, self.read_fn)
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn it_restores_topics_from_snapshots() {
+        let layout = small_layout();
+        let mut file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 5).layout(layout).get_or_create("backup".to_string()).unwrap();
+        file_system.set_event_times(true).unwrap();
+        file_system.enable_key_index(8).unwrap();
+        file_system.write_topic_message(&vec![7u8; 1500]).unwrap();
+        file_system.write_marker("checkpoint").unwrap();
+        file_system.write_topic_message_keyed(b"order-1", &"paid".to_string()).unwrap();
+        file_system.stable_store("state".to_string()).unwrap();
+
+        let mut bytes = Vec::new();
+        let manifest = file_system.export_snapshot(&mut bytes).unwrap();
+        assert_eq!((manifest.height, manifest.data_blocks, manifest.event_stream_name.as_str()), (3, 4, "backup"));
+        assert!(manifest.size() < 100_000 && bytes.len() as u64 > manifest.size());
+
+        let (restored, imported) = EventFilesystemBuilder::with_storage(VecStorage::default(), || 9).import_snapshot(bytes.as_slice()).unwrap();
+        assert_eq!(imported, manifest);
+        for chunk_size in [1000, bytes.len() as u64, 1 << 21] {
+            let count = file_system.snapshot_chunk_count(chunk_size).unwrap();
+            assert_eq!(count, (bytes.len() as u64).div_ceil(chunk_size));
+            let chunks: Vec<u8> = (0..count).flat_map(|chunk| file_system.export_chunk(chunk, chunk_size).unwrap()).collect();
+            assert_eq!(chunks, bytes);
+            assert!(file_system.export_chunk(count, chunk_size).is_err());
+        }
+        assert!(file_system.snapshot_chunk_count(0).is_err());
+        assert_eq!(restored.read_topic_message::<Vec<u8>>(0).unwrap(), vec![7u8; 1500]);
+        assert!(restored.is_marker(1).unwrap());
+        assert_eq!(restored.read_by_key::<String>(b"order-1").unwrap(), vec![(2, "paid".to_string())]);
+        assert_eq!(restored.stable_restore::<String>().unwrap(), "state");
+        assert!(restored.event_times());
+        assert_eq!(restored.write_topic_message(&"next".to_string()).unwrap(), 3);
+        assert!(matches!(EventFilesystemBuilder::with_storage(restored.storage().clone(), || 9).import_snapshot(bytes.as_slice()), Err(FsError::InvalidState(_))));
+
+        // A damaged or cut off stream leaves nothing that opens.
+        let last = bytes.len() - 40;
+        bytes[last] ^= 1;
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 9);
+        assert!(matches!(builder.clone().import_snapshot(bytes.as_slice()), Err(FsError::InvalidArgument(_))));
+        assert!(matches!(builder.clone().import_snapshot(&bytes[..100]), Err(FsError::Io(_))));
+        assert!(builder.open().is_err());
     }
-}
 
-fn is_magic_number_valid(reader: BlockRead) -> bool {
-    let mut bytes = [0u8; 8];
-    reader(MAGIC_NUMBER_IDX, &mut bytes);
-    let magic_number = u64::from_le_bytes(bytes);
-    magic_number == TOPIC_HEADER_MAGIC
-}
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn it_exports_prometheus_metrics() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "metrics".to_string());
+        file_system.write_topic_message(&vec![0u8; 600]).unwrap();
+        file_system.write_topic_message(&1u64).unwrap();
+        assert!(file_system.read_topic_message::<u64>(5).is_err());
 
-fn write_magic_number(writer: BlockWrite) {
-    writer(0, &TOPIC_HEADER_MAGIC.to_le_bytes());
-}
+        let text = file_system.metrics_text();
+        for line in [
+            "# TYPE ic_event_fs_topic_height gauge",
+            "ic_event_fs_topic_height{topic=\"metrics\"} 2",
+            "ic_event_fs_data_blocks{topic=\"metrics\"} 3",
+            "ic_event_fs_data_bytes{topic=\"metrics\"} 1536",
+            "# TYPE ic_event_fs_messages_written_total counter",
+            "ic_event_fs_messages_written_total{topic=\"metrics\"} 2",
+            "ic_event_fs_bytes_written_total{topic=\"metrics\"} 616",
+            "ic_event_fs_errors_total{topic=\"metrics\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {} in\n{}", line, text);
+        }
+    }
 
-fn read_topic_block(reader: BlockRead) -> TopicHeaderBlock {
-    let mut topic_block_size = &mut [0u8; 8];
-    reader(TOPIC_BLOCK_SIZE_IDX, topic_block_size);
-    let topic_block_size = u64::from_le_bytes(*topic_block_size);
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn it_exports_compaction_progress() {
+        thread_local! {
+            static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counter() -> u64 {
+            INSTRUCTIONS.with(|c| {
+                c.set(c.get() + 10);
+                c.get()
+            })
+        }
 
-    let mut bytes = vec![0u8; topic_block_size as usize];
-    reader(TOPIC_BLOCK_DATA_START_IDX, &mut bytes);
-    bincode::deserialize(&bytes).unwrap()
-}
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let mut file_system = builder.clone().get_or_create("compacted".to_string()).unwrap();
+        file_system.enable_segment_manifests(10).unwrap();
+        for i in 0..30u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        let gauges = |file_system: &EventFilesystem<VecStorage>| {
+            let text = file_system.metrics_text();
+            ["compaction_segments_compacted", "compaction_segments_pending", "last_compaction_height"]
+                .map(|name| text.lines().find_map(|l| l.strip_prefix(&format!("ic_event_fs_{}{{topic=\"compacted\"}} ", name))).unwrap().to_string())
+        };
+        assert_eq!(gauges(&file_system), ["0", "0", "0"]);
 
-fn write_topic_block(header: &TopicHeaderBlock, writer: BlockWrite) -> () {
-    let topic_block_bytes = bincode::serialize(header).unwrap();
-    assert!(topic_block_bytes.len() <= TOPIC_BLOCK_MAX_SIZE);
-    let topic_block_size = (topic_block_bytes.len() as u64).to_le_bytes();
+        let odd = |entry: &IndexExportEntry, _: &[u8]| entry.height % 2 == 1;
+        let progress = file_system.compact_with_filter(odd, &InstructionBudget::new(counter, 125)).unwrap();
+        assert_eq!(progress.scanned, 13);
+        assert_eq!(gauges(&file_system), ["1", "2", "0"]);
+        assert!(file_system.metrics_text().lines().any(|l| l == "# TYPE ic_event_fs_compaction_segments_pending gauge"));
 
-    writer(TOPIC_BLOCK_SIZE_IDX, &topic_block_size);
-    writer(TOPIC_BLOCK_DATA_START_IDX, &topic_block_bytes);
-}
+        file_system.compact_with_filter(odd, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!(gauges(&file_system), ["0", "0", "15"]);
+        assert_eq!(gauges(&builder.open().unwrap()), ["0", "0", "15"]);
+    }
 
-fn write_index_height(height: u64, writer: BlockWrite) -> () {
-    debug!("Writing block height to stable {}", height);
-    writer(INDEX_HEIGHT_IDX, &height.to_le_bytes());
-}
+    #[test]
+    fn it_journals_errors_across_upgrades() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 7, "test".to_string());
+        file_system.write_topic_message(&1u64).unwrap();
+        assert!(file_system.read_topic_message::<u64>(5).is_err());
+        assert!(file_system.read_topic_message::<String>(0).is_err());
+        get_write()(file_system.layout().canaries[0], &0u64.to_le_bytes());
+        assert!(file_system.check().is_err());
 
-fn write_data_block_height(height: u64, writer: BlockWrite) -> () {
-    debug!("Writing block height to stable {}", height);
-    writer(DATA_BLOCK_HEIGHT_IDX, &height.to_le_bytes());
-}
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        let errors = file_system.recent_errors();
+        let summary: Vec<(&str, Option<u64>, u64)> = errors.iter().map(|e| (e.operation.as_str(), e.height, e.timestamp)).collect();
+        assert_eq!(summary, vec![("read", Some(5), 7), ("read", Some(0), 7), ("check", None, 7)]);
+        assert!(matches!(errors[1].error, FsError::Deserialize(_)));
+        assert!(matches!(errors[2].error, FsError::RedZoneOverwritten { .. }));
+    }
 
-fn read_index_height(reader: BlockRead) -> u64 {
-    let mut bytes = [0u8; 8];
-    reader(INDEX_HEIGHT_IDX, &mut bytes);
-    u64::from_le_bytes(bytes)
-}
+    #[test]
+    fn it_runs_on_vec_storage() {
+        let file_system = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("heap".to_string()).unwrap();
+        for i in 0..5u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        file_system.stable_store("state".to_string()).unwrap();
+        file_system.check().unwrap();
 
-fn read_data_block_height(reader: BlockRead) -> u64 {
-    let mut bytes = [0u8; 8];
-    reader(DATA_BLOCK_HEIGHT_IDX, &mut bytes);
-    u64::from_le_bytes(bytes)
-}
+        let snapshot = file_system.storage().clone();
+        let reopened = EventFilesystemBuilder::with_storage(snapshot, || 0).open().unwrap();
+        assert_eq!(reopened.read_topic_messages::<u64>(0, 5).unwrap(), (0..5).collect::<Vec<_>>());
+        assert_eq!(reopened.stable_restore::<String>().unwrap(), "state");
+        assert!(EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).open().is_err());
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::borrow::BorrowMut;
-    use std::cell::RefCell;
-    use std::ops::Range;
+    #[test]
+    fn it_records_instruction_histograms() {
+        thread_local! {
+            static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counter() -> u64 {
+            INSTRUCTIONS.with(|c| {
+                c.set(c.get() + 100);
+                c.get()
+            })
+        }
 
-    use crate::{BlockRead, BlockWrite, EventFilesystem, IDX_ZONE_END, read_topic_block, TOPIC_HEADER_MAGIC};
+        let file_system = EventFilesystemBuilder::new(get_write(), get_read(), || 0).instruction_counter(counter).get_or_create("test".to_string()).unwrap();
+        for i in 0..3u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        file_system.read_topic_messages::<u64>(0, 3).unwrap();
+        assert_eq!(file_system.instruction_histogram(Operation::Read), None);
 
-    thread_local! {
-        static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; IDX_ZONE_END as usize + 1024 * 1024]);
+        let writes = file_system.instruction_histogram(Operation::Write).unwrap();
+        assert_eq!((writes.count, writes.sum, writes.percentile(99.0)), (3, 300, Some(127)));
+        #[cfg(feature = "metrics")]
+        {
+            let text = file_system.metrics_text();
+            for line in [
+                "# TYPE ic_event_fs_instructions histogram",
+                "ic_event_fs_instructions_bucket{topic=\"test\",operation=\"write\",le=\"127\"} 3",
+                "ic_event_fs_instructions_count{topic=\"test\",operation=\"read_range\"} 1",
+            ] {
+                assert!(text.lines().any(|l| l == line), "missing {} in\n{}", line, text);
+            }
+        }
+        assert_eq!(EventFilesystem::get_file_system(get_write(), get_read(), || 0).instruction_histogram(Operation::Write), None);
     }
 
     #[test]
-    fn it_gets_filesystem_and_rw() {
-        let writer = get_write();
-        let reader = get_read();
+    fn it_reads_recent_messages_with_one_stable_call() {
+        thread_local! {
+            static READS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counting_read(offset: u64, bytes: &mut [u8]) {
+            READS.with(|r| r.set(r.get() + 1));
+            get_read()(offset, bytes)
+        }
 
-        let file_system = EventFilesystem::get_or_create(
-            writer,
-            reader,
-            || 0,
-            "test".to_string(),
-        );
+        let file_system = EventFilesystem::get_or_create(get_write(), counting_read, || 0, "test".to_string());
+        for i in 0..100u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        let reads = || READS.with(|r| r.get());
 
-        MEMORY.with(|v| {
-            let v = v.borrow();
-            let magic_maybe: &[u8] = &v[0..8];
-            let test = (&v[8..16]).to_vec().clone().get(0);
-            let u64_magic = u64::from_le_bytes(magic_maybe.try_into().unwrap());
-            assert_eq!(u64_magic, TOPIC_HEADER_MAGIC);
-        });
+        assert_eq!(file_system.read_topic_message::<u64>(70).unwrap(), 70);
+        let before = reads();
+        assert_eq!(file_system.read_topic_message::<u64>(71).unwrap(), 71);
+        assert_eq!(reads() - before, 1);
 
-        let topic_block = read_topic_block(reader);
-        assert_eq!(topic_block.event_stream_name, "test");
+        file_system.write_topic_message(&100u64).unwrap();
+        assert_eq!(file_system.read_topic_message::<u64>(100).unwrap(), 100);
+        assert_eq!(file_system.read_topic_messages::<u64>(0, 101).unwrap(), (0..101).collect::<Vec<u64>>());
+    }
 
-        let message : String = "hello world".to_string();
+    #[test]
+    fn it_serves_repeated_reads_from_the_block_cache() {
+        thread_local! {
+            static READS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn counting_read(offset: u64, bytes: &mut [u8]) {
+            READS.with(|r| r.set(r.get() + 1));
+            get_read()(offset, bytes)
+        }
 
-        let res = file_system.write_topic_message::<String>(&message).unwrap();
+        let file_system = EventFilesystem::get_or_create(get_write(), counting_read, || 0, "test".to_string());
+        file_system.write_topic_message(&vec![7u8; 20 * 1024]).unwrap();
+        file_system.write_topic_message(&1u64).unwrap();
+        file_system.write_topic_message(&vec![9u8; 200 * 1024]).unwrap();
+        let reads = || READS.with(|r| r.get());
 
-        let file_system = EventFilesystem::get_file_system(
-            writer,
-            reader,
-            || 0,
-        );
+        for _ in 0..3 {
+            assert_eq!(file_system.read_topic_message::<Vec<u8>>(0).unwrap(), vec![7u8; 20 * 1024]);
+            assert_eq!(file_system.read_topic_message::<u64>(1).unwrap(), 1);
+        }
+        let before = reads();
+        file_system.read_topic_message::<Vec<u8>>(0).unwrap();
+        assert_eq!(reads(), before);
+        assert_eq!(file_system.block_cache_stats(), BlockCacheStats { hits: 125, misses: 42, cached_blocks: 42 });
 
-        assert_eq!(file_system.read_topic_messages::<String>(res, 1).unwrap()[0], message);
+        file_system.read_topic_message::<Vec<u8>>(2).unwrap();
+        file_system.read_topic_message::<Vec<u8>>(2).unwrap();
+        assert_eq!(file_system.block_cache_stats().cached_blocks, 42);
+        #[cfg(feature = "metrics")]
+        assert!(file_system.metrics_text().contains("ic_event_fs_block_cache_hits_total{topic=\"test\"} 125"));
+    }
 
-        let message2 = "hello world2".to_string();
-        let res = file_system.write_topic_message(&message2).unwrap();
+    #[test]
+    fn it_deduplicates_recent_payloads() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 7, "test".to_string());
+        file_system.write_topic_message(&"a").unwrap();
+        file_system.write_topic_message(&"b").unwrap();
+        file_system.enable_deduplication(DeduplicationConfig { window: 2, mode: DuplicateMode::Reject }).unwrap();
+        assert_eq!(file_system.write_topic_message(&"a"), Err(FsError::Duplicate { height: 0 }));
+        file_system.write_marker("heartbeat").unwrap();
+        file_system.write_marker("heartbeat").unwrap();
+        file_system.write_topic_message(&"c").unwrap();
+        assert_eq!(file_system.write_topic_message(&"a"), Ok(5));
 
-        assert_eq!(res, 1);
-        assert_eq!(file_system.read_topic_message::<String>(res).unwrap(), message2);
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 7);
+        assert_eq!(file_system.deduplication(), Some(DeduplicationConfig { window: 2, mode: DuplicateMode::Reject }));
+        assert_eq!(file_system.write_topic_message(&"c"), Err(FsError::Duplicate { height: 4 }));
+
+        file_system.enable_deduplication(DeduplicationConfig { window: 2, mode: DuplicateMode::Flag }).unwrap();
+        assert_eq!(file_system.write_topic_message(&"a"), Ok(6));
+        let expected = EventFilesystemEvent::DuplicateWritten(DuplicateWritten { height: 6, duplicate_of: 5, timestamp: 7 });
+        assert_eq!(file_system.admin_events().last(), Some(&expected));
+
+        file_system.disable_deduplication().unwrap();
+        file_system.write_topic_message(&"a").unwrap();
+        assert_eq!(EventFilesystem::get_file_system(get_write(), get_read(), || 7).deduplication(), None);
     }
 
     #[test]
-    fn it_updates_topic_height() {
-        let writer = get_write();
-        let reader = get_read();
+    #[cfg(feature = "aggregates")]
+    fn it_keeps_aggregates_across_upgrades() {
+        fn total(accumulator: &mut Value, message: &Value) {
+            *accumulator = json!(accumulator.as_u64().unwrap_or(0) + message.as_u64().unwrap_or(0));
+        }
 
-        let file_system = EventFilesystem::get_or_create(
-            writer,
-            reader,
-            || 0,
-            "test".to_string(),
-        );
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        file_system.write_topic_message(&100u64).unwrap();
+        file_system.register_aggregate("total", json!(0), total).unwrap();
+        file_system.write_topic_message(&2u64).unwrap();
+        file_system.write_marker("heartbeat").unwrap();
+        file_system.write_topic_message(&3u64).unwrap();
+        assert_eq!(file_system.aggregate("total"), Some(json!(5)));
+        assert!(file_system.write_topic_message(&BTreeMap::from([((1u8, 2u8), 3u8)])).is_err());
+        assert_eq!(file_system.get_topic_height(), 4);
 
-        assert_eq!(file_system.get_topic_height(), 0);
-        for i in 0..100 {
-            let message : String = format!("hello world {}", i);
-            let res = file_system.write_topic_message::<String>(&message).unwrap();
+        let file_system = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        assert_eq!(file_system.aggregate("total"), None);
+        file_system.register_aggregate("total", json!(0), total).unwrap();
+        file_system.write_topic_message(&4u64).unwrap();
+        assert_eq!(file_system.aggregate("total"), Some(json!(9)));
+    }
+
+    #[test]
+    #[cfg(feature = "aggregates")]
+    fn it_commits_batches_together_with_their_aggregates() {
+        fn count(accumulator: &mut Value, _: &Value) {
+            *accumulator = json!(accumulator.as_u64().unwrap_or(0) + 1);
+        }
+        fn text(accumulator: &mut Value, message: &Value) {
+            *accumulator = json!(format!("{}{}", accumulator.as_str().unwrap_or(""), message.as_str().unwrap_or("")));
         }
-        assert_eq!(file_system.get_topic_height(), 100);
 
-        let file_system = EventFilesystem::get_file_system(
-            writer,
-            reader,
-            || 0,
-        );
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let file_system = builder.clone().get_or_create("folded".to_string()).unwrap();
+        file_system.register_aggregate("count", json!(0), count).unwrap();
+        file_system.register_aggregate("text", json!(""), text).unwrap();
+        let batch = ["x".repeat(1000), "y".repeat(1000)];
+        let mut written = 0;
+        // The text accumulator outgrows the meta zone, after the count was saved.
+        let error = loop {
+            match file_system.write_topic_messages(&batch) {
+                Ok(_) => written += 2,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, FsError::OutOfSpace(_)), "{:?}", error);
+        assert_eq!(file_system.get_topic_height(), written);
+        assert_eq!(file_system.aggregate("count"), Some(json!(written)));
 
-        assert_eq!(file_system.get_topic_height(), 100);
+        let file_system = builder.open().unwrap();
+        file_system.register_aggregate("count", json!(0), count).unwrap();
+        assert_eq!((file_system.get_topic_height(), file_system.aggregate("count")), (written, Some(json!(written))));
     }
 
     #[test]
-    fn it_writes_and_gets_free_blockstore() {
-        let file_system = EventFilesystem::get_or_create(
-            get_write(),
-            get_read(),
-            || 0,
-            "test".to_string(),
-        );
+    fn it_persists_read_heat_map() {
+        let file_system = EventFilesystem::get_or_create(get_write(), get_read(), || 0, "test".to_string());
+        for i in 0..30u64 {
+            file_system.write_topic_message(&i).unwrap();
+        }
+        file_system.read_topic_message::<u64>(0).unwrap();
+        assert!(file_system.heat_map().is_empty());
 
-        let data = "hello world";
+        file_system.enable_heat_map(10).unwrap();
+        file_system.read_topic_message::<u64>(25).unwrap();
+        file_system.read_topic_messages::<u64>(5, 10).unwrap();
+        assert!(file_system.read_topic_message::<u64>(30).is_err());
+        file_system.save_heat_map().unwrap();
 
-        file_system.stable_store(data);
+        let reopened = EventFilesystem::get_file_system(get_write(), get_read(), || 0);
+        reopened.enable_heat_map(10).unwrap();
+        assert_eq!(reopened.heat_map(), vec![
+            HeatMapSegment { start: 0, end: 10, reads: 5 },
+            HeatMapSegment { start: 10, end: 20, reads: 5 },
+            HeatMapSegment { start: 20, end: 30, reads: 1 },
+        ]);
 
-        assert_eq!(file_system.stable_restore::<String>().unwrap(), data);
+        reopened.disable_heat_map().unwrap();
+        reopened.read_topic_message::<u64>(1).unwrap();
+        assert!(reopened.heat_map().is_empty());
+    }
+
+    thread_local! {
+        static SECOND_MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; 4 * 1024 * 1024]);
+        static THIRD_MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; 4 * 1024 * 1024]);
+    }
+
+    // Small topics on their own memories, for tests that need more than one topic.
+    fn second_topic(clock: fn() -> u64) -> EventFilesystemBuilder {
+        let small = small_layout();
+        EventFilesystemBuilder::new(
+            |offset, bytes| SECOND_MEMORY.with(|mem| mem.borrow_mut()[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes)),
+            |offset, bytes| SECOND_MEMORY.with(|mem| bytes.copy_from_slice(&mem.borrow()[offset as usize..offset as usize + bytes.len()])),
+            clock,
+        ).layout(small)
     }
 
+    fn third_topic(clock: fn() -> u64) -> EventFilesystemBuilder {
+        let small = small_layout();
+        EventFilesystemBuilder::new(
+            |offset, bytes| THIRD_MEMORY.with(|mem| mem.borrow_mut()[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes)),
+            |offset, bytes| THIRD_MEMORY.with(|mem| bytes.copy_from_slice(&mem.borrow()[offset as usize..offset as usize + bytes.len()])),
+            clock,
+        ).layout(small)
+    }
+
+    #[allow(clippy::needless_return, unused_mut)]
     fn get_write() -> BlockWrite {
         return |offset, bytes| {
             MEMORY.with(|mut mem| {
@@ -297,6 +5695,7 @@ mod tests {
         };
     }
 
+    #[allow(clippy::needless_return, clippy::clone_on_copy, clippy::unnecessary_cast, unused_mut)]
     fn get_read() -> BlockRead {
         return |offset, bytes| {
             MEMORY.with(|mut mem| {
@@ -309,4 +5708,8 @@ mod tests {
             });
         };
     }
+
+    fn memory() -> Storage {
+        Storage::new(FnStorage::new(get_write(), get_read()))
+    }
 }
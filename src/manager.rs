@@ -0,0 +1,380 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::{EventFilesystem, EventFilesystemBuilder, FsError, LayoutConfig};
+use crate::constants::{MAGIC_NUMBER_IDX, U64_SIZE};
+use crate::read_write::{BlockRead, BlockWrite};
+use crate::storage::{BlockStorage, FnStorage};
+
+const DIRECTORY_MAGIC: u64 = 0x5452_4f50_4943_5331;
+// magic | directory size | directory | ... | lock size | creation lock
+const DIRECTORY_SIZE: u64 = 64 * 1024;
+const LOCK_SIZE: u64 = 1024;
+const LOCK_IDX: u64 = DIRECTORY_SIZE - LOCK_SIZE;
+pub const MAX_TOPICS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicPartition {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+// The memory of one partition, offsets are relative to its start. An access past its end panics, which
+// traps in a canister and rolls the call back.
+#[derive(Debug)]
+pub struct PartitionStorage<S: BlockStorage = FnStorage> {
+    backing: Rc<RefCell<S>>,
+    partition: TopicPartition,
+}
+
+impl<S: BlockStorage> PartitionStorage<S> {
+    pub fn partition(&self) -> &TopicPartition {
+        &self.partition
+    }
+
+    fn offset(&self, offset: u64, len: u64) -> u64 {
+        let partition = &self.partition;
+        let end = offset.checked_add(len).filter(|end| *end <= partition.size);
+        assert!(end.is_some(), "Topic {} accessed {} bytes at {}, past its {} byte partition", partition.name, len, offset, partition.size);
+        partition.offset + offset
+    }
+}
+
+impl<S: BlockStorage> BlockStorage for PartitionStorage<S> {
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.backing.borrow().read(self.offset(offset, buf.len() as u64), buf)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let offset = self.offset(offset, data.len() as u64);
+        self.backing.borrow_mut().write(offset, data)
+    }
+
+    fn grow(&mut self, end: u64) -> Result<(), String> {
+        if end > self.partition.size {
+            return Err(format!("Topic {} needs {} bytes, its partition has {}", self.partition.name, end, self.partition.size));
+        }
+        self.backing.borrow_mut().grow(self.partition.offset + end)
+    }
+}
+
+// Partitions one stable memory into named topics, each a complete topic with its own header, zones
+// and layout. The directory of partitions sits at the start of the memory and partitions follow in
+// creation order with the size they were created with. A topic that outgrows its partition traps,
+// which rolls the call back and leaves the other topics untouched.
+pub struct TopicManager<S: BlockStorage = FnStorage> {
+    backing: Rc<RefCell<S>>,
+    partitions: RefCell<Vec<TopicPartition>>,
+    // One handle per opened topic. Handles of the same topic would each keep their own heights and
+    // caches and overwrite each other's writes.
+    handles: RefCell<BTreeMap<String, Rc<EventFilesystem<PartitionStorage<S>>>>>,
+    clock: fn() -> u64,
+}
+
+impl TopicManager<FnStorage> {
+    pub fn init(write_fn: BlockWrite, read_fn: BlockRead, clock: fn() -> u64) -> Result<TopicManager, FsError> {
+        TopicManager::with_storage(FnStorage::new(write_fn, read_fn), clock)
+    }
+}
+
+impl<S: BlockStorage> TopicManager<S> {
+    pub fn with_storage(mut storage: S, clock: fn() -> u64) -> Result<TopicManager<S>, FsError> {
+        let mut magic = [0u8; 8];
+        storage.read(0, &mut magic);
+        let partitions = match u64::from_le_bytes(magic) {
+            0 => {
+                write_directory(&[], &mut storage)?;
+                storage.write(0, &DIRECTORY_MAGIC.to_le_bytes());
+                Vec::new()
+            }
+            DIRECTORY_MAGIC => {
+                let mut size = [0u8; 8];
+                storage.read(U64_SIZE, &mut size);
+                let size = u64::from_le_bytes(size).min(LOCK_IDX - 2 * U64_SIZE);
+                let mut bytes = vec![0u8; size as usize];
+                storage.read(2 * U64_SIZE, &mut bytes);
+                bincode::deserialize(&bytes).map_err(|e| FsError::Deserialize(format!("topic directory: {}", e)))?
+            }
+            _ => return Err(FsError::InvalidState("Memory holds something other than a topic directory".to_string())),
+        };
+        Ok(TopicManager { backing: Rc::new(RefCell::new(storage)), partitions: RefCell::new(partitions), handles: RefCell::new(BTreeMap::new()), clock })
+    }
+
+    pub fn topics(&self) -> Vec<TopicPartition> {
+        self.partitions.borrow().clone()
+    }
+
+    // Reserves `size` bytes after the last partition and creates the topic in them. Creating a topic
+    // that exists opens it, so call paths that race to create it between awaits get the same handle.
+    // The partition is held by a creation lock record until the topic opened and only then listed.
+    pub fn create_topic(&self, name: &str, size: u64, layout: LayoutConfig) -> Result<Rc<EventFilesystem<PartitionStorage<S>>>, FsError> {
+        layout.validate()?;
+        if size <= layout.data_block_offset(0) {
+            return Err(FsError::InvalidArgument(format!("{} bytes can't hold the zones of layout {:?}", size, layout)));
+        }
+        let mut partitions = self.topics();
+        if let Some(existing) = partitions.iter().find(|partition| partition.name == name) {
+            if existing.size != size {
+                return Err(FsError::InvalidArgument(format!("Topic {} exists with {} bytes, not {}", name, existing.size, size)));
+            }
+            if let Some(topic) = self.handles.borrow().get(name) {
+                if topic.get_topic_header().layout != layout {
+                    return Err(FsError::InvalidArgument(format!("Topic was created with layout {:?}, not {:?}", topic.get_topic_header().layout, layout)));
+                }
+                return Ok(topic.clone());
+            }
+            let topic = self.partition_builder(existing.clone()).layout(layout).get_or_create(name.to_string())?;
+            return Ok(self.share(name, topic));
+        }
+        if partitions.len() == MAX_TOPICS {
+            return Err(FsError::OutOfSpace(format!("A memory holds at most {} topics", MAX_TOPICS)));
+        }
+        // A lock left by a creation that didn't finish holds unlisted space, which is taken over. What
+        // the creation got to write there is dropped so it isn't opened under the new name.
+        if let Some(lock) = self.creation_lock()? {
+            debug!("Taking over interrupted creation of topic {}", lock.name);
+            self.backing.borrow_mut().write(lock.offset + MAGIC_NUMBER_IDX, &[0u8; U64_SIZE as usize]);
+        }
+        let offset = partitions.last().map_or(DIRECTORY_SIZE, |last| last.offset + last.size);
+        let partition = TopicPartition { name: name.to_string(), offset, size };
+        write_lock(Some(&partition), &mut *self.backing.borrow_mut())?;
+        partitions.push(partition.clone());
+
+        let created = self.partition_builder(partition).layout(layout).get_or_create(name.to_string())
+            .and_then(|topic| write_directory(&partitions, &mut *self.backing.borrow_mut()).map(|_| topic));
+        if created.is_ok() {
+            *self.partitions.borrow_mut() = partitions;
+        }
+        write_lock(None, &mut *self.backing.borrow_mut())?;
+        created.map(|topic| self.share(name, topic))
+    }
+
+    // The partition a topic is being created in, left behind when a creation didn't finish.
+    pub fn creation_lock(&self) -> Result<Option<TopicPartition>, FsError> {
+        let backing = self.backing.borrow();
+        let mut size = [0u8; 8];
+        backing.read(LOCK_IDX, &mut size);
+        let size = u64::from_le_bytes(size).min(LOCK_SIZE - U64_SIZE);
+        let mut bytes = vec![0u8; size as usize];
+        backing.read(LOCK_IDX + U64_SIZE, &mut bytes);
+        match size {
+            0 => Ok(None),
+            _ => bincode::deserialize(&bytes).map(Some).map_err(|e| FsError::Deserialize(format!("topic creation lock: {}", e))),
+        }
+    }
+
+    // The topic's handle, opened on first use and shared by every later call.
+    pub fn open_topic(&self, name: &str) -> Result<Rc<EventFilesystem<PartitionStorage<S>>>, FsError> {
+        if let Some(topic) = self.handles.borrow().get(name) {
+            return Ok(topic.clone());
+        }
+        self.open_topic_with(name, |builder| builder)
+    }
+
+    // Opens the topic with options beyond the layout, e.g. an instruction counter. They only apply to
+    // the handle they open, a topic that is already open is refused.
+    pub fn open_topic_with(&self,
+                           name: &str,
+                           options: impl FnOnce(EventFilesystemBuilder<PartitionStorage<S>>) -> EventFilesystemBuilder<PartitionStorage<S>>,
+    ) -> Result<Rc<EventFilesystem<PartitionStorage<S>>>, FsError> {
+        if self.handles.borrow().contains_key(name) {
+            return Err(FsError::InvalidState(format!("Topic {} is already open", name)));
+        }
+        let partition = self.topics().into_iter().find(|partition| partition.name == name)
+            .ok_or_else(|| FsError::InvalidArgument(format!("Unknown topic {}", name)))?;
+        let topic = options(self.partition_builder(partition)).open()?;
+        Ok(self.share(name, topic))
+    }
+
+    // Commits subscriber offsets across topics, each topic's offsets with a single write through its
+    // shared handle, so handles held by callers see them. Every commit is checked before any is
+    // written, so an unknown topic or subscriber commits nothing.
+    pub fn commit_offsets(&self, commits: &[(&str, Principal, u64)]) -> Result<(), FsError> {
+        let mut by_topic: BTreeMap<&str, Vec<(Principal, u64)>> = BTreeMap::new();
+        for (topic, subscriber, offset) in commits {
+            by_topic.entry(topic).or_default().push((*subscriber, *offset));
+        }
+        let topics = by_topic.into_iter()
+            .map(|(topic, commits)| Ok((self.open_topic(topic)?, commits)))
+            .collect::<Result<Vec<_>, FsError>>()?;
+        for (topic, commits) in &topics {
+            topic.check_commits(commits)?;
+        }
+        topics.iter().try_for_each(|(topic, commits)| topic.commit_offsets(commits))
+    }
+
+    fn share(&self, name: &str, topic: EventFilesystem<PartitionStorage<S>>) -> Rc<EventFilesystem<PartitionStorage<S>>> {
+        let topic = Rc::new(topic);
+        self.handles.borrow_mut().insert(name.to_string(), topic.clone());
+        topic
+    }
+
+    fn partition_builder(&self, partition: TopicPartition) -> EventFilesystemBuilder<PartitionStorage<S>> {
+        EventFilesystemBuilder::with_storage(PartitionStorage { backing: self.backing.clone(), partition }, self.clock)
+    }
+}
+
+fn write_directory(partitions: &[TopicPartition], storage: &mut impl BlockStorage) -> Result<(), FsError> {
+    let bytes = bincode::serialize(partitions).map_err(|e| FsError::Serialize(e.to_string()))?;
+    if bytes.len() as u64 > LOCK_IDX - 2 * U64_SIZE {
+        return Err(FsError::OutOfSpace("Topic directory is full".to_string()));
+    }
+    storage.write(2 * U64_SIZE, &bytes);
+    storage.write(U64_SIZE, &(bytes.len() as u64).to_le_bytes());
+    Ok(())
+}
+
+fn write_lock(partition: Option<&TopicPartition>, storage: &mut impl BlockStorage) -> Result<(), FsError> {
+    let bytes = match partition {
+        Some(partition) => bincode::serialize(partition).map_err(|e| FsError::Serialize(e.to_string()))?,
+        None => Vec::new(),
+    };
+    if bytes.len() as u64 > LOCK_SIZE - U64_SIZE {
+        return Err(FsError::InvalidArgument(format!("Topic name {} is too long", partition.map_or("", |p| &p.name))));
+    }
+    storage.write(LOCK_IDX + U64_SIZE, &bytes);
+    storage.write(LOCK_IDX, &(bytes.len() as u64).to_le_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+    use std::rc::Rc;
+
+    use candid::Principal;
+
+    use crate::{BlockStorage, FnStorage, FsError, LayoutConfig, VecStorage};
+    use crate::fixtures::small_layout;
+    use crate::manager::{PartitionStorage, TopicManager, write_directory, write_lock};
+
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; 6 * 1024 * 1024]);
+    }
+
+    fn write(offset: u64, bytes: &[u8]) {
+        MEMORY.with(|mem| mem.borrow_mut()[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes))
+    }
+
+    fn read(offset: u64, bytes: &mut [u8]) {
+        MEMORY.with(|mem| bytes.copy_from_slice(&mem.borrow()[offset as usize..offset as usize + bytes.len()]))
+    }
+
+    #[test]
+    fn it_hosts_topics_side_by_side() {
+        let small = small_layout();
+        let manager = TopicManager::init(write, read, || 0).unwrap();
+        let orders = manager.create_topic("orders", 2 * 1024 * 1024, small).unwrap();
+        let payments = manager.create_topic("payments", 2 * 1024 * 1024, small).unwrap();
+        assert!(manager.create_topic("orders", 4 * 1024 * 1024, small).is_err());
+        assert!(manager.create_topic("tiny", 1024, small).is_err());
+        for i in 0..10u64 {
+            orders.write_topic_message(&format!("order {}", i)).unwrap();
+            payments.write_topic_message(&i).unwrap();
+        }
+        orders.stable_store("orders state".to_string()).unwrap();
+
+        let manager = TopicManager::init(write, read, || 0).unwrap();
+        assert_eq!(manager.topics().iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!["orders", "payments"]);
+        let orders = manager.open_topic("orders").unwrap();
+        let payments = manager.open_topic("payments").unwrap();
+        assert_eq!(orders.read_topic_message::<String>(9).unwrap(), "order 9");
+        assert_eq!(payments.read_topic_messages::<u64>(0, 10).unwrap(), (0..10).collect::<Vec<_>>());
+        assert_eq!(orders.stable_restore::<String>().unwrap(), "orders state");
+        assert_eq!(payments.get_topic_header().event_stream_name, "payments");
+        assert!(matches!(manager.open_topic("refunds"), Err(FsError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn it_creates_topics_idempotently() {
+        let small = small_layout();
+        let manager = TopicManager::init(write, read, || 0).unwrap();
+        let orders = manager.create_topic("orders", 2 * 1024 * 1024, small).unwrap();
+        orders.write_topic_message(&"first".to_string()).unwrap();
+        let again = manager.create_topic("orders", 2 * 1024 * 1024, small).unwrap();
+        assert_eq!(again.read_topic_message::<String>(0).unwrap(), "first");
+        assert_eq!(manager.topics().len(), 1);
+
+        let other = LayoutConfig { index_zone_size: 8192, ..small };
+        assert!(manager.create_topic("orders", 2 * 1024 * 1024, other).is_err());
+        assert_eq!(manager.creation_lock().unwrap(), None);
+        assert_eq!(TopicManager::init(write, read, || 0).unwrap().topics().len(), 1);
+
+        // A creation that wrote its topic but didn't get to list it leaves its lock, the next creation
+        // takes the space over.
+        manager.create_topic("refunds", 2 * 1024 * 1024, small).unwrap();
+        let interrupted = manager.topics()[1].clone();
+        write_directory(&manager.topics()[..1], &mut FnStorage::new(write, read)).unwrap();
+        write_lock(Some(&interrupted), &mut FnStorage::new(write, read)).unwrap();
+        let manager = TopicManager::init(write, read, || 0).unwrap();
+        assert_eq!(manager.creation_lock().unwrap(), Some(interrupted.clone()));
+        let payments = manager.create_topic("payments", 2 * 1024 * 1024, small).unwrap();
+        assert_eq!(payments.get_topic_header().event_stream_name, "payments");
+        assert_eq!(manager.creation_lock().unwrap(), None);
+        assert_eq!(manager.topics()[1].offset, interrupted.offset);
+    }
+
+    #[test]
+    fn it_shares_one_handle_per_topic() {
+        let small = small_layout();
+        let manager = TopicManager::with_storage(VecStorage::default(), || 0).unwrap();
+        let created = manager.create_topic("orders", 2 * 1024 * 1024, small).unwrap();
+        let opened = manager.open_topic("orders").unwrap();
+        assert!(Rc::ptr_eq(&created, &opened));
+        created.write_topic_message(&"first".to_string()).unwrap();
+        opened.write_topic_message(&"second".to_string()).unwrap();
+        let again = manager.create_topic("orders", 2 * 1024 * 1024, small).unwrap();
+        assert_eq!(again.read_topic_messages::<String>(0, 2).unwrap(), vec!["first", "second"]);
+        assert!(matches!(manager.open_topic_with("orders", |builder| builder), Err(FsError::InvalidState(_))));
+    }
+
+    #[test]
+    fn it_keeps_managers_apart() {
+        let small = small_layout();
+        let first = TopicManager::with_storage(VecStorage::default(), || 0).unwrap();
+        let second = TopicManager::with_storage(VecStorage::default(), || 0).unwrap();
+        first.create_topic("orders", 2 * 1024 * 1024, small).unwrap().write_topic_message(&1u64).unwrap();
+        let payments = second.create_topic("payments", 2 * 1024 * 1024, small).unwrap();
+        assert_eq!(first.open_topic("orders").unwrap().read_topic_message::<u64>(0).unwrap(), 1);
+        assert!(first.open_topic("payments").is_err());
+        assert_eq!(payments.get_topic_height(), 0);
+        assert_eq!(payments.storage().partition(), &second.topics()[0]);
+
+        let mut partition = PartitionStorage { backing: Rc::new(RefCell::new(VecStorage::default())), partition: second.topics()[0].clone() };
+        assert!(partition.grow(2 * 1024 * 1024).is_ok());
+        assert!(partition.grow(2 * 1024 * 1024 + 1).is_err());
+        assert!(catch_unwind(AssertUnwindSafe(|| partition.write(2 * 1024 * 1024 - 4, &[0u8; 8]))).is_err());
+    }
+
+    #[test]
+    fn it_commits_offsets_across_topics() {
+        let small = small_layout();
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let manager = TopicManager::with_storage(VecStorage::default(), || 0).unwrap();
+        for name in ["orders", "payments"] {
+            let topic = manager.create_topic(name, 2 * 1024 * 1024, small).unwrap();
+            topic.write_topic_messages(&[1u64, 2, 3]).unwrap();
+            topic.add_subscriber(a, 0).unwrap();
+            topic.add_subscriber(b, 0).unwrap();
+        }
+        let orders = manager.open_topic("orders").unwrap();
+
+        assert!(manager.commit_offsets(&[("orders", a, 2), ("payments", a, 4)]).is_err());
+        assert!(manager.commit_offsets(&[("orders", a, 2), ("refunds", a, 1)]).is_err());
+        assert_eq!(manager.open_topic("orders").unwrap().subscriber_offset(a), Some(0));
+        manager.commit_offsets(&[("orders", a, 2), ("payments", a, 3), ("orders", b, 1)]).unwrap();
+        assert_eq!(orders.subscribers(), vec![(a, 2), (b, 1)]);
+        assert_eq!(manager.open_topic("payments").unwrap().subscribers(), vec![(a, 3), (b, 0)]);
+
+        // A commit through the held handle builds on the manager's instead of overwriting it.
+        orders.commit_offsets(&[(b, 3)]).unwrap();
+        let manager = TopicManager::with_storage(manager.backing.borrow().clone(), || 0).unwrap();
+        assert_eq!(manager.open_topic("orders").unwrap().subscribers(), vec![(a, 2), (b, 3)]);
+    }
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use crate::{BincodeCodec, BlockStorage, EventFilesystem, EventFilesystemBuilder, FsError, IndexExportEntry, StableEncode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MergeOrder {
+    // By write timestamp, the first topic goes first on ties. Each topic keeps its own order.
+    Timestamp,
+    // All of the first topic, then all of the second.
+    Concatenate,
+}
+
+// A message of a merged topic. `payload` is the message as its origin serialized it, empty for markers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergedMessage {
+    pub origin: String,
+    pub height: u64,
+    pub timestamp: u64,
+    pub payload: Vec<u8>,
+}
+
+impl StableEncode for MergedMessage {}
+
+impl MergedMessage {
+    pub fn is_marker(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    // `codec` is the origin topic's codec at `height`.
+    pub fn decode<T: DeserializeOwned>(&self, codec: &BincodeCodec) -> Result<T, FsError> {
+        codec.deserialize(&self.payload)
+    }
+}
+
+// Creates a topic with `target` holding the committed messages of `a` and `b` as MergedMessages, tagged
+// with the name of the topic they came from. Origin heights are recorded as aliases of the new heights,
+// so resolve_alias(origin, height) finds a message that was referenced before the merge.
+pub fn merge_topics<A: BlockStorage, B: BlockStorage, T: BlockStorage>(a: &EventFilesystem<A>,
+                                                                       b: &EventFilesystem<B>,
+                                                                       order: MergeOrder,
+                                                                       target: EventFilesystemBuilder<T>,
+                                                                       event_stream_name: String,
+) -> Result<EventFilesystem<T>, FsError> {
+    let origins = [&a.get_topic_header().event_stream_name, &b.get_topic_header().event_stream_name];
+    if origins[0] == origins[1] {
+        return Err(FsError::InvalidArgument(format!("Both topics are named {}, merged messages couldn't tell them apart", origins[0])));
+    }
+    a.check_not_migrating()?;
+    b.check_not_migrating()?;
+    let merged = target.get_or_create(event_stream_name)?;
+    if merged.get_topic_height() > 0 {
+        return Err(FsError::InvalidArgument("Topics can only be merged into an empty topic".to_string()));
+    }
+
+    let a_entries = committed_entries(a)?;
+    let b_entries = committed_entries(b)?;
+    let mut sequence = Vec::with_capacity(a_entries.len() + b_entries.len());
+    match order {
+        MergeOrder::Concatenate => {
+            sequence.extend(a_entries.into_iter().map(|entry| (0, entry)));
+            sequence.extend(b_entries.into_iter().map(|entry| (1, entry)));
+        }
+        MergeOrder::Timestamp => {
+            let mut a_entries = a_entries.into_iter().peekable();
+            let mut b_entries = b_entries.into_iter().peekable();
+            loop {
+                let next = match (a_entries.peek(), b_entries.peek()) {
+                    (Some(x), Some(y)) if x.timestamp > y.timestamp => b_entries.next().map(|entry| (1, entry)),
+                    (Some(_), _) => a_entries.next().map(|entry| (0, entry)),
+                    (None, _) => b_entries.next().map(|entry| (1, entry)),
+                };
+                match next {
+                    Some(next) => sequence.push(next),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    let sources = [(&a.reader, &a.storage), (&b.reader, &b.storage)];
+    let mut aliases = [Vec::new(), Vec::new()];
+    for (origin, entry) in sequence {
+        let (reader, storage) = sources[origin];
+        let message = MergedMessage {
+            origin: origins[origin].clone(),
+            height: entry.height,
+            timestamp: entry.timestamp,
+            payload: reader.read_payload(entry.height, storage)?,
+        };
+        let height = merged.write_topic_message(&message)?;
+        aliases[origin].push((entry.height, height));
+    }
+    for (origin, aliases) in origins.into_iter().zip(aliases) {
+        merged.record_aliases(origin, aliases)?;
+    }
+    Ok(merged)
+}
+
+fn committed_entries<S: BlockStorage>(topic: &EventFilesystem<S>) -> Result<Vec<IndexExportEntry>, FsError> {
+    (0..topic.committed_height.get())
+        .map(|height| topic.reader.read_idx(height, &topic.storage).map(IndexExportEntry::from))
+        .collect()
+}
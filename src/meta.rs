@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::arena::{Arena, ArenaStats};
+use crate::constants::*;
+use crate::error::FsError;
+use crate::layout::LayoutConfig;
+use crate::regions::StableRegion;
+use crate::storage::Storage;
+
+// Named records for the filesystem's own bookkeeping, stored in the meta zone at the tail of the
+// free memory block. The zone starts with a pointer to the directory (name -> record) followed by
+// an arena holding the directory and the records, each as a length prefixed allocation. The keyed
+// stable store keeps the canister's values the same way in a region of the stable store zone.
+pub(crate) struct MetaStore {
+    zone_idx: u64,
+    arena: Arena,
+    directory: RefCell<BTreeMap<String, u64>>,
+    storage: Storage,
+}
+
+impl MetaStore {
+    pub(crate) fn open(layout: &LayoutConfig, storage: &Storage) -> Result<MetaStore, FsError> {
+        MetaStore::open_in(layout.meta_zone_idx(), layout.idx_zone_idx() - CANARY_SIZE, storage)
+    }
+
+    // The directory pointer takes the region's length slot.
+    pub(crate) fn open_region(region: StableRegion, storage: &Storage) -> Result<MetaStore, FsError> {
+        MetaStore::open_in(region.offset, region.data_offset() + region.capacity, storage)
+    }
+
+    // Starts an empty store in a region that may still hold older bytes.
+    pub(crate) fn create_in_region(region: StableRegion, storage: &Storage) -> Result<MetaStore, FsError> {
+        storage.write(region.offset, &0u64.to_le_bytes());
+        storage.write(region.data_offset(), &0u64.to_le_bytes());
+        MetaStore::open_region(region, storage)
+    }
+
+    fn open_in(zone_idx: u64, end: u64, storage: &Storage) -> Result<MetaStore, FsError> {
+        let arena = Arena::open_in(zone_idx + U64_SIZE, end, storage.clone())?;
+        let store = MetaStore {
+            zone_idx,
+            arena,
+            directory: RefCell::new(BTreeMap::new()),
+            storage: storage.clone(),
+        };
+        let directory_ptr = store.directory_ptr();
+        if directory_ptr != 0 {
+            let bytes = store.read_record(directory_ptr)?;
+            let directory = bincode::deserialize(&bytes).map_err(|e| FsError::Deserialize(format!("meta directory: {}", e)))?;
+            *store.directory.borrow_mut() = directory;
+        }
+        Ok(store)
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<Option<Vec<u8>>, FsError> {
+        match self.directory.borrow().get(name) {
+            Some(ptr) => self.read_record(*ptr).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // A record that moves is freed only once the directory points to its copy, so a put that fails leaves
+    // the previous value readable.
+    pub(crate) fn put(&self, name: &str, bytes: &[u8]) -> Result<(), FsError> {
+        let existing = self.directory.borrow().get(name).copied();
+        let ptr = self.write_record(existing, bytes)?;
+        if existing != Some(ptr) {
+            self.directory.borrow_mut().insert(name.to_string(), ptr);
+            if let Err(e) = self.save_directory() {
+                match existing {
+                    Some(existing) => self.directory.borrow_mut().insert(name.to_string(), existing),
+                    None => self.directory.borrow_mut().remove(name),
+                };
+                self.arena.free(ptr)?;
+                return Err(e);
+            }
+            if let Some(existing) = existing {
+                self.arena.free(existing)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether there was a record.
+    pub(crate) fn remove(&self, name: &str) -> Result<bool, FsError> {
+        let Some(ptr) = self.directory.borrow_mut().remove(name) else {
+            return Ok(false);
+        };
+        self.save_directory()?;
+        self.arena.free(ptr)?;
+        Ok(true)
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.directory.borrow().keys().cloned().collect()
+    }
+
+    pub(crate) fn get_value<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, FsError> {
+        match self.get(name)? {
+            Some(bytes) => bincode::deserialize(&bytes)
+                .map(Some)
+                .map_err(|e| FsError::Deserialize(format!("meta record {}: {}", name, e))),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn put_value<T: Serialize>(&self, name: &str, value: &T) -> Result<(), FsError> {
+        let bytes = bincode::serialize(value).map_err(|e| FsError::Serialize(format!("meta record {}: {}", name, e)))?;
+        self.put(name, &bytes)
+    }
+
+    // End of the records, everything from here to the end of the zone was never allocated.
+    #[cfg(feature = "snapshot")]
+    pub(crate) fn used_end(&self) -> u64 {
+        self.arena.end() - self.arena.stats().unallocated_bytes
+    }
+
+    pub(crate) fn stats(&self) -> ArenaStats {
+        self.arena.stats()
+    }
+
+    fn save_directory(&self) -> Result<(), FsError> {
+        let bytes = bincode::serialize(&*self.directory.borrow()).map_err(|e| FsError::Serialize(format!("meta directory: {}", e)))?;
+        let existing = match self.directory_ptr() {
+            0 => None,
+            ptr => Some(ptr),
+        };
+        let ptr = self.write_record(existing, &bytes)?;
+        self.storage.write(self.zone_idx, &ptr.to_le_bytes());
+        if let Some(existing) = existing.filter(|existing| *existing != ptr) {
+            self.arena.free(existing)?;
+        }
+        Ok(())
+    }
+
+    // Writes in place when the record still fits, otherwise into a new allocation the caller frees
+    // `existing` for.
+    fn write_record(&self, existing: Option<u64>, bytes: &[u8]) -> Result<u64, FsError> {
+        let size = U64_SIZE + bytes.len() as u64;
+        let ptr = match existing {
+            Some(ptr) if self.arena.resize_in_place(ptr, size)? => ptr,
+            _ => self.arena.allocate(size)?,
+        };
+        self.arena.write(ptr, 0, &(bytes.len() as u64).to_le_bytes())?;
+        self.arena.write(ptr, U64_SIZE, bytes)?;
+        Ok(ptr)
+    }
+
+    fn read_record(&self, ptr: u64) -> Result<Vec<u8>, FsError> {
+        let mut len = [0u8; 8];
+        self.arena.read(ptr, 0, &mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > self.arena.capacity(ptr)? - U64_SIZE {
+            return Err(FsError::InvalidState(format!("Meta record at {} claims {} bytes, above its allocation", ptr, len)));
+        }
+        let mut bytes = vec![0u8; len as usize];
+        self.arena.read(ptr, U64_SIZE, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn directory_ptr(&self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.storage.read(self.zone_idx, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use crate::constants::*;
+    use crate::error::FsError;
+    use crate::layout::LayoutConfig;
+    use crate::meta::MetaStore;
+    use crate::storage::{FnStorage, Storage};
+
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; IDX_ZONE_IDX as usize]);
+    }
+
+    fn write(offset: u64, data: &[u8]) {
+        MEMORY.with(|v| v.borrow_mut()[offset as usize..offset as usize + data.len()].copy_from_slice(data));
+    }
+
+    fn read(offset: u64, data: &mut [u8]) {
+        MEMORY.with(|v| data.copy_from_slice(&v.borrow()[offset as usize..offset as usize + data.len()]));
+    }
+
+    #[test]
+    fn it_puts_and_gets_records() {
+        let store = MetaStore::open(&LayoutConfig::default(), &Storage::new(FnStorage::new(write, read))).unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+
+        store.put("a", b"first").unwrap();
+        store.put("b", &[7u8; 300]).unwrap();
+        store.put("a", &[1u8; 1000]).unwrap();
+
+        assert_eq!(store.get("a").unwrap(), Some(vec![1u8; 1000]));
+        assert_eq!(store.get("b").unwrap(), Some(vec![7u8; 300]));
+    }
+
+    #[test]
+    fn it_reloads_directory_on_open() {
+        let store = MetaStore::open(&LayoutConfig::default(), &Storage::new(FnStorage::new(write, read))).unwrap();
+        store.put_value("height", &42u64).unwrap();
+        store.put_value("name", &"orders".to_string()).unwrap();
+
+        let store = MetaStore::open(&LayoutConfig::default(), &Storage::new(FnStorage::new(write, read))).unwrap();
+        assert_eq!(store.get_value::<u64>("height").unwrap(), Some(42));
+        assert_eq!(store.get_value::<String>("name").unwrap(), Some("orders".to_string()));
+    }
+
+    #[test]
+    fn it_keeps_the_previous_record_when_a_put_fails() {
+        let storage = Storage::new(FnStorage::new(write, read));
+        let store = MetaStore::open_in(0, 400, &storage).unwrap();
+        store.put("a", &[1u8; 40]).unwrap();
+        store.put("b", &[2u8; 40]).unwrap();
+        assert!(matches!(store.put("a", &[3u8; 200]), Err(FsError::OutOfSpace(_))));
+        // The record fits, the directory listing its long name doesn't.
+        let long_name = "c".repeat(150);
+        assert!(matches!(store.put(&long_name, &[4u8; 8]), Err(FsError::OutOfSpace(_))));
+        assert_eq!(store.get("a").unwrap(), Some(vec![1u8; 40]));
+        assert_eq!(store.names(), vec!["a", "b"]);
+        store.put("d", &[5u8; 8]).unwrap();
+
+        let store = MetaStore::open_in(0, 400, &storage).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(vec![1u8; 40]));
+        assert_eq!(store.names(), vec!["a", "b", "d"]);
+    }
+}
@@ -0,0 +1,194 @@
+#[cfg(feature = "metrics")]
+use std::fmt::Write;
+
+use serde::Serialize;
+
+#[cfg(feature = "metrics")]
+const PREFIX: &str = "ic_event_fs";
+// Bucket 0 holds zero, bucket i holds counts below 2^i.
+const HISTOGRAM_BUCKETS: usize = 65;
+
+// Lifetime counters since the filesystem was opened, they restart from zero after an upgrade.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Counters {
+    pub(crate) bytes_written: u64,
+    pub(crate) errors: u64,
+    pub(crate) alarm_breaches: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Operation {
+    Write,
+    Read,
+    ReadRange,
+}
+
+#[cfg(feature = "metrics")]
+impl Operation {
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            Operation::Write => "write",
+            Operation::Read => "read",
+            Operation::ReadRange => "read_range",
+        }
+    }
+}
+
+// Instructions spent per call in power of two buckets, so it stays the same size however many calls
+// it records. Percentiles are the upper bound of the bucket they fall in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InstructionHistogram {
+    #[serde(serialize_with = "serialize_buckets")]
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl Default for InstructionHistogram {
+    fn default() -> Self {
+        InstructionHistogram { buckets: [0; HISTOGRAM_BUCKETS], count: 0, sum: 0 }
+    }
+}
+
+impl InstructionHistogram {
+    pub(crate) fn record(&mut self, instructions: u64) {
+        self.buckets[(u64::BITS - instructions.leading_zeros()) as usize] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(instructions);
+    }
+
+    // `percentile` is between 0 and 100, e.g. 99 for p99. None until something was recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        self.cumulative().find(|(_, count)| *count >= rank).map(|(upper_bound, _)| upper_bound)
+    }
+
+    // Inclusive upper bound and cumulative count of every bucket up to the last non-empty one.
+    pub fn cumulative(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let used = self.buckets.iter().rposition(|count| *count > 0).map_or(0, |last| last + 1);
+        self.buckets[..used].iter().enumerate().scan(0, |total, (i, count)| {
+            *total += count;
+            Some((if i == 0 { 0 } else { u64::MAX >> (u64::BITS as usize - i) }, *total))
+        })
+    }
+}
+
+fn serialize_buckets<S: serde::Serializer>(buckets: &[u64; HISTOGRAM_BUCKETS], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(buckets.iter())
+}
+
+// Renders samples in the Prometheus text exposition format, every sample labelled with the topic.
+#[cfg(feature = "metrics")]
+pub(crate) struct MetricsText {
+    topic: String,
+    out: String,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsText {
+    pub(crate) fn new(topic: &str) -> Self {
+        MetricsText {
+            topic: escape_label(topic),
+            out: String::new(),
+        }
+    }
+
+    pub(crate) fn gauge(&mut self, name: &str, help: &str, value: u64) -> &mut Self {
+        self.sample(name, "gauge", help, value)
+    }
+
+    pub(crate) fn counter(&mut self, name: &str, help: &str, value: u64) -> &mut Self {
+        self.sample(&format!("{}_total", name), "counter", help, value)
+    }
+
+    pub(crate) fn histogram(&mut self, name: &str, help: &str, histograms: &[(Operation, InstructionHistogram)]) -> &mut Self {
+        if histograms.is_empty() {
+            return self;
+        }
+        let _ = writeln!(self.out, "# HELP {}_{} {}", PREFIX, name, help);
+        let _ = writeln!(self.out, "# TYPE {}_{} histogram", PREFIX, name);
+        for (operation, histogram) in histograms {
+            let labels = format!("topic=\"{}\",operation=\"{}\"", self.topic, operation.label());
+            for (upper_bound, count) in histogram.cumulative() {
+                let _ = writeln!(self.out, "{}_{}_bucket{{{},le=\"{}\"}} {}", PREFIX, name, labels, upper_bound, count);
+            }
+            let _ = writeln!(self.out, "{}_{}_bucket{{{},le=\"+Inf\"}} {}", PREFIX, name, labels, histogram.count);
+            let _ = writeln!(self.out, "{}_{}_sum{{{}}} {}", PREFIX, name, labels, histogram.sum);
+            let _ = writeln!(self.out, "{}_{}_count{{{}}} {}", PREFIX, name, labels, histogram.count);
+        }
+        self
+    }
+
+    pub(crate) fn finish(&mut self) -> String {
+        std::mem::take(&mut self.out)
+    }
+
+    fn sample(&mut self, name: &str, kind: &str, help: &str, value: u64) -> &mut Self {
+        let _ = writeln!(self.out, "# HELP {}_{} {}", PREFIX, name, help);
+        let _ = writeln!(self.out, "# TYPE {}_{} {}", PREFIX, name, kind);
+        let _ = writeln!(self.out, "{}_{}{{topic=\"{}\"}} {}", PREFIX, name, self.topic, value);
+        self
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::InstructionHistogram;
+    #[cfg(feature = "metrics")]
+    use crate::metrics::{MetricsText, Operation};
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn it_renders_exposition_format() {
+        let text = MetricsText::new("a \"quoted\"\ntopic")
+            .gauge("topic_height", "Messages in the topic.", 3)
+            .counter("errors", "Failed operations.", 1)
+            .finish();
+        assert_eq!(text, concat!(
+            "# HELP ic_event_fs_topic_height Messages in the topic.\n",
+            "# TYPE ic_event_fs_topic_height gauge\n",
+            "ic_event_fs_topic_height{topic=\"a \\\"quoted\\\"\\ntopic\"} 3\n",
+            "# HELP ic_event_fs_errors_total Failed operations.\n",
+            "# TYPE ic_event_fs_errors_total counter\n",
+            "ic_event_fs_errors_total{topic=\"a \\\"quoted\\\"\\ntopic\"} 1\n",
+        ));
+    }
+
+    #[test]
+    fn it_buckets_instructions_by_powers_of_two() {
+        let mut histogram = InstructionHistogram::default();
+        assert_eq!(histogram.percentile(99.0), None);
+        for instructions in [0, 1, 5, 6, 7, 100, 100, 100, 100, 5000] {
+            histogram.record(instructions);
+        }
+        assert_eq!(histogram.percentile(10.0), Some(0));
+        assert_eq!(histogram.percentile(50.0), Some(7));
+        assert_eq!(histogram.percentile(90.0), Some(127));
+        assert_eq!(histogram.percentile(99.0), Some(8191));
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.percentile(100.0), Some(u64::MAX));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn it_renders_histograms() {
+        let mut small = InstructionHistogram::default();
+        small.record(3);
+        let text = MetricsText::new("t").histogram("instructions", "Instructions per call.", &[(Operation::Write, small)]).finish();
+        assert_eq!(text, concat!(
+            "# HELP ic_event_fs_instructions Instructions per call.\n",
+            "# TYPE ic_event_fs_instructions histogram\n",
+            "ic_event_fs_instructions_bucket{topic=\"t\",operation=\"write\",le=\"0\"} 0\n",
+            "ic_event_fs_instructions_bucket{topic=\"t\",operation=\"write\",le=\"1\"} 0\n",
+            "ic_event_fs_instructions_bucket{topic=\"t\",operation=\"write\",le=\"3\"} 1\n",
+            "ic_event_fs_instructions_bucket{topic=\"t\",operation=\"write\",le=\"+Inf\"} 1\n",
+            "ic_event_fs_instructions_sum{topic=\"t\",operation=\"write\"} 3\n",
+            "ic_event_fs_instructions_count{topic=\"t\",operation=\"write\"} 1\n",
+        ));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::budget::InstructionBudget;
+use crate::error::FsError;
+use crate::export::IndexExportEntry;
+use crate::index_block::IndexBlock;
+use crate::layout::LayoutConfig;
+use crate::read_write::{MemoryReader, write_idx};
+use crate::storage::Storage;
+
+const MOVE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Progress of growing the index zone of an existing topic, kept in the meta zone between calls.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexGrowth {
+    pub index_zone_size: u64,
+    // Bytes at the start of the data zone that haven't moved yet.
+    pub remaining: u64,
+}
+
+impl IndexGrowth {
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+// Moves the data zone up by `shift` bytes, last chunk first so nothing is overwritten before it has
+// been copied. Always moves at least one chunk so every call makes progress.
+pub(crate) fn move_data_zone(growth: &mut IndexGrowth,
+                             data_zone_start: u64,
+                             shift: u64,
+                             budget: &InstructionBudget,
+                             storage: &Storage,
+) {
+    let mut buf = Vec::new();
+    while growth.remaining > 0 {
+        let chunk = growth.remaining.min(MOVE_CHUNK_SIZE);
+        let offset = data_zone_start + growth.remaining - chunk;
+        buf.resize(chunk as usize, 0);
+        storage.read(offset, &mut buf);
+        storage.write(offset + shift, &buf);
+        growth.remaining -= chunk;
+        if budget.is_exhausted() {
+            break;
+        }
+    }
+}
+
+// Progress of rewriting a topic without the messages a filter drops, kept in the meta zone between
+// calls. Kept messages are renumbered from zero in their original order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compaction {
+    pub height: u64,
+    // Messages looked at so far, everything below has been rewritten.
+    pub scanned: u64,
+    pub kept: u64,
+    data_blocks: u64,
+    // Latest marker of each kind before the rewrite, and those that were kept by their new height.
+    old_markers: BTreeMap<String, u64>,
+    markers: BTreeMap<String, u64>,
+    // Subscriber offsets, cursor positions and bookmarks before the rewrite, and the height each maps to.
+    old_positions: BTreeSet<u64>,
+    positions: BTreeMap<u64, u64>,
+    // Index and data bytes freed, set once the compaction is done. Not persisted, it is only known then.
+    #[serde(skip)]
+    pub reclaimed_bytes: u64,
+}
+
+impl Compaction {
+    pub(crate) fn new(height: u64, markers: BTreeMap<String, u64>, positions: BTreeSet<u64>) -> Self {
+        Compaction {
+            height,
+            scanned: 0,
+            kept: 0,
+            data_blocks: 0,
+            old_markers: markers,
+            markers: BTreeMap::new(),
+            old_positions: positions,
+            positions: BTreeMap::new(),
+            reclaimed_bytes: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.scanned == self.height
+    }
+
+    pub(crate) fn data_blocks(&self) -> u64 {
+        self.data_blocks
+    }
+
+    pub(crate) fn markers(&self) -> &BTreeMap<String, u64> {
+        &self.markers
+    }
+
+    // Where a position taken when the compaction started points once it is done: the next kept message
+    // at or after it. Positions at the old height move to the new one.
+    pub(crate) fn remap(&self, position: u64) -> u64 {
+        self.positions.get(&position).copied().unwrap_or(self.kept)
+    }
+}
+
+// Progress of routing a topic's messages into two new topics, kept in the source's meta zone and saved
+// with every routed message. Only the messages committed when the split started are routed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicSplit {
+    pub height: u64,
+    pub scanned: u64,
+    pub matched: u64,
+    // Source heights each destination has everything below of. Markers go to both, one that reached
+    // only the first isn't written to it again.
+    pub matching_scanned: u64,
+    pub rest_scanned: u64,
+    // Names of the destinations, a resume has to be into the same topics.
+    pub matching: String,
+    pub rest: String,
+}
+
+impl TopicSplit {
+    pub(crate) fn new(height: u64, matching: String, rest: String) -> Self {
+        TopicSplit { height, scanned: 0, matched: 0, matching_scanned: 0, rest_scanned: 0, matching, rest }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.scanned == self.height
+    }
+}
+
+// Kept messages only ever move down, each payload is read in full before it is written so overlapping
+// moves are safe. Always looks at one message at least so every call makes progress.
+pub(crate) fn compact(compaction: &mut Compaction,
+                      keep: impl Fn(&IndexExportEntry, &[u8]) -> bool,
+                      reader: &MemoryReader,
+                      layout: &LayoutConfig,
+                      budget: &InstructionBudget,
+                      storage: &Storage,
+) -> Result<(), FsError> {
+    while !compaction.is_done() {
+        let height = compaction.scanned;
+        if compaction.old_positions.contains(&height) {
+            compaction.positions.insert(height, compaction.kept);
+        }
+        let idx = reader.read_idx(height, storage)?;
+        let payload = reader.read_raw_payload(height, storage)?;
+        if keep(&IndexExportEntry::from(idx), &reader.decode_payload(height, payload.clone())?) {
+            let blocks = layout.block_count(idx.data_size);
+            let moved = IndexBlock {
+                height: compaction.kept,
+                data_size: idx.data_size,
+                start_idx: compaction.data_blocks,
+                end_idx: compaction.data_blocks + blocks,
+                timestamp: idx.timestamp,
+                flags: idx.flags,
+            };
+            if !payload.is_empty() {
+                storage.write(layout.data_block_offset(moved.start_idx), &payload);
+            }
+            write_idx(&moved, layout, storage)?;
+            reader.invalidate_index(moved.height);
+            for (kind, _) in compaction.old_markers.iter().filter(|(_, marker)| **marker == height) {
+                compaction.markers.insert(kind.clone(), moved.height);
+            }
+            compaction.kept += 1;
+            compaction.data_blocks += blocks;
+        }
+        compaction.scanned += 1;
+        if budget.is_exhausted() {
+            break;
+        }
+    }
+    Ok(())
+}
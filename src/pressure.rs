@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use crate::alarms::AlarmKind;
+use crate::error::FsError;
+use crate::health::ZoneUsage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PressureLevel {
+    Ok,
+    // Writes still succeed, producers should send smaller batches or slow down.
+    Soft,
+    // Writes fail or are about to, producers should hold back until the level drops.
+    Hard,
+}
+
+// Shares of a bounded resource, from 0 to 1, at which pressure() turns Soft and Hard. Alarm thresholds
+// count as the capacity of their window.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Watermarks {
+    pub soft: f64,
+    pub hard: f64,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Watermarks { soft: 0.8, hard: 0.95 }
+    }
+}
+
+impl Watermarks {
+    pub fn validate(&self) -> Result<(), FsError> {
+        if !(0.0..=1.0).contains(&self.soft) || !(self.soft..=1.0).contains(&self.hard) {
+            return Err(FsError::InvalidArgument(format!("Watermarks need 0 <= soft <= hard <= 1, got {} and {}", self.soft, self.hard)));
+        }
+        Ok(())
+    }
+
+    fn level(&self, utilization: f64) -> PressureLevel {
+        if utilization >= self.hard {
+            PressureLevel::Hard
+        } else if utilization >= self.soft {
+            PressureLevel::Soft
+        } else {
+            PressureLevel::Ok
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PressureCause {
+    IndexSlots(ZoneUsage),
+    Meta(ZoneUsage),
+    // Activity in the current alarm window against the alarm's threshold.
+    Alarm { kind: AlarmKind, observed: u64, threshold: u64 },
+    // A growth, compaction, truncation or import that rejects writes until it finishes.
+    Blocked(String),
+}
+
+// Outcome of EventFilesystem::pressure, `level` is the highest level of its causes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Pressure {
+    pub level: PressureLevel,
+    pub causes: Vec<(PressureLevel, PressureCause)>,
+}
+
+impl Pressure {
+    pub(crate) fn new(watermarks: &Watermarks, zones: impl IntoIterator<Item = PressureCause>) -> Self {
+        let causes: Vec<_> = zones.into_iter()
+            .map(|cause| (cause_level(watermarks, &cause), cause))
+            .filter(|(level, _)| *level != PressureLevel::Ok)
+            .collect();
+        let level = causes.iter().map(|(level, _)| *level).max().unwrap_or(PressureLevel::Ok);
+        Pressure { level, causes }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.level == PressureLevel::Ok
+    }
+}
+
+fn cause_level(watermarks: &Watermarks, cause: &PressureCause) -> PressureLevel {
+    match cause {
+        PressureCause::IndexSlots(usage) | PressureCause::Meta(usage) => watermarks.level(usage.utilization()),
+        PressureCause::Alarm { observed, threshold, .. } => match threshold {
+            0 => watermarks.level(if *observed == 0 { 0.0 } else { 1.0 }),
+            threshold => watermarks.level(*observed as f64 / *threshold as f64),
+        },
+        PressureCause::Blocked(_) => PressureLevel::Hard,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::alarms::AlarmKind;
+    use crate::health::ZoneUsage;
+    use crate::pressure::{Pressure, PressureCause, PressureLevel, Watermarks};
+
+    #[test]
+    fn it_takes_the_highest_level_of_its_causes() {
+        let watermarks = Watermarks::default();
+        let ok = Pressure::new(&watermarks, [PressureCause::IndexSlots(ZoneUsage { used: 10, capacity: 100 })]);
+        assert!(ok.is_ok() && ok.causes.is_empty());
+
+        let pressure = Pressure::new(&watermarks, [
+            PressureCause::IndexSlots(ZoneUsage { used: 85, capacity: 100 }),
+            PressureCause::Alarm { kind: AlarmKind::BytesPerMinute, observed: 990, threshold: 1000 },
+            PressureCause::Meta(ZoneUsage { used: 0, capacity: 0 }),
+        ]);
+        assert_eq!(pressure.level, PressureLevel::Hard);
+        assert_eq!(pressure.causes.iter().map(|(level, _)| *level).collect::<Vec<_>>(), vec![PressureLevel::Soft, PressureLevel::Hard]);
+
+        assert!(Watermarks { soft: 0.9, hard: 0.5 }.validate().is_err());
+        assert!(Watermarks { soft: 0.5, hard: 1.1 }.validate().is_err());
+        assert!(Watermarks::default().validate().is_ok());
+    }
+}
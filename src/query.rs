@@ -0,0 +1,126 @@
+use std::ops::Range;
+
+use serde_json::Value;
+
+use crate::filter::MessageFilter;
+use crate::times::TimeDomain;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueryOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+// How EventFilesystem::query finds its candidates. Every plan still checks all conditions on each
+// candidate, the plan only decides how many it looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryPlan {
+    // The heights recorded under the key.
+    KeyIndex,
+    // The heights between two binary searches over ingestion times.
+    TimeSeek,
+    // Every height in the height range.
+    Scan,
+}
+
+// Conditions messages have to meet, all of them. Kinds, tags and producers are matched against the
+// `kind`, `tags` and `producer` fields of the message, see MessageFilter for anything else. Markers
+// never match.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Query {
+    pub(crate) heights: Option<Range<u64>>,
+    pub(crate) time: Option<(TimeDomain, Range<u64>)>,
+    pub(crate) key: Option<Vec<u8>>,
+    pub(crate) filter: Option<MessageFilter>,
+    pub(crate) limit: Option<u64>,
+    pub(crate) order: QueryOrder,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Query::default()
+    }
+
+    pub fn heights(mut self, heights: Range<u64>) -> Self {
+        self.heights = Some(heights);
+        self
+    }
+
+    pub fn time(mut self, domain: TimeDomain, range: Range<u64>) -> Self {
+        self.time = Some((domain, range));
+        self
+    }
+
+    // Messages written with write_topic_message_keyed under `key`, needs the key index.
+    pub fn key(mut self, key: &[u8]) -> Self {
+        self.key = Some(key.to_vec());
+        self
+    }
+
+    // Any of the kinds.
+    pub fn kinds<'k>(self, kinds: impl IntoIterator<Item = &'k str>) -> Self {
+        let any = kinds.into_iter()
+            .map(|kind| MessageFilter::Eq(vec!["kind".to_string()], Value::from(kind)))
+            .reduce(|a, b| MessageFilter::Or(Box::new(a), Box::new(b)));
+        self.filter(any.unwrap_or(MessageFilter::Not(Box::new(MessageFilter::All))))
+    }
+
+    pub fn tag(self, tag: &str) -> Self {
+        self.filter(MessageFilter::Contains(vec!["tags".to_string()], Value::from(tag)))
+    }
+
+    pub fn producer(self, producer: &str) -> Self {
+        self.filter(MessageFilter::Eq(vec!["producer".to_string()], Value::from(producer)))
+    }
+
+    // Combined with the filters given before.
+    pub fn filter(mut self, filter: MessageFilter) -> Self {
+        self.filter = Some(match self.filter.take() {
+            Some(existing) => MessageFilter::And(Box::new(existing), Box::new(filter)),
+            None => filter,
+        });
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn order(mut self, order: QueryOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    // The key index narrows a query down the most, ingestion time seeks come next.
+    pub fn plan(&self) -> QueryPlan {
+        match (&self.key, &self.time) {
+            (Some(_), _) => QueryPlan::KeyIndex,
+            (None, Some((TimeDomain::Ingestion, _))) => QueryPlan::TimeSeek,
+            _ => QueryPlan::Scan,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::query::{Query, QueryPlan};
+    use crate::times::TimeDomain;
+
+    #[test]
+    fn it_plans_by_the_narrowest_index() {
+        assert_eq!(Query::new().heights(0..10).plan(), QueryPlan::Scan);
+        assert_eq!(Query::new().time(TimeDomain::Event, 0..10).plan(), QueryPlan::Scan);
+        assert_eq!(Query::new().time(TimeDomain::Ingestion, 0..10).plan(), QueryPlan::TimeSeek);
+        assert_eq!(Query::new().time(TimeDomain::Ingestion, 0..10).key(b"a").plan(), QueryPlan::KeyIndex);
+
+        let filter = Query::new().kinds(["paid", "refunded"]).tag("vip").filter.unwrap();
+        assert!(filter.matches_value(&json!({"kind": "refunded", "tags": ["vip"]})));
+        assert!(!filter.matches_value(&json!({"kind": "created", "tags": ["vip"]})));
+        assert!(!filter.matches_value(&json!({"kind": "paid", "tags": []})));
+        assert!(!Query::new().kinds([]).filter.unwrap().matches_value(&json!({"kind": "paid"})));
+    }
+}
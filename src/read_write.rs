@@ -1,34 +1,63 @@
-use std::fs::read;
-use std::io::{Read, Write};
-use log::{debug};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use candid::CandidType;
 use serde::de::DeserializeOwned;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{BLOCK_SIZE, IDX_BLOCK_SIZE, IDX_ZONE_END, IDX_ZONE_IDX};
+use crate::{BlockStorage, EventFilesystem, FnStorage, IDX_BLOCK_SIZE};
+use crate::budget::InstructionBudget;
+use crate::codec::BincodeCodec;
+use crate::compression::{CompressionDictionary, decompress, MAX_COMPRESSION_HEADER_SIZE, PayloadCompression};
+use crate::error::FsError;
+use crate::hash::crc32;
+use crate::ids::{IdGenerator, ulid_id};
 use crate::index_block::IndexBlock;
-use crate::topic_message::TopicMessage;
+use crate::layout::LayoutConfig;
+use crate::settings::SettingsHistory;
+use crate::storage::Storage;
 
 pub type BlockWrite = fn(offset: u64, data: &[u8]) -> ();
 
 pub type BlockRead = fn(offset: u64, buf: &mut [u8]) -> ();
 
+// Grows the memory by `pages` and returns its previous size in pages, like stable64_grow.
+pub type BlockGrow = fn(pages: u64) -> Result<u64, String>;
+
 pub struct MemoryWriter {
     index_block_offset: u64,
     data_block_offset: u64,
     clock: fn() -> u64,
+    // Serialization buffer reused across writes, it only grows when a larger message arrives.
+    scratch: Vec<u8>,
+    alloc_stats: AllocStats,
+    codec: BincodeCodec,
+    layout: LayoutConfig,
+    event_times: bool,
+    ids: bool,
+    id_generator: IdGenerator,
+    // The last id handed out, later ones are kept above it.
+    last_id: u128,
+    compression: PayloadCompression,
+    // The dictionary zstd compresses with, the newest the topic holds.
+    dictionary: Option<CompressionDictionary>,
+    checksums: bool,
 }
 
-fn get_block_count(data_size : u64) -> u64 {
-    let mut blocks = data_size / BLOCK_SIZE;
-    if data_size % BLOCK_SIZE != 0 {
-        blocks += 1;
-    }
-    return blocks;
-}
-
-fn get_data_offset_from_height(height: u64) -> u64 {
-    return IDX_ZONE_END + (height * BLOCK_SIZE);
+// Payloads written while event times are enabled start with the event time, markers stay empty.
+const EVENT_TIME_SIZE: usize = 8;
+// Then, while message ids are enabled, the message id.
+const ID_SIZE: usize = 16;
+// Then, while checksums are enabled, the CRC32 of everything else the payload holds.
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct AllocStats {
+    pub writes: u64,
+    pub scratch_grows: u64,
+    pub scratch_capacity: u64,
 }
 
 impl MemoryWriter
@@ -38,45 +67,223 @@ impl MemoryWriter
             index_block_offset,
             data_block_offset,
             clock,
+            scratch: Vec::new(),
+            alloc_stats: AllocStats::default(),
+            codec: BincodeCodec::default(),
+            layout: LayoutConfig::default(),
+            event_times: false,
+            ids: false,
+            id_generator: ulid_id,
+            last_id: 0,
+            compression: PayloadCompression::None,
+            dictionary: None,
+            checksums: false,
         }
     }
 
-    pub fn write<S: Serialize>(&mut self, value: &S, writer: BlockWrite) -> Result<IndexBlock, String> {
-        let bytes = bincode::serialize(value).map_err(|e| format!("Failed to serialize: {}", e))?;
+    pub(crate) fn set_event_times(&mut self, enabled: bool) {
+        self.event_times = enabled;
+    }
+
+    // `last_id` is the id of the latest message that has one.
+    pub(crate) fn set_ids(&mut self, enabled: bool, last_id: u128) {
+        self.ids = enabled;
+        self.last_id = last_id;
+    }
+
+    pub(crate) fn set_id_generator(&mut self, generator: IdGenerator) {
+        self.id_generator = generator;
+    }
+
+    pub fn set_codec(&mut self, codec: BincodeCodec) {
+        self.codec = codec;
+    }
+
+    pub(crate) fn set_compression(&mut self, compression: PayloadCompression) {
+        self.compression = compression;
+    }
+
+    pub(crate) fn set_dictionary(&mut self, dictionary: Option<CompressionDictionary>) {
+        self.dictionary = dictionary;
+    }
+
+    pub(crate) fn set_checksums(&mut self, enabled: bool) {
+        self.checksums = enabled;
+    }
+
+    pub fn set_layout(&mut self, layout: LayoutConfig) {
+        self.layout = layout;
+    }
+
+    // Continues appending from these offsets, for rewrites that moved the end of the topic.
+    pub(crate) fn set_offsets(&mut self, index_block_offset: u64, data_block_offset: u64) {
+        self.index_block_offset = index_block_offset;
+        self.data_block_offset = data_block_offset;
+    }
+
+    #[cfg(test)]
+    pub fn write<S: Serialize>(&mut self, value: &S, storage: &Storage) -> Result<IndexBlock, FsError> {
+        let codec = self.codec;
+        self.write_with(|buf| codec.serialize_into(buf, value), None, storage, |_| Ok(()))
+    }
+
+    // A payload that was serialized elsewhere, e.g. by the topic an import comes from.
+    #[cfg(feature = "jobs")]
+    pub(crate) fn write_payload(&mut self, payload: &[u8], storage: &Storage) -> Result<IndexBlock, FsError> {
+        if payload.len() as u64 > self.codec.size_limit {
+            return Err(FsError::MessageTooLarge { size: payload.len() as u64, limit: self.codec.size_limit });
+        }
+        let serialize = |buf: &mut Vec<u8>| {
+            buf.extend_from_slice(payload);
+            Ok(())
+        };
+        self.write_with(serialize, None, storage, |_| Ok(()))
+    }
+
+    // `check` sees the serialized payload before anything is written and can refuse it. The event time
+    // defaults to the write timestamp and can only be given when event times are enabled.
+    pub(crate) fn write_with(&mut self, serialize: impl FnOnce(&mut Vec<u8>) -> Result<(), FsError>, event_time: Option<u64>, storage: &Storage, check: impl FnOnce(&[u8]) -> Result<(), FsError>) -> Result<IndexBlock, FsError> {
+        if self.index_block_offset >= self.layout.max_index_entries() {
+            return Err(FsError::IndexZoneFull { capacity: self.layout.max_index_entries() });
+        }
+        if event_time.is_some() && !self.event_times {
+            return Err(FsError::InvalidState("Event times are not enabled for this topic".to_string()));
+        }
+        let timestamp = (self.clock)();
+        let capacity = self.scratch.capacity();
+        self.scratch.clear();
+        let time_size = if self.event_times { EVENT_TIME_SIZE } else { 0 };
+        let envelope = time_size + if self.ids { ID_SIZE } else { 0 };
+        self.scratch.resize(envelope, 0);
+        serialize(&mut self.scratch)?;
+        let mut id = None;
+        if self.scratch.len() == envelope {
+            self.scratch.clear();
+        } else {
+            self.scratch[..time_size].copy_from_slice(&event_time.unwrap_or(timestamp).to_le_bytes()[..time_size]);
+            if self.ids {
+                // ids stay strictly increasing even if the clock goes back
+                let next = (self.id_generator)(timestamp, self.index_block_offset).max(self.last_id.saturating_add(1));
+                self.scratch[time_size..envelope].copy_from_slice(&next.to_le_bytes());
+                id = Some(next);
+            }
+        }
+        if self.scratch.capacity() != capacity {
+            self.alloc_stats.scratch_grows += 1;
+            self.alloc_stats.scratch_capacity = self.scratch.capacity() as u64;
+        }
+        self.alloc_stats.writes += 1;
+        check(&self.scratch[envelope.min(self.scratch.len())..])?;
+        let mut flags = IndexBlock::COMMITTED;
+        if self.compression != PayloadCompression::None && !self.scratch.is_empty() {
+            let payload = self.scratch.split_off(envelope);
+            self.compression.compress_into(&payload, &mut self.scratch, self.dictionary.as_ref())?;
+            flags |= IndexBlock::COMPRESSED;
+        }
+        if self.checksums && !self.scratch.is_empty() {
+            let checksum = crc32(&[&self.scratch])?;
+            self.scratch.splice(envelope..envelope, checksum.to_le_bytes());
+        }
+        let bytes = &self.scratch;
+
+        // Most events fit in one block, they skip the block count math and are written together with
+        // their index entry.
+        let single_block = !bytes.is_empty() && bytes.len() as u64 <= self.layout.block_size;
 
         // Calculate how many whole blocks we need to fill
-        let mut blocks = get_block_count(bytes.len() as u64);
+        let blocks = match single_block {
+            true => 1,
+            false => self.layout.block_count(bytes.len() as u64),
+        };
 
         let idx = IndexBlock {
             height: self.index_block_offset,
             data_size: bytes.len() as u64,
             start_idx: self.data_block_offset,
-            end_idx: self.data_block_offset + blocks as u64,
-            timestamp: (self.clock)(),
+            end_idx: self.data_block_offset + blocks,
+            timestamp,
+            flags,
         };
 
-        // record index block
-        self.write_idx(&idx, writer);
+        // grow the memory before anything is written so a failure leaves the topic as it was
+        let end = match bytes.is_empty() {
+            true => self.layout.index_entry_offset(idx.height) + self.layout.index_entry_size(),
+            false => self.layout.data_block_offset(self.data_block_offset) + bytes.len() as u64,
+        };
+        storage.grow(end)?;
+
+        if single_block {
+            let offset = self.layout.data_block_offset(self.data_block_offset);
+            storage.try_write_all(&[(self.layout.index_entry_offset(idx.height), &idx.to_bytes()[..self.layout.index_entry_size() as usize]), (offset, bytes)])?;
+        } else {
+            // record index block
+            write_idx(&idx, &self.layout, storage)?;
+        }
 
-        // write data
-        let offset = IDX_ZONE_END + (self.data_block_offset * BLOCK_SIZE);
-        debug!("Writing data at offset {} for idx {:?}", offset, idx);
-        writer(offset, &bytes);
+        // write data, markers (empty payloads) are index only and never touch the data zone
+        if !single_block && !bytes.is_empty() {
+            let offset = self.layout.data_block_offset(self.data_block_offset);
+            debug!("Writing data at offset {} for idx {:?}", offset, idx);
+            storage.try_write(offset, bytes)?;
+        }
 
         // move offset
-        self.data_block_offset += blocks as u64;
+        self.data_block_offset += blocks;
         self.index_block_offset += 1;
+        if let Some(id) = id {
+            self.last_id = id;
+        }
 
         Ok(idx)
     }
 
-    fn write_idx(&mut self, idx: &IndexBlock, writer: BlockWrite) -> Result<(), String> {
-        let bytes = bincode::serialize(idx).map_err(|e| format!("Failed to serialize: {}", e))?;
-        // Move to index region, move over number of blocks
-        let mut offset = IDX_ZONE_IDX + (idx.height * IDX_BLOCK_SIZE);
-        debug!("Writing index block: {:?} offset {}", idx, offset);
-        writer(offset, &bytes);
-        Ok(())
+    // The index entry of a message streamed in chunks, without its payload yet, and the envelope that
+    // goes in front of the payload. Streamed payloads can't be compressed or checksummed.
+    pub(crate) fn begin_stream(&mut self) -> Result<(IndexBlock, Vec<u8>, Option<u128>), FsError> {
+        if self.index_block_offset >= self.layout.max_index_entries() {
+            return Err(FsError::IndexZoneFull { capacity: self.layout.max_index_entries() });
+        }
+        if self.compression != PayloadCompression::None || self.checksums {
+            return Err(FsError::Unsupported("Streamed messages can't be compressed or checksummed".to_string()));
+        }
+        let timestamp = (self.clock)();
+        let mut envelope = Vec::new();
+        if self.event_times {
+            envelope.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        let mut id = None;
+        if self.ids {
+            let next = (self.id_generator)(timestamp, self.index_block_offset).max(self.last_id.saturating_add(1));
+            envelope.extend_from_slice(&next.to_le_bytes());
+            id = Some(next);
+        }
+        let idx = IndexBlock {
+            height: self.index_block_offset,
+            data_size: 0,
+            start_idx: self.data_block_offset,
+            end_idx: self.data_block_offset,
+            timestamp,
+            flags: IndexBlock::COMMITTED,
+        };
+        Ok((idx, envelope, id))
+    }
+
+    // Records the index entry of a streamed message whose payload is in place and moves past it.
+    pub(crate) fn finish_stream(&mut self, mut idx: IndexBlock, id: Option<u128>, storage: &Storage) -> Result<IndexBlock, FsError> {
+        idx.end_idx = idx.start_idx + self.layout.block_count(idx.data_size);
+        storage.grow(self.layout.index_entry_offset(idx.height) + self.layout.index_entry_size())?;
+        write_idx(&idx, &self.layout, storage)?;
+        self.data_block_offset = idx.end_idx;
+        self.index_block_offset += 1;
+        if let Some(id) = id {
+            self.last_id = id;
+        }
+        self.alloc_stats.writes += 1;
+        Ok(idx)
+    }
+
+    pub fn alloc_stats(&self) -> AllocStats {
+        self.alloc_stats
     }
 
     pub fn data_block_offset(&self) -> u64 {
@@ -88,20 +295,289 @@ impl MemoryWriter
     }
 }
 
-pub struct MemoryReader;
+// A message written in chunks straight into consecutive data blocks, for payloads too large to hold
+// in memory at once. The bytes are stored as they are, like EventFilesystem::write_raw. Other writes
+// are refused until it is finished or dropped, a dropped message leaves nothing committed.
+pub struct MessageWriter<'a, S: BlockStorage = FnStorage> {
+    fs: &'a EventFilesystem<S>,
+    idx: IndexBlock,
+    envelope: u64,
+    id: Option<u128>,
+}
+
+impl<'a, S: BlockStorage> MessageWriter<'a, S> {
+    pub(crate) fn new(fs: &'a EventFilesystem<S>, idx: IndexBlock, envelope: &[u8], id: Option<u128>) -> Result<Self, FsError> {
+        fs.streaming.set(true);
+        let mut message = MessageWriter { fs, idx, envelope: envelope.len() as u64, id };
+        message.write(envelope)?;
+        Ok(message)
+    }
+
+    pub fn height(&self) -> u64 {
+        self.idx.height
+    }
+
+    // Payload bytes appended so far.
+    pub fn size(&self) -> u64 {
+        self.idx.data_size - self.envelope
+    }
+
+    pub fn append(&mut self, bytes: &[u8]) -> Result<(), FsError> {
+        let limit = self.fs.codecs.current().size_limit;
+        let size = self.size() + bytes.len() as u64;
+        if size > limit {
+            return Err(FsError::MessageTooLarge { size, limit });
+        }
+        self.write(bytes)
+    }
+
+    // Commits the message and returns its height. A message nothing was appended to is stored like a
+    // marker.
+    pub fn finish(mut self) -> Result<u64, FsError> {
+        if self.size() == 0 {
+            self.idx.data_size = 0;
+        }
+        self.fs.finish_message(self.idx, self.id)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), FsError> {
+        let offset = self.fs.topic_header.layout.data_block_offset(self.idx.start_idx) + self.idx.data_size;
+        self.fs.storage.grow(offset + bytes.len() as u64)?;
+        self.fs.storage.try_write(offset, bytes)?;
+        self.idx.data_size += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl<S: BlockStorage> Drop for MessageWriter<'_, S> {
+    fn drop(&mut self) {
+        self.fs.streaming.set(false);
+    }
+}
+
+// The serialized message read straight from its data blocks as the caller asks for bytes, so large
+// payloads can be decoded without holding them whole. Bypasses the block cache and doesn't verify
+// checksums, see EventFilesystem::verify_all for that.
+pub struct MessageReader<'a, S: BlockStorage = FnStorage> {
+    fs: &'a EventFilesystem<S>,
+    offset: u64,
+    remaining: u64,
+    size: u64,
+}
+
+impl<'a, S: BlockStorage> MessageReader<'a, S> {
+    pub(crate) fn new(fs: &'a EventFilesystem<S>, offset: u64, size: u64) -> Self {
+        MessageReader { fs, offset, remaining: size, size }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Bytes not read yet.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl<S: BlockStorage> Read for MessageReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        self.fs.storage.read(self.offset, &mut buf[..len]);
+        self.offset += len as u64;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+pub(crate) fn write_idx(idx: &IndexBlock, layout: &LayoutConfig, storage: &Storage) -> Result<(), FsError> {
+    let bytes = &idx.to_bytes()[..layout.index_entry_size() as usize];
+    // Move to index region, move over number of blocks
+    let offset = layout.index_entry_offset(idx.height);
+    debug!("Writing index block: {:?} offset {}", idx, offset);
+    storage.try_write(offset, bytes)
+}
+
+// Messages decoded before the budget ran out; `resume_from` is set when the range was cut short.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialRange<T> {
+    pub messages: Vec<T>,
+    pub resume_from: Option<u64>,
+}
+
+// Payload bytes a page of EventFilesystem::read_page holds at most, by default and as the highest
+// setting. Leaves room below the 2MB response limit for heights and the candid encoding.
+pub const MAX_PAGE_BYTES: u64 = 1536 * 1024;
+
+// Messages of one read_page call with their heights. Pass `next` as `after` to continue.
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct Page<T> {
+    pub messages: Vec<(u64, T)>,
+    // Last height the page covered, markers included. The requested `after` when it covered none.
+    pub next: Option<u64>,
+    // Whether committed messages follow `next`.
+    pub has_more: bool,
+}
+
+// Index entries read together as one page when the cache is enabled.
+const INDEX_PAGE_ENTRIES: u64 = 64;
+
+// Recently read index pages, most recently used last. With a warm page a message read is a single
+// stable read for its payload instead of two dependent ones.
+#[derive(Default)]
+struct IndexPageCache {
+    capacity: usize,
+    pages: Vec<(u64, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub cached_blocks: u64,
+}
+
+// Recently read data blocks by block number with their last use. A payload is served from here only
+// when all of its blocks are cached, otherwise it is read with one stable call and cached if it fits.
+#[derive(Default)]
+struct BlockCache {
+    capacity: usize,
+    blocks: BTreeMap<u64, (u64, Vec<u8>)>,
+    clock: u64,
+    stats: BlockCacheStats,
+}
+
+impl BlockCache {
+    fn read(&mut self, start: u64, buf: &mut [u8], block_size: u64) -> bool {
+        let count = buf.len().div_ceil(block_size as usize) as u64;
+        if (start..start + count).any(|block| !self.blocks.contains_key(&block)) {
+            self.stats.misses += count;
+            return false;
+        }
+        self.clock += 1;
+        for (block, chunk) in (start..).zip(buf.chunks_mut(block_size as usize)) {
+            let (last_used, bytes) = self.blocks.get_mut(&block).unwrap();
+            *last_used = self.clock;
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        self.stats.hits += count;
+        true
+    }
+
+    fn insert(&mut self, start: u64, buf: &[u8], block_size: u64) {
+        if buf.len().div_ceil(block_size as usize) > self.capacity {
+            return;
+        }
+        self.clock += 1;
+        for (block, chunk) in (start..).zip(buf.chunks(block_size as usize)) {
+            if self.blocks.len() == self.capacity {
+                let oldest = self.blocks.iter().min_by_key(|(_, (last_used, _))| *last_used).map(|(block, _)| *block).unwrap();
+                self.blocks.remove(&oldest);
+            }
+            self.blocks.insert(block, (self.clock, chunk.to_vec()));
+        }
+    }
+}
+
+pub struct MemoryReader {
+    codecs: SettingsHistory<BincodeCodec>,
+    event_times: SettingsHistory<bool>,
+    ids: SettingsHistory<bool>,
+    compressions: SettingsHistory<PayloadCompression>,
+    // Every dictionary payloads may have been compressed with.
+    dictionaries: Vec<CompressionDictionary>,
+    checksums: SettingsHistory<bool>,
+    layout: LayoutConfig,
+    index_cache: RefCell<IndexPageCache>,
+    block_cache: RefCell<BlockCache>,
+}
 
 impl MemoryReader
 {
     pub(crate) fn new() -> Self {
         MemoryReader {
+            codecs: SettingsHistory::new(BincodeCodec::default()),
+            event_times: SettingsHistory::new(false),
+            ids: SettingsHistory::new(false),
+            compressions: SettingsHistory::new(PayloadCompression::None),
+            dictionaries: Vec::new(),
+            checksums: SettingsHistory::new(false),
+            layout: LayoutConfig::default(),
+            index_cache: RefCell::new(IndexPageCache::default()),
+            block_cache: RefCell::new(BlockCache::default()),
         }
     }
 
+    // Each message is decoded with the codec in force at its height.
+    pub(crate) fn set_codecs(&mut self, codecs: SettingsHistory<BincodeCodec>) {
+        self.codecs = codecs;
+    }
+
+    pub(crate) fn set_event_times(&mut self, event_times: SettingsHistory<bool>) {
+        self.event_times = event_times;
+    }
+
+    pub(crate) fn set_ids(&mut self, ids: SettingsHistory<bool>) {
+        self.ids = ids;
+    }
+
+    // Messages written while compression was on start with a compression header.
+    pub(crate) fn set_compressions(&mut self, compressions: SettingsHistory<PayloadCompression>) {
+        self.compressions = compressions;
+    }
+
+    pub(crate) fn set_dictionaries(&mut self, dictionaries: Vec<CompressionDictionary>) {
+        self.dictionaries = dictionaries;
+    }
+
+    pub(crate) fn dictionaries(&self) -> &[CompressionDictionary] {
+        &self.dictionaries
+    }
+
+    pub(crate) fn set_checksums(&mut self, checksums: SettingsHistory<bool>) {
+        self.checksums = checksums;
+    }
+
+    pub fn set_layout(&mut self, layout: LayoutConfig) {
+        self.layout = layout;
+        self.index_cache.borrow_mut().pages.clear();
+        self.clear_block_cache();
+    }
+
+    // Zero disables the cache. Data blocks are never rewritten in place, only moves of the data zone
+    // need to call `clear_block_cache`.
+    pub fn set_block_cache_blocks(&mut self, blocks: usize) {
+        let mut cache = self.block_cache.borrow_mut();
+        cache.capacity = blocks;
+        cache.blocks.clear();
+    }
+
+    pub(crate) fn clear_block_cache(&self) {
+        self.block_cache.borrow_mut().blocks.clear();
+    }
+
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        let cache = self.block_cache.borrow();
+        BlockCacheStats { cached_blocks: cache.blocks.len() as u64, ..cache.stats }
+    }
+
+    // Zero disables the cache. Whoever writes the index must call `invalidate_index` for every entry written.
+    pub fn set_index_cache_pages(&mut self, pages: usize) {
+        let mut cache = self.index_cache.borrow_mut();
+        cache.capacity = pages;
+        cache.pages.truncate(pages);
+    }
+
+    pub(crate) fn invalidate_index(&self, height: u64) {
+        let page = height / INDEX_PAGE_ENTRIES;
+        self.index_cache.borrow_mut().pages.retain(|(cached, _)| *cached != page);
+    }
+
     // We could do a lotttt more here, but for now we'll just loop
-    pub fn read_range<T : DeserializeOwned>(&self, start: u64, count: u64, reader: BlockRead) -> Result<Vec<T>, String> {
+    pub fn read_range<T : DeserializeOwned>(&self, start: u64, count: u64, storage: &Storage) -> Result<Vec<T>, FsError> {
         let mut messages = Vec::new();
         for i in start..start + count {
-            match self.read_topic_message(i, reader) {
+            match self.read_topic_message(i, storage) {
                 Ok(msg) => messages.push(msg),
                 Err(e) => return Err(e),
             }
@@ -109,50 +585,235 @@ impl MemoryReader
         Ok(messages)
     }
 
-    pub(crate) fn read_topic_message<T : DeserializeOwned>(&self, height: u64, reader: BlockRead) -> Result<T, String> {
-        let idx = self.read_idx(height, reader)?;
+    // Always decodes at least one message so callers resuming from `resume_from` make progress.
+    pub fn read_range_budgeted<T : DeserializeOwned>(&self, start: u64, count: u64, storage: &Storage, budget: &InstructionBudget) -> Result<PartialRange<T>, FsError> {
+        let mut messages = Vec::new();
+        for i in start..start + count {
+            if !messages.is_empty() && budget.is_exhausted() {
+                debug!("Read budget exhausted at {}", i);
+                return Ok(PartialRange { messages, resume_from: Some(i) });
+            }
+            messages.push(self.read_topic_message(i, storage)?);
+        }
+        Ok(PartialRange { messages, resume_from: None })
+    }
+
+    pub(crate) fn read_topic_message<T : DeserializeOwned>(&self, height: u64, storage: &Storage) -> Result<T, FsError> {
+        let payload = self.read_payload(height, storage)?;
+        self.codecs.at(height).deserialize::<T>(&payload)
+    }
+
+    // The serialized message, empty for markers.
+    pub(crate) fn read_payload(&self, height: u64, storage: &Storage) -> Result<Vec<u8>, FsError> {
+        let payload = self.read_raw_payload(height, storage)?;
+        self.decode_payload(height, payload)
+    }
+
+    // The serialized message out of a payload as written.
+    pub(crate) fn decode_payload(&self, height: u64, mut payload: Vec<u8>) -> Result<Vec<u8>, FsError> {
+        self.verify_checksum(height, &payload)?;
+        payload.drain(..self.envelope_size(height, payload.len()));
+        if payload.is_empty() || *self.compressions.at(height) == PayloadCompression::None {
+            return Ok(payload);
+        }
+        decompress(&payload, self.codecs.at(height).size_limit, &self.dictionaries)
+    }
+
+    // Ok(false) for markers and messages written while checksums were disabled, which have none.
+    pub(crate) fn verify_checksum(&self, height: u64, payload: &[u8]) -> Result<bool, FsError> {
+        if payload.is_empty() || !*self.checksums.at(height) {
+            return Ok(false);
+        }
+        let start = self.prefix_size(height, payload.len());
+        let stored = &payload[start..start + CHECKSUM_SIZE];
+        if crc32(&[&payload[..start], &payload[start + CHECKSUM_SIZE..]])?.to_le_bytes() != stored {
+            return Err(FsError::CorruptData { height });
+        }
+        Ok(true)
+    }
+
+    // None for markers and messages written while event times were disabled.
+    pub(crate) fn read_event_time(&self, height: u64, storage: &Storage) -> Result<Option<u64>, FsError> {
+        let idx = self.read_idx(height, storage)?;
+        self.validate_idx(height, &idx)?;
+        if self.event_time_size(height, idx.data_size as usize) == 0 {
+            return Ok(None);
+        }
+        let mut bytes = [0u8; EVENT_TIME_SIZE];
+        storage.read(self.layout.data_block_offset(idx.start_idx), &mut bytes);
+        Ok(Some(u64::from_le_bytes(bytes)))
+    }
+
+    // None for markers and messages written while message ids were disabled.
+    pub(crate) fn read_id(&self, height: u64, storage: &Storage) -> Result<Option<u128>, FsError> {
+        let idx = self.read_idx(height, storage)?;
+        self.validate_idx(height, &idx)?;
+        if self.id_size(height, idx.data_size as usize) == 0 {
+            return Ok(None);
+        }
+        let mut bytes = [0u8; ID_SIZE];
+        let offset = self.event_time_size(height, idx.data_size as usize) as u64;
+        storage.read(self.layout.data_block_offset(idx.start_idx) + offset, &mut bytes);
+        Ok(Some(u128::from_le_bytes(bytes)))
+    }
+
+    pub(crate) fn envelope_size(&self, height: u64, payload_size: usize) -> usize {
+        match *self.checksums.at(height) && payload_size > 0 {
+            true => self.prefix_size(height, payload_size) + CHECKSUM_SIZE,
+            false => self.prefix_size(height, payload_size),
+        }
+    }
+
+    // The event time and message id in front of the checksum.
+    fn prefix_size(&self, height: u64, payload_size: usize) -> usize {
+        self.event_time_size(height, payload_size) + self.id_size(height, payload_size)
+    }
+
+    fn id_size(&self, height: u64, payload_size: usize) -> usize {
+        match *self.ids.at(height) && payload_size > 0 {
+            true => ID_SIZE,
+            false => 0,
+        }
+    }
+
+    fn event_time_size(&self, height: u64, payload_size: usize) -> usize {
+        match *self.event_times.at(height) && payload_size > 0 {
+            true => EVENT_TIME_SIZE,
+            false => 0,
+        }
+    }
+
+    // The payload as written, including the event time and checksum.
+    pub(crate) fn read_raw_payload(&self, height: u64, storage: &Storage) -> Result<Vec<u8>, FsError> {
+        let idx = self.read_idx(height, storage)?;
         debug!("Read index  {:?}", idx);
 
-        let read_start = get_data_offset_from_height(idx.start_idx);
+        self.validate_idx(height, &idx)?;
+
+        if idx.is_marker() {
+            return Ok(Vec::new());
+        }
+
         let mut buf = vec![0u8; idx.data_size as usize];
-        debug!("Reading {:?} from offset {:?}", idx.data_size, read_start);
-        reader(read_start, &mut buf);
+        let mut cache = self.block_cache.borrow_mut();
+        if cache.capacity == 0 || !cache.read(idx.start_idx, &mut buf, self.layout.block_size) {
+            let read_start = self.layout.data_block_offset(idx.start_idx);
+            debug!("Reading {:?} from offset {:?}", idx.data_size, read_start);
+            storage.read(read_start, &mut buf);
+            cache.insert(idx.start_idx, &buf, self.layout.block_size);
+        }
+        Ok(buf)
+    }
 
-        bincode::deserialize::<T>(&*buf).map_err(|e| format!("Failed to deserialize: {}", e))
+    // Where the serialized message starts in storage and its size, to read it in pieces. Compressed
+    // messages can only be read whole.
+    pub(crate) fn payload_span(&self, height: u64, storage: &Storage) -> Result<(u64, u64), FsError> {
+        let idx = self.read_idx(height, storage)?;
+        self.validate_idx(height, &idx)?;
+        let envelope = self.envelope_size(height, idx.data_size as usize) as u64;
+        if idx.data_size > envelope && *self.compressions.at(height) != PayloadCompression::None {
+            return Err(FsError::Unsupported(format!("Message {} is compressed, it can only be read whole", height)));
+        }
+        Ok((self.layout.data_block_offset(idx.start_idx) + envelope, idx.data_size - envelope))
     }
 
-    pub fn read_idx(&self, offset: u64, reader: BlockRead) -> Result<IndexBlock, String> {
-        let mut bytes = [0u8; IDX_BLOCK_SIZE as usize];
-        reader(IDX_ZONE_IDX + (IDX_BLOCK_SIZE * offset), &mut bytes);
-        let idx = bincode::deserialize(&bytes).map_err(|e| format!("Failed to deserialize: {}", e))?;
-        Ok(idx)
+    // Rejects entries whose size or block span cannot be trusted before anything is allocated for them.
+    fn validate_idx(&self, height: u64, idx: &IndexBlock) -> Result<(), FsError> {
+        let corrupt = |reason: String| Err(FsError::CorruptIndex { height, reason });
+        if idx.height != height {
+            return corrupt(format!("entry claims height {}", idx.height));
+        }
+        let envelope = self.envelope_size(height, idx.data_size as usize) as u64;
+        if idx.data_size < envelope {
+            return corrupt(format!("claims {} bytes, too few for its envelope", idx.data_size));
+        }
+        let mut size_limit = self.codecs.at(height).size_limit;
+        if *self.compressions.at(height) != PayloadCompression::None {
+            size_limit += MAX_COMPRESSION_HEADER_SIZE as u64;
+        }
+        if idx.data_size - envelope > size_limit {
+            return corrupt(format!("claims {} bytes, above the {} byte limit", idx.data_size, size_limit));
+        }
+        if idx.end_idx < idx.start_idx || idx.end_idx - idx.start_idx != self.layout.block_count(idx.data_size) {
+            return corrupt(format!("blocks {}..{} do not hold {} bytes", idx.start_idx, idx.end_idx, idx.data_size));
+        }
+        if idx.end_idx.checked_mul(self.layout.block_size).and_then(|end| end.checked_add(self.layout.idx_zone_end())).is_none() {
+            return corrupt(format!("blocks {}..{} run past the end of stable memory", idx.start_idx, idx.end_idx));
+        }
+        Ok(())
+    }
+
+    pub fn read_idx(&self, offset: u64, storage: &Storage) -> Result<IndexBlock, FsError> {
+        if offset >= self.layout.max_index_entries() {
+            return Err(FsError::CorruptIndex { height: offset, reason: "outside the index zone".to_string() });
+        }
+        let mut cache = self.index_cache.borrow_mut();
+        if cache.capacity == 0 {
+            let mut bytes = [0u8; IDX_BLOCK_SIZE as usize];
+            let bytes = &mut bytes[..self.layout.index_entry_size() as usize];
+            storage.read(self.layout.index_entry_offset(offset), bytes);
+            return IndexBlock::from_entry(bytes);
+        }
+
+        let page = offset / INDEX_PAGE_ENTRIES;
+        let entry = match cache.pages.iter().position(|(cached, _)| *cached == page) {
+            Some(position) => cache.pages.remove(position),
+            None => {
+                let first = page * INDEX_PAGE_ENTRIES;
+                let entries = INDEX_PAGE_ENTRIES.min(self.layout.max_index_entries() - first);
+                let mut bytes = vec![0u8; (entries * self.layout.index_entry_size()) as usize];
+                storage.read(self.layout.index_entry_offset(first), &mut bytes);
+                if cache.pages.len() == cache.capacity {
+                    cache.pages.remove(0);
+                }
+                (page, bytes)
+            }
+        };
+        let size = self.layout.index_entry_size() as usize;
+        let start = (offset % INDEX_PAGE_ENTRIES) as usize * size;
+        let idx = IndexBlock::from_entry(&entry.1[start..start + size]);
+        cache.pages.push(entry);
+        idx
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
 
+    use crate::budget::InstructionBudget;
     use crate::constants::*;
-    use crate::read_write::{get_block_count, get_data_offset_from_height, MemoryReader, MemoryWriter};
+    use crate::error::FsError;
+    use crate::index_block::IndexBlock;
+    use crate::layout::LayoutConfig;
+    use crate::read_write::{MemoryReader, MemoryWriter};
+    use crate::storage::{FnStorage, Storage};
 
     thread_local! {
         static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0u8; IDX_ZONE_END as usize + 1024 * 1024 * 128]);
+        static COUNTER: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn tick() -> u64 {
+        COUNTER.with(|c| {
+            c.set(c.get() + 1);
+            c.get()
+        })
     }
 
     fn get_writer() -> MemoryWriter {
-        MemoryWriter {
-            index_block_offset: 0,
-            data_block_offset: 0,
-            clock: || 0,
-        }
+        MemoryWriter::new(0, 0, || 0)
     }
 
     fn get_reader() -> MemoryReader {
-        MemoryReader {
-        }
+        MemoryReader::new()
+    }
+
+    fn memory() -> Storage {
+        Storage::new(FnStorage::new(write, read))
     }
 
+    #[allow(clippy::unused_unit)]
     fn write(offset: u64, data: &[u8]) -> () {
         MEMORY.with(|v| {
             let mut v = v.borrow_mut();
@@ -162,6 +823,7 @@ mod test {
         });
     }
 
+    #[allow(clippy::unused_unit, unused_mut)]
     fn read(offset: u64, data: &mut [u8]) -> () {
         MEMORY.with(|v| {
             let mut v = v.borrow();
@@ -172,18 +834,20 @@ mod test {
     }
 
     #[test]
+    #[allow(unused_mut)]
     fn it_writes_and_reads_a_blob() {
         let message = "Hello, world!".to_string();
         let mut writer = get_writer();
         let mut reader = get_reader();
 
-        let res = writer.write(&message, write).unwrap();
-        let out = reader.read_topic_message::<String>(res.height, read);
+        let res = writer.write(&message, &memory()).unwrap();
+        let out = reader.read_topic_message::<String>(res.height, &memory());
 
         assert_eq!(out.unwrap(), message);
     }
 
     #[test]
+    #[allow(unused_mut)]
     fn it_writes_and_reads_multiple() {
         let bytes = "Hello, world!".to_string();
         let bytes_two = "Foo Bar Baz".to_string();
@@ -192,13 +856,13 @@ mod test {
         let mut writer = get_writer();
         let mut reader = get_reader();
 
-        let res = writer.write(&bytes, write).unwrap();
-        let res_two = writer.write(&bytes_two, write).unwrap();
-        let res_three = writer.write(&bytes_three, write).unwrap();
+        let res = writer.write(&bytes, &memory()).unwrap();
+        let res_two = writer.write(&bytes_two, &memory()).unwrap();
+        let res_three = writer.write(&bytes_three, &memory()).unwrap();
 
-        let out = reader.read_topic_message::<String>(res.height, read).unwrap();
-        let out_two = reader.read_topic_message::<String>(res_two.height, read).unwrap();
-        let out_three = reader.read_topic_message::<String>(res_three.height, read).unwrap();
+        let out = reader.read_topic_message::<String>(res.height, &memory()).unwrap();
+        let out_two = reader.read_topic_message::<String>(res_two.height, &memory()).unwrap();
+        let out_three = reader.read_topic_message::<String>(res_three.height, &memory()).unwrap();
 
         assert_eq!(out, bytes);
         assert_eq!(out_two, bytes_two);
@@ -206,6 +870,7 @@ mod test {
     }
 
     #[test]
+    #[allow(unused_mut, unused_variables)]
     fn it_writes_and_reads_large_multiple() {
         let bytes = vec![12u8; 1024 * 1024];
         let bytes_two = vec![33u8; 1024 * 1024];
@@ -213,17 +878,18 @@ mod test {
         let mut writer = get_writer();
         let mut reader = get_reader();
 
-        let res = writer.write(&bytes, write).unwrap();
-        let res_two = writer.write(&bytes_two, write).unwrap();
+        let res = writer.write(&bytes, &memory()).unwrap();
+        let res_two = writer.write(&bytes_two, &memory()).unwrap();
 
-        let out = reader.read_topic_message::<Vec<u8>>(0, read).unwrap();
-        let out_two = reader.read_topic_message::<Vec<u8>>(1, read).unwrap();
+        let out = reader.read_topic_message::<Vec<u8>>(0, &memory()).unwrap();
+        let out_two = reader.read_topic_message::<Vec<u8>>(1, &memory()).unwrap();
 
         assert_eq!(out, bytes);
         assert_eq!(out_two, bytes_two);
     }
 
     #[test]
+    #[allow(unused_mut, unused_variables)]
     fn it_writes_partial_block() {
         let bytes = vec![12u8; 512];
         let bytes_two = vec![33u8; 513];
@@ -232,13 +898,13 @@ mod test {
         let mut writer = get_writer();
         let mut reader = get_reader();
 
-        let res = writer.write(&bytes, write).unwrap();
-        let res_two = writer.write(&bytes_two, write).unwrap();
-        let res_three = writer.write(&bytes_three, write).unwrap();
+        let res = writer.write(&bytes, &memory()).unwrap();
+        let res_two = writer.write(&bytes_two, &memory()).unwrap();
+        let res_three = writer.write(&bytes_three, &memory()).unwrap();
 
-        let out = reader.read_topic_message::<Vec<u8>>(0, read).unwrap();
-        let out_two = reader.read_topic_message::<Vec<u8>>(1, read).unwrap();
-        let out_three = reader.read_topic_message::<Vec<u8>>(2, read).unwrap();
+        let out = reader.read_topic_message::<Vec<u8>>(0, &memory()).unwrap();
+        let out_two = reader.read_topic_message::<Vec<u8>>(1, &memory()).unwrap();
+        let out_three = reader.read_topic_message::<Vec<u8>>(2, &memory()).unwrap();
 
         assert_eq!(out, bytes);
         assert_eq!(out_two, bytes_two);
@@ -246,19 +912,100 @@ mod test {
     }
 
     #[test]
+    fn it_stops_range_reads_when_budget_is_spent() {
+        let mut writer = get_writer();
+        let reader = get_reader();
+        for i in 0..10u64 {
+            writer.write(&i, &memory()).unwrap();
+        }
+
+        let budget = InstructionBudget::new(tick, 3);
+        let first = reader.read_range_budgeted::<u64>(0, 10, &memory(), &budget).unwrap();
+        assert_eq!(first.messages, vec![0, 1, 2]);
+        assert_eq!(first.resume_from, Some(3));
+
+        let rest = reader.read_range_budgeted::<u64>(3, 7, &memory(), &InstructionBudget::unlimited()).unwrap();
+        assert_eq!(rest.messages, (3..10).collect::<Vec<u64>>());
+        assert_eq!(rest.resume_from, None);
+    }
+
+    #[test]
+    fn it_reuses_scratch_buffer_for_small_writes() {
+        let mut writer = get_writer();
+        let reader = get_reader();
+
+        writer.write(&"warm up".to_string(), &memory()).unwrap();
+        let warm = writer.alloc_stats();
+        for i in 0..100 {
+            writer.write(&format!("event {}", i), &memory()).unwrap();
+        }
+        let stats = writer.alloc_stats();
+        assert_eq!(stats.writes, 101);
+        assert_eq!(stats.scratch_grows, warm.scratch_grows);
+
+        writer.write(&vec![1u8; 4096], &memory()).unwrap();
+        assert_eq!(writer.alloc_stats().scratch_grows, warm.scratch_grows + 1);
+        assert_eq!(reader.read_topic_message::<String>(50, &memory()).unwrap(), "event 49");
+    }
+
+    #[test]
+    fn it_writes_single_block_payloads_with_their_index_entry() {
+        let mut writer = get_writer();
+        let reader = get_reader();
+
+        // bincode puts an 8 byte length in front of the bytes
+        let fits = writer.write(&vec![1u8; BLOCK_SIZE as usize - 8], &memory()).unwrap();
+        let spills = writer.write(&vec![2u8; BLOCK_SIZE as usize - 7], &memory()).unwrap();
+        let after = writer.write(&vec![3u8; 10], &memory()).unwrap();
+        assert_eq!((fits.start_idx, fits.end_idx, fits.data_size), (0, 1, BLOCK_SIZE));
+        assert_eq!((spills.start_idx, spills.end_idx), (1, 3));
+        assert_eq!((after.start_idx, after.end_idx), (3, 4));
+
+        assert_eq!(reader.read_idx(0, &memory()).unwrap(), fits);
+        assert_eq!(reader.read_topic_message::<Vec<u8>>(0, &memory()).unwrap(), vec![1u8; BLOCK_SIZE as usize - 8]);
+        assert_eq!(reader.read_topic_message::<Vec<u8>>(2, &memory()).unwrap(), vec![3u8; 10]);
+    }
+
+    #[test]
+    fn it_rejects_corrupt_index_entries_before_allocating() {
+        let mut writer = get_writer();
+        let reader = get_reader();
+        let idx = writer.write(&"fine".to_string(), &memory()).unwrap();
+
+        let corrupt = |entry: IndexBlock| {
+            write(IDX_ZONE_IDX, &entry.to_bytes());
+            reader.read_topic_message::<String>(0, &memory())
+        };
+        for entry in [
+            IndexBlock { data_size: u64::MAX, ..idx },
+            IndexBlock { height: 7, ..idx },
+            IndexBlock { end_idx: idx.start_idx + 5, ..idx },
+            IndexBlock { start_idx: u64::MAX / BLOCK_SIZE, end_idx: u64::MAX / BLOCK_SIZE + 1, ..idx },
+        ] {
+            assert!(matches!(corrupt(entry), Err(FsError::CorruptIndex { height: 0, .. })));
+        }
+        assert_eq!(corrupt(idx).unwrap(), "fine");
+        assert!(matches!(reader.read_idx(u64::MAX / IDX_BLOCK_SIZE, &memory()), Err(FsError::CorruptIndex { .. })));
+    }
+
+    #[test]
+    #[allow(clippy::identity_op)]
     pub fn it_gets_block_count_for_data() {
-        assert_eq!(get_block_count(0), 0);
-        assert_eq!(get_block_count(1), 1);
-        assert_eq!(get_block_count(512*1), 1);
-        assert_eq!(get_block_count(512*2), 2);
-        assert_eq!(get_block_count(512*2 + 1), 3);
-        assert_eq!(get_block_count(512*10 + 50), 11);
+        let layout = LayoutConfig::default();
+        assert_eq!(layout.block_count(0), 0);
+        assert_eq!(layout.block_count(1), 1);
+        assert_eq!(layout.block_count(512*1), 1);
+        assert_eq!(layout.block_count(512*2), 2);
+        assert_eq!(layout.block_count(512*2 + 1), 3);
+        assert_eq!(layout.block_count(512*10 + 50), 11);
+        assert_eq!(LayoutConfig { block_size: 64, ..layout }.block_count(512*10 + 50), 81);
     }
 
     #[test]
     pub fn it_get_offset_from_block_height() {
-        assert_eq!(get_data_offset_from_height(0), IDX_ZONE_END);
-        assert_eq!(get_data_offset_from_height(1), IDX_ZONE_END + BLOCK_SIZE);
-        assert_eq!(get_data_offset_from_height(10), IDX_ZONE_END + BLOCK_SIZE * 10);
+        let layout = LayoutConfig::default();
+        assert_eq!(layout.data_block_offset(0), IDX_ZONE_END);
+        assert_eq!(layout.data_block_offset(1), IDX_ZONE_END + BLOCK_SIZE);
+        assert_eq!(layout.data_block_offset(10), IDX_ZONE_END + BLOCK_SIZE * 10);
     }
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+
+use crate::constants::IDX_BLOCK_SIZE;
+use crate::error::FsError;
+use crate::index_block::IndexBlock;
+use crate::layout::LayoutConfig;
+use crate::read_write::{MemoryReader, write_idx};
+use crate::storage::Storage;
+
+// What open does when the committed heights disagree with the index entries, e.g. after a write that
+// failed halfway outside a canister.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HeightPolicy {
+    // Refuses to open with a HeightMismatch error when entries below the stored height are missing or
+    // the data height is behind them. Valid entries past the stored height are left uncommitted, a
+    // write that failed before its commit leaves them behind.
+    #[default]
+    FailFast,
+    // Moves the heights to where the valid entries end.
+    TrustIndex,
+    // Keeps the stored height: missing entries become aborted markers, valid entries past it are cleared.
+    TrustHeight,
+}
+
+// How the heights and the index disagreed at open. Valid entries are committed, sit in the slot of
+// their height, own the blocks their size needs and follow each other contiguously.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeightReport {
+    pub stored_height: u64,
+    // Where the run of valid entries the stored height belongs to ends.
+    pub index_height: u64,
+    pub data_block_height: u64,
+    // Data blocks the valid entries end at, the data height may only be ahead of it.
+    pub index_data_end: u64,
+    // The first entry that was looked at and why it didn't fit the stored height.
+    pub reason: String,
+    // The policy that was applied, None when it failed fast.
+    pub repaired_with: Option<HeightPolicy>,
+}
+
+// Compares the committed heights with the index around them. Only looks past the stored height when
+// the entry at it is valid, so a consistent topic costs two entry reads.
+pub(crate) fn check_heights(stored_height: u64,
+                            data_block_height: u64,
+                            reader: &MemoryReader,
+                            layout: &LayoutConfig,
+                            storage: &Storage,
+) -> Option<HeightReport> {
+    let capacity = layout.max_index_entries();
+    let valid = |height: u64, data_start: Option<u64>| -> Result<IndexBlock, String> {
+        let idx = reader.read_idx(height, storage).map_err(|e| e.to_string())?;
+        if !idx.is_committed() {
+            return Err("never written".to_string());
+        }
+        if idx.height != height {
+            return Err(format!("holds height {}", idx.height));
+        }
+        // Unflagged entries can't tell a zeroed slot from an empty message, past the stored height it's a slot.
+        let zeroed = IndexBlock { height, data_size: 0, start_idx: 0, end_idx: 0, timestamp: 0, flags: IndexBlock::COMMITTED };
+        if data_start.is_some() && layout.index_entry_size() < IDX_BLOCK_SIZE && idx == zeroed {
+            return Err("never written".to_string());
+        }
+        if idx.end_idx < idx.start_idx || idx.end_idx - idx.start_idx != layout.block_count(idx.data_size) {
+            return Err(format!("{} bytes don't fit blocks {}..{}", idx.data_size, idx.start_idx, idx.end_idx));
+        }
+        if data_start.is_some_and(|start| idx.start_idx != start) {
+            return Err(format!("starts at block {}, not {}", idx.start_idx, data_start.unwrap_or_default()));
+        }
+        Ok(idx)
+    };
+
+    let last = stored_height.min(capacity).checked_sub(1).map(|height| (height, valid(height, None)));
+    let data_end = match &last {
+        Some((_, Ok(idx))) => idx.end_idx,
+        _ => 0,
+    };
+    let report = |index_height: u64, index_data_end: u64, reason: String| HeightReport {
+        stored_height,
+        index_height,
+        data_block_height,
+        index_data_end,
+        reason,
+        repaired_with: None,
+    };
+    match last {
+        _ if stored_height > capacity => {
+            let (index_height, data_end) = last_valid_below(capacity, &valid);
+            Some(report(index_height, data_end, format!("the index zone holds {} entries", capacity)))
+        }
+        Some((height, Err(reason))) => {
+            let (index_height, data_end) = last_valid_below(height, &valid);
+            Some(report(index_height, data_end, format!("entry {} {}", height, reason)))
+        }
+        _ if data_block_height < data_end => {
+            Some(report(stored_height, data_end, format!("data height {} is behind the index", data_block_height)))
+        }
+        _ => {
+            let mut end = (stored_height, data_end);
+            while end.0 < capacity {
+                match valid(end.0, Some(end.1)) {
+                    Ok(idx) => end = (end.0 + 1, idx.end_idx),
+                    Err(_) => break,
+                }
+            }
+            Some(report(end.0, end.1, format!("entries {}..{} are valid past the stored height", stored_height, end.0)))
+                .filter(|_| end.0 > stored_height)
+        }
+    }
+}
+
+// Height and data end of the valid entries below `height`, from the highest one.
+fn last_valid_below(height: u64, valid: &impl Fn(u64, Option<u64>) -> Result<IndexBlock, String>) -> (u64, u64) {
+    (0..height).rev().find_map(|height| valid(height, None).ok()).map_or((0, 0), |idx| (idx.height + 1, idx.end_idx))
+}
+
+// Rewrites the index so it ends at the returned index and data heights, None leaves them as they are.
+pub(crate) fn repair_heights(report: &HeightReport,
+                             policy: HeightPolicy,
+                             reader: &MemoryReader,
+                             layout: &LayoutConfig,
+                             storage: &Storage,
+) -> Result<Option<(u64, u64)>, FsError> {
+    let data_height = report.data_block_height.max(report.index_data_end);
+    match policy {
+        HeightPolicy::FailFast if report.index_height > report.stored_height => Ok(None),
+        HeightPolicy::FailFast => Err(FsError::HeightMismatch(report.clone())),
+        HeightPolicy::TrustIndex => Ok(Some((report.index_height, data_height))),
+        HeightPolicy::TrustHeight if report.index_height > report.stored_height => {
+            for height in report.stored_height..report.index_height {
+                storage.try_write(layout.index_entry_offset(height), &[0u8; IDX_BLOCK_SIZE as usize][..layout.index_entry_size() as usize])?;
+                reader.invalidate_index(height);
+            }
+            Ok(Some((report.stored_height, data_height)))
+        }
+        HeightPolicy::TrustHeight => {
+            let height = report.stored_height.min(layout.max_index_entries());
+            let timestamp = match report.index_height.checked_sub(1) {
+                Some(last) => reader.read_idx(last, storage)?.timestamp,
+                None => 0,
+            };
+            for height in report.index_height..height {
+                let aborted = IndexBlock {
+                    height,
+                    data_size: 0,
+                    start_idx: report.index_data_end,
+                    end_idx: report.index_data_end,
+                    timestamp,
+                    flags: IndexBlock::COMMITTED | IndexBlock::ABORTED,
+                };
+                write_idx(&aborted, layout, storage)?;
+                reader.invalidate_index(height);
+            }
+            Ok(Some((height, data_height)))
+        }
+    }
+}
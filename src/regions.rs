@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::constants::U64_SIZE;
+use crate::error::FsError;
+
+// The region's length slot is at `offset`, its data follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StableRegion {
+    pub offset: u64,
+    pub capacity: u64,
+}
+
+impl StableRegion {
+    pub(crate) fn data_offset(&self) -> u64 {
+        self.offset + U64_SIZE
+    }
+}
+
+// Named regions carved downwards from the top of the stable store zone, so subsystems like a
+// certification tree or a heap snapshot don't compete for the single stable_store slot.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RegionRegistry {
+    regions: BTreeMap<String, StableRegion>,
+}
+
+impl RegionRegistry {
+    pub(crate) fn get(&self, name: &str) -> Option<StableRegion> {
+        self.regions.get(name).copied()
+    }
+
+    pub(crate) fn regions(&self) -> &BTreeMap<String, StableRegion> {
+        &self.regions
+    }
+
+    // Bytes taken from the top of the zone, length slots included.
+    pub(crate) fn reserved(&self) -> u64 {
+        self.regions.values().map(|region| region.capacity + U64_SIZE).sum()
+    }
+
+    // `floor` is the first byte regions can't reach, the end of what stable_store currently holds.
+    pub(crate) fn create(&mut self, name: &str, capacity: u64, zone_end: u64, floor: u64) -> Result<StableRegion, FsError> {
+        if self.regions.contains_key(name) {
+            return Err(FsError::InvalidArgument(format!("Region {} already exists", name)));
+        }
+        let offset = zone_end.checked_sub(self.reserved())
+            .and_then(|top| top.checked_sub(capacity))
+            .and_then(|top| top.checked_sub(U64_SIZE))
+            .filter(|offset| *offset >= floor)
+            .ok_or_else(|| FsError::OutOfSpace(format!("No room for a {} byte region below {}", capacity, zone_end - self.reserved())))?;
+        let region = StableRegion { offset, capacity };
+        self.regions.insert(name.to_string(), region);
+        Ok(region)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::regions::{RegionRegistry, StableRegion};
+
+    #[test]
+    fn it_carves_regions_from_the_top() {
+        let mut registry = RegionRegistry::default();
+        assert_eq!(registry.create("tree", 92, 1000, 500).unwrap(), StableRegion { offset: 900, capacity: 92 });
+        assert_eq!(registry.create("heap", 392, 1000, 500).unwrap(), StableRegion { offset: 500, capacity: 392 });
+        assert_eq!(registry.reserved(), 500);
+        assert!(registry.create("tree", 8, 1000, 0).is_err());
+        assert!(registry.create("more", 0, 1000, 500).is_err());
+        assert_eq!(registry.get("heap").unwrap().data_offset(), 508);
+    }
+}
@@ -0,0 +1,123 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::codec::BincodeCodec;
+use crate::error::FsError;
+use crate::settings::SettingsHistory;
+use crate::stream::StreamResponse;
+use crate::{BlockStorage, EventFilesystem, FnStorage, Writable};
+
+const TRANSFORMS_RECORD: &str = "replication.transforms";
+// Stream events don't say which kind a marker was, replicated markers all get this one.
+pub const REPLICATED_MARKER: &str = "replicated";
+
+pub type TransformFn<T> = fn(T) -> Result<T, FsError>;
+
+// Applied to every replicated message before it is appended, e.g. to strip fields the follower must
+// not keep. Bump `version` whenever `apply` changes what it produces.
+pub struct PayloadTransform<T> {
+    pub version: u32,
+    pub apply: TransformFn<T>,
+}
+
+// Appends what a StreamClient pulls from a leader to a local topic. Messages are decoded with the
+// leader's codec and written with the local one, so a follower can also change codecs. Leader heights
+// are recorded as aliases of `source`, batches that were already applied are skipped.
+//
+// The transform version each local message was appended with is kept per height range, like codecs, so
+// replicas that diverge from the leader can be explained by the transform they ran.
+pub struct Follower<T, S: BlockStorage = FnStorage> {
+    fs: EventFilesystem<S>,
+    source: String,
+    source_codec: BincodeCodec,
+    transform: Option<PayloadTransform<T>>,
+    transforms: SettingsHistory<Option<u32>>,
+    _message: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned + Writable, S: BlockStorage> Follower<T, S> {
+    pub fn new(fs: EventFilesystem<S>, source: &str, source_codec: BincodeCodec, transform: Option<PayloadTransform<T>>) -> Result<Self, FsError> {
+        let mut transforms = fs.meta.get_value(TRANSFORMS_RECORD)?.unwrap_or_else(|| SettingsHistory::new(None));
+        let version = transform.as_ref().map(|transform| transform.version);
+        if *transforms.current() != version {
+            transforms.set(fs.get_topic_height(), version);
+            fs.meta.put_value(TRANSFORMS_RECORD, &transforms)?;
+        }
+        Ok(Follower { fs, source: source.to_string(), source_codec, transform, transforms, _message: PhantomData })
+    }
+
+    // Appends the batch and returns the local heights of the messages that weren't replicated before.
+    // Leader ingestion times are kept as event times when the local topic has them enabled. A message
+    // that fails stops the batch, the ones before it stay appended and recorded.
+    pub fn apply(&self, response: &StreamResponse) -> Result<Vec<u64>, FsError> {
+        let mut aliases = Vec::new();
+        let result = response.batch.iter()
+            .filter(|event| self.fs.resolve_alias(&self.source, event.height).is_none())
+            .try_for_each(|event| {
+                let height = match event.is_marker() {
+                    true => self.fs.write_marker(REPLICATED_MARKER)?,
+                    false => self.append(event.decode(&self.source_codec)?, event.timestamp)?,
+                };
+                aliases.push((event.height, height));
+                Ok(())
+            });
+        self.fs.record_aliases(&self.source, aliases.iter().copied())?;
+        result.map(|_| aliases.into_iter().map(|(_, height)| height).collect())
+    }
+
+    fn append(&self, message: T, timestamp: u64) -> Result<u64, FsError> {
+        let message = match &self.transform {
+            Some(transform) => (transform.apply)(message)?,
+            None => message,
+        };
+        match self.fs.event_times() {
+            true => self.fs.write_topic_message_at(&message, timestamp),
+            false => self.fs.write_topic_message(&message),
+        }
+    }
+
+    // The transform version the message at local `height` was appended with, None without a transform.
+    pub fn transform_version(&self, height: u64) -> Option<u32> {
+        *self.transforms.at(height)
+    }
+
+    // The leader height of a local message.
+    pub fn source_height(&self, height: u64) -> Option<u64> {
+        self.fs.aliases_of(height).into_iter().find(|(source, _)| *source == self.source).map(|(_, source_height)| source_height)
+    }
+
+    pub fn filesystem(&self) -> &EventFilesystem<S> {
+        &self.fs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replication::{Follower, PayloadTransform};
+    use crate::{BincodeCodec, EventFilesystemBuilder, StreamRequest, VecStorage};
+
+    #[test]
+    fn it_replicates_through_a_transform() {
+        let leader = EventFilesystemBuilder::with_storage(VecStorage::default(), || 7).get_or_create("leader".to_string()).unwrap();
+        for secret in ["a", "b"] {
+            leader.write_topic_message(&secret.to_string()).unwrap();
+        }
+        leader.write_marker("checkpoint").unwrap();
+        let batch = leader.serve_stream(&StreamRequest { cursor: None, limit: 10 }, None, None).unwrap();
+
+        let local = EventFilesystemBuilder::with_storage(VecStorage::default(), || 9).get_or_create("follower".to_string()).unwrap();
+        local.write_topic_message(&"local".to_string()).unwrap();
+        let redact = PayloadTransform { version: 2, apply: |message: String| Ok(message.replace('a', "*")) };
+        let follower = Follower::new(local, "leader", BincodeCodec::default(), Some(redact)).unwrap();
+        assert_eq!(follower.apply(&batch).unwrap(), vec![1, 2, 3]);
+        assert_eq!(follower.apply(&batch).unwrap(), Vec::<u64>::new());
+
+        let fs = follower.filesystem();
+        assert_eq!(fs.read_topic_messages::<String>(0, 3).unwrap(), vec!["local", "*", "b"]);
+        assert!(fs.is_marker(3).unwrap());
+        assert_eq!(follower.source_height(2), Some(1));
+        assert_eq!(follower.transform_version(0), None);
+        assert_eq!(follower.transform_version(3), Some(2));
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+use crate::stable_encode::StableEncode;
+
+// Summaries waiting for delivery beyond this drop the oldest one.
+pub const MAX_OUTBOX_SUMMARIES: usize = 32;
+
+// What a topic reports to fleet monitoring. Written bytes and errors count since the topic was opened.
+// Fields are only ever appended, ops topics keep reading older summaries.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct StatsSummary {
+    // Increases by one per summary, delivery acknowledges up to a sequence number.
+    pub sequence: u64,
+    pub topic: String,
+    pub timestamp: u64,
+    pub height: u64,
+    pub first_message: u64,
+    pub data_bytes: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+}
+
+impl StableEncode for StatsSummary {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsReportConfig {
+    // Clock time between two summaries.
+    pub interval: u64,
+    // Whether summaries are queued for delivery, besides being written to an ops topic.
+    pub outbox: bool,
+}
+
+// Kept in the meta zone, so undelivered summaries survive upgrades.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StatsReports {
+    config: StatsReportConfig,
+    // Clock time of the last summary, None before the first.
+    last_report: Option<u64>,
+    next_sequence: u64,
+    outbox: VecDeque<StatsSummary>,
+}
+
+impl StatsReports {
+    pub(crate) fn new(config: StatsReportConfig) -> Result<Self, FsError> {
+        check_config(&config)?;
+        Ok(StatsReports { config, last_report: None, next_sequence: 0, outbox: VecDeque::new() })
+    }
+
+    pub(crate) fn config(&self) -> StatsReportConfig {
+        self.config
+    }
+
+    // Keeps the queue and the sequence when the topic is reconfigured.
+    pub(crate) fn reconfigure(&mut self, config: StatsReportConfig) -> Result<(), FsError> {
+        check_config(&config)?;
+        self.config = config;
+        if !config.outbox {
+            self.outbox.clear();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_due(&self, now: u64) -> bool {
+        self.last_report.is_none_or(|last| now >= last.saturating_add(self.config.interval))
+    }
+
+    pub(crate) fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub(crate) fn record(&mut self, summary: &StatsSummary) {
+        self.last_report = Some(summary.timestamp);
+        self.next_sequence = summary.sequence + 1;
+        if self.config.outbox {
+            if self.outbox.len() == MAX_OUTBOX_SUMMARIES {
+                self.outbox.pop_front();
+            }
+            self.outbox.push_back(summary.clone());
+        }
+    }
+
+    pub(crate) fn pending(&self, max: usize) -> Vec<StatsSummary> {
+        self.outbox.iter().take(max).cloned().collect()
+    }
+
+    // Drops the summaries up to and including `sequence`, returns how many.
+    pub(crate) fn acknowledge(&mut self, sequence: u64) -> usize {
+        let delivered = self.outbox.iter().take_while(|summary| summary.sequence <= sequence).count();
+        self.outbox.drain(..delivered);
+        delivered
+    }
+}
+
+fn check_config(config: &StatsReportConfig) -> Result<(), FsError> {
+    if config.interval == 0 {
+        return Err(FsError::InvalidArgument("Stats report interval must be positive".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::reports::{MAX_OUTBOX_SUMMARIES, StatsReportConfig, StatsReports, StatsSummary};
+
+    fn summary(sequence: u64, timestamp: u64) -> StatsSummary {
+        StatsSummary { sequence, topic: "test".to_string(), timestamp, height: 0, first_message: 0, data_bytes: 0, bytes_written: 0, errors: 0 }
+    }
+
+    #[test]
+    fn it_queues_summaries_until_acknowledged() {
+        let mut reports = StatsReports::new(StatsReportConfig { interval: 10, outbox: true }).unwrap();
+        assert!(reports.is_due(0));
+        reports.record(&summary(0, 5));
+        assert!(!reports.is_due(14) && reports.is_due(15));
+        for sequence in 1..=MAX_OUTBOX_SUMMARIES as u64 {
+            reports.record(&summary(sequence, 5 + sequence * 10));
+        }
+        assert_eq!(reports.next_sequence(), MAX_OUTBOX_SUMMARIES as u64 + 1);
+        assert_eq!(reports.pending(2).iter().map(|summary| summary.sequence).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(reports.acknowledge(3), 3);
+        assert_eq!(reports.acknowledge(3), 0);
+        assert_eq!(reports.pending(usize::MAX).len(), MAX_OUTBOX_SUMMARIES - 3);
+
+        reports.reconfigure(StatsReportConfig { interval: 10, outbox: false }).unwrap();
+        assert!(reports.pending(usize::MAX).is_empty());
+        assert!(reports.reconfigure(StatsReportConfig { interval: 0, outbox: true }).is_err());
+        assert!(StatsReports::new(StatsReportConfig { interval: 0, outbox: true }).is_err());
+    }
+}
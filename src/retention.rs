@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+
+use crate::budget::InstructionBudget;
+use crate::error::FsError;
+use crate::index_block::IndexBlock;
+use crate::layout::LayoutConfig;
+use crate::read_write::{MemoryReader, write_idx};
+use crate::storage::Storage;
+
+// How much of a topic apply_retention keeps, by message count or by ingestion time on the topic clock.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    MaxMessages(u64),
+    MaxAgeNanos(u64),
+}
+
+// Holds apply_retention back at the slowest consumer, the lowest subscriber offset or cursor position,
+// so nothing is dropped before every consumer read it. A consumer more than `override_lag` messages
+// behind the height no longer holds messages back, None lets it hold them forever.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConsumerRetention {
+    pub override_lag: Option<u64>,
+}
+
+// Progress of dropping the messages below `before`, kept in the meta zone between calls. Heights don't
+// change: entries below `before` become tombstones that keep their timestamp so time seeks still work,
+// and the payloads above move down by the `shift` data blocks the dropped ones owned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Truncation {
+    pub before: u64,
+    pub height: u64,
+    // Entries looked at so far, starting from the first message the topic kept before.
+    pub scanned: u64,
+    shift: u64,
+}
+
+impl Truncation {
+    pub(crate) fn new(first: u64, before: u64, height: u64, shift: u64) -> Self {
+        // Without data to reclaim only the dropped entries are rewritten.
+        let height = if shift == 0 { before } else { height };
+        Truncation { before, height, scanned: first.min(height), shift }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.scanned == self.height
+    }
+
+    pub(crate) fn shift(&self) -> u64 {
+        self.shift
+    }
+}
+
+// Payloads only ever move down and are read in full before they are written, so overlapping moves are
+// safe. Always looks at one entry at least so every call makes progress.
+pub(crate) fn truncate(truncation: &mut Truncation,
+                       reader: &MemoryReader,
+                       layout: &LayoutConfig,
+                       budget: &InstructionBudget,
+                       storage: &Storage,
+) -> Result<(), FsError> {
+    let mut buf = Vec::new();
+    while !truncation.is_done() {
+        let height = truncation.scanned;
+        let idx = reader.read_idx(height, storage)?;
+        let rewritten = if height < truncation.before {
+            IndexBlock { data_size: 0, start_idx: 0, end_idx: 0, flags: idx.flags | IndexBlock::ERASED, ..idx }
+        } else {
+            if !idx.is_marker() {
+                buf.resize(idx.data_size as usize, 0);
+                storage.read(layout.data_block_offset(idx.start_idx), &mut buf);
+                storage.write(layout.data_block_offset(idx.start_idx - truncation.shift), &buf);
+            }
+            IndexBlock { start_idx: idx.start_idx - truncation.shift, end_idx: idx.end_idx - truncation.shift, ..idx }
+        };
+        write_idx(&rewritten, layout, storage)?;
+        reader.invalidate_index(height);
+        truncation.scanned += 1;
+        if budget.is_exhausted() {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::{BlockStorage, EventFilesystem, FnStorage};
+use crate::error::FsError;
+
+// Messages from the newest committed one back to the first one left, with their heights. Markers
+// are skipped by their index entry without reading a payload. Index entries are read in cached
+// pages, walking back costs a payload read per message. Messages committed after the iterator was
+// created aren't returned, it stops after the first error.
+pub struct ReverseMessages<'a, T, S: BlockStorage = FnStorage> {
+    fs: &'a EventFilesystem<S>,
+    // One past the next height to look at.
+    next: u64,
+    first: u64,
+    message: PhantomData<T>,
+}
+
+impl<'a, T, S: BlockStorage> ReverseMessages<'a, T, S> {
+    pub(crate) fn new(fs: &'a EventFilesystem<S>) -> Self {
+        ReverseMessages { fs, next: fs.get_topic_height(), first: fs.first_message_height(), message: PhantomData }
+    }
+}
+
+impl<T: DeserializeOwned, S: BlockStorage> Iterator for ReverseMessages<'_, T, S> {
+    type Item = Result<(u64, T), FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next > self.first {
+            self.next -= 1;
+            let height = self.next;
+            let message = match self.fs.is_marker(height) {
+                Ok(true) => continue,
+                Ok(false) => self.fs.read_topic_message(height).map(|message| (height, message)),
+                Err(e) => Err(e),
+            };
+            if message.is_err() {
+                self.next = self.first;
+            }
+            return Some(message);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EventFilesystemBuilder, InstructionBudget, VecStorage};
+    use crate::fixtures::small_layout;
+
+    #[test]
+    fn it_walks_back_to_the_first_message_left() {
+        let layout = small_layout();
+        let mut fs = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout).get_or_create("test".to_string()).unwrap();
+        for i in 0..5u64 {
+            fs.write_topic_message(&i).unwrap();
+        }
+        fs.write_marker("tick").unwrap();
+        assert_eq!(fs.iter_rev::<u64>().collect::<Result<Vec<_>, _>>().unwrap(), vec![(4, 4), (3, 3), (2, 2), (1, 1), (0, 0)]);
+
+        fs.truncate_before(2, &InstructionBudget::unlimited()).unwrap();
+        assert_eq!(fs.read_last::<u64>(2).unwrap(), vec![(4, 4), (3, 3)]);
+        assert_eq!(fs.read_last::<u64>(10).unwrap().len(), 3);
+        let mut failing = fs.iter_rev::<String>();
+        assert!(failing.next().unwrap().is_err());
+        assert!(failing.next().is_none());
+    }
+}
@@ -0,0 +1,247 @@
+use serde_json::{Map, Value};
+
+use crate::error::FsError;
+
+const KEYWORDS: [&str; 17] = [
+    "$schema", "title", "description", "type", "enum", "const", "properties", "required",
+    "additionalProperties", "items", "minItems", "maxItems", "minimum", "maximum", "minLength",
+    "maxLength", "oneOf",
+];
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "integer", "string"];
+
+// A subset of JSON Schema checked against a message's serde representation before it is written:
+// type, enum, const, properties, required, additionalProperties, items, oneOf and the min/max bounds.
+// Other keywords, `pattern` included, are refused when the schema is parsed rather than ignored, so a
+// typo can't switch a check off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageSchema {
+    source: String,
+    schema: Value,
+}
+
+impl MessageSchema {
+    pub fn parse(json: &str) -> Result<MessageSchema, FsError> {
+        let schema: Value = serde_json::from_str(json).map_err(|e| FsError::InvalidArgument(format!("Schema is not JSON: {}", e)))?;
+        check_schema(&schema, "$")?;
+        Ok(MessageSchema { source: json.to_string(), schema })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Fails with the path of the first value that doesn't conform.
+    pub fn validate(&self, message: &Value) -> Result<(), FsError> {
+        validate(&self.schema, message, "$").map_err(|(path, reason)| FsError::SchemaViolation { path, reason })
+    }
+}
+
+fn invalid(path: &str, reason: &str) -> FsError {
+    FsError::InvalidArgument(format!("Invalid schema at {}: {}", path, reason))
+}
+
+fn check_schema(schema: &Value, path: &str) -> Result<(), FsError> {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(_) => return Ok(()),
+        _ => return Err(invalid(path, "expected an object or a boolean")),
+    };
+    if let Some(keyword) = schema.keys().find(|keyword| !KEYWORDS.contains(&keyword.as_str())) {
+        return Err(invalid(path, &format!("unsupported keyword {}", keyword)));
+    }
+    match schema.get("type") {
+        None => {}
+        Some(Value::String(name)) if TYPES.contains(&name.as_str()) => {}
+        Some(Value::Array(names)) if names.iter().all(|name| name.as_str().is_some_and(|name| TYPES.contains(&name))) => {}
+        Some(_) => return Err(invalid(path, "unknown type")),
+    }
+    for keyword in ["minItems", "maxItems", "minLength", "maxLength"] {
+        if schema.get(keyword).is_some_and(|bound| !bound.is_u64()) {
+            return Err(invalid(path, &format!("{} must be a non-negative integer", keyword)));
+        }
+    }
+    for keyword in ["minimum", "maximum"] {
+        if schema.get(keyword).is_some_and(|bound| !bound.is_number()) {
+            return Err(invalid(path, &format!("{} must be a number", keyword)));
+        }
+    }
+    if schema.get("enum").is_some_and(|values| !values.is_array()) {
+        return Err(invalid(path, "enum must be an array"));
+    }
+    if let Some(required) = schema.get("required") {
+        if !required.as_array().is_some_and(|names| names.iter().all(Value::is_string)) {
+            return Err(invalid(path, "required must be an array of property names"));
+        }
+    }
+    match schema.get("properties") {
+        None => {}
+        Some(Value::Object(properties)) => {
+            for (name, property) in properties {
+                check_schema(property, &format!("{}.{}", path, name))?;
+            }
+        }
+        Some(_) => return Err(invalid(path, "properties must be an object")),
+    }
+    if let Some(additional) = schema.get("additionalProperties") {
+        check_schema(additional, &format!("{}.*", path))?;
+    }
+    if let Some(items) = schema.get("items") {
+        check_schema(items, &format!("{}[]", path))?;
+    }
+    match schema.get("oneOf") {
+        None => {}
+        Some(Value::Array(options)) if !options.is_empty() => {
+            for (i, option) in options.iter().enumerate() {
+                check_schema(option, &format!("{}|{}", path, i))?;
+            }
+        }
+        Some(_) => return Err(invalid(path, "oneOf must be a non-empty array")),
+    }
+    Ok(())
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    let actual = type_name(value);
+    actual == name || (name == "number" && actual == "integer")
+}
+
+// Schemas were checked when parsed, so lookups that don't have the expected shape are skipped.
+fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), (String, String)> {
+    let fail = |reason: String| Err((path.to_string(), reason));
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return fail("no value is allowed here".to_string()),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    match schema.get("type") {
+        Some(Value::String(name)) if !has_type(value, name) => return fail(format!("expected {}, got {}", name, type_name(value))),
+        Some(Value::Array(names)) if !names.iter().filter_map(Value::as_str).any(|name| has_type(value, name)) => {
+            return fail(format!("expected one of {}, got {}", Value::Array(names.clone()), type_name(value)));
+        }
+        _ => {}
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            return fail(format!("{} is not one of {}", value, Value::Array(values.clone())));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return fail(format!("expected {}, got {}", expected, value));
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("oneOf") {
+        let matching = options.iter().filter(|option| validate(option, value, path).is_ok()).count();
+        if matching != 1 {
+            return fail(format!("matches {} of the oneOf schemas, expected exactly one", matching));
+        }
+    }
+
+    match value {
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(f64::NAN);
+            if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64).filter(|minimum| n < *minimum) {
+                return fail(format!("{} is below the minimum {}", n, minimum));
+            }
+            if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64).filter(|maximum| n > *maximum) {
+                return fail(format!("{} is above the maximum {}", n, maximum));
+            }
+        }
+        Value::String(s) => {
+            let length = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64).filter(|min| length < *min) {
+                return fail(format!("{} characters, at least {} are required", length, min));
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64).filter(|max| length > *max) {
+                return fail(format!("{} characters, at most {} are allowed", length, max));
+            }
+        }
+        Value::Array(items) => {
+            let count = items.len() as u64;
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64).filter(|min| count < *min) {
+                return fail(format!("{} items, at least {} are required", count, min));
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64).filter(|max| count > *max) {
+                return fail(format!("{} items, at most {} are allowed", count, max));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(item_schema, item, &format!("{}[{}]", path, i))?;
+                }
+            }
+        }
+        Value::Object(fields) => validate_object(schema, fields, path)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn validate_object(schema: &Map<String, Value>, fields: &Map<String, Value>, path: &str) -> Result<(), (String, String)> {
+    let empty = Map::new();
+    let properties = schema.get("properties").and_then(Value::as_object).unwrap_or(&empty);
+    for name in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+        if !fields.contains_key(name) {
+            return Err((path.to_string(), format!("missing required property {}", name)));
+        }
+    }
+    for (name, field) in fields {
+        let field_path = format!("{}.{}", path, name);
+        match (properties.get(name), schema.get("additionalProperties")) {
+            (Some(property), _) => validate(property, field, &field_path)?,
+            (None, Some(additional)) => validate(additional, field, &field_path)?,
+            (None, None) => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::error::FsError;
+    use crate::schema::MessageSchema;
+
+    #[test]
+    fn it_validates_against_a_schema_subset() {
+        let schema = MessageSchema::parse(r#"{
+            "type": "object",
+            "required": ["kind", "amount"],
+            "additionalProperties": false,
+            "properties": {
+                "kind": { "enum": ["transfer", "refund"] },
+                "amount": { "type": "integer", "minimum": 0 },
+                "memo": { "type": ["string", "null"], "maxLength": 8 },
+                "tags": { "type": "array", "items": { "type": "string" }, "maxItems": 2 }
+            }
+        }"#).unwrap();
+
+        assert_eq!(schema.validate(&json!({ "kind": "transfer", "amount": 5, "memo": null, "tags": ["a"] })), Ok(()));
+        assert_eq!(schema.validate(&json!({ "kind": "transfer", "amount": 5, "memo": "tx-1" })), Ok(()));
+        let violation = |path: &str, reason: &str| Err(FsError::SchemaViolation { path: path.to_string(), reason: reason.to_string() });
+        assert_eq!(schema.validate(&json!({ "kind": "transfer" })), violation("$", "missing required property amount"));
+        assert_eq!(schema.validate(&json!({ "kind": "mint", "amount": 1 })), violation("$.kind", "\"mint\" is not one of [\"transfer\",\"refund\"]"));
+        assert_eq!(schema.validate(&json!({ "kind": "refund", "amount": 1.5 })), violation("$.amount", "expected integer, got number"));
+        assert_eq!(schema.validate(&json!({ "kind": "refund", "amount": 1, "tags": ["a", 2] })), violation("$.tags[1]", "expected string, got integer"));
+        assert_eq!(schema.validate(&json!({ "kind": "refund", "amount": 1, "memo": "too long memo" })), violation("$.memo", "13 characters, at most 8 are allowed"));
+        assert_eq!(schema.validate(&json!({ "kind": "refund", "amount": 1, "extra": true })), violation("$.extra", "no value is allowed here"));
+
+        assert!(MessageSchema::parse(r#"{ "type": "object", "propertys": {} }"#).is_err());
+        assert!(MessageSchema::parse(r#"{ "type": "text" }"#).is_err());
+        assert!(MessageSchema::parse(r#"{ "pattern": "^tx-" }"#).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+use crate::index_block::IndexBlock;
+use crate::key_index::key_hash;
+
+// What a segment of consecutive heights holds, to prune time and key lookups and as a unit of work
+// for export and archival. Timestamps are ingestion times.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentManifest {
+    pub start: u64,
+    // Heights committed into the segment so far, markers included.
+    pub entries: u64,
+    // Bytes stored, envelopes included.
+    pub bytes: u64,
+    pub min_timestamp: u64,
+    pub max_timestamp: u64,
+    // Key index hashes of the keyed messages, None while the segment has none.
+    pub min_key_hash: Option<u64>,
+    pub max_key_hash: Option<u64>,
+}
+
+impl SegmentManifest {
+    pub fn heights(&self) -> Range<u64> {
+        self.start..self.start + self.entries
+    }
+
+    // Whether messages ingested within `time` may be in the segment.
+    pub fn may_hold_time(&self, time: &Range<u64>) -> bool {
+        self.entries > 0 && time.start <= self.max_timestamp && self.min_timestamp < time.end
+    }
+
+    pub fn may_hold_key(&self, key: &[u8]) -> bool {
+        let hash = key_hash(key);
+        matches!((self.min_key_hash, self.max_key_hash), (Some(min), Some(max)) if min <= hash && hash <= max)
+    }
+}
+
+// Manifests of the fixed size segments from `covered_from` on. A segment is only described from its
+// first height, so enabling the manifests or restarting them after a compaction starts with the next
+// segment. Segments that were truncated entirely are dropped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SegmentManifests {
+    segment_size: u64,
+    covered_from: u64,
+    // Height the next committed message gets, the manifests describe everything before it.
+    next: u64,
+    segments: Vec<SegmentManifest>,
+}
+
+impl SegmentManifests {
+    pub(crate) fn new(segment_size: u64, height: u64) -> Result<Self, FsError> {
+        if segment_size == 0 {
+            return Err(FsError::InvalidArgument("Segment size must be positive".to_string()));
+        }
+        Ok(SegmentManifests {
+            segment_size,
+            covered_from: height.div_ceil(segment_size).saturating_mul(segment_size),
+            next: height,
+            segments: Vec::new(),
+        })
+    }
+
+    pub(crate) fn segment_size(&self) -> u64 {
+        self.segment_size
+    }
+
+    pub(crate) fn next(&self) -> u64 {
+        self.next
+    }
+
+    pub(crate) fn segments(&self) -> &[SegmentManifest] {
+        &self.segments
+    }
+
+    // Committed entries are recorded in height order.
+    pub(crate) fn record(&mut self, idx: &IndexBlock) {
+        self.next = idx.height + 1;
+        if idx.height < self.covered_from {
+            return;
+        }
+        let start = idx.height / self.segment_size * self.segment_size;
+        if self.segments.last().map(|segment| segment.start) != Some(start) {
+            self.segments.push(SegmentManifest {
+                start,
+                entries: 0,
+                bytes: 0,
+                min_timestamp: idx.timestamp,
+                max_timestamp: idx.timestamp,
+                min_key_hash: None,
+                max_key_hash: None,
+            });
+        }
+        let segment = self.segments.last_mut().expect("a segment was just pushed");
+        segment.entries += 1;
+        segment.bytes += idx.data_size;
+        segment.min_timestamp = segment.min_timestamp.min(idx.timestamp);
+        segment.max_timestamp = segment.max_timestamp.max(idx.timestamp);
+    }
+
+    pub(crate) fn record_key(&mut self, height: u64, key: &[u8]) {
+        let hash = key_hash(key);
+        if let Some(segment) = self.segments.iter_mut().rev().find(|segment| segment.heights().contains(&height)) {
+            segment.min_key_hash = Some(segment.min_key_hash.map_or(hash, |min| min.min(hash)));
+            segment.max_key_hash = Some(segment.max_key_hash.map_or(hash, |max| max.max(hash)));
+        }
+    }
+
+    pub(crate) fn drop_before(&mut self, first: u64) {
+        self.segments.retain(|segment| segment.start + self.segment_size > first);
+    }
+
+    // Ranges within `heights` that may hold messages ingested within `time` and written under `key`.
+    // Heights no manifest describes are always included.
+    pub(crate) fn candidates(&self, heights: Range<u64>, time: Option<&Range<u64>>, key: Option<&[u8]>) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        let mut push = |range: Range<u64>| {
+            let range = range.start.max(heights.start)..range.end.min(heights.end);
+            if range.is_empty() {
+                return;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        };
+        let mut described = 0;
+        for segment in &self.segments {
+            push(described..segment.start);
+            if time.is_none_or(|time| segment.may_hold_time(time)) && key.is_none_or(|key| segment.may_hold_key(key)) {
+                push(segment.heights());
+            }
+            described = segment.heights().end;
+        }
+        push(described..u64::MAX);
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::index_block::IndexBlock;
+    use crate::segments::SegmentManifests;
+
+    fn idx(height: u64, timestamp: u64) -> IndexBlock {
+        IndexBlock { height, data_size: 10, start_idx: height, end_idx: height + 1, timestamp, flags: IndexBlock::COMMITTED }
+    }
+
+    #[test]
+    fn it_prunes_segments_by_time_and_key() {
+        let mut manifests = SegmentManifests::new(4, 2).unwrap();
+        for height in 2..11 {
+            manifests.record(&idx(height, height * 10));
+        }
+        manifests.record_key(5, b"a");
+        manifests.record_key(9, b"b");
+        assert_eq!(manifests.next(), 11);
+        let segments = manifests.segments();
+        assert_eq!(segments.iter().map(|segment| segment.heights()).collect::<Vec<_>>(), vec![4..8, 8..11]);
+        assert_eq!((segments[0].bytes, segments[0].min_timestamp, segments[0].max_timestamp), (40, 40, 70));
+        assert!(segments[0].may_hold_key(b"a") && !segments[1].may_hold_key(b"a"));
+
+        assert_eq!(manifests.candidates(0..11, None, None), vec![0..11]);
+        assert_eq!(manifests.candidates(0..11, Some(&(85..95)), None), vec![0..4, 8..11]);
+        assert_eq!(manifests.candidates(1..20, Some(&(0..10)), None), vec![1..4, 11..20]);
+        assert_eq!(manifests.candidates(0..11, None, Some(b"a")), vec![0..8]);
+        assert_eq!(manifests.candidates(0..11, None, Some(b"c")), vec![0..4]);
+
+        manifests.drop_before(9);
+        assert_eq!(manifests.segments().len(), 1);
+        assert!(SegmentManifests::new(0, 0).is_err());
+    }
+}
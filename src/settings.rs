@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+// A setting that can change over the life of a topic. Each value is in force from the height it was
+// set at, so messages are always interpreted with the value they were written with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SettingsHistory<T> {
+    changes: Vec<(u64, T)>,
+}
+
+impl<T: Clone> SettingsHistory<T> {
+    pub(crate) fn new(initial: T) -> Self {
+        SettingsHistory { changes: vec![(0, initial)] }
+    }
+
+    pub(crate) fn at(&self, height: u64) -> &T {
+        let position = self.changes.partition_point(|(from, _)| *from <= height);
+        &self.changes[position.saturating_sub(1)].1
+    }
+
+    pub(crate) fn current(&self) -> &T {
+        &self.changes[self.changes.len() - 1].1
+    }
+
+    // Takes effect from `height`, nothing has been written there yet. A change at the height of the
+    // previous one replaces it, as no message was written in between.
+    pub(crate) fn set(&mut self, height: u64, value: T) {
+        self.changes.retain(|(from, _)| *from < height);
+        if self.changes.is_empty() {
+            self.changes.push((0, value));
+        } else {
+            self.changes.push((height, value));
+        }
+    }
+
+    pub(crate) fn changes(&self) -> &[(u64, T)] {
+        &self.changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::settings::SettingsHistory;
+
+    #[test]
+    fn it_resolves_settings_by_height() {
+        let mut history = SettingsHistory::new("a");
+        history.set(0, "b");
+        history.set(5, "c");
+        history.set(9, "d");
+        history.set(9, "e");
+
+        assert_eq!(history.changes(), &[(0, "b"), (5, "c"), (9, "e")]);
+        assert_eq!(*history.at(0), "b");
+        assert_eq!(*history.at(4), "b");
+        assert_eq!(*history.at(5), "c");
+        assert_eq!(*history.at(100), "e");
+        assert_eq!(*history.current(), "e");
+    }
+}
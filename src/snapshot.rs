@@ -0,0 +1,216 @@
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::storage::Storage;
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"ICFSSNAP";
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+// Sections are streamed and hashed in pieces of this size.
+const PIECE_SIZE: u64 = 64 * 1024;
+const MAX_MANIFEST_SIZE: u32 = 1024 * 1024;
+const MAX_DIGEST_SIZE: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SnapshotSectionKind {
+    StableStore,
+    Region,
+    Meta,
+    Index,
+    Data,
+    // Magic number, heights and topic block. Written last on import, so a snapshot that fails to
+    // restore never leaves a topic that opens.
+    Header,
+}
+
+// Stable memory bytes copied as they are, restored at the same offsets.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotSection {
+    pub kind: SnapshotSectionKind,
+    pub bytes: Range<u64>,
+}
+
+// Stream layout: magic | format version u32 | manifest length u32 | bincode manifest without the
+// digest | section bytes in manifest order | digest length u32 | digest. Numbers are little endian.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub format_version: u32,
+    pub binary_version: u32,
+    pub event_stream_name: String,
+    pub hash_algorithm: HashAlgorithm,
+    pub height: u64,
+    pub first_message: u64,
+    pub data_blocks: u64,
+    pub sections: Vec<SnapshotSection>,
+    // Chained with the topic's hash over the section bytes in 64 KiB pieces, digest = hash(digest | piece).
+    pub digest: Vec<u8>,
+}
+
+impl SnapshotManifest {
+    // Section bytes the snapshot holds, without the framing.
+    pub fn size(&self) -> u64 {
+        self.sections.iter().map(|section| section.bytes.end - section.bytes.start).sum()
+    }
+}
+
+pub(crate) fn write_snapshot(manifest: &mut SnapshotManifest, storage: &Storage, hasher: &dyn Hasher, writer: &mut impl Write) -> Result<(), FsError> {
+    manifest.digest.clear();
+    writer.write_all(&encode_prefix(manifest)?).map_err(io_error)?;
+    let mut digest = Vec::new();
+    let mut buf = Vec::new();
+    for section in &manifest.sections {
+        for piece in pieces(&section.bytes) {
+            buf.resize((piece.end - piece.start) as usize, 0);
+            storage.read(piece.start, &mut buf);
+            writer.write_all(&buf).map_err(io_error)?;
+            digest = hasher.digest(&[digest.as_slice(), &buf].concat());
+        }
+    }
+    writer.write_all(&(digest.len() as u32).to_le_bytes()).map_err(io_error)?;
+    writer.write_all(&digest).map_err(io_error)?;
+    writer.flush().map_err(io_error)?;
+    manifest.digest = digest;
+    Ok(())
+}
+
+// Bytes `range` of the stream write_snapshot writes for `manifest`, e.g. to hand it out in chunks. The
+// digest is only chained when the range reaches the trailer, that reads every section.
+pub(crate) fn read_stream(manifest: &SnapshotManifest, storage: &Storage, hasher: &dyn Hasher, range: Range<u64>) -> Result<Vec<u8>, FsError> {
+    let mut bytes = Vec::with_capacity((range.end - range.start) as usize);
+    let prefix = encode_prefix(manifest)?;
+    let mut position = 0;
+    copy_overlap(&prefix, &mut position, &range, &mut bytes);
+    for section in &manifest.sections {
+        let len = section.bytes.end - section.bytes.start;
+        let start = range.start.clamp(position, position + len);
+        let end = range.end.clamp(position, position + len);
+        if start < end {
+            let offset = bytes.len();
+            bytes.resize(offset + (end - start) as usize, 0);
+            storage.read(section.bytes.start + start - position, &mut bytes[offset..]);
+        }
+        position += len;
+    }
+    if range.end > position {
+        let digest = digest_sections(manifest, storage, hasher);
+        let trailer = [&(digest.len() as u32).to_le_bytes()[..], &digest].concat();
+        copy_overlap(&trailer, &mut position, &range, &mut bytes);
+    }
+    Ok(bytes)
+}
+
+// Length of the stream write_snapshot writes for `manifest`.
+pub(crate) fn stream_size(manifest: &SnapshotManifest, hasher: &dyn Hasher) -> Result<u64, FsError> {
+    let digest_size = hasher.digest(&[]).len() as u64;
+    Ok(encode_prefix(manifest)?.len() as u64 + manifest.size() + 4 + digest_size)
+}
+
+fn encode_prefix(manifest: &SnapshotManifest) -> Result<Vec<u8>, FsError> {
+    let encoded = bincode::serialize(&SnapshotManifest { digest: Vec::new(), ..manifest.clone() }).map_err(|e| FsError::Serialize(e.to_string()))?;
+    Ok([&SNAPSHOT_MAGIC[..], &SNAPSHOT_FORMAT_VERSION.to_le_bytes(), &(encoded.len() as u32).to_le_bytes(), &encoded].concat())
+}
+
+fn digest_sections(manifest: &SnapshotManifest, storage: &Storage, hasher: &dyn Hasher) -> Vec<u8> {
+    let mut digest = Vec::new();
+    let mut buf = Vec::new();
+    for piece in manifest.sections.iter().flat_map(|section| pieces(&section.bytes)) {
+        buf.resize((piece.end - piece.start) as usize, 0);
+        storage.read(piece.start, &mut buf);
+        digest = hasher.digest(&[digest.as_slice(), &buf].concat());
+    }
+    digest
+}
+
+// Appends the part of `part`, which starts at stream offset `position`, that falls into `range`.
+fn copy_overlap(part: &[u8], position: &mut u64, range: &Range<u64>, bytes: &mut Vec<u8>) {
+    let len = part.len() as u64;
+    let start = range.start.clamp(*position, *position + len);
+    let end = range.end.clamp(*position, *position + len);
+    bytes.extend_from_slice(&part[(start - *position) as usize..(end - *position) as usize]);
+    *position += len;
+}
+
+// Writes the sections into `storage` and returns the manifest with the digest the stream carried.
+// `hasher` picks the hasher for the manifest's algorithm.
+pub(crate) fn read_snapshot(reader: &mut impl Read, storage: &Storage, hasher: impl FnOnce(HashAlgorithm) -> Result<Box<dyn Hasher>, FsError>) -> Result<SnapshotManifest, FsError> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(io_error)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(FsError::InvalidArgument("Not a topic snapshot".to_string()));
+    }
+    let version = read_u32(reader)?;
+    if version > SNAPSHOT_FORMAT_VERSION {
+        return Err(FsError::Unsupported(format!("Snapshot format {} is newer than {}", version, SNAPSHOT_FORMAT_VERSION)));
+    }
+    let encoded = read_bytes(reader, MAX_MANIFEST_SIZE)?;
+    let mut manifest: SnapshotManifest = bincode::deserialize(&encoded).map_err(|e| FsError::Deserialize(format!("snapshot manifest: {}", e)))?;
+    let hasher = hasher(manifest.hash_algorithm)?;
+    let mut digest = Vec::new();
+    let mut header = Vec::new();
+    let mut buf = Vec::new();
+    for section in &manifest.sections {
+        if section.bytes.start > section.bytes.end {
+            return Err(FsError::InvalidArgument(format!("Snapshot section {:?} ends before it starts", section)));
+        }
+        if section.kind != SnapshotSectionKind::Header {
+            storage.grow(section.bytes.end)?;
+        }
+        for piece in pieces(&section.bytes) {
+            buf.resize((piece.end - piece.start) as usize, 0);
+            reader.read_exact(&mut buf).map_err(io_error)?;
+            digest = hasher.digest(&[digest.as_slice(), &buf].concat());
+            match section.kind {
+                SnapshotSectionKind::Header => header.push((piece.start, buf.clone())),
+                _ => storage.write(piece.start, &buf),
+            }
+        }
+    }
+    if read_bytes(reader, MAX_DIGEST_SIZE)? != digest {
+        return Err(FsError::InvalidArgument("Snapshot doesn't match its digest".to_string()));
+    }
+    for (offset, bytes) in header {
+        storage.grow(offset + bytes.len() as u64)?;
+        storage.write(offset, &bytes);
+    }
+    manifest.digest = digest;
+    Ok(manifest)
+}
+
+fn pieces(bytes: &Range<u64>) -> impl Iterator<Item = Range<u64>> + '_ {
+    (bytes.start..bytes.end).step_by(PIECE_SIZE as usize).map(|start| start..(start + PIECE_SIZE).min(bytes.end))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, FsError> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes).map_err(io_error)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut impl Read, limit: u32) -> Result<Vec<u8>, FsError> {
+    let len = read_u32(reader)?;
+    if len > limit {
+        return Err(FsError::InvalidArgument(format!("Snapshot field of {} bytes exceeds {}", len, limit)));
+    }
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes).map_err(io_error)?;
+    Ok(bytes)
+}
+
+fn io_error(e: std::io::Error) -> FsError {
+    FsError::Io(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::snapshot::pieces;
+
+    #[test]
+    fn it_splits_sections_into_pieces() {
+        assert_eq!(pieces(&(10..10)).count(), 0);
+        assert_eq!(pieces(&(0..65536)).collect::<Vec<_>>(), vec![0..65536]);
+        assert_eq!(pieces(&(100..140000)).collect::<Vec<_>>(), vec![100..65636, 65636..131172, 131172..140000]);
+    }
+}
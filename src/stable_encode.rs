@@ -0,0 +1,43 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+// Marks types whose encoding doesn't drift when their definition is edited in a compatible way. bincode
+// encodes struct fields in declaration order and enum variants by index, so implement it for structs
+// whose fields are only ever appended and for enums declared with versioned_event!, never for plain
+// enums. The payloads of a versioned enum should be StableEncode as well.
+pub trait StableEncode {}
+
+macro_rules! stable_encode {
+    ($($t:ty),+) => {
+        $(impl StableEncode for $t {})+
+    };
+}
+
+stable_encode!(bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, String, str, ());
+
+impl<T: StableEncode + ?Sized> StableEncode for &T {}
+impl<T: StableEncode + ?Sized> StableEncode for Box<T> {}
+impl<T: StableEncode> StableEncode for [T] {}
+impl<T: StableEncode, const N: usize> StableEncode for [T; N] {}
+impl<T: StableEncode> StableEncode for Vec<T> {}
+impl<T: StableEncode> StableEncode for Option<T> {}
+impl<T: StableEncode> StableEncode for BTreeSet<T> {}
+impl<K: StableEncode, V: StableEncode> StableEncode for BTreeMap<K, V> {}
+impl<A: StableEncode, B: StableEncode> StableEncode for (A, B) {}
+impl<A: StableEncode, B: StableEncode, C: StableEncode> StableEncode for (A, B, C) {}
+impl<A: StableEncode, B: StableEncode, C: StableEncode, D: StableEncode> StableEncode for (A, B, C, D) {}
+
+// What the write APIs accept. With the `strict` feature only StableEncode types can be written, so a
+// raw serde type whose layout can drift is caught at compile time.
+#[cfg(not(feature = "strict"))]
+pub trait Writable: Serialize {}
+
+#[cfg(not(feature = "strict"))]
+impl<T: Serialize + ?Sized> Writable for T {}
+
+#[cfg(feature = "strict")]
+pub trait Writable: Serialize + StableEncode {}
+
+#[cfg(feature = "strict")]
+impl<T: Serialize + StableEncode + ?Sized> Writable for T {}
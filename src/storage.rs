@@ -0,0 +1,402 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::any::Any;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::constants::WASM_PAGE_SIZE;
+use crate::error::FsError;
+use crate::read_write::{BlockGrow, BlockRead, BlockWrite};
+
+// Byte addressed memory a topic lives in. Reads of bytes that were never written must return zeroes,
+// stable memory and a zeroed Vec both do.
+pub trait BlockStorage: 'static {
+    fn read(&self, offset: u64, buf: &mut [u8]);
+    fn write(&mut self, offset: u64, data: &[u8]);
+
+    // Makes sure the first `end` bytes can be written. Storage that grows on writes has nothing to do.
+    fn grow(&mut self, _end: u64) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// The plain fn pointers topics were opened with before storage backends could hold state.
+#[derive(Debug, Clone, Copy)]
+pub struct FnStorage {
+    write_fn: BlockWrite,
+    read_fn: BlockRead,
+    grow_fn: Option<BlockGrow>,
+    // Pages the memory is known to have, so grow_fn is only called when a write reaches past them.
+    pages: u64,
+}
+
+impl FnStorage {
+    pub fn new(write_fn: BlockWrite, read_fn: BlockRead) -> Self {
+        FnStorage { write_fn, read_fn, grow_fn: None, pages: 0 }
+    }
+
+    // Grows the memory before writes past its end, e.g.
+    // `|pages| ic_cdk::api::stable::stable64_grow(pages).map_err(|e| e.to_string())`.
+    pub fn with_grow_fn(mut self, grow_fn: BlockGrow) -> Self {
+        self.grow_fn = Some(grow_fn);
+        self
+    }
+}
+
+impl BlockStorage for FnStorage {
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        (self.read_fn)(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        (self.write_fn)(offset, data)
+    }
+
+    fn grow(&mut self, end: u64) -> Result<(), String> {
+        let (Some(grow_fn), pages) = (self.grow_fn, end.div_ceil(WASM_PAGE_SIZE)) else {
+            return Ok(());
+        };
+        if pages <= self.pages {
+            return Ok(());
+        }
+        // Growing by nothing reports the current size, like stable64_grow.
+        self.pages = grow_fn(0)?;
+        if pages > self.pages {
+            grow_fn(pages - self.pages)?;
+            self.pages = pages;
+        }
+        Ok(())
+    }
+}
+
+// Heap memory that grows on writes, e.g. for tests or topics that don't need to survive an upgrade.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VecStorage {
+    pub bytes: Vec<u8>,
+}
+
+impl BlockStorage for VecStorage {
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let start = (offset as usize).min(self.bytes.len());
+        let end = (offset as usize + buf.len()).min(self.bytes.len());
+        buf[..end - start].copy_from_slice(&self.bytes[start..end]);
+        buf[end - start..].fill(0);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let end = offset as usize + data.len();
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+        self.bytes[offset as usize..end].copy_from_slice(data);
+    }
+}
+
+// A file holding a copy of a topic's memory, e.g. a stable memory dump, for tools that run off-chain.
+// Reads past the end of the file return zeroes, writes extend it. I/O errors panic like a trap would.
+#[derive(Debug)]
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(FileStorage { file: File::options().read(true).write(true).create(true).truncate(false).open(path)? })
+    }
+
+    // Opens the file read-only, writes then panic. Open the topic with
+    // EventFilesystemBuilder::open_read_only, a plain open writes to its meta zone.
+    pub fn open_read_only(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(FileStorage { file: File::open(path)? })
+    }
+}
+
+impl BlockStorage for FileStorage {
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset)).expect("file to seek");
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..]).expect("file to read") {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf[read..].fill(0);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        self.file.seek(SeekFrom::Start(offset)).expect("file to seek");
+        self.file.write_all(data).expect("file to write");
+    }
+}
+
+// A read-only memory map of a topic file, so scans over large files don't make a read call per block.
+// The mapping is private: writes, like the ones opening a topic makes, change the mapped pages but
+// never the file, and writes past its end panic.
+#[cfg(all(unix, feature = "mmap"))]
+#[derive(Debug)]
+pub struct MmapStorage {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl MmapStorage {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len()).map_err(|_| std::io::Error::from(std::io::ErrorKind::OutOfMemory))?;
+        if len == 0 {
+            return Ok(MmapStorage { ptr: std::ptr::null_mut(), len });
+        }
+        // SAFETY: a fresh private mapping of `len` bytes of a file we hold open, checked for failure. The
+        // mapping stays valid after the file is closed.
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(MmapStorage { ptr: ptr.cast(), len })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            // SAFETY: `ptr` maps `len` bytes until drop.
+            len => unsafe { std::slice::from_raw_parts(self.ptr, len) },
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        match self.len {
+            0 => &mut [],
+            // SAFETY: `ptr` maps `len` writable bytes until drop, `&mut self` makes this the only borrow.
+            len => unsafe { std::slice::from_raw_parts_mut(self.ptr, len) },
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Drop for MmapStorage {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: unmaps exactly the mapping made in `open`, no borrows of it outlive `self`.
+            unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl BlockStorage for MmapStorage {
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let bytes = self.bytes();
+        let start = (offset as usize).min(bytes.len());
+        let end = (offset as usize).saturating_add(buf.len()).min(bytes.len());
+        buf[..end - start].copy_from_slice(&bytes[start..end]);
+        buf[end - start..].fill(0);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let end = offset as usize + data.len();
+        assert!(end <= self.len, "write up to {} past the end of the {} byte mapping", end, self.len);
+        self.bytes_mut()[offset as usize..end].copy_from_slice(data);
+    }
+}
+
+// The canister's stable memory, grown page by page as writes reach past its end. Traps when it can't
+// grow, which rolls the call back.
+#[cfg(feature = "ic")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StableMemoryStorage;
+
+#[cfg(feature = "ic")]
+impl BlockStorage for StableMemoryStorage {
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        ic_cdk::api::stable::stable64_read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        self.grow(offset + data.len() as u64).expect("stable memory to grow");
+        ic_cdk::api::stable::stable64_write(offset, data)
+    }
+
+    fn grow(&mut self, end: u64) -> Result<(), String> {
+        let pages = end.div_ceil(WASM_PAGE_SIZE);
+        let size = ic_cdk::api::stable::stable64_size();
+        if pages > size {
+            ic_cdk::api::stable::stable64_grow(pages - size).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+// A shared handle to a topic's storage, cheap to clone. The filesystem and the structures it keeps in
+// stable memory, like the meta zone's arena, each hold one.
+#[derive(Clone)]
+pub struct Storage(Rc<RefCell<dyn BlockStorage>>);
+
+impl Storage {
+    pub fn new(storage: impl BlockStorage) -> Self {
+        Storage(Rc::new(RefCell::new(storage)))
+    }
+
+    pub(crate) fn shared<S: BlockStorage>(storage: &Rc<RefCell<S>>) -> Self {
+        Storage(storage.clone())
+    }
+
+    pub fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.0.borrow().read(offset, buf)
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) {
+        self.0.borrow_mut().write(offset, data)
+    }
+
+    // Like write, but a backend that panics, e.g. a file that can't be written, is reported instead of
+    // unwinding through the caller's state. Traps on the IC can't be caught, they roll the call back.
+    pub fn try_write(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        catch_unwind(AssertUnwindSafe(|| self.write(offset, data)))
+            .map_err(|panic| FsError::WriteFailed { offset, reason: panic_message(panic) })
+    }
+
+    // Several writes under one borrow and one unwind guard, in order. A failure reports the offset of
+    // the write that panicked, the ones before it are done.
+    pub fn try_write_all(&self, writes: &[(u64, &[u8])]) -> Result<(), FsError> {
+        let mut current = writes.first().map_or(0, |(offset, _)| *offset);
+        catch_unwind(AssertUnwindSafe(|| {
+            let mut storage = self.0.borrow_mut();
+            for (offset, data) in writes {
+                current = *offset;
+                storage.write(*offset, data);
+            }
+        })).map_err(|panic| FsError::WriteFailed { offset: current, reason: panic_message(panic) })
+    }
+
+    // A backend that panics while growing reports GrowFailed like one that returns an error.
+    pub fn grow(&self, end: u64) -> Result<(), FsError> {
+        catch_unwind(AssertUnwindSafe(|| self.0.borrow_mut().grow(end)))
+            .unwrap_or_else(|panic| Err(panic_message(panic)))
+            .map_err(|reason| FsError::GrowFailed { needed: end, reason })
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map_or("the storage panicked", |message| message).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use crate::{EventFilesystemBuilder, FsError};
+    use crate::fixtures::small_layout;
+    use crate::storage::{BlockStorage, FileStorage, FnStorage, Storage, VecStorage};
+
+    #[test]
+    fn it_grows_vec_storage_on_write() {
+        let mut storage = VecStorage::default();
+        storage.write(4, &[1, 2]);
+        let mut buf = [9u8; 8];
+        storage.read(3, &mut buf);
+        assert_eq!(buf, [0, 1, 2, 0, 0, 0, 0, 0]);
+
+        let shared = Storage::new(storage);
+        let other = shared.clone();
+        other.write(0, &[7]);
+        let mut buf = [0u8; 1];
+        shared.read(0, &mut buf);
+        assert_eq!(buf, [7]);
+    }
+
+    #[test]
+    fn it_reports_the_write_that_failed() {
+        struct Failing(VecStorage);
+        impl BlockStorage for Failing {
+            fn read(&self, offset: u64, buf: &mut [u8]) {
+                self.0.read(offset, buf)
+            }
+
+            fn write(&mut self, offset: u64, data: &[u8]) {
+                assert!(offset < 100, "disk full");
+                self.0.write(offset, data)
+            }
+        }
+        let storage = Storage::new(Failing(VecStorage::default()));
+        storage.try_write_all(&[(0, &[1]), (10, &[2, 3])]).unwrap();
+        let failed = storage.try_write_all(&[(20, &[4]), (100, &[5])]);
+        assert!(matches!(failed, Err(FsError::WriteFailed { offset: 100, reason }) if reason == "disk full"));
+        let mut buf = [0u8; 21];
+        storage.read(0, &mut buf);
+        assert_eq!((buf[0], buf[10], buf[11], buf[20]), (1, 2, 3, 4));
+        // the panic released the borrow
+        storage.write(0, &[6]);
+    }
+
+    #[test]
+    fn it_grows_fn_storage_only_past_its_end() {
+        thread_local! {
+            static PAGES: Cell<u64> = const { Cell::new(1) };
+            static CALLS: Cell<u64> = const { Cell::new(0) };
+        }
+        fn grow(pages: u64) -> Result<u64, String> {
+            CALLS.with(|calls| calls.set(calls.get() + 1));
+            PAGES.with(|size| Ok(size.replace(size.get() + pages)))
+        }
+        let mut storage = FnStorage::new(|_, _| {}, |_, _| {}).with_grow_fn(grow);
+        storage.grow(100).unwrap();
+        assert_eq!((PAGES.with(Cell::get), CALLS.with(Cell::get)), (1, 1));
+        storage.grow(3 * 65536).unwrap();
+        storage.grow(65536).unwrap();
+        assert_eq!((PAGES.with(Cell::get), CALLS.with(Cell::get)), (3, 3));
+        assert!(FnStorage::new(|_, _| {}, |_, _| {}).grow(u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn it_reads_topics_from_files() {
+        let path = std::env::temp_dir().join(format!("ic_event_fs_topic_{}", std::process::id()));
+        let small = small_layout();
+        let topic = EventFilesystemBuilder::with_storage(FileStorage::open(&path).unwrap(), || 0).layout(small).get_or_create("file".to_string()).unwrap();
+        for i in 0..3u64 {
+            topic.write_topic_message(&format!("event {}", i)).unwrap();
+        }
+        drop(topic);
+        let written = std::fs::read(&path).unwrap();
+
+        let mut buf = [9u8; 4];
+        FileStorage::open_read_only(&path).unwrap().read(written.len() as u64 - 2, &mut buf);
+        assert_eq!(buf[2..], [0, 0]);
+        let topic = EventFilesystemBuilder::with_storage(FileStorage::open_read_only(&path).unwrap(), || 0).open_read_only().unwrap();
+        assert_eq!(topic.read_topic_messages::<String>(0, 3).unwrap(), vec!["event 0", "event 1", "event 2"]);
+        drop(topic);
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+        let topic = EventFilesystemBuilder::with_storage(FileStorage::open(&path).unwrap(), || 0).open().unwrap();
+        assert_eq!(topic.read_topic_messages::<String>(0, 3).unwrap(), vec!["event 0", "event 1", "event 2"]);
+        #[cfg(all(unix, feature = "mmap"))]
+        {
+            let written = std::fs::read(&path).unwrap();
+            let mapped = crate::storage::MmapStorage::open(&path).unwrap();
+            assert_eq!(mapped.len(), written.len() as u64);
+            let topic = EventFilesystemBuilder::with_storage(mapped, || 0).open().unwrap();
+            assert_eq!(topic.read_topic_message::<String>(2).unwrap(), "event 2");
+            drop(topic);
+            assert_eq!(std::fs::read(&path).unwrap(), written);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
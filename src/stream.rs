@@ -0,0 +1,104 @@
+use candid::CandidType;
+#[cfg(feature = "ic")]
+use candid::Principal;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::codec::BincodeCodec;
+use crate::error::FsError;
+
+// Batches stay well below the 2MB inter-canister message limit, but always carry one message at least.
+pub const MAX_STREAM_BATCH: u32 = 1000;
+pub const MAX_STREAM_BATCH_BYTES: u64 = 1536 * 1024;
+
+// Candid protocol between two canisters using ic_fs. The serving canister exposes a method taking a
+// StreamRequest and returning Result<StreamResponse, String>, see EventFilesystem::serve_stream.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StreamRequest {
+    // Token from a previous response, None starts at the first message.
+    pub cursor: Option<String>,
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StreamResponse {
+    pub batch: Vec<StreamEvent>,
+    // Where the next request continues, the request's position again once the batch is empty.
+    pub next_cursor: String,
+    // Committed height of the topic when the batch was read.
+    pub height: u64,
+    // `ic_cdk::api::data_certificate()` of the serving canister, only available in query calls.
+    pub certificate: Option<Vec<u8>>,
+}
+
+// Payloads are sent as stored, encoded with the codec the message was written with. Markers have an
+// empty payload.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct StreamEvent {
+    pub height: u64,
+    pub timestamp: u64,
+    pub payload: Vec<u8>,
+}
+
+impl StreamEvent {
+    pub fn is_marker(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, codec: &BincodeCodec) -> Result<T, FsError> {
+        codec.deserialize(&self.payload)
+    }
+}
+
+// Pulls batches from a canister serving a topic and keeps the cursor between calls. Keep the client
+// in canister state (or its cursor in stable memory) to resume where the last pull stopped.
+#[cfg(feature = "ic")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamClient {
+    pub canister: Principal,
+    pub method: String,
+    pub cursor: Option<String>,
+}
+
+#[cfg(feature = "ic")]
+impl StreamClient {
+    pub fn new(canister: Principal, method: &str) -> Self {
+        StreamClient { canister, method: method.to_string(), cursor: None }
+    }
+
+    pub fn resume(canister: Principal, method: &str, cursor: String) -> Self {
+        StreamClient { canister, method: method.to_string(), cursor: Some(cursor) }
+    }
+
+    // The cursor only moves once the response arrived, a failed call can simply be retried.
+    pub async fn next_batch(&mut self, limit: u32) -> Result<StreamResponse, FsError> {
+        let request = StreamRequest { cursor: self.cursor.clone(), limit };
+        let (result,): (Result<StreamResponse, String>,) = ic_cdk::api::call::call(self.canister, &self.method, (request,))
+            .await
+            .map_err(|(code, message)| FsError::Remote(format!("{} rejected the call ({:?}): {}", self.canister, code, message)))?;
+        let response = result.map_err(FsError::Remote)?;
+        self.cursor = Some(response.next_cursor.clone());
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use candid::{Decode, Encode};
+
+    use crate::stream::{StreamEvent, StreamRequest, StreamResponse};
+
+    #[test]
+    fn it_round_trips_through_candid() {
+        let request = StreamRequest { cursor: Some("token".to_string()), limit: 10 };
+        assert_eq!(Decode!(&Encode!(&request).unwrap(), StreamRequest).unwrap(), request);
+
+        let response: Result<StreamResponse, String> = Ok(StreamResponse {
+            batch: vec![StreamEvent { height: 0, timestamp: 5, payload: vec![1, 2] }],
+            next_cursor: "next".to_string(),
+            height: 1,
+            certificate: None,
+        });
+        assert_eq!(Decode!(&Encode!(&response).unwrap(), Result<StreamResponse, String>).unwrap(), response);
+    }
+}
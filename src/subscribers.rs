@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+
+// Room for a few hundred subscribers, a principal and its offset take about 40 bytes.
+pub const SUBSCRIBER_REGION_SIZE: u64 = 16 * 1024;
+
+// Subscribers and the height up to which each has committed, i.e. the next message it will read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SubscriberRegistry {
+    offsets: BTreeMap<Principal, u64>,
+}
+
+impl SubscriberRegistry {
+    pub(crate) fn add(&mut self, subscriber: Principal, offset: u64) -> Result<(), FsError> {
+        if self.offsets.contains_key(&subscriber) {
+            return Err(FsError::InvalidArgument(format!("{} is already subscribed", subscriber)));
+        }
+        self.offsets.insert(subscriber, offset);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, subscriber: &Principal) -> Result<(), FsError> {
+        self.offsets.remove(subscriber).map(|_| ()).ok_or_else(|| unknown(subscriber))
+    }
+
+    pub(crate) fn commit(&mut self, subscriber: &Principal, offset: u64) -> Result<(), FsError> {
+        let committed = self.offsets.get_mut(subscriber).ok_or_else(|| unknown(subscriber))?;
+        *committed = offset;
+        Ok(())
+    }
+
+    pub(crate) fn remap(&mut self, remap: impl Fn(u64) -> u64) {
+        self.offsets.values_mut().for_each(|offset| *offset = remap(*offset));
+    }
+
+    pub(crate) fn offset(&self, subscriber: &Principal) -> Option<u64> {
+        self.offsets.get(subscriber).copied()
+    }
+
+    pub(crate) fn subscribers(&self) -> Vec<(Principal, u64)> {
+        self.offsets.iter().map(|(subscriber, offset)| (*subscriber, *offset)).collect()
+    }
+
+    // Subscribers more than `max_lag` messages behind `height`, with how far behind they are.
+    pub(crate) fn lagging(&self, height: u64, max_lag: u64) -> Vec<(Principal, u64)> {
+        self.offsets.iter()
+            .map(|(subscriber, offset)| (*subscriber, height.saturating_sub(*offset)))
+            .filter(|(_, lag)| *lag > max_lag)
+            .collect()
+    }
+}
+
+fn unknown(subscriber: &Principal) -> FsError {
+    FsError::InvalidArgument(format!("{} is not subscribed", subscriber))
+}
+
+#[cfg(test)]
+mod test {
+    use candid::Principal;
+
+    use crate::subscribers::SubscriberRegistry;
+
+    #[test]
+    fn it_tracks_offsets_and_lag() {
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut registry = SubscriberRegistry::default();
+        registry.add(a, 0).unwrap();
+        registry.add(b, 8).unwrap();
+        assert!(registry.add(a, 3).is_err());
+        assert_eq!(registry.lagging(10, 2), vec![(a, 10)]);
+
+        registry.commit(&a, 9).unwrap();
+        assert_eq!(registry.lagging(10, 0), vec![(a, 1), (b, 2)]);
+        registry.remove(&b).unwrap();
+        assert!(registry.commit(&b, 10).is_err());
+        assert_eq!(registry.subscribers(), vec![(a, 9)]);
+    }
+}
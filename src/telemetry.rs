@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeCodec;
+use crate::compression::PayloadCompression;
+use crate::hash::HashAlgorithm;
+use crate::retention::RetentionPolicy;
+
+// Usage since telemetry was enabled, kept in the meta zone so it survives upgrades. Only updates that
+// commit are counted, calls that trap roll their counts back with everything else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageCounters {
+    // Clock time telemetry was enabled at.
+    pub since: u64,
+    pub opens: u64,
+    pub messages_written: u64,
+    pub markers_written: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+}
+
+impl UsageCounters {
+    pub(crate) fn new(since: u64) -> Self {
+        UsageCounters { since, ..UsageCounters::default() }
+    }
+
+    pub(crate) fn record_write(&mut self, data_size: u64) {
+        match data_size {
+            0 => self.markers_written += 1,
+            bytes => {
+                self.messages_written += 1;
+                self.bytes_written += bytes;
+            }
+        }
+    }
+}
+
+// Which optional features a topic has switched on, for operators inventorying many canisters. The
+// features are read from the topic's settings every time, `usage` is None unless telemetry is enabled.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Telemetry {
+    pub binary_version: u32,
+    pub hash_algorithm: HashAlgorithm,
+    pub codec: BincodeCodec,
+    pub compression: PayloadCompression,
+    pub checksums: bool,
+    pub event_times: bool,
+    pub message_ids: bool,
+    pub deduplication: bool,
+    pub schema: bool,
+    pub aggregates: bool,
+    pub heat_map: bool,
+    pub retention: Option<RetentionPolicy>,
+    pub access_control: bool,
+    pub controllers: u64,
+    pub subscribers: u64,
+    pub cursors: u64,
+    pub registers: u64,
+    pub regions: u64,
+    pub usage: Option<UsageCounters>,
+}
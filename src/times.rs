@@ -0,0 +1,126 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeDomain {
+    // When the event happened, as given by the writer. Not ordered by height.
+    Event,
+    // When the topic stored the message, from its clock. Ordered by height as long as the clock is.
+    Ingestion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageTimes {
+    pub height: u64,
+    pub ingestion_time: u64,
+    // None for markers and messages written while event times were disabled.
+    pub event_time: Option<u64>,
+}
+
+impl MessageTimes {
+    pub fn time(&self, domain: TimeDomain) -> Option<u64> {
+        match domain {
+            TimeDomain::Event => self.event_time,
+            TimeDomain::Ingestion => Some(self.ingestion_time),
+        }
+    }
+
+    // How long after the event the topic stored it, negative when the writer's clock is ahead.
+    pub fn clock_skew(&self) -> Option<i128> {
+        self.event_time.map(|event_time| self.ingestion_time as i128 - event_time as i128)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct CorrectionRun {
+    start: u64,
+    len: u64,
+    time: u64,
+}
+
+// Ingestion times that went back because the clock regressed, e.g. on topics written before the clock
+// was monotonic. Such messages are treated as stored at the latest time before them, which keeps time
+// seeks ordered. Consecutive regressed messages share that time, so they are kept as runs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TimeCorrections {
+    scanned: u64,
+    latest: u64,
+    runs: Vec<CorrectionRun>,
+}
+
+impl TimeCorrections {
+    pub(crate) fn scanned(&self) -> u64 {
+        self.scanned
+    }
+
+    // Records the ingestion time of the next height to scan.
+    pub(crate) fn scan(&mut self, timestamp: u64) {
+        let height = self.scanned;
+        self.scanned += 1;
+        if timestamp >= self.latest {
+            self.latest = timestamp;
+            return;
+        }
+        match self.runs.last_mut() {
+            Some(run) if run.start + run.len == height && run.time == self.latest => run.len += 1,
+            _ => self.runs.push(CorrectionRun { start: height, len: 1, time: self.latest }),
+        }
+    }
+
+    pub(crate) fn corrected(&self, height: u64, timestamp: u64) -> u64 {
+        let run = self.runs[..self.runs.partition_point(|run| run.start <= height)].last();
+        match run {
+            Some(run) if height < run.start + run.len => run.time,
+            _ => timestamp,
+        }
+    }
+
+    pub(crate) fn corrections(&self) -> impl Iterator<Item = (Range<u64>, u64)> + '_ {
+        self.runs.iter().map(|run| (run.start..run.start + run.len, run.time))
+    }
+
+    pub(crate) fn corrected_count(&self) -> u64 {
+        self.runs.iter().map(|run| run.len).sum()
+    }
+}
+
+// Progress of repair_timestamps, which can stop when its budget runs out and resume on the next call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimestampRepair {
+    pub scanned: u64,
+    pub height: u64,
+    // Messages whose ingestion time went back and is corrected for time seeks.
+    pub corrected: u64,
+}
+
+impl TimestampRepair {
+    pub fn is_done(&self) -> bool {
+        self.scanned == self.height
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::times::{MessageTimes, TimeCorrections, TimeDomain};
+
+    #[test]
+    fn it_corrects_regressed_times_as_runs() {
+        let mut corrections = TimeCorrections::default();
+        for timestamp in [10, 20, 5, 7, 30, 25, 40] {
+            corrections.scan(timestamp);
+        }
+        assert_eq!(corrections.corrections().collect::<Vec<_>>(), vec![(2..4, 20), (5..6, 30)]);
+        assert_eq!(corrections.corrected(3, 7), 20);
+        assert_eq!(corrections.corrected(4, 30), 30);
+        assert_eq!(corrections.corrected_count(), 3);
+    }
+
+    #[test]
+    fn it_reports_clock_skew() {
+        let times = MessageTimes { height: 0, ingestion_time: 100, event_time: Some(130) };
+        assert_eq!(times.clock_skew(), Some(-30));
+        assert_eq!(times.time(TimeDomain::Ingestion), Some(100));
+        assert_eq!(MessageTimes { event_time: None, ..times }.time(TimeDomain::Event), None);
+    }
+}
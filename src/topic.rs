@@ -0,0 +1,107 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::{BlockStorage, EventFilesystem, FnStorage, Writable};
+use crate::error::FsError;
+
+// A view of a topic that only writes and reads messages of type `T`.
+pub struct Topic<'a, T, S: BlockStorage = FnStorage> {
+    fs: &'a EventFilesystem<S>,
+    _message: PhantomData<fn(T) -> T>,
+}
+
+// Messages appended inside Topic::batch, nothing is written before the closure returns.
+pub struct Batch<T> {
+    messages: Vec<T>,
+}
+
+impl<T> Batch<T> {
+    pub fn append(&mut self, message: T) -> &mut Self {
+        self.messages.push(message);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl<'a, T: Writable + DeserializeOwned, S: BlockStorage> Topic<'a, T, S> {
+    pub fn new(fs: &'a EventFilesystem<S>) -> Self {
+        Topic { fs, _message: PhantomData }
+    }
+
+    pub fn append(&self, message: &T) -> Result<u64, FsError> {
+        self.fs.write_topic_message(message)
+    }
+
+    pub fn read(&self, height: u64) -> Result<T, FsError> {
+        self.fs.read_topic_message(height)
+    }
+
+    pub fn read_range(&self, start: u64, take: u64) -> Result<Vec<T>, FsError> {
+        self.fs.read_topic_messages(start, take)
+    }
+
+    pub fn height(&self) -> u64 {
+        self.fs.get_topic_height()
+    }
+
+    // Commits everything `f` appended as one write_topic_messages call, so either all of it lands or
+    // none of it does. Returning an error, e.g. with `?`, or panicking discards the batch.
+    pub fn batch<E: From<FsError>>(&self, f: impl FnOnce(&mut Batch<T>) -> Result<(), E>) -> Result<Vec<u64>, E> {
+        let mut batch = Batch { messages: Vec::new() };
+        f(&mut batch)?;
+        Ok(self.fs.write_topic_messages(&batch.messages)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    use crate::{EventFilesystemBuilder, FsError, VecStorage};
+    #[cfg(feature = "schema")]
+    use crate::MessageSchema;
+
+    #[test]
+    fn it_commits_batches_atomically() {
+        let fs = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).get_or_create("test".to_string()).unwrap();
+        let topic = fs.topic::<String>();
+        assert_eq!(topic.append(&"first".to_string()), Ok(0));
+        let heights = topic.batch(|b| {
+            b.append("second".to_string()).append("third".to_string());
+            Ok::<_, FsError>(())
+        });
+        assert_eq!(heights, Ok(vec![1, 2]));
+
+        let aborted = topic.batch(|b| {
+            b.append("dropped".to_string());
+            Err(FsError::InvalidState("abort".to_string()))
+        });
+        assert!(aborted.is_err());
+        let panicked = catch_unwind(AssertUnwindSafe(|| topic.batch(|b| -> Result<(), FsError> {
+            b.append("dropped".to_string());
+            panic!("abort")
+        })));
+        assert!(panicked.is_err());
+        assert_eq!(topic.height(), 3);
+
+        #[cfg(feature = "schema")]
+        {
+            fs.set_schema(Some(MessageSchema::parse(r#"{ "type": "string", "maxLength": 5 }"#).unwrap())).unwrap();
+            let rejected = topic.batch(|b| {
+                b.append("short".to_string()).append("too long".to_string());
+                Ok::<_, FsError>(())
+            });
+            assert!(matches!(rejected, Err(FsError::SchemaViolation { .. })));
+            assert_eq!(topic.read_range(0, 3).unwrap(), vec!["first", "second", "third"]);
+            assert_eq!(topic.height(), 3);
+        }
+    }
+}
@@ -1,20 +1,133 @@
-use std::io::Write;
+use std::collections::BTreeMap;
 
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+use crate::hash::HashAlgorithm;
+use crate::layout::{LayoutConfig, LegacyLayoutConfig};
 
 pub const TOPIC_HEADER_MAGIC: u64 = 123246369;
+// Held in the magic slot while a topic is being created, replaced by TOPIC_HEADER_MAGIC as the last write.
+pub const TOPIC_INITIALIZING_MAGIC: u64 = 123246370;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TopicHeaderBlock {
     pub event_stream_name: String,
     pub first_message_ptr: u64,
     pub binary_version: u32,
+    pub hash_algorithm: u8,
+    pub layout: LayoutConfig,
+    // Write-once values set with set_once, they share the header zone with the fields above.
+    pub registers: BTreeMap<String, Vec<u8>>,
+}
+
+// Header layout written before the block size was configurable, those topics use 512 byte blocks.
+#[derive(Deserialize)]
+struct RegistersTopicHeaderBlock {
+    event_stream_name: String,
+    first_message_ptr: u64,
+    binary_version: u32,
+    hash_algorithm: u8,
+    layout: LegacyLayoutConfig,
+    registers: BTreeMap<String, Vec<u8>>,
+}
+
+// Header layout written before write-once registers were added.
+#[derive(Deserialize)]
+struct LayoutTopicHeaderBlock {
+    event_stream_name: String,
+    first_message_ptr: u64,
+    binary_version: u32,
+    hash_algorithm: u8,
+    layout: LegacyLayoutConfig,
+}
+
+// Header layout written before zone sizes were configurable, those topics use the default layout.
+#[derive(Deserialize)]
+struct HashedTopicHeaderBlock {
+    event_stream_name: String,
+    first_message_ptr: u64,
+    binary_version: u32,
+    hash_algorithm: u8,
+}
+
+// Header layout written before the hash algorithm was recorded.
+#[derive(Deserialize)]
+struct LegacyTopicHeaderBlock {
+    event_stream_name: String,
+    first_message_ptr: u64,
+    binary_version: u32,
+}
+
+impl TopicHeaderBlock {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FsError> {
+        Self::parse(bytes).map(|(header, _)| header)
+    }
+
+    // Also tells whether the header recorded its layout, topics created before that have no meta zone.
+    pub(crate) fn parse(bytes: &[u8]) -> Result<(Self, bool), FsError> {
+        // Older headers rarely parse as newer ones, a layout that doesn't validate gives them away.
+        if let Ok(header) = bincode::deserialize::<TopicHeaderBlock>(bytes) {
+            if header.layout.validate().is_ok() {
+                return Ok((header, true));
+            }
+        }
+        if let Ok(header) = bincode::deserialize::<RegistersTopicHeaderBlock>(bytes) {
+            return Ok((TopicHeaderBlock {
+                event_stream_name: header.event_stream_name,
+                first_message_ptr: header.first_message_ptr,
+                binary_version: header.binary_version,
+                hash_algorithm: header.hash_algorithm,
+                layout: header.layout.into(),
+                registers: header.registers,
+            }, true));
+        }
+        if let Ok(header) = bincode::deserialize::<LayoutTopicHeaderBlock>(bytes) {
+            return Ok((TopicHeaderBlock {
+                event_stream_name: header.event_stream_name,
+                first_message_ptr: header.first_message_ptr,
+                binary_version: header.binary_version,
+                hash_algorithm: header.hash_algorithm,
+                layout: header.layout.into(),
+                registers: BTreeMap::new(),
+            }, true));
+        }
+        if let Ok(hashed) = bincode::deserialize::<HashedTopicHeaderBlock>(bytes) {
+            return Ok((TopicHeaderBlock {
+                event_stream_name: hashed.event_stream_name,
+                first_message_ptr: hashed.first_message_ptr,
+                binary_version: hashed.binary_version,
+                hash_algorithm: hashed.hash_algorithm,
+                layout: LayoutConfig::default(),
+                registers: BTreeMap::new(),
+            }, false));
+        }
+        let legacy: LegacyTopicHeaderBlock = bincode::deserialize(bytes)
+            .map_err(|e| FsError::Deserialize(format!("topic header: {}", e)))?;
+        Ok((TopicHeaderBlock {
+            event_stream_name: legacy.event_stream_name,
+            first_message_ptr: legacy.first_message_ptr,
+            binary_version: legacy.binary_version,
+            hash_algorithm: HashAlgorithm::Sha256.id(),
+            layout: LayoutConfig::default(),
+            registers: BTreeMap::new(),
+        }, false))
+    }
+
+    pub fn hash_algorithm(&self) -> Result<HashAlgorithm, FsError> {
+        HashAlgorithm::from_id(self.hash_algorithm)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::index_block::IndexBlock;
-    use crate::topic_header_block::{TOPIC_HEADER_MAGIC, TopicHeaderBlock};
+    use std::collections::BTreeMap;
+
+    use serde::Serialize;
+
+    use crate::hash::HashAlgorithm;
+    use crate::layout::LayoutConfig;
+    use crate::topic_header_block::TopicHeaderBlock;
 
     #[test]
     fn it_serializes_and_deserializes() {
@@ -22,10 +135,110 @@ mod test {
             event_stream_name: "test_stream".parse().unwrap(),
             first_message_ptr: 0,
             binary_version: 1_000_000,
+            hash_algorithm: HashAlgorithm::Sha256.id(),
+            layout: LayoutConfig::default(),
+            registers: BTreeMap::from([("deployment".to_string(), vec![1, 2, 3])]),
         };
 
         let res = bincode::serialize(&idx).unwrap();
         assert!(res.len() <= 512);
         assert_eq!(idx, bincode::deserialize(&res).unwrap());
+        assert_eq!(idx, TopicHeaderBlock::from_bytes(&res).unwrap());
+    }
+
+    #[test]
+    fn it_reads_headers_without_hash_algorithm() {
+        #[derive(Serialize)]
+        struct Legacy {
+            event_stream_name: String,
+            first_message_ptr: u64,
+            binary_version: u32,
+        }
+
+        let bytes = bincode::serialize(&Legacy {
+            event_stream_name: "old_stream".to_string(),
+            first_message_ptr: 3,
+            binary_version: 1_000_000,
+        }).unwrap();
+
+        let header = TopicHeaderBlock::from_bytes(&bytes).unwrap();
+        assert_eq!(header.event_stream_name, "old_stream");
+        assert_eq!(header.first_message_ptr, 3);
+        assert_eq!(header.hash_algorithm().unwrap(), HashAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn it_reads_headers_without_layout() {
+        #[derive(Serialize)]
+        struct Hashed {
+            event_stream_name: String,
+            first_message_ptr: u64,
+            binary_version: u32,
+            hash_algorithm: u8,
+        }
+
+        let bytes = bincode::serialize(&Hashed {
+            event_stream_name: "hashed_stream".to_string(),
+            first_message_ptr: 0,
+            binary_version: 1_000_000,
+            hash_algorithm: HashAlgorithm::Crc32.id(),
+        }).unwrap();
+
+        let header = TopicHeaderBlock::from_bytes(&bytes).unwrap();
+        assert_eq!(header.hash_algorithm().unwrap(), HashAlgorithm::Crc32);
+        assert_eq!(header.layout, LayoutConfig::default());
+    }
+
+    #[test]
+    fn it_reads_headers_without_registers() {
+        #[derive(Serialize)]
+        struct Layout {
+            event_stream_name: String,
+            first_message_ptr: u64,
+            binary_version: u32,
+            hash_algorithm: u8,
+            layout: (u64, u64, u64),
+        }
+
+        let layout = LayoutConfig { index_zone_size: 4096, ..LayoutConfig::default() };
+        let bytes = bincode::serialize(&Layout {
+            event_stream_name: "sized_stream".to_string(),
+            first_message_ptr: 0,
+            binary_version: 1_000_000,
+            hash_algorithm: HashAlgorithm::Sha256.id(),
+            layout: (layout.free_memory_block_size, layout.meta_zone_size, layout.index_zone_size),
+        }).unwrap();
+
+        let header = TopicHeaderBlock::from_bytes(&bytes).unwrap();
+        assert_eq!(header.layout, layout);
+        assert!(header.registers.is_empty());
+    }
+
+    #[test]
+    fn it_reads_headers_without_block_size() {
+        #[derive(Serialize)]
+        struct Registers {
+            event_stream_name: String,
+            first_message_ptr: u64,
+            binary_version: u32,
+            hash_algorithm: u8,
+            layout: (u64, u64, u64),
+            registers: BTreeMap<String, Vec<u8>>,
+        }
+
+        let layout = LayoutConfig::default();
+        for registers in [BTreeMap::new(), BTreeMap::from([("deployment".to_string(), vec![7; 16])])] {
+            let bytes = bincode::serialize(&Registers {
+                event_stream_name: "registered_stream".to_string(),
+                first_message_ptr: 2,
+                binary_version: 1_000_000,
+                hash_algorithm: HashAlgorithm::Sha256.id(),
+                layout: (layout.free_memory_block_size, layout.meta_zone_size, layout.index_zone_size),
+                registers: registers.clone(),
+            }).unwrap();
+
+            let header = TopicHeaderBlock::from_bytes(&bytes).unwrap();
+            assert_eq!((header.layout, header.first_message_ptr, header.registers), (layout, 2, registers));
+        }
     }
 }
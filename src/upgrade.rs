@@ -0,0 +1,64 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{BlockStorage, EventFilesystem};
+use crate::error::FsError;
+use crate::hash::{Hasher, Sha256Hasher};
+
+// Starts the state saved by pre_upgrade_save, so restoring what stable_store wrote fails clearly.
+pub const UPGRADE_MAGIC: &[u8; 8] = b"ICFSUPG1";
+
+const CHECKSUM_SIZE: usize = 32;
+
+// Keeps canister state across an upgrade in the stable store zone, replacing what stable_store
+// holds. Call it from #[pre_upgrade] and restore with post_upgrade_restore.
+pub fn pre_upgrade_save<T: Serialize, S: BlockStorage>(fs: &EventFilesystem<S>, state: &T) -> Result<(), FsError> {
+    let state = bincode::serialize(state).map_err(|e| FsError::Serialize(e.to_string()))?;
+    let mut data = Vec::with_capacity(UPGRADE_MAGIC.len() + CHECKSUM_SIZE + state.len());
+    data.extend_from_slice(UPGRADE_MAGIC);
+    data.extend_from_slice(&Sha256Hasher.digest(&state));
+    data.extend_from_slice(&state);
+    fs.store_bytes(data)
+}
+
+// The state saved by pre_upgrade_save, checked against its checksum before it is decoded.
+pub fn post_upgrade_restore<T: DeserializeOwned, S: BlockStorage>(fs: &EventFilesystem<S>) -> Result<T, FsError> {
+    let data = fs.restore_bytes()?;
+    let header = UPGRADE_MAGIC.len() + CHECKSUM_SIZE;
+    if data.len() < header || &data[..UPGRADE_MAGIC.len()] != UPGRADE_MAGIC {
+        return Err(FsError::InvalidState("The stable store holds no state saved by pre_upgrade_save".to_string()));
+    }
+    let (checksum, state) = data[UPGRADE_MAGIC.len()..].split_at(CHECKSUM_SIZE);
+    if Sha256Hasher.digest(state) != checksum {
+        return Err(FsError::InvalidState("The upgrade state doesn't match its checksum".to_string()));
+    }
+    bincode::deserialize(state).map_err(|e| FsError::Deserialize(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{EventFilesystemBuilder, VecStorage};
+    use crate::constants::FREE_MEMORY_BLOCK_START_IDX;
+    use crate::fixtures::small_layout;
+    use crate::upgrade::{post_upgrade_restore, pre_upgrade_save, UPGRADE_MAGIC};
+
+    #[test]
+    fn it_restores_the_state_saved_before_an_upgrade() {
+        let layout = small_layout();
+        let builder = EventFilesystemBuilder::with_storage(VecStorage::default(), || 0).layout(layout);
+        let fs = builder.clone().get_or_create("state".to_string()).unwrap();
+        fs.stable_store(7u64).unwrap();
+        assert!(post_upgrade_restore::<u64, _>(&fs).is_err());
+
+        let state = BTreeMap::from([("a".to_string(), 1u64), ("b".to_string(), 2)]);
+        pre_upgrade_save(&fs, &state).unwrap();
+        let fs = builder.open().unwrap();
+        assert_eq!(post_upgrade_restore::<BTreeMap<String, u64>, _>(&fs).unwrap(), state);
+
+        let offset = FREE_MEMORY_BLOCK_START_IDX + UPGRADE_MAGIC.len() as u64 + 32;
+        fs.storage.write(offset, &[0xff]);
+        assert!(post_upgrade_restore::<BTreeMap<String, u64>, _>(&fs).is_err());
+    }
+}
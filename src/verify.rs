@@ -0,0 +1,120 @@
+#[cfg(feature = "jobs")]
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+#[cfg(feature = "jobs")]
+use crate::export::IndexExportEntry;
+#[cfg(feature = "jobs")]
+use crate::hash::Hasher;
+#[cfg(feature = "jobs")]
+use crate::jobs::ExportChunk;
+
+// Outcome of EventFilesystem::verify_all.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CorruptionReport {
+    pub scanned: u64,
+    // Messages whose checksum matched.
+    pub verified: u64,
+    // Markers and messages written while checksums were disabled, only their index entry was checked.
+    pub unchecked: u64,
+    pub corrupt: Vec<(u64, FsError)>,
+}
+
+impl CorruptionReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+// Outcome of EventFilesystem::verify_restorable. Problems are listed by chunk sequence, parsing stops
+// at the first chunk that doesn't follow the ones before it.
+#[cfg(feature = "jobs")]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RestoreReport {
+    pub chunks: u64,
+    // Source heights the snapshot covers, a full snapshot starts at zero.
+    pub heights: Range<u64>,
+    pub messages: u64,
+    pub markers: u64,
+    pub payload_bytes: u64,
+    // Chained digest of the payloads, see ExportKind::Digest.
+    pub digest: Vec<u8>,
+    // Whether it matched the expected digest, None when none was given.
+    pub digest_matches: Option<bool>,
+    // Messages stored earlier than the one before them, restores keep them but time seeks need repair.
+    pub timestamp_regressions: u64,
+    // Whether the index zone has room for every message.
+    pub fits: bool,
+    pub complete: bool,
+    pub problems: Vec<(u64, FsError)>,
+}
+
+#[cfg(feature = "jobs")]
+impl RestoreReport {
+    pub fn is_restorable(&self) -> bool {
+        self.problems.is_empty() && self.complete && self.fits && self.digest_matches != Some(false)
+    }
+}
+
+// Parses the chunks an import would stage and checks them against what import_chunk and commit_import
+// enforce, without writing anything.
+#[cfg(feature = "jobs")]
+pub(crate) fn verify_snapshot<'a>(chunks: impl IntoIterator<Item = &'a ExportChunk>,
+                                  hasher: &dyn Hasher,
+                                  expected_digest: Option<&[u8]>,
+                                  size_limit: u64,
+                                  capacity: u64,
+) -> RestoreReport {
+    let mut report = RestoreReport::default();
+    let mut job_id = None;
+    let mut last_timestamp = None;
+    for chunk in chunks {
+        if report.complete || chunk.sequence != report.chunks || job_id.is_some_and(|id| id != chunk.job_id) {
+            let reason = format!("chunk {} of job {} doesn't follow chunk {} of job {:?}", chunk.sequence, chunk.job_id, report.chunks, job_id);
+            report.problems.push((chunk.sequence, FsError::InvalidArgument(reason)));
+            break;
+        }
+        job_id = Some(chunk.job_id);
+        let entries: Vec<(IndexExportEntry, Vec<u8>)> = match bincode::deserialize(&chunk.bytes) {
+            Ok(entries) => entries,
+            Err(e) => {
+                report.problems.push((chunk.sequence, FsError::Deserialize(format!("snapshot chunk {}: {}", chunk.sequence, e))));
+                break;
+            }
+        };
+        for (entry, payload) in &entries {
+            if report.messages + report.markers == 0 {
+                report.heights = entry.height..entry.height;
+            }
+            if entry.height != report.heights.end {
+                let reason = format!("entry {} follows height {}", entry.height, report.heights.end);
+                report.problems.push((chunk.sequence, FsError::CorruptIndex { height: entry.height, reason }));
+            }
+            if (entry.data_size == 0) != payload.is_empty() {
+                let reason = format!("claims {} bytes but carries {}", entry.data_size, payload.len());
+                report.problems.push((chunk.sequence, FsError::CorruptIndex { height: entry.height, reason }));
+            }
+            if payload.len() as u64 > size_limit {
+                report.problems.push((chunk.sequence, FsError::MessageTooLarge { size: payload.len() as u64, limit: size_limit }));
+            }
+            if last_timestamp.is_some_and(|last| entry.timestamp < last) {
+                report.timestamp_regressions += 1;
+            }
+            last_timestamp = Some(entry.timestamp);
+            match payload.is_empty() {
+                true => report.markers += 1,
+                false => report.messages += 1,
+            }
+            report.payload_bytes += payload.len() as u64;
+            report.digest = hasher.digest(&[report.digest.as_slice(), payload].concat());
+            report.heights.end = entry.height + 1;
+        }
+        report.chunks += 1;
+        report.complete = chunk.done;
+    }
+    report.digest_matches = expected_digest.map(|expected| expected == report.digest);
+    report.fits = report.messages + report.markers <= capacity;
+    report
+}
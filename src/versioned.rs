@@ -0,0 +1,136 @@
+// Declares an event enum whose variants are encoded by an explicit id instead of their position.
+// bincode writes a variant's declaration index, so reordering or removing a variant of a plain enum
+// silently makes old messages decode as the wrong variant. Each variant carries one payload (use
+// `()` for none) and is encoded as the tuple `(id, payload)`. Ids must be unique, which is checked at
+// compile time, and should never be reused once messages with them have been written.
+//
+//     versioned_event! {
+//         #[derive(Debug, Clone, PartialEq)]
+//         pub enum AccountEvent {
+//             1 => Opened(String),
+//             2 => Deposited(u64),
+//             4 => Closed(()),
+//         }
+//     }
+#[macro_export]
+macro_rules! versioned_event {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($id:literal => $variant:ident($payload:ty)),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($variant($payload)),+
+        }
+
+        const _: () = {
+            let ids: &[u32] = &[$($id),+];
+            let mut i = 0;
+            while i < ids.len() {
+                let mut j = i + 1;
+                while j < ids.len() {
+                    assert!(ids[i] != ids[j], concat!("duplicate variant id in ", stringify!($name)));
+                    j += 1;
+                }
+                i += 1;
+            }
+        };
+
+        impl $name {
+            #[allow(dead_code)]
+            pub fn variant_id(&self) -> u32 {
+                match self {
+                    $($name::$variant(_) => $id),+
+                }
+            }
+        }
+
+        impl $crate::StableEncode for $name {}
+
+        impl $crate::__serde::Serialize for $name {
+            fn serialize<S: $crate::__serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                use $crate::__serde::ser::SerializeTuple;
+                let mut tuple = serializer.serialize_tuple(2)?;
+                match self {
+                    $($name::$variant(payload) => {
+                        tuple.serialize_element(&($id as u32))?;
+                        tuple.serialize_element(payload)?;
+                    })+
+                }
+                tuple.end()
+            }
+        }
+
+        impl<'de> $crate::__serde::Deserialize<'de> for $name {
+            fn deserialize<D: $crate::__serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct VariantVisitor;
+
+                impl<'de> $crate::__serde::de::Visitor<'de> for VariantVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        write!(f, concat!("a variant id and payload of ", stringify!($name)))
+                    }
+
+                    fn visit_seq<A: $crate::__serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<$name, A::Error> {
+                        use $crate::__serde::de::Error;
+                        let id: u32 = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(0, &self))?;
+                        match id {
+                            $($id => seq.next_element()?.map($name::$variant).ok_or_else(|| A::Error::invalid_length(1, &self)),)+
+                            _ => Err(A::Error::custom(format!(concat!("unknown ", stringify!($name), " variant id {}"), id))),
+                        }
+                    }
+                }
+
+                deserializer.deserialize_tuple(2, VariantVisitor)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::BincodeCodec;
+
+    mod v1 {
+        versioned_event! {
+            #[derive(Debug, Clone, PartialEq)]
+            pub enum AccountEvent {
+                1 => Opened(String),
+                2 => Deposited(u64),
+                3 => Withdrawn(u64),
+            }
+        }
+    }
+
+    // Reordered, Withdrawn removed and a variant added.
+    mod v2 {
+        versioned_event! {
+            #[derive(Debug, Clone, PartialEq)]
+            pub enum AccountEvent {
+                4 => Closed(()),
+                2 => Deposited(u64),
+                1 => Opened(String),
+            }
+        }
+    }
+
+    #[test]
+    fn it_decodes_variants_by_id_after_reordering() {
+        let codec = BincodeCodec::default();
+        let opened = codec.serialize(&v1::AccountEvent::Opened("alice".to_string())).unwrap();
+        let deposited = codec.serialize(&v1::AccountEvent::Deposited(5)).unwrap();
+        let withdrawn = codec.serialize(&v1::AccountEvent::Withdrawn(5)).unwrap();
+
+        assert_eq!(codec.deserialize::<v2::AccountEvent>(&opened).unwrap(), v2::AccountEvent::Opened("alice".to_string()));
+        assert_eq!(codec.deserialize::<v2::AccountEvent>(&deposited).unwrap(), v2::AccountEvent::Deposited(5));
+        assert!(codec.deserialize::<v2::AccountEvent>(&withdrawn).is_err());
+        assert_eq!(v2::AccountEvent::Closed(()).variant_id(), 4);
+
+        let json = serde_json::to_string(&v2::AccountEvent::Deposited(7)).unwrap();
+        assert_eq!(json, "[2,7]");
+        assert_eq!(serde_json::from_str::<v1::AccountEvent>(&json).unwrap(), v1::AccountEvent::Deposited(7));
+    }
+}